@@ -71,13 +71,19 @@ pub trait BasisCore: Clone + std::fmt::Debug {
 }
 
 
-pub trait Basis: Clone + std::fmt::Debug + BitOps + BasisCore + Sync + Ord {
+pub trait Basis: Clone + std::fmt::Debug + BitOps + BasisCore + Send + Sync + Ord {
     type B: Bits;
 
     fn bit_capacity(&self) -> usize {
         self._bit_capacity()
     }
 
+    /// The number of bits the basis was made with, where it keeps track of it (a [`DBasis`]); a
+    /// static basis is always its full capacity.
+    fn bit_length(&self) -> usize {
+        self.bit_capacity()
+    }
+
     fn is_zero(&self) -> bool;
 
     fn size_descriptor() -> &'static str;
@@ -176,7 +182,65 @@ pub trait Basis: Clone + std::fmt::Debug + BitOps + BasisCore + Sync + Ord {
 
     fn parity(&self) -> bool;
 
-    fn pretty_print(&self);
+    /// `out = self & rhs`. Unlike [`RefAnd::and`], this can reuse `out`'s allocation (see [`DBasis`]).
+    #[inline(always)]
+    fn and_into(&self, rhs: &Self, out: &mut Self) {
+        *out = self.and(rhs);
+    }
+
+    /// `out = self | rhs`, reusing `out`'s allocation where possible.
+    #[inline(always)]
+    fn or_into(&self, rhs: &Self, out: &mut Self) {
+        *out = self.or(rhs);
+    }
+
+    /// `out = self ^ rhs`, reusing `out`'s allocation where possible.
+    #[inline(always)]
+    fn xor_into(&self, rhs: &Self, out: &mut Self) {
+        *out = self.xor(rhs);
+    }
+
+    /// `(self & rhs).popcnt()`. [`DBasis`] overrides this to count chunk by chunk, without
+    /// allocating the intermediate basis.
+    #[inline(always)]
+    fn and_popcnt(&self, rhs: &Self) -> usize {
+        self.and(rhs).popcnt()
+    }
+
+    /// Number of bits set in both `self` and `other`, i.e. the number of Y qubits when `self` and
+    /// `other` are the x and z parts of a Pauli.
+    #[inline(always)]
+    fn count_y(&self, other: &Self) -> usize {
+        self.and_popcnt(other)
+    }
+
+    /// Number of bits which differ between `self` and `other`.
+    fn hamming_distance(&self, other: &Self) -> usize {
+        self.xor(other).popcnt()
+    }
+
+    /// The raw bits, for debugging.
+    fn pretty_string(&self) -> String;
+
+    fn pretty_print(&self) {
+        print!("{}", self.pretty_string());
+    }
+}
+
+
+/// The Pauli string of `(x, z)` (e.g. `IXYZ`, qubit 0 first), without a `Rotate`/`Measure` prefix.
+///
+/// Always in the default letters; the text output's `--pauli-convention` and `--qubit-order` don't
+/// apply.
+pub fn pauli_string<B: Basis>(x: &B, z: &B, n_qubits: usize) -> String {
+    (0..n_qubits)
+        .map(|qubit| match (x.get_bit(qubit), z.get_bit(qubit)) {
+            (false, false) => 'I',
+            (true, false) => 'X',
+            (true, true) => 'Y',
+            (false, true) => 'Z',
+        })
+        .collect()
 }
 
 
@@ -241,4 +305,37 @@ pub mod tests {
     // fn test_simple_d() {
     //     test_simple::<DBasis>();
     // }
+
+    use super::*;
+
+    fn check_hamming_distance<B: Basis>(bit_length: usize) {
+        let zero = B::zero(bit_length);
+        let one = B::one(bit_length);
+        let first = B::one_bit(bit_length, 0);
+
+        assert_eq!(zero.hamming_distance(&zero), 0);
+        assert_eq!(one.hamming_distance(&one), 0);
+        assert_eq!(zero.hamming_distance(&one), bit_length);
+        assert_eq!(one.hamming_distance(&zero), bit_length);
+        assert_eq!(first.hamming_distance(&one), bit_length - 1);
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        check_hamming_distance::<Basis8>(8);
+        check_hamming_distance::<Basis16>(16);
+        check_hamming_distance::<Basis32>(32);
+        check_hamming_distance::<Basis64>(64);
+        check_hamming_distance::<Basis128>(128);
+        check_hamming_distance::<Basis256>(255);
+        check_hamming_distance::<DBasis>(300);
+    }
+
+    #[test]
+    fn test_pauli_string() {
+        let x = Basis8::with_true_bits(5, &[1, 2]);
+        let z = Basis8::with_true_bits(5, &[2, 3]);
+        assert_eq!(pauli_string(&x, &z, 5), "IXYZI");
+        assert_eq!(pauli_string(&DBasis::zero(3), &DBasis::one(3), 3), "ZZZ");
+    }
 }
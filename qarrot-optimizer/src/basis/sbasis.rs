@@ -220,7 +220,7 @@ impl<B: Bits> Basis for SBasis<B> {
         self.bits &= !Self::B::bit_k(bit);  
     }
 
-    fn pretty_print(&self) {
-        self.bits.pretty_print();
+    fn pretty_string(&self) -> String {
+        self.bits.pretty_string()
     }
 }
@@ -20,7 +20,7 @@ pub const fn chunk_bit<B: Bits>(bit: usize) -> (usize, usize) {
     (bit / B::BITS, bit % B::BITS)
 }
 
-pub trait Bits: Sized + Copy + BitOps + Not<Output = Self> + LNeg + std::fmt::Debug + std::fmt::Binary + Sync + Ord {
+pub trait Bits: Sized + Copy + BitOps + Not<Output = Self> + LNeg + std::fmt::Debug + std::fmt::Binary + Send + Sync + Ord {
     const BITS: usize;
     const ZERO: Self;
     const ONE: Self;
@@ -35,9 +35,21 @@ pub trait Bits: Sized + Copy + BitOps + Not<Output = Self> + LNeg + std::fmt::De
 
     fn bit_k(k: usize) -> Self;
 
+    /// Shift left as an integer by `n`, so bit `k` (see [`Bits::bit_k`]) moves to bit `k - n`,
+    /// i.e. towards qubit 0. Bits shifted out are dropped; `n >= BITS` gives zero.
+    fn shl(self, n: usize) -> Self;
+
+    /// Shift right as an integer by `n`, so bit `k` moves to bit `k + n`. Bits shifted out are
+    /// dropped; `n >= BITS` gives zero.
+    fn shr(self, n: usize) -> Self;
+
     fn popcnt(&self) -> usize;
 
-    fn pretty_print(&self);
+    fn pretty_string(&self) -> String;
+
+    fn pretty_print(&self) {
+        print!("{}", self.pretty_string());
+    }
 
     fn parity(&self) -> bool {
         // it seems like this usually optimizes well (into parity flag check)
@@ -78,14 +90,23 @@ macro_rules! impl_bits_prim {
                 (1 as Self).checked_shl(Self::BITS - k as u32 - 1).unwrap_or(Self::ZERO)
             }
 
+            #[inline(always)]
+            fn shl(self, n: usize) -> Self {
+                u32::try_from(n).ok().and_then(|n| self.checked_shl(n)).unwrap_or(Self::ZERO)
+            }
+
+            #[inline(always)]
+            fn shr(self, n: usize) -> Self {
+                u32::try_from(n).ok().and_then(|n| self.checked_shr(n)).unwrap_or(Self::ZERO)
+            }
+
             #[inline(always)]
             fn popcnt(&self) -> usize {
                 self.count_ones() as usize
             }
 
-            #[inline(always)]
-            fn pretty_print(&self) {
-                print!("{:b}", self)
+            fn pretty_string(&self) -> String {
+                format!("{:b}", self)
             }
         }
     };
@@ -144,6 +165,17 @@ mod tests {
         assert_eq!(_test_all_bits::<u8>(), u8::MAX);
     }
 
+    #[test]
+    fn test_shift_prim() {
+        assert_eq!(Bits::shl(0b1001_0110u8, 3), 0b1011_0000);
+        assert_eq!(Bits::shr(0b1001_0110u8, 3), 0b0001_0010);
+        assert_eq!(Bits::shl(u8::MAX, 8), 0);
+        assert_eq!(Bits::shr(u128::MAX, 200), 0);
+        assert_eq!(Bits::shl(u64::bit_k(5), 5), u64::bit_k(0));
+        assert_eq!(Bits::shr(u16::bit_k(2), 13), u16::bit_k(15));
+        assert_eq!(Bits::shl(u32::MAX, usize::MAX), 0);
+    }
+
     #[test]
     fn test_mask_first_k() {
         let b = u8::mask_first_k(3);
@@ -18,6 +18,55 @@ impl Bits256 {
             bits
         }
     }
+
+    /// The 256-bit number `hi << 128 | lo`.
+    #[inline(always)]
+    pub const fn from_u128_pair(lo: u128, hi: u128) -> Self {
+        Self::new([lo as u64, (lo >> 64) as u64, hi as u64, (hi >> 64) as u64])
+    }
+}
+
+
+// numeric conversions: widening zero-extends (so `1` is `Bits256::ONE`), narrowing fails if any of
+// the high bits are set
+
+impl From<u64> for Bits256 {
+    #[inline(always)]
+    fn from(value: u64) -> Self {
+        Self::new([value, 0, 0, 0])
+    }
+}
+
+
+impl From<u128> for Bits256 {
+    #[inline(always)]
+    fn from(value: u128) -> Self {
+        Self::from_u128_pair(value, 0)
+    }
+}
+
+
+impl TryFrom<Bits256> for u64 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Bits256) -> Result<Self, Self::Error> {
+        if value.bits[1..].iter().any(|&limb| limb != 0) {
+            anyhow::bail!("{:?} doesn't fit in 64 bits", value);
+        }
+        Ok(value.bits[0])
+    }
+}
+
+
+impl TryFrom<Bits256> for u128 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Bits256) -> Result<Self, Self::Error> {
+        if value.bits[2..].iter().any(|&limb| limb != 0) {
+            anyhow::bail!("{:?} doesn't fit in 128 bits", value);
+        }
+        Ok((value.bits[1] as u128) << 64 | value.bits[0] as u128)
+    }
 }
 
 
@@ -174,19 +223,54 @@ impl Bits for Bits256 {
         }
     }
 
+    fn shl(self, n: usize) -> Self {
+        let (limbs, shift) = (n / 64, n % 64);
+        let mut bits = [0; 4];
+        // limb 0 is the least significant, so bits move up the limbs, carrying in from below
+        for (i, limb) in bits.iter_mut().enumerate().skip(limbs) {
+            *limb = self.bits[i - limbs] << shift;
+            if shift != 0 && i > limbs {
+                *limb |= self.bits[i - limbs - 1] >> (64 - shift);
+            }
+        }
+        Self { bits }
+    }
+
+    fn shr(self, n: usize) -> Self {
+        let (limbs, shift) = (n / 64, n % 64);
+        let mut bits = [0; 4];
+        for (i, limb) in bits.iter_mut().enumerate().take(4usize.saturating_sub(limbs)) {
+            *limb = self.bits[i + limbs] >> shift;
+            if shift != 0 && i + limbs + 1 < 4 {
+                *limb |= self.bits[i + limbs + 1] << (64 - shift);
+            }
+        }
+        Self { bits }
+    }
+
     fn popcnt(&self) -> usize {
         self.bits.iter().map(|i| i.count_ones() as usize).sum()
     }
 
-    fn pretty_print(&self) {
-        print!("{:b}", self)
+    #[inline(always)]
+    fn parity(&self) -> bool {
+        // the parity of the xor of the limbs is the parity of their total popcount, so this only
+        // needs one popcount instead of four
+        let folded = self.bits[0] ^ self.bits[1] ^ self.bits[2] ^ self.bits[3];
+        folded.parity()
+    }
+
+    fn pretty_string(&self) -> String {
+        format!("{:b}", self)
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::bits::b256::clamping_sub;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use crate::bits::{b256::{clamping_sub, Bits256}, Bits};
 
     #[test]
     fn test_clamping_sub() {
@@ -194,4 +278,75 @@ mod tests {
         assert_eq!(clamping_sub(5, 5), 0);
         assert_eq!(clamping_sub(5, 6), 0);
     }
+
+    // bit by bit, using only bit_k
+    fn shifted(b: Bits256, n: isize) -> Bits256 {
+        let mut expected = Bits256::ZERO;
+        for k in 0..256 {
+            let to = k as isize - n;
+            if b & Bits256::bit_k(k) != Bits256::ZERO && (0..256).contains(&to) {
+                expected |= Bits256::bit_k(to as usize);
+            }
+        }
+        expected
+    }
+
+    #[test]
+    fn test_shift() {
+        let mut rng = SmallRng::seed_from_u64(944);
+        for _ in 0..16 {
+            let b = Bits256::new(rng.gen());
+            for n in [0, 1, 5, 63, 64, 65, 127, 128, 129, 191, 192, 193, 255, 256, 300] {
+                assert_eq!(Bits::shl(b, n), shifted(b, n as isize), "{:?} << {}", b, n);
+                assert_eq!(Bits::shr(b, n), shifted(b, -(n as isize)), "{:?} >> {}", b, n);
+            }
+        }
+
+        // carries across each limb boundary
+        assert_eq!(Bits::shl(Bits256::ONE, 63), Bits256::new([1 << 63, 0, 0, 0]));
+        assert_eq!(Bits::shl(Bits256::ONE, 64), Bits256::new([0, 1, 0, 0]));
+        assert_eq!(Bits::shl(Bits256::ONE, 65), Bits256::new([0, 2, 0, 0]));
+        assert_eq!(Bits::shl(Bits256::ONE, 191), Bits256::new([0, 0, 1 << 63, 0]));
+        assert_eq!(Bits::shr(Bits256::new([0, 0, 0, 1]), 191), Bits256::new([2, 0, 0, 0]));
+        assert_eq!(Bits::shl(Bits256::MAX, 255), Bits256::bit_k(0));
+        assert_eq!(Bits::shr(Bits256::MAX, 255), Bits256::ONE);
+    }
+
+    #[test]
+    fn test_parity() {
+        let mut rng = SmallRng::seed_from_u64(930);
+        for _ in 0..1024 {
+            let b = Bits256::new(rng.gen());
+            assert_eq!(b.parity(), !b.popcnt().is_multiple_of(2), "{:?}", b);
+        }
+        assert!(!Bits256::ZERO.parity());
+        assert!(Bits256::bit_k(200).parity());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut rng = SmallRng::seed_from_u64(979);
+        for _ in 0..64 {
+            let small: u64 = rng.gen();
+            assert_eq!(u64::try_from(Bits256::from(small)).unwrap(), small);
+            assert_eq!(u128::try_from(Bits256::from(small)).unwrap(), small as u128);
+
+            let (lo, hi): (u128, u128) = (rng.gen(), rng.gen());
+            assert_eq!(u128::try_from(Bits256::from(lo)).unwrap(), lo);
+            let b = Bits256::from_u128_pair(lo, hi);
+            assert_eq!(Bits::shr(b, 128), Bits256::from(hi));
+            assert_eq!(b & Bits256::from(u128::MAX), Bits256::from(lo));
+        }
+
+        assert_eq!(Bits256::from(1u64), Bits256::ONE);
+        assert_eq!(Bits256::from_u128_pair(1 << 64 | 2, 3 << 64 | 4), Bits256::new([2, 1, 4, 3]));
+        assert_eq!(Bits256::from_u128_pair(u128::MAX, u128::MAX), Bits256::MAX);
+
+        // narrowing fails if any high bit is set
+        assert!(u64::try_from(Bits256::from(1u128 << 64)).is_err());
+        assert!(u128::try_from(Bits256::from(u128::MAX)).is_ok());
+        for k in 128..256 {
+            assert!(u128::try_from(Bits::shl(Bits256::ONE, k)).is_err(), "1 << {}", k);
+        }
+    }
 }
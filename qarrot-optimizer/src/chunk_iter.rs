@@ -1,6 +1,8 @@
 
+use std::mem;
+
 // NOTE: this cannot in general be cast to SIMD types, as the alignment is not guaranteed
-// make sure you use an unaligned load 
+// make sure you use an unaligned load (or see `aligned_chunks`)
 
 
 pub struct IterChunks<'a, T, const CHUNK_SIZE: usize> {
@@ -67,6 +69,58 @@ pub fn chunks<const CHUNK_SIZE: usize, T>(slice: &[T]) -> IterChunks<'_, T, CHUN
 }
 
 
+/// [`IterChunks`] over a slice which starts on a multiple of [`AlignedChunks::ALIGN`] bytes, so
+/// every chunk is aligned to the size of a chunk (e.g. 32 bytes for `[u64; 4]`) and can be loaded
+/// with aligned SIMD loads.
+pub struct AlignedChunks<'a, T, const CHUNK_SIZE: usize> {
+    inner: IterChunks<'a, T, CHUNK_SIZE>,
+}
+
+impl<'a, T, const CHUNK_SIZE: usize> AlignedChunks<'a, T, CHUNK_SIZE> {
+    /// The alignment of every chunk, in bytes.
+    pub const ALIGN: usize = mem::size_of::<[T; CHUNK_SIZE]>();
+
+    /// `None` if `slice` doesn't start suitably aligned, or chunks aren't a power of two bytes.
+    #[inline(always)]
+    pub fn new(slice: &'a [T]) -> Option<Self> {
+        if !Self::ALIGN.is_power_of_two() || !(slice.as_ptr() as usize).is_multiple_of(Self::ALIGN) {
+            return None;
+        }
+        Some(Self { inner: IterChunks::new(slice) })
+    }
+
+    #[inline(always)]
+    pub fn next_chunk(&mut self) -> Option<&'a [T; CHUNK_SIZE]> {
+        let chunk = self.inner.next_chunk();
+        debug_assert!(chunk.is_none_or(|chunk| (chunk.as_ptr() as usize).is_multiple_of(Self::ALIGN)));
+        chunk
+    }
+
+    #[inline(always)]
+    pub fn remainder(&mut self) -> &'a [T] {
+        self.inner.remainder()
+    }
+}
+
+
+/// Chunks of a slice, aligned for SIMD loads if the slice happens to start aligned (see
+/// [`aligned_chunks`]).
+pub enum MaybeAlignedChunks<'a, T, const CHUNK_SIZE: usize> {
+    Aligned(AlignedChunks<'a, T, CHUNK_SIZE>),
+    Unaligned(IterChunks<'a, T, CHUNK_SIZE>),
+}
+
+
+/// Like [`chunks`], but probes the slice's alignment once up front, so the caller can pick an
+/// aligned or unaligned loop (rather than checking every chunk). The chunks are the same either way.
+pub fn aligned_chunks<const CHUNK_SIZE: usize, T>(slice: &[T]) -> MaybeAlignedChunks<'_, T, CHUNK_SIZE> {
+    match AlignedChunks::new(slice) {
+        Some(aligned) => MaybeAlignedChunks::Aligned(aligned),
+        None => MaybeAlignedChunks::Unaligned(IterChunks::new(slice)),
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,7 +133,7 @@ mod tests {
         assert_eq!(iter.next_chunk(), Some(&[4, 5, 6, 7]));
         assert_eq!(iter.next_chunk(), None);
         assert_eq!(iter.remainder(), &[8, 9, 10]);
-        assert_eq!(iter.remainder(), &[]);
+        assert_eq!(iter.remainder(), &[] as &[i32]);
     }
 
     #[test]
@@ -93,6 +147,48 @@ mod tests {
         assert_eq!(&buf, &[4, 5, 6, 7]);
         assert!(!iter.next_chunk_into(&mut buf));
         assert_eq!(iter.remainder(), &[8, 9, 10]);
-        assert_eq!(iter.remainder(), &[]);
+        assert_eq!(iter.remainder(), &[] as &[usize]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_aligned_chunks() {
+        #[repr(align(32))]
+        struct Aligned([u64; 19]);
+        let buf = Aligned(std::array::from_fn(|i| i as u64 * 3 + 1));
+
+        for offset in 0..8 {
+            let slice = &buf.0[offset..];
+            let mut expected = Vec::new();
+            let mut iter = chunks::<4, _>(slice);
+            while let Some(chunk) = iter.next_chunk() {
+                expected.push(*chunk);
+            }
+            let expected_remainder = iter.remainder();
+
+            let mut actual = Vec::new();
+            let remainder = match aligned_chunks::<4, _>(slice) {
+                MaybeAlignedChunks::Aligned(mut iter) => {
+                    // [u64; 4] is 32 bytes, so only every fourth offset is aligned
+                    assert_eq!(offset % 4, 0);
+                    while let Some(chunk) = iter.next_chunk() {
+                        assert_eq!(chunk.as_ptr() as usize % 32, 0);
+                        actual.push(*chunk);
+                    }
+                    iter.remainder()
+                },
+                MaybeAlignedChunks::Unaligned(mut iter) => {
+                    assert_ne!(offset % 4, 0);
+                    while let Some(chunk) = iter.next_chunk() {
+                        actual.push(*chunk);
+                    }
+                    iter.remainder()
+                },
+            };
+            assert_eq!(actual, expected, "offset {}", offset);
+            assert_eq!(remainder, expected_remainder, "offset {}", offset);
+        }
+
+        // 3 * 8 bytes isn't a power of two, so never aligned
+        assert!(matches!(aligned_chunks::<3, _>(&buf.0), MaybeAlignedChunks::Unaligned(_)));
+    }
+}
@@ -1,6 +1,12 @@
-use std::{fmt::Debug, ops::{BitXorAssign, MulAssign}};
+use std::{fmt::{Debug, Display}, mem, ops::{BitXorAssign, MulAssign}};
 
-use crate::{basis::Basis, symplectic::Symplectic};
+use anyhow::bail;
+
+use crate::{basis::{pauli_string, Basis}, operation::{angle::Angle, Operation, OperationKind}, symplectic::Symplectic};
+
+
+/// Above this many qubits, [`Clifford`]'s `Display` only shows the first this many rows and columns.
+pub const MAX_DISPLAY_QUBITS: usize = 32;
 
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -63,7 +69,8 @@ impl<B: Basis> Clifford<B> {
         let mut ans: Symplectic<B> = Symplectic::zero(n_qubits);
         let mut i_count = 0;
 
-        // let mut current_y = B::zero(n_qubits);
+        // reused for every row, to avoid allocating temporaries in the dynamic basis case
+        let mut scratch = B::zero(n_qubits);
 
         for i in 0..n_qubits {
             if !old.get_bit(i) {
@@ -71,8 +78,9 @@ impl<B: Basis> Clifford<B> {
             }
             let xor_with = &rows[i];
 
-            i_count += xor_with.x.and(&xor_with.z).popcnt();
-            let n_commutations = ans.z.and(&xor_with.x).popcnt();
+            i_count += xor_with.x.count_y(&xor_with.z);
+            ans.z.and_into(&xor_with.x, &mut scratch);
+            let n_commutations = scratch.popcnt();
             i_count += 2 * n_commutations;
             ans.bitxor_assign(xor_with);
         }
@@ -81,7 +89,7 @@ impl<B: Basis> Clifford<B> {
     }
 
     pub fn conjugate(&self, sign: bool, x: &B, z: &B) -> Symplectic<B> {
-        let n_i_i = x.and(z).popcnt(); // | x & z |
+        let n_i_i = x.count_y(z); // | x & z |
 
         let (new_x, new_x_i) = Self::_conj(&self.x_rows, self.n_qubits, x);
         let (new_z, new_z_i) = Self::_conj(&self.z_rows, self.n_qubits, z);
@@ -107,13 +115,22 @@ impl<B: Basis> Clifford<B> {
         new
     }
 
+    /// The conjugate of +Z on `qubit`, i.e. `self.conjugate(false, &zero, &z_i)`.
+    ///
+    /// That's just the qubit's z-row, so this is a copy rather than a pass over the tableau (e.g.
+    /// for the Z measurement the optimizer appends on every qubit).
+    #[inline(always)]
+    pub fn conjugate_basis_z(&self, qubit: usize) -> Symplectic<B> {
+        self.z_rows[qubit].clone()
+    }
+
     pub fn from_pi4(&mut self, sign: bool, x: &B, z: &B) {
         self.set_identity();
 
         let input = Symplectic { sign, x: x.clone(), z: z.clone() };
 
         for row in self.x_rows.iter_mut() {
-            if !row.commutes_with(&input) {
+            if row.anticommutes_with(&input) {
                 // row.mul_by(&input, self.n_qubits);
                 let mul = input.mul(row);
                 *row = mul;
@@ -121,7 +138,7 @@ impl<B: Basis> Clifford<B> {
         }
 
         for row in self.z_rows.iter_mut() {
-            if !row.commutes_with(&input) {
+            if row.anticommutes_with(&input) {
                 // row.mul_by(&input, self.n_qubits);
                 let mul = input.mul(row);
                 *row = mul;
@@ -135,17 +152,107 @@ impl<B: Basis> Clifford<B> {
         let input = Symplectic { sign, x: x.clone(), z: z.clone() };
 
         for row in self.x_rows.iter_mut() {
-            if !row.commutes_with(&input) {
+            if row.anticommutes_with(&input) {
                 row.sign = true;
             }
         }
 
         for row in self.z_rows.iter_mut() {
-            if !row.commutes_with(&input) {
+            if row.anticommutes_with(&input) {
                 row.sign = true;
             }
         }
     }
+
+    /// Whether the rows form a valid symplectic basis: each x-row anticommutes with its z-row, and
+    /// commutes with every other row (and likewise for the z-rows).
+    ///
+    /// A tableau which fails this isn't a Clifford at all, which would mean a sign or commutation
+    /// bug somewhere. This is O(n^3), so it's meant for debugging and tests.
+    pub fn is_valid(&self) -> bool {
+        let n = self.n_qubits;
+        for i in 0..n {
+            if self.x_rows[i].commutes_with(&self.z_rows[i]) {
+                return false;
+            }
+            for j in (i + 1)..n {
+                if !self.x_rows[i].commutes_with(&self.x_rows[j])
+                    || !self.z_rows[i].commutes_with(&self.z_rows[j])
+                    || !self.x_rows[i].commutes_with(&self.z_rows[j])
+                    || !self.z_rows[i].commutes_with(&self.x_rows[j]) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Panic if the tableau isn't valid (see [`Clifford::is_valid`]); does nothing in release builds.
+    #[inline(always)]
+    pub fn debug_assert_valid(&self) {
+        debug_assert!(self.is_valid(), "invalid Clifford tableau: {:?}", self);
+    }
+
+    /// Compose a pi/4 rotation into this Clifford in place.
+    ///
+    /// Equivalent to `from_pi4` into a buffer, `buffer *= self`, then `self.set_to(buffer)`, but
+    /// without a second tableau. Conjugation is a homomorphism, so the new row for an anticommuting
+    /// qubit Pauli Q is conjugate(P)·row(Q): one conjugation for the rotation's Pauli P, then a
+    /// multiplication per updated row. The other rows are unchanged.
+    pub fn compose_pi4(&mut self, sign: bool, x: &B, z: &B) {
+        let conjugated = self.conjugate(sign, x, z);
+
+        // reused for every updated row, to avoid allocating temporaries in the dynamic basis case
+        let mut scratch = Symplectic::zero(self.n_qubits);
+        for i in 0..self.n_qubits {
+            // X_i anticommutes with P if P has Z or Y on qubit i, and Z_i if it has X or Y
+            for (rows, anticommutes) in [(&mut self.x_rows, z.get_bit(i)), (&mut self.z_rows, x.get_bit(i))] {
+                if anticommutes {
+                    scratch.set_zero();
+                    scratch ^= &conjugated;
+                    scratch.mul_by(&rows[i]);
+                    mem::swap(&mut rows[i], &mut scratch);
+                }
+            }
+        }
+    }
+
+    /// The operation `op` becomes when moved from before this Clifford to after it, as when
+    /// pushing T gates forward (see [`crate::optimization::push_accumulator`]).
+    ///
+    /// Barriers are unchanged. Clifford (pi/2 and pi/4) rotations are an error, since they change
+    /// the Clifford rather than the operation, as are nops.
+    pub fn apply_to_operation(&self, op: &Operation<B>) -> anyhow::Result<Operation<B>> {
+        match op.kind {
+            OperationKind::Nop => bail!("can't apply a Clifford to a nop"),
+            OperationKind::Barrier => Ok(op.clone()),
+            OperationKind::Measurement { phase } => {
+                let new = self.conjugate(phase.sign_bit(), &op.x, &op.z);
+                Ok(Operation::measurement(new.x, new.z, new.sign.into()).with_src_line(op.src_line()))
+            },
+            OperationKind::Rotation { angle: angle @ (Angle::PlusPi8 | Angle::MinusPi8) } => {
+                let new = self.conjugate(angle.sign_bit(), &op.x, &op.z);
+                Ok(Operation::rotation(new.x, new.z, angle.use_sign_bit(new.sign)).with_src_line(op.src_line()))
+            },
+            OperationKind::Rotation { angle } => bail!("can't apply a Clifford to a {:?} rotation, which is itself a Clifford", angle),
+        }
+    }
+
+    /// Compose a pi/2 rotation into this Clifford in place (see [`Clifford::compose_pi4`]).
+    pub fn compose_pi2(&mut self, _sign: bool, x: &B, z: &B) {
+        // a pi/2 rotation just negates the anticommuting rows (regardless of its sign)
+        for (i, row) in self.x_rows.iter_mut().enumerate() {
+            if z.get_bit(i) {
+                row.sign = !row.sign;
+            }
+        }
+
+        for (i, row) in self.z_rows.iter_mut().enumerate() {
+            if x.get_bit(i) {
+                row.sign = !row.sign;
+            }
+        }
+    }
 }
 
 
@@ -157,6 +264,29 @@ impl<B: Basis> MulAssign<&Clifford<B>> for Clifford<B> {
         for row in self.z_rows.iter_mut() {
             *row = rhs.conjugate(row.sign, &row.x, &row.z);
         }
+        #[cfg(feature = "validate-cliffords")]
+        self.debug_assert_valid();
+    }
+}
+
+
+/// The tableau as one line per row, x rows then z rows, e.g. `X0: +XZII` for the image of X on
+/// qubit 0.
+impl<B: Basis> Display for Clifford<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shown = self.n_qubits.min(MAX_DISPLAY_QUBITS);
+        let ellipsis = if shown < self.n_qubits { "…" } else { "" };
+
+        for (name, rows) in [("X", &self.x_rows), ("Z", &self.z_rows)] {
+            for (i, row) in rows.iter().take(shown).enumerate() {
+                let sign = if row.sign { '-' } else { '+' };
+                writeln!(f, "{}{}: {}{}{}", name, i, sign, pauli_string(&row.x, &row.z, shown), ellipsis)?;
+            }
+            if shown < self.n_qubits {
+                writeln!(f, "… ({} more {} rows)", self.n_qubits - shown, name)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -165,7 +295,7 @@ impl<B: Basis> MulAssign<&Clifford<B>> for Clifford<B> {
 mod tests {
     use rand::{rngs::SmallRng, Rng, SeedableRng};
 
-    use crate::basis::{Basis128, Basis16, Basis8};
+    use crate::{basis::{Basis128, Basis16, Basis8, DBasis}, operation::phase::{GlobalPhase, Phase}};
 
     use super::*;
     
@@ -226,9 +356,119 @@ mod tests {
             };
 
             clifford.from_pi4(pi4.sign, &pi4.x, &pi4.z);
+            clifford.debug_assert_valid();
+        }
+    }
+
+    #[test]
+    fn test_apply_to_operation() {
+        // a Z gate (pi/2 about Z) on qubit 0 flips the sign of X there, and leaves Z alone
+        let mut clifford = Clifford::<Basis8>::identity(2);
+        clifford.compose_pi2(false, &Basis8::zero(2), &Basis8::one_bit(2, 0));
+
+        let measure_x = Operation::measurement(Basis8::one_bit(2, 0), Basis8::zero(2), Phase::Positive);
+        let evolved = clifford.apply_to_operation(&measure_x).unwrap();
+        assert_eq!(evolved, Operation::measurement(Basis8::one_bit(2, 0), Basis8::zero(2), Phase::Negative));
+
+        let measure_zz = Operation::measurement(Basis8::zero(2), Basis8::one(2), Phase::Negative);
+        assert_eq!(clifford.apply_to_operation(&measure_zz).unwrap(), measure_zz);
+
+        assert!(clifford.apply_to_operation(&Operation::rotation(Basis8::zero(2), Basis8::one(2), Angle::PlusPi4)).is_err());
+        assert!(clifford.apply_to_operation(&Operation::rotation(Basis8::zero(2), Basis8::one(2), Angle::Pi2)).is_err());
+        assert_eq!(clifford.apply_to_operation(&Operation::barrier(2)).unwrap(), Operation::barrier(2));
+
+        // agrees with pushing T gates forward through a random Clifford
+        let mut rng = SmallRng::seed_from_u64(954);
+        let mut clifford = Clifford::<Basis16>::identity(10);
+        for _ in 0..32 {
+            clifford.compose_pi4(rng.gen(), &Basis16::rand(10, &mut rng), &Basis16::rand(10, &mut rng));
+        }
+        for _ in 0..64 {
+            let op = if rng.gen() {
+                Operation::measurement(Basis16::rand(10, &mut rng), Basis16::rand(10, &mut rng), Phase::from(rng.gen::<bool>()))
+            } else {
+                Operation::rotation(Basis16::rand(10, &mut rng), Basis16::rand(10, &mut rng), if rng.gen() { Angle::PlusPi8 } else { Angle::MinusPi8 })
+            };
+            let (_, _, pushed) = crate::optimization::push_accumulator(&mut clifford.clone(), &mut GlobalPhase::default(), &op);
+            assert_eq!(clifford.apply_to_operation(&op).unwrap(), pushed.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_conjugate_basis_z() {
+        let mut rng = SmallRng::seed_from_u64(966);
+        let mut clifford = Clifford::<Basis128>::identity(100);
+        for _ in 0..64 {
+            clifford.compose_pi4(rng.gen(), &Basis128::rand(100, &mut rng), &Basis128::rand(100, &mut rng));
+        }
+        for qubit in 0..100 {
+            let z = Basis128::one_bit(100, qubit);
+            assert_eq!(clifford.conjugate_basis_z(qubit), clifford.conjugate(false, &Basis128::zero(100), &z));
         }
     }
 
+    #[test]
+    fn test_valid() {
+        let mut clifford = Clifford::<Basis8>::identity(4);
+        assert!(clifford.is_valid());
+
+        // an x-row which commutes with its z-row
+        clifford.x_rows[1].x.set_bit_false(1);
+        clifford.x_rows[1].z.set_bit_true(1);
+        assert!(!clifford.is_valid());
+
+        // an x-row which anticommutes with another qubit's z-row
+        let mut clifford = Clifford::<Basis8>::identity(4);
+        clifford.x_rows[0].x.set_bit_true(2);
+        assert!(!clifford.is_valid());
+
+        // signs don't matter
+        let mut clifford = Clifford::<Basis8>::identity(4);
+        clifford.z_rows[3].sign = true;
+        assert!(clifford.is_valid());
+    }
+
+    fn test_compose<B: Basis>(n_qubits: usize) {
+        let mut rng = SmallRng::seed_from_u64(891);
+        let mut expected = Clifford::<B>::identity(n_qubits);
+        let mut buf = Clifford::<B>::identity(n_qubits);
+        let mut composed = Clifford::<B>::identity(n_qubits);
+
+        for i in 0..256 {
+            let sign = rng.gen();
+            let x = B::rand(n_qubits, &mut rng);
+            let z = B::rand(n_qubits, &mut rng);
+
+            if i % 4 == 0 {
+                buf.from_pi2(sign, &x, &z);
+                composed.compose_pi2(sign, &x, &z);
+            } else {
+                buf.from_pi4(sign, &x, &z);
+                composed.compose_pi4(sign, &x, &z);
+            }
+            buf *= &expected;
+            expected.set_to(&buf);
+
+            assert_eq!(composed, expected);
+        }
+        composed.debug_assert_valid();
+    }
+
+    #[test]
+    fn test_compose_8() {
+        test_compose::<Basis8>(8);
+    }
+
+    #[test]
+    fn test_compose_128() {
+        test_compose::<Basis128>(100);
+    }
+
+    #[test]
+    fn test_compose_dyn() {
+        test_compose::<DBasis>(150);
+    }
+
     #[test]
     fn test_mul_ident() {
         let ident = Clifford::identity(128);
@@ -247,6 +487,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_display() {
+        let clifford: Clifford<Basis8> = Clifford::identity(4);
+        assert_eq!(
+            clifford.to_string(),
+            "X0: +XIII\nX1: +IXII\nX2: +IIXI\nX3: +IIIX\nZ0: +ZIII\nZ1: +IZII\nZ2: +IIZI\nZ3: +IIIZ\n",
+        );
+
+        let wide: Clifford<Basis128> = Clifford::identity(40);
+        let shown = wide.to_string();
+        assert_eq!(shown.lines().count(), 2 * (MAX_DISPLAY_QUBITS + 1));
+        assert!(shown.lines().next().unwrap().ends_with("II…"));
+        assert!(shown.contains("… (8 more Z rows)"));
+    }
+
     #[test]
     fn test_dbg() {
         let mut clifford: Clifford<Basis16> = Clifford::identity(9);
@@ -277,4 +532,4 @@ mod tests {
 
         assert!(!result.sign);
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,47 @@
-use std::{fs, io::{self, BufReader}, path};
+use std::{fmt, fs, io::{self, BufReader}, path};
 
+#[cfg(feature = "bzip2")]
+use bzip2::read::BzDecoder;
 use flate2::read::GzDecoder;
 use fs2::FileExt;
 use log::{trace, warn};
 use memmap2::MmapMut;
 
+use crate::{basis::Basis, operation::Operation};
+
 pub mod lexer;
 pub mod parser;
 
 
+/// Widens every operation from `n_qubits` to `width` qubits (see `--min-qubits`).
+#[derive(Debug)]
+pub struct PadQubits<I> {
+    inner: I,
+    n_qubits: usize,
+    width: usize,
+}
+
+
+impl<I> PadQubits<I> {
+    pub fn new(inner: I, n_qubits: usize, width: usize) -> Self {
+        Self { inner, n_qubits, width }
+    }
+}
+
+
+impl<B: Basis, I: Iterator<Item = anyhow::Result<Operation<B>>>> Iterator for PadQubits<I> {
+    type Item = anyhow::Result<Operation<B>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|op| op.map(|op| op.padded(self.n_qubits, self.width)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+
 pub struct LockingFileReference {
     mmap: MmapMut,
     file_handle: fs::File,
@@ -49,12 +82,31 @@ impl Drop for LockingFileReference {
 }
 
 
-#[derive(Debug)]
+/// Default `--stdin-chunk-size`.
+pub const DEFAULT_STDIN_CHUNK_SIZE: usize = 64 << 10;
+
+
 pub enum Input {
     // Buffer(&'a [u8]),
     File(BufReader<fs::File>),
     GZip(GzDecoder<BufReader<fs::File>>),
-    Stdin(io::Stdin),
+    #[cfg(feature = "bzip2")]
+    Bzip2(BzDecoder<BufReader<fs::File>>),
+    Stdin(BufReader<io::Stdin>),
+}
+
+
+// by hand, since BzDecoder isn't Debug
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::File(file) => f.debug_tuple("File").field(file).finish(),
+            Input::GZip(gzip) => f.debug_tuple("GZip").field(gzip).finish(),
+            #[cfg(feature = "bzip2")]
+            Input::Bzip2(_) => f.write_str("Bzip2(..)"),
+            Input::Stdin(stdin) => f.debug_tuple("Stdin").field(stdin).finish(),
+        }
+    }
 }
 
 
@@ -64,6 +116,8 @@ impl io::Read for Input {
             // Input::Buffer(b) => b.read(buf),
             Input::File(f) => f.read(buf),
             Input::GZip(g) => g.read(buf),
+            #[cfg(feature = "bzip2")]
+            Input::Bzip2(b) => b.read(buf),
             Input::Stdin(s) => s.read(buf),
         }
     }
@@ -83,7 +137,16 @@ impl Input {
         Ok(Self::GZip(GzDecoder::new(BufReader::new(file))))
     }
 
-    pub fn stdin() -> anyhow::Result<Self> {
-        Ok(Self::Stdin(io::stdin()))
+    #[cfg(feature = "bzip2")]
+    pub fn new_bzip2(path: impl AsRef<path::Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = fs::File::open(path)?;
+        Ok(Self::Bzip2(BzDecoder::new(BufReader::new(file))))
+    }
+
+    /// Read STDIN `chunk_size` bytes at a time. The text lexer reads a byte at a time, which
+    /// would otherwise lock (and maybe read) STDIN for every byte.
+    pub fn stdin(chunk_size: usize) -> anyhow::Result<Self> {
+        Ok(Self::Stdin(BufReader::with_capacity(chunk_size, io::stdin())))
     }
 }
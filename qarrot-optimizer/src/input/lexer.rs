@@ -1,9 +1,10 @@
-use std::{collections::VecDeque, fmt::Write, io::{Bytes, Read}};
+use std::{collections::VecDeque, fmt::Write, io::{BufReader, Bytes, Read}, iter::Peekable};
 use lazy_static::lazy_static;
 use regex::Regex;
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
+use log::warn;
 
-use crate::operation::phase::Phase;
+use crate::operation::{angle::Angle, phase::Phase};
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,6 +32,95 @@ impl TryFrom<char> for Pauli {
 
 
 
+/// Which letters stand for the single-bit Paulis of a symplectic `(x, z)` pair.
+///
+/// `XZ` (the default) writes `x` as `X` and `z` as `Z`; `ZX` swaps them. `I` and `Y` (neither and both
+/// bits) are the same in both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PauliConvention {
+    #[default]
+    XZ,
+    ZX,
+}
+
+
+impl PauliConvention {
+    // letters for (x, z) = (0, 0), (1, 0), (0, 1), (1, 1)
+    fn table(&self) -> [char; 4] {
+        match self {
+            PauliConvention::XZ => ['I', 'X', 'Z', 'Y'],
+            PauliConvention::ZX => ['I', 'Z', 'X', 'Y'],
+        }
+    }
+
+    #[inline(always)]
+    pub fn to_char(&self, x: bool, z: bool) -> char {
+        self.table()[x as usize | ((z as usize) << 1)]
+    }
+
+    /// Parse a (case-insensitive) letter into the Pauli it stands for under this convention.
+    pub fn parse(&self, ch: char) -> Result<Pauli, char> {
+        match self.table().iter().position(|letter| *letter == ch.to_ascii_uppercase()) {
+            Some(0) => Ok(Pauli::I),
+            Some(1) => Ok(Pauli::X),
+            Some(2) => Ok(Pauli::Z),
+            Some(3) => Ok(Pauli::Y),
+            _ => Err(ch),
+        }
+    }
+}
+
+
+impl TryFrom<&str> for PauliConvention {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "xz" => Ok(Self::XZ),
+            "zx" => Ok(Self::ZX),
+            _ => bail!("unrecognized Pauli convention {:?}, possible values are \"xz\" or \"zx\".", value),
+        }
+    }
+}
+
+
+/// Which end of a Pauli string qubit 0 is written at.
+///
+/// `Msb` (the default) writes qubit 0 leftmost, e.g. `XII` is X on qubit 0; `Lsb` writes it
+/// rightmost, as little-endian tools expect, so the same operation is `IIX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QubitOrder {
+    #[default]
+    Msb,
+    Lsb,
+}
+
+
+impl QubitOrder {
+    /// The qubit written at `position` (counting from the left) of an `n_qubits` Pauli string.
+    #[inline(always)]
+    pub fn qubit(&self, position: usize, n_qubits: usize) -> usize {
+        match self {
+            QubitOrder::Msb => position,
+            QubitOrder::Lsb => n_qubits - 1 - position,
+        }
+    }
+}
+
+
+impl TryFrom<&str> for QubitOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "msb" => Ok(Self::Msb),
+            "lsb" => Ok(Self::Lsb),
+            _ => bail!("unrecognized qubit order {:?}, possible values are \"msb\" or \"lsb\".", value),
+        }
+    }
+}
+
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Token {
     Rotate(i8),
@@ -41,24 +131,68 @@ pub enum Token {
 }
 
 
+/// The most Paulis a line may have before the number of qubits is known (see
+/// [`TokenIterator::set_max_paulis`]), so a huge run length can't exhaust memory.
+pub const DEFAULT_MAX_PAULIS: usize = 1 << 20;
+
+
 #[derive(Debug)]
 pub struct TokenIterator<R: Read> {
-    source: Bytes<R>,
+    source: Peekable<Bytes<BufReader<R>>>,
     token_buf: VecDeque<Token>,
     line_buf: String,
     line_count: usize,
+    lenient: bool,
+    convention: PauliConvention,
+    order: QubitOrder,
+    max_paulis: usize,
 }
 
 
 impl<R: Read> TokenIterator<R> {
     pub fn new(source: R) -> Self {
         Self {
-            source: source.bytes(),
+            // read byte by byte (to find the line endings), so buffer the source
+            source: BufReader::new(source).bytes().peekable(),
             token_buf: VecDeque::with_capacity(128),
             line_buf: String::with_capacity(128),
             line_count: 0,
+            lenient: false,
+            convention: PauliConvention::default(),
+            order: QubitOrder::default(),
+            max_paulis: DEFAULT_MAX_PAULIS,
         }
     }
+
+    pub fn set_pauli_convention(&mut self, convention: PauliConvention) {
+        self.convention = convention;
+    }
+
+    pub fn set_qubit_order(&mut self, order: QubitOrder) {
+        self.order = order;
+    }
+
+    /// Lines with more than `max_paulis` Paulis (e.g. once the number of qubits is known) are an
+    /// error, checked before any run-length encoded run is expanded.
+    pub fn set_max_paulis(&mut self, max_paulis: usize) {
+        self.max_paulis = max_paulis;
+    }
+
+    /// In lenient mode, lines that can't be tokenized are skipped (with a warning) instead of
+    /// being an error. Useful for recovering what we can from partially corrupt files.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    /// The (1-indexed) line number of the most recently read line (the last one, for a line
+    /// continued with `\`).
+    pub fn line_number(&self) -> usize {
+        self.line_count
+    }
 }
 
 
@@ -87,9 +221,15 @@ lazy_static! {
         // optional whitespace
         // literal ':'
         // optional whitespace
-        // at least one pauli (named capture group 'paulis')
+        // at least one pauli, each optionally followed by a run length (named capture group 'paulis')
         // optional whitespace
-        Regex::new(r"^\s*Rotate\s+(?<angle>\-?\d+)\s*:\s*(?<paulis>[IXYZ]+)\s*$").unwrap()
+        Regex::new(r"^\s*Rotate\s+(?<angle>\-?\d+)\s*:\s*(?<paulis>[IXYZixyz]\d*(\s*[IXYZixyz]\d*)*)\s*$").unwrap()
+    };
+
+    static ref GATE: Regex = {
+        // a single-qubit Z rotation written as its named gate (see `--output-angles-as-gates`),
+        // otherwise like `Rotate`
+        Regex::new(r"^\s*(?<gate>Tdg|T|Sdg|S|Z)\s*:\s*(?<paulis>[IXYZixyz]\d*(\s*[IXYZixyz]\d*)*)\s*$").unwrap()
     };
 
     static ref MEASURE: Regex = {
@@ -99,15 +239,69 @@ lazy_static! {
         // sign (named capture group 'sign')
         // optional whitespace
         // literal ':'
-        // at least one pauli (named capture group 'paulis')
+        // at least one pauli, each optionally followed by a run length (named capture group 'paulis')
         // optional whitespace
-        Regex::new(r"^\s*Measure\s+(?<sign>[+-])\s*:\s*(?<paulis>[IXYZ]+)\s*$").unwrap()
+        Regex::new(r"^\s*Measure\s+(?<sign>[+-])\s*:\s*(?<paulis>[IXYZixyz]\d*(\s*[IXYZixyz]\d*)*)\s*$").unwrap()
     };
 } 
 
 
+/// Pushes the Pauli tokens for a (possibly run-length encoded) Pauli string.
+///
+/// The dense format (`IIXZ`) is just the special case where every run length is omitted, so both
+/// are accepted here; a run such as `I40` expands to forty `I` tokens.
+///
+/// The tokens are always pushed qubit 0 first, so with [`QubitOrder::Lsb`] they're reversed.
+///
+/// Fails without pushing the run if it would take the string past `max_paulis` Paulis.
+fn push_paulis(token_buf: &mut VecDeque<Token>, paulis: &str, convention: PauliConvention, order: QubitOrder, max_paulis: usize) -> anyhow::Result<()> {
+    let start = token_buf.len();
+    let mut count: usize = 0;
+    let bytes = paulis.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let ch = bytes[i] as char;
+        i += 1;
+        if ch.is_ascii_whitespace() {
+            continue;
+        }
+        let pauli = convention.parse(ch).map_err(|ch| anyhow!("unexpected character {:?} in Pauli string", ch))?;
+
+        let run_start = i;
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        let run: usize = if i > run_start {
+            paulis[run_start..i].parse().with_context(|| format!("run length {} is too large", &paulis[run_start..i]))?
+        } else {
+            1
+        };
+        if run == 0 {
+            bail!("run length 0 in Pauli string");
+        }
+        count = match count.checked_add(run) {
+            Some(count) if count <= max_paulis => count,
+            _ => bail!("more than {} Paulis in Pauli string", max_paulis),
+        };
+
+        for _ in 0..run {
+            token_buf.push_back(Token::Pauli(pauli));
+        }
+    }
+
+    if order == QubitOrder::Lsb {
+        token_buf.make_contiguous()[start..].reverse();
+    }
+
+    Ok(())
+}
+
+
 impl<R: Read> TokenIterator<R> {
-    fn pop(&mut self) -> anyhow::Result<Option<Token>> {
+    /// The next token, or an error if the next line can't be read or tokenized (unlike
+    /// [`Iterator::next`], which panics).
+    pub fn pop(&mut self) -> anyhow::Result<Option<Token>> {
         if let Some(tok) = self.token_buf.pop_front() {
             return Ok(Some(tok));
         }
@@ -120,6 +314,7 @@ impl<R: Read> TokenIterator<R> {
         Ok(self.token_buf.pop_front())
     }
 
+    // lines end with `\n`, `\r\n` or a lone `\r` (old Mac style), or at the end of the file
     fn read_line(&mut self) -> anyhow::Result<usize> {
         let mut read = 0;
 
@@ -131,6 +326,14 @@ impl<R: Read> TokenIterator<R> {
                         read += 1;
                         break;
                     },
+                    Ok(b'\r') => {
+                        read += 1;
+                        if let Some(Ok(b'\n')) = self.source.peek() {
+                            self.source.next();
+                            read += 1;
+                        }
+                        break;
+                    },
                     Ok(byte) => {
                         read += 1;
 
@@ -154,24 +357,49 @@ impl<R: Read> TokenIterator<R> {
     }
 
     fn fill_token_buf(&mut self) -> anyhow::Result<()>{
-        // read lines until one is nonempty
         loop {
-            self.line_count += 1;
-            self.line_buf.clear();
-            let len = self.read_line()?;
+            // read lines until one is nonempty
+            loop {
+                self.line_count += 1;
+                self.line_buf.clear();
+                let len = self.read_line()?;
+
+                if len == 0 {
+                    // EOF
+                    return Ok(());
+                }
 
-            if len == 0 {
-                // EOF
-                return Ok(());
+                if self.line_buf.chars().any(|ch| !ch.is_ascii_whitespace()) {
+                    break;
+                }
             }
 
-            if self.line_buf.chars().any(|ch| !ch.is_ascii_whitespace()) {
-                break;
+            // a trailing backslash continues the line (see `--output-columns`)
+            while self.line_buf.trim_end().ends_with('\\') {
+                let continued = self.line_buf.trim_end().len() - 1;
+                self.line_buf.truncate(continued);
+                self.line_buf.push(' ');
+                self.line_count += 1;
+                if self.read_line()? == 0 {
+                    bail!("Unexpected end of file after line continuation on line {}", self.line_count - 1);
+                }
             }
-        }
 
-        // otherwise, fill the token buffer
+            // otherwise, fill the token buffer
+            let buffered = self.token_buf.len();
+            match self.tokenize_line() {
+                Ok(()) => return Ok(()),
+                Err(e) if self.lenient => {
+                    warn!("skipping line {}: {:#}", self.line_count, e);
+                    // drop anything pushed before the error
+                    self.token_buf.truncate(buffered);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
+    fn tokenize_line(&mut self) -> anyhow::Result<()> {
         if let Some(m) = REPEAT.captures(&self.line_buf) {
             let val: u32 = m["repeats"].parse().with_context(||
                 format!("Could not parse repeat statement on line {} ('{}'): wrong or too large repeat count", self.line_count, self.line_buf)
@@ -183,9 +411,16 @@ impl<R: Read> TokenIterator<R> {
                 bail!("Angle on line {} too large ", self.line_count);
             }
             self.token_buf.push_back(Token::Rotate(angle));
-
-            for ch in m["paulis"].chars() {
-                self.token_buf.push_back(Token::Pauli(Pauli::try_from(ch).unwrap()))
+            push_paulis(&mut self.token_buf, &m["paulis"], self.convention, self.order, self.max_paulis).with_context(|| format!("Invalid Pauli string on line {} ('{}')", self.line_count, self.line_buf))?;
+        } else if let Some(m) = GATE.captures(&self.line_buf) {
+            let angle = Angle::from_gate_name(&m["gate"]).unwrap();
+            self.token_buf.push_back(Token::Rotate(angle as i8));
+            let paulis_start = self.token_buf.len();
+            push_paulis(&mut self.token_buf, &m["paulis"], self.convention, self.order, self.max_paulis).with_context(|| format!("Invalid Pauli string on line {} ('{}')", self.line_count, self.line_buf))?;
+
+            let mut non_identity = self.token_buf.range(paulis_start..).filter(|tok| **tok != Token::Pauli(Pauli::I));
+            if non_identity.next() != Some(&Token::Pauli(Pauli::Z)) || non_identity.next().is_some() {
+                bail!("{} gate on line {} is not a single-qubit Z rotation ('{}')", &m["gate"], self.line_count, self.line_buf);
             }
         } else if let Some(m) = MEASURE.captures(&self.line_buf) {
             let phase = match &m["sign"] {
@@ -194,10 +429,7 @@ impl<R: Read> TokenIterator<R> {
                 _ => unreachable!()
             };
             self.token_buf.push_back(Token::Measure(phase));
-
-            for ch in m["paulis"].chars() {
-                self.token_buf.push_back(Token::Pauli(Pauli::try_from(ch).unwrap()))
-            }
+            push_paulis(&mut self.token_buf, &m["paulis"], self.convention, self.order, self.max_paulis).with_context(|| format!("Invalid Pauli string on line {} ('{}')", self.line_count, self.line_buf))?;
         } else if END.is_match(&self.line_buf) {
             self.token_buf.push_back(Token::End)
         } else {
@@ -245,6 +477,45 @@ mod tests {
         lexer.into_iter().collect()
     }
 
+    #[test]
+    fn test_pauli_convention() {
+        for ch in ['I', 'X', 'Y', 'Z', 'x', 'y', 'z'] {
+            assert_eq!(PauliConvention::XZ.parse(ch), Pauli::try_from(ch));
+        }
+        assert_eq!(PauliConvention::ZX.parse('Z'), Ok(Pauli::X));
+        assert_eq!(PauliConvention::ZX.parse('x'), Ok(Pauli::Z));
+        assert_eq!(PauliConvention::ZX.parse('Y'), Ok(Pauli::Y));
+        assert_eq!(PauliConvention::ZX.parse('Q'), Err('Q'));
+
+        let mut lexer = TokenIterator::new("Rotate 1: ZXI\n".as_bytes());
+        lexer.set_pauli_convention(PauliConvention::ZX);
+        let toks: Vec<_> = lexer.collect();
+        assert_eq!(toks, tokens("Rotate 1: XZI\n"));
+
+        // the letters are case-insensitive in text input too, as in JSON
+        assert_eq!(tokens("Rotate 1: xZi2\nMeasure -: y i\n"), tokens("Rotate 1: XZI2\nMeasure -: Y I\n"));
+        let mut lexer = TokenIterator::new("Rotate 1: zxi\n".as_bytes());
+        lexer.set_pauli_convention(PauliConvention::ZX);
+        let toks: Vec<_> = lexer.collect();
+        assert_eq!(toks, tokens("Rotate 1: XZI\n"));
+    }
+
+    #[test]
+    fn test_lenient() {
+        let src = "Rotate 1: XZ\nRotate one: XZ\nRotate 7: XZ\nMeasure -: ZI\n";
+        let mut lexer = TokenIterator::new(src.as_bytes());
+        lexer.set_lenient(true);
+        let toks: Vec<_> = lexer.collect();
+        assert_eq!(toks, tokens("Rotate 1: XZ\nMeasure -: ZI\n"));
+
+        let mut strict = TokenIterator::new(src.as_bytes());
+        strict.next();
+        assert!(strict.peek().is_ok());
+        strict.next();
+        strict.next();
+        assert!(strict.peek().is_err());
+    }
+
     #[test]
     fn test_end() {
         let src = "End\n";
@@ -313,6 +584,90 @@ mod tests {
         assert_eq!(toks[4], Token::Pauli(Pauli::Z));
     }
 
+    #[test]
+    fn test_gate() {
+        assert_eq!(tokens("T: IIZI\nSdg: Z\n"), tokens("Rotate 1: IIZI\nRotate -2: Z\n"));
+
+        let mut lexer = TokenIterator::new("Tdg: IXII\n".as_bytes());
+        let err = lexer.pop().unwrap_err();
+        assert!(format!("{:#}", err).contains("Tdg gate on line 1 is not a single-qubit Z rotation"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_rle() {
+        let toks = tokens("Rotate 1: I3 X Z2\n");
+        assert_eq!(toks.len(), 7);
+        assert_eq!(toks[0], Token::Rotate(1));
+        assert_eq!(&toks[1..4], &[Token::Pauli(Pauli::I); 3]);
+        assert_eq!(toks[4], Token::Pauli(Pauli::X));
+        assert_eq!(&toks[5..7], &[Token::Pauli(Pauli::Z); 2]);
+
+        let toks = tokens("Measure -: Y I40\r\n");
+        assert_eq!(toks.len(), 42);
+        assert_eq!(toks[0], Token::Measure(Phase::Negative));
+        assert_eq!(toks[1], Token::Pauli(Pauli::Y));
+        assert!(toks[2..].iter().all(|tok| *tok == Token::Pauli(Pauli::I)));
+    }
+
+    #[test]
+    fn test_rle_bad_run() {
+        let err = |src: &str, max_paulis: usize| {
+            let mut lexer = TokenIterator::new(src.as_bytes());
+            lexer.set_max_paulis(max_paulis);
+            format!("{:#}", lexer.pop().unwrap_err())
+        };
+
+        let msg = err("Rotate 1: X0 Z\n", DEFAULT_MAX_PAULIS);
+        assert!(msg.contains("run length 0"), "{}", msg);
+
+        // rejected before expanding the run (which would otherwise take forever)
+        let msg = err(&format!("Rotate 1: Z I{}\n", usize::MAX), DEFAULT_MAX_PAULIS);
+        assert!(msg.contains("more than 1048576 Paulis"), "{}", msg);
+        let msg = err("Rotate 1: I4294967296\n", DEFAULT_MAX_PAULIS);
+        assert!(msg.contains("more than 1048576 Paulis"), "{}", msg);
+
+        // and against the number of qubits once it's known
+        let msg = err("Rotate 1: X I3\n", 3);
+        assert!(msg.contains("more than 3 Paulis"), "{}", msg);
+        let mut lexer = TokenIterator::new("Rotate 1: X I2\n".as_bytes());
+        lexer.set_max_paulis(3);
+        let mut buf = Vec::new();
+        lexer.pop_line(&mut buf).unwrap();
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn test_continuation() {
+        assert_eq!(tokens("Rotate 1: XI \\\n          IZ\nEnd\n"), tokens("Rotate 1: XIIZ\nEnd\n"));
+        assert_eq!(tokens("Measure -: X \\\n  Y \\  \n  Z\n"), tokens("Measure -: XYZ\n"));
+
+        let mut lexer = TokenIterator::new("Rotate 1: XI \\\n".as_bytes());
+        assert!(lexer.pop().is_err());
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let unix = "Repeat 2\nRotate 1: XZ\nMeasure -: ZI\nEnd\nRotate -2: YI\n";
+        let expected = tokens(unix);
+        assert_eq!(expected.len(), 11);
+
+        for ending in ["\r\n", "\r"] {
+            let src = unix.replace('\n', ending);
+            assert_eq!(tokens(&src), expected, "{:?}", src);
+            // no terminator on the last line
+            assert_eq!(tokens(src.trim_end()), expected, "{:?}", src);
+        }
+        // mixed, with blank lines
+        assert_eq!(tokens("\rRepeat 2\r\nRotate 1: XZ\r\rMeasure -: ZI\nEnd\r\n\r\nRotate -2: YI"), expected);
+
+        // each terminator ends exactly one line, so line numbers still count from 1
+        let mut lexer = TokenIterator::new("Rotate 1: XZ\r\nRotate 1: ZX\rRotate 1: QQ\r\n".as_bytes());
+        let err = (0..9).map(|_| lexer.pop()).find_map(Result::err).unwrap();
+        assert!(format!("{:#}", err).contains("line 3"), "{:#}", err);
+        assert_eq!(lexer.line_number(), 3);
+        assert_eq!(tokens("Rotate 1: XI \\\r  IZ\rEnd"), tokens("Rotate 1: XIIZ\nEnd\n"));
+    }
+
     #[test]
     fn test_first_line() {
         let src = r#"
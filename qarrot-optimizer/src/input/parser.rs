@@ -1,6 +1,7 @@
 use std::io::Read;
 
 use anyhow::{bail, Context};
+use log::warn;
 
 use crate::{basis::Basis, operation::{angle::Angle, phase::Phase, Operation}};
 
@@ -30,11 +31,17 @@ use super::lexer::{TokenIterator, Token, Pauli};
 //   (b) we reach EOF
 // continue more than that if the first block we read is a repeat
 
+/// Repeat counts above this are warned about by default (see [`InstructionIterator::set_large_repeat_threshold`]).
+pub const DEFAULT_LARGE_REPEAT_THRESHOLD: usize = 1 << 20;
+
+
 #[derive(Debug)]
 pub struct InstructionIterator<R: Read, B: Basis> {
     source: TokenIterator<R>,
     target_buf_size: usize,
     shrink_after_repeat: bool,
+    large_repeat_threshold: usize,
+    repeat_unroll_limit: Option<usize>,
 
     n_qubits: usize,
 
@@ -51,6 +58,8 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
             source,
             target_buf_size,
             shrink_after_repeat,
+            large_repeat_threshold: DEFAULT_LARGE_REPEAT_THRESHOLD,
+            repeat_unroll_limit: None,
             n_qubits,
             operation_buf: Vec::with_capacity(target_buf_size),
             index_in_operation_buf: 0,
@@ -58,13 +67,28 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
         }
     }
 
+    /// Warn when a repeat block is repeated more than `threshold` times.
+    ///
+    /// Huge repeat counts are usually a mistake (or a hostile input), and expand to a huge number
+    /// of operations.
+    pub fn set_large_repeat_threshold(&mut self, threshold: usize) {
+        self.large_repeat_threshold = threshold;
+    }
+
+    /// Fail when a single repeat block contains more than `limit` operations.
+    ///
+    /// The whole block is buffered so it can be replayed, so this bounds the memory used by a
+    /// (probably malformed) huge block. `None` (the default) means no limit.
+    pub fn set_repeat_unroll_limit(&mut self, limit: Option<usize>) {
+        self.repeat_unroll_limit = limit;
+    }
+
     pub fn prepend_repeat(&mut self, repeats: usize, op: Operation<B>) -> anyhow::Result<()> {
         if self.index_in_operation_buf != 0 || !self.operation_buf.is_empty() {
             panic!("Internal error: must prepend before reading from instruction iterator")
         }
-        if repeats > 0 {
-            self.read_repeat(repeats, Some(op)).context("while prepending repeat")?;
-        }
+        // still read a `Repeat 0` block, so that its contents are skipped
+        self.read_repeat(repeats, Some(op)).context("while prepending repeat")?;
         Ok(())
     }
 
@@ -80,35 +104,39 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
 
         self.operation_buf.clear();
 
-        let Some(first) = self.source.next() else {
-            // all done, no more tokens
-            return Ok(());
+        let first = loop {
+            let Some(first) = self.source.pop().context("while filling parser buffer")? else {
+                // all done, no more tokens
+                return Ok(());
+            };
+
+            match first {
+                Token::Repeat(r) => {
+                    self.read_repeat(r as usize, None).context("while filling next chunk")?;
+                    if !self.operation_buf.is_empty() {
+                        return Ok(());
+                    }
+                    // the repeat block was empty (e.g. `Repeat 0`), so there's nothing to return yet
+                },
+                Token::End => {
+                    bail!("End found while not in repeat")
+                },
+                Token::Pauli(_) => {
+                    bail!("Internal error: Pauli found out of order")
+                },
+                Token::Rotate(_) | Token::Measure(_) => {
+                    if let Some(op) = self.complete(first)? {
+                        break op;
+                    }
+                },
+            }
         };
 
         if self.shrink_after_repeat && (self.operation_buf.capacity() > self.target_buf_size) {
             self.operation_buf.shrink_to(self.target_buf_size);
         }
 
-        match first {
-            Token::Repeat(r) => {
-                self.read_repeat(r as usize, None).context("while filling next chunk")?;
-                return Ok(());
-            },
-            Token::End => {
-                bail!("End found while not in repeat")
-            },
-            Token::Pauli(_) => {
-                bail!("Internal error: Pauli found out of order")
-            },
-            Token::Rotate(a) => {
-                let op = complete_rotation(self.n_qubits, &mut self.source, a.into())?;
-                self.operation_buf.push(op);
-            },
-            Token::Measure(p) => {
-                let op = complete_measurement(self.n_qubits, &mut self.source, p)?;
-                self.operation_buf.push(op);
-            },
-        }
+        self.operation_buf.push(first);
         // if we're here, we're not in a repeat
         // so we read until either we reach the target buffer size or until we find a repeat
 
@@ -117,14 +145,12 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
             match self.source.peek().context("while filling parser buffer")? {
                 None => break, // done
                 Some(Token::Repeat(_)) => break, // read the repeat next time we fill the buffer,
-                Some(Token::End) => panic!("error message here"),
+                Some(Token::End) => bail!("unexpected End without matching Repeat"),
                 Some(_) => (), // read this now
             }
-            let next = self.source.next().unwrap(); // can unwrap because we checked if it was none already
-            let op = match next {
-                Token::Measure(p) => complete_measurement(self.n_qubits, &mut self.source, p).unwrap(),
-                Token::Rotate(a) => complete_rotation(self.n_qubits, &mut self.source, a.into()).unwrap(),
-                _ => unreachable!()
+            let next = self.source.pop()?.unwrap(); // can unwrap because we checked if it was none already
+            let Some(op) = self.complete(next)? else {
+                continue;
             };
             op_count += 1;
             self.operation_buf.push(op);
@@ -134,7 +160,11 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
     }
 
     fn read_repeat(&mut self, r: usize, prepend: Option<Operation<B>>) -> anyhow::Result<()> {
-        self.repeats_remaining = r - 1;
+        if r > self.large_repeat_threshold {
+            warn!("repeat count {} is larger than the warning threshold ({}); this block will be expanded {} times", r, self.large_repeat_threshold, r);
+        }
+
+        self.repeats_remaining = r.saturating_sub(1);
 
         self.index_in_operation_buf = 0;
 
@@ -154,21 +184,61 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
                 Some(Token::Repeat(_)) => bail!("found nested repeat block"),
                 Some(Token::End) => {
                     // drop the token, but we're done
-                    self.source.next().unwrap();
+                    self.source.pop()?;
                     break;
                 },
                 Some(_) => (), // read this now
             }
-            let next = self.source.next().unwrap(); // can unwrap because we checked if it was none already
-            let op = match next {
-                Token::Measure(p) => complete_measurement(self.n_qubits, &mut self.source, p).unwrap(),
-                Token::Rotate(a) => complete_rotation(self.n_qubits, &mut self.source, a.into()).unwrap(),
+            let next = self.source.pop()?.unwrap(); // can unwrap because we checked if it was none already
+            if let Some(op) = self.complete(next)? {
+                self.operation_buf.push(op);
+            }
+            if let Some(limit) = self.repeat_unroll_limit {
+                if self.operation_buf.len() > limit {
+                    bail!("repeat block has more than {} operations (see --repeat-unroll-limit)", limit);
+                }
+            }
+        }
+
+        if r == 0 || self.operation_buf.is_empty() {
+            // nothing to emit; the block was only read to skip over it
+            self.operation_buf.clear();
+            self.repeats_remaining = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Finish reading the operation started by `first` (a `Rotate` or `Measure` token).
+    ///
+    /// In lenient mode, operations with the wrong number of Paulis are skipped (returning `None`).
+    fn complete(&mut self, first: Token) -> anyhow::Result<Option<Operation<B>>> {
+        if self.source.is_lenient() {
+            let line = u32::try_from(self.source.line_number()).ok();
+            let Some((x, z)) = paulis_lenient(self.n_qubits, &mut self.source)? else {
+                return Ok(None);
+            };
+            return match first {
+                Token::Measure(p) => Ok(Some(Operation::measurement(x, z, p).with_src_line(line))),
+                Token::Rotate(a) => Ok(Some(Operation::rotation(x, z, a.into()).with_src_line(line))),
                 _ => unreachable!()
             };
-            self.operation_buf.push(op);
         }
 
-        Ok(())
+        let op = match first {
+            Token::Measure(p) => complete_measurement(self.n_qubits, &mut self.source, p)?,
+            Token::Rotate(a) => complete_rotation(self.n_qubits, &mut self.source, a.into())?,
+            _ => unreachable!()
+        };
+        Ok(Some(op))
+    }
+
+    /// A lower bound on the number of operations left: the rest of the buffer, plus the whole
+    /// buffer again for each remaining repeat. Operations not read from the source yet aren't
+    /// counted.
+    pub fn remaining_hint(&self) -> usize {
+        let buffered = self.operation_buf.len() - self.index_in_operation_buf;
+        self.repeats_remaining.saturating_mul(self.operation_buf.len()).saturating_add(buffered)
     }
 
     pub fn next(&mut self) -> anyhow::Result<Option<&Operation<B>>> {
@@ -198,21 +268,45 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
 }
 
 
+/// Iterating yields the first parse error (if any) as the last item, rather than panicking.
 impl<R: Read, B: Basis> Iterator for InstructionIterator<R, B> {
-    type Item = Operation<B>;
+    type Item = anyhow::Result<Operation<B>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next().map(|op| op.cloned()).unwrap()
+        self.next().map(|op| op.cloned()).transpose()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_hint(), None)
     }
 }
 
 
 fn paulis<R: Read, B: Basis>(n_qubits: usize, source: &mut TokenIterator<R>) -> anyhow::Result<(B, B)> {
+    // read with `pop`, so a bad line after a short operation is an error rather than a panic
+    let mut source_err = None;
+    let tokens = std::iter::from_fn(|| source.pop().unwrap_or_else(|err| {
+        source_err = Some(err);
+        None
+    }));
+    let paulis = paulis_from_tokens(n_qubits, tokens);
+    match source_err {
+        Some(err) => Err(err),
+        None => paulis,
+    }
+}
+
+/// Read exactly `n_qubits` Pauli tokens into `(x, z)`.
+///
+/// Shared by the parser (reading from the lexer) and [`Operation::try_from_tokens`] (reading from
+/// an already tokenized line).
+pub(crate) fn paulis_from_tokens<B: Basis>(n_qubits: usize, tokens: impl IntoIterator<Item = Token>) -> anyhow::Result<(B, B)> {
+    let mut tokens = tokens.into_iter();
     let mut x = B::zero(n_qubits);
     let mut z = B::zero(n_qubits);
 
     for qb in 0..n_qubits {
-        let Some(tok) = source.next() else {
+        let Some(tok) = tokens.next() else {
             bail!("Unexpected EOF (only found {}/{} Paulis)", qb, n_qubits);
         };
         let Token::Pauli(p) = tok else {
@@ -229,47 +323,152 @@ fn paulis<R: Read, B: Basis>(n_qubits: usize, source: &mut TokenIterator<R>) ->
     Ok((x, z))
 }
 
+/// Like [`paulis`], but consumes all the Paulis of the operation and returns `None` (with a warning)
+/// if there aren't exactly `n_qubits` of them.
+fn paulis_lenient<R: Read, B: Basis>(n_qubits: usize, source: &mut TokenIterator<R>) -> anyhow::Result<Option<(B, B)>> {
+    let line = source.line_number();
+    let mut x = B::zero(n_qubits);
+    let mut z = B::zero(n_qubits);
+
+    // each line starts with a non-Pauli token, so this can't run into the next operation
+    let mut count = 0;
+    while let Some(&Token::Pauli(p)) = source.peek()? {
+        source.pop()?;
+        if count < n_qubits {
+            if matches!(p, Pauli::X | Pauli::Y) {
+                x.set_bit_true(count);
+            }
+            if matches!(p, Pauli::Z | Pauli::Y) {
+                z.set_bit_true(count);
+            }
+        }
+        count += 1;
+    }
+
+    if count != n_qubits {
+        warn!("skipping operation with {} Paulis (expected {}) on line {}", count, n_qubits, line);
+        return Ok(None);
+    }
+
+    Ok(Some((x, z)))
+}
+
 pub(crate) fn complete_measurement<R: Read, B: Basis>(n_qubits: usize, source: &mut TokenIterator<R>, phase: Phase) -> anyhow::Result<Operation<B>> {
-    let (x, z) = paulis(n_qubits, source).context("while reading measurement")?;
+    // the whole line was tokenized with its first token, so this is the operation's line
+    let line = source.line_number();
+    let (x, z) = paulis(n_qubits, source).with_context(|| format!("while reading measurement on line {}", line))?;
 
-    Ok(Operation::measurement(x, z, phase))
+    Ok(Operation::measurement(x, z, phase).with_src_line(u32::try_from(line).ok()))
 }
 
 pub(crate) fn complete_rotation<R: Read, B: Basis>(n_qubits: usize, source: &mut TokenIterator<R>, angle: Angle) -> anyhow::Result<Operation<B>> {
-    let (x, z) = paulis(n_qubits, source).context("while reading rotation")?;
+    let line = source.line_number();
+    let (x, z) = paulis(n_qubits, source).with_context(|| format!("while reading rotation on line {}", line))?;
 
-    Ok(Operation::rotation(x, z, angle))
+    Ok(Operation::rotation(x, z, angle).with_src_line(u32::try_from(line).ok()))
 }
 
-pub(crate) fn complete_op<B: Basis>(n_qubits: usize, tokens: &[Token]) -> anyhow::Result<Operation<B>> {
-    if tokens.len() <= 1 {
-        bail!("internal error: too few tokens given while building operation");
+
+
+#[cfg(test)]
+mod tests {
+    use crate::basis::Basis8;
+
+    use super::*;
+
+    fn parse(input: &str) -> Vec<Operation<Basis8>> {
+        let tokens = TokenIterator::new(input.as_bytes());
+        InstructionIterator::<_, Basis8>::new(2, tokens, 16, false).collect::<anyhow::Result<_>>().unwrap()
     }
-    let mut x = B::zero(n_qubits);
-    let mut z = B::zero(n_qubits);
 
-    for qb in 0..n_qubits {
-        let Some(tok) = tokens.get(qb + 1) else {
-            bail!("Unexpected EOF (only found {}/{} Paulis)", qb, n_qubits);
-        };
-        let Token::Pauli(p) = tok else {
-            bail!("Unexpected token {:?} (only found {}/{} Paulis)", tok, qb, n_qubits);
-        };
-        if matches!(p, Pauli::X | Pauli::Y) {
-            x.set_bit_true(qb);
-        }
-        if matches!(p, Pauli::Z | Pauli::Y) {
-            z.set_bit_true(qb);
+    #[test]
+    fn test_remaining_hint() {
+        let src = "Rotate 1: XI\nRotate 2: IZ\nRepeat 3\nRotate 1: ZZ\nMeasure +: XX\nEnd\nRotate -1: YI\nRepeat 0\nRotate 1: ZI\nEnd\nRepeat 2\nMeasure -: IZ\nEnd\n";
+        let expected = 2 + 3 * 2 + 1 + 2;
+        for buf_size in [1, 2, 16] {
+            let mut parser = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), buf_size, false);
+            let mut remaining = expected;
+            let mut hints = Vec::new();
+            loop {
+                let (lower, upper) = Iterator::size_hint(&parser);
+                assert!(lower <= remaining, "hint {} with {} left (buffer size {})", lower, remaining, buf_size);
+                assert_eq!(upper, None);
+                hints.push(lower);
+                if Iterator::next(&mut parser).is_none() {
+                    break;
+                }
+                remaining -= 1;
+            }
+            assert_eq!(remaining, 0);
+            // the hint counts the repeats once their block is buffered
+            assert!(hints.contains(&(3 * 2 - 1)), "{:?}", hints);
         }
     }
 
-    match tokens[0] {
-        Token::Measure(phase) => {
-            Ok(Operation::measurement(x, z, phase))
-        }
-        Token::Rotate(angle) => {
-            Ok(Operation::rotation(x, z, angle.into()))
-        }
-        _ => bail!("Internal error: invalid token to start operation")
+    #[test]
+    fn test_repeat_0() {
+        let ops = parse("Rotate 1: IZ\nRepeat 0\nRotate 2: XX\nRotate 2: ZZ\nEnd\nMeasure +: ZZ\n");
+        assert_eq!(ops, parse("Rotate 1: IZ\nMeasure +: ZZ\n"));
+
+        let ops = parse("Repeat 0\nRotate 2: XX\nEnd\nRepeat 0\nRotate 2: ZZ\nEnd\nRotate 1: IZ\n");
+        assert_eq!(ops, parse("Rotate 1: IZ\n"));
+
+        assert!(parse("Repeat 0\nRotate 2: XX\nEnd\n").is_empty());
+    }
+
+    #[test]
+    fn test_repeat_unroll_limit() {
+        let src = "Rotate 1: IZ\nRepeat 2\nRotate 2: XX\nRotate 2: ZZ\nEnd\nMeasure +: ZZ\n";
+
+        let mut parser = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), 16, false);
+        parser.set_repeat_unroll_limit(Some(2));
+        let ops = parser.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(ops, parse(src));
+        assert_eq!(ops.len(), 6);
+
+        let mut parser = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), 16, false);
+        parser.set_repeat_unroll_limit(Some(1));
+        assert!(parser.next().unwrap().is_some());
+        let err = parser.next().unwrap_err();
+        assert!(format!("{:#}", err).contains("more than 1 operations"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_repeat_empty() {
+        let ops = parse("Rotate 1: IZ\nRepeat 5\nEnd\nMeasure +: ZZ\n");
+        assert_eq!(ops, parse("Rotate 1: IZ\nMeasure +: ZZ\n"));
+    }
+
+    #[test]
+    fn test_prepend_repeat_0() {
+        let tokens = TokenIterator::new("Rotate 2: ZZ\nEnd\nRotate 1: IZ\n".as_bytes());
+        let mut parser = InstructionIterator::<_, Basis8>::new(2, tokens, 16, false);
+        parser.prepend_repeat(0, Operation::rotation(Basis8::zero(2), Basis8::one_bit(2, 0), Angle::PlusPi4)).unwrap();
+        let ops = parser.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(ops, parse("Rotate 1: IZ\n"));
+    }
+
+    #[test]
+    fn test_error_line_number() {
+        let src = "Rotate 1: IZ\n\nMeasure +: X\nRotate 2: ZZ\n";
+        let err = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), 16, false)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("while reading measurement on line 3"), "{:#}", err);
+
+        let src = "Rotate 1: IZ\nRotate 2: Z";
+        let err = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), 16, false)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("while reading rotation on line 2"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_lenient() {
+        let src = "Rotate 1: IZ\nRotate 2: XXX\nRepeat 2\nRotate 2: X\nMeasure -: XZ\nEnd\nMeasure +: ZZ\n";
+        let mut tokens = TokenIterator::new(src.as_bytes());
+        tokens.set_lenient(true);
+        let ops = InstructionIterator::<_, Basis8>::new(2, tokens, 16, false).collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(ops, parse("Rotate 1: IZ\nMeasure -: XZ\nMeasure -: XZ\nMeasure +: ZZ\n"));
     }
 }
@@ -0,0 +1,305 @@
+//! The JSON circuit format: an array of operation objects, e.g.
+//!
+//! ```text
+//! [
+//! {"kind":"rotation","angle":1,"paulis":["Z","I","X"]},
+//! {"kind":"measurement","phase":"-","paulis":["I","Y","I"]}
+//! ]
+//! ```
+//!
+//! `angle` is the same code as in the text format (`Rotate 1`), `phase` is `"+"` or `"-"`, and
+//! `paulis` has one letter per qubit (`I`, `X`, `Y` or `Z`).
+
+use std::{io::{BufReader, Bytes, Read}, marker::PhantomData};
+
+use anyhow::{bail, Context};
+use serde_json::Value;
+
+use crate::{basis::Basis, input::lexer::{Pauli, PauliConvention}, operation::Operation};
+
+
+/// Reads the objects of a JSON array one at a time, so the whole array is never in memory.
+#[derive(Debug)]
+pub struct ObjectIterator<R: Read> {
+    source: Bytes<BufReader<R>>,
+    object_buf: Vec<u8>,
+    peeked: Option<Value>,
+    started: bool,
+    done: bool,
+    object_count: usize,
+}
+
+
+impl<R: Read> ObjectIterator<R> {
+    pub fn new(source: R) -> Self {
+        Self {
+            source: BufReader::new(source).bytes(),
+            object_buf: Vec::with_capacity(256),
+            peeked: None,
+            started: false,
+            done: false,
+            object_count: 0,
+        }
+    }
+
+    /// The (1-indexed) number of the most recently read object.
+    pub fn object_number(&self) -> usize {
+        self.object_count
+    }
+
+    pub fn peek(&mut self) -> anyhow::Result<Option<&Value>> {
+        if self.peeked.is_none() {
+            self.peeked = self.read_object()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    pub fn pop(&mut self) -> anyhow::Result<Option<Value>> {
+        match self.peeked.take() {
+            Some(value) => Ok(Some(value)),
+            None => self.read_object(),
+        }
+    }
+
+    fn next_byte(&mut self) -> anyhow::Result<Option<u8>> {
+        Ok(self.source.next().transpose()?)
+    }
+
+    fn next_non_whitespace(&mut self) -> anyhow::Result<Option<u8>> {
+        while let Some(byte) = self.next_byte()? {
+            if !byte.is_ascii_whitespace() {
+                return Ok(Some(byte));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_object(&mut self) -> anyhow::Result<Option<Value>> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.started {
+            match self.next_non_whitespace()? {
+                Some(b'[') => self.started = true,
+                Some(byte) => bail!("Expected a JSON array, found '{}'", byte as char),
+                None => {
+                    self.done = true;
+                    return Ok(None);
+                },
+            }
+        }
+
+        let mut byte = self.next_non_whitespace()?;
+        if self.object_count > 0 {
+            // objects are separated by commas, with none after the last one
+            match byte {
+                Some(b',') => {
+                    byte = self.next_non_whitespace()?;
+                    if byte == Some(b']') {
+                        bail!("Trailing comma after object {}", self.object_count);
+                    }
+                },
+                Some(b'{') => bail!("Missing comma after object {}", self.object_count),
+                _ => (),
+            }
+        }
+        match byte {
+            Some(b'{') => (),
+            Some(b']') => {
+                self.done = true;
+                if let Some(byte) = self.next_non_whitespace()? {
+                    bail!("Unexpected '{}' after the end of the array", byte as char);
+                }
+                return Ok(None);
+            },
+            Some(byte) if self.object_count == 0 => bail!("Expected an object or the end of the array, found '{}'", byte as char),
+            Some(byte) => bail!("Expected an object after object {}, found '{}'", self.object_count, byte as char),
+            None => bail!("Unexpected end of file after object {} (the array isn't closed)", self.object_count),
+        }
+        self.object_count += 1;
+
+        // find the matching brace, skipping over strings
+        self.object_buf.clear();
+        self.object_buf.push(b'{');
+        let mut depth = 1;
+        let mut in_string = false;
+        let mut escaped = false;
+        while depth > 0 {
+            let Some(byte) = self.next_byte()? else {
+                bail!("Unexpected end of file in object {}", self.object_count);
+            };
+            self.object_buf.push(byte);
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => (),
+                }
+            } else {
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' => depth += 1,
+                    b'}' => depth -= 1,
+                    _ => (),
+                }
+            }
+        }
+
+        let value = serde_json::from_slice(&self.object_buf).with_context(|| format!("Invalid JSON in object {}", self.object_count))?;
+        Ok(Some(value))
+    }
+}
+
+
+fn paulis_of(object: &Value) -> anyhow::Result<&Vec<Value>> {
+    match object.get("paulis") {
+        Some(Value::Array(paulis)) => Ok(paulis),
+        Some(_) => bail!("\"paulis\" must be an array"),
+        None => bail!("missing \"paulis\""),
+    }
+}
+
+
+/// The number of qubits of the circuit, from the length of an object's `paulis`.
+pub fn n_qubits_of(object: &Value) -> anyhow::Result<usize> {
+    Ok(paulis_of(object)?.len())
+}
+
+
+/// Build an operation from one object of the array.
+pub fn operation_from_json<B: Basis>(n_qubits: usize, object: &Value) -> anyhow::Result<Operation<B>> {
+    let paulis = paulis_of(object)?;
+    if paulis.len() != n_qubits {
+        bail!("{} Paulis (expected {})", paulis.len(), n_qubits);
+    }
+
+    let mut x = B::zero(n_qubits);
+    let mut z = B::zero(n_qubits);
+    for (q, pauli) in paulis.iter().enumerate() {
+        let letter = pauli.as_str().and_then(|letter| {
+            let mut chars = letter.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        });
+        let Some(letter) = letter else {
+            bail!("invalid Pauli {} (expected one of \"I\", \"X\", \"Y\" or \"Z\")", pauli);
+        };
+        match PauliConvention::default().parse(letter) {
+            Ok(Pauli::I) => (),
+            Ok(Pauli::X) => x.set_bit_true(q),
+            Ok(Pauli::Z) => z.set_bit_true(q),
+            Ok(Pauli::Y) => {
+                x.set_bit_true(q);
+                z.set_bit_true(q);
+            },
+            Err(ch) => bail!("invalid Pauli '{}'", ch),
+        }
+    }
+
+    match object.get("kind").and_then(Value::as_str) {
+        Some("rotation") => {
+            let angle = object.get("angle").and_then(Value::as_i64).filter(|angle| angle.abs() <= 2);
+            let Some(angle) = angle else {
+                bail!("rotation needs an \"angle\" of -2 to 2");
+            };
+            Ok(Operation::rotation(x, z, (angle as i8).into()))
+        },
+        Some("measurement") => {
+            let phase = match object.get("phase").and_then(Value::as_str) {
+                Some("+") => false,
+                Some("-") => true,
+                _ => bail!("measurement needs a \"phase\" of \"+\" or \"-\""),
+            };
+            Ok(Operation::measurement(x, z, phase.into()))
+        },
+        _ => bail!("\"kind\" must be \"rotation\" or \"measurement\""),
+    }
+}
+
+
+/// Yields the operations of a JSON circuit, like the text and QASM `InstructionIterator`s.
+#[derive(Debug)]
+pub struct InstructionIterator<R: Read, B: Basis> {
+    source: ObjectIterator<R>,
+    n_qubits: usize,
+    failed: bool,
+    basis: PhantomData<B>,
+}
+
+
+impl<R: Read, B: Basis> InstructionIterator<R, B> {
+    pub fn new(n_qubits: usize, source: ObjectIterator<R>) -> Self {
+        Self {
+            source,
+            n_qubits,
+            failed: false,
+            basis: PhantomData,
+        }
+    }
+
+    fn next_operation(&mut self) -> anyhow::Result<Option<Operation<B>>> {
+        let Some(object) = self.source.pop()? else {
+            return Ok(None);
+        };
+        let op = operation_from_json(self.n_qubits, &object).with_context(|| format!("in object {}", self.source.object_number()))?;
+        Ok(Some(op))
+    }
+}
+
+
+impl<R: Read, B: Basis> Iterator for InstructionIterator<R, B> {
+    type Item = anyhow::Result<Operation<B>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // don't emit anything after an error
+        if self.failed {
+            return None;
+        }
+        let next = self.next_operation().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::Basis8, operation::angle::Angle};
+
+    use super::*;
+
+    fn parse(src: &str) -> anyhow::Result<Vec<Operation<Basis8>>> {
+        InstructionIterator::new(3, ObjectIterator::new(src.as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let src = r#"  [ {"kind": "rotation", "angle": -1, "paulis": ["Z", "i", "Y"]},
+            {"paulis": ["I", "X", "I"], "kind": "measurement", "phase": "-", "note": "{\"}"} ]"#;
+        assert_eq!(parse(src).unwrap(), vec![
+            Operation::rotation(Basis8::with_true_bits(3, &[2]), Basis8::with_true_bits(3, &[0, 2]), Angle::MinusPi8),
+            Operation::measurement(Basis8::with_true_bits(3, &[1]), Basis8::zero(3), true.into()),
+        ]);
+        assert!(parse("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |src: &str| format!("{:#}", parse(src).unwrap_err());
+
+        assert!(err(r#"{"kind": "rotation"}"#).contains("Expected a JSON array"));
+        assert!(err(r#"[{"kind": "rotation", "angle": 3, "paulis": ["I", "I", "Z"]}]"#).contains("in object 1: rotation needs an \"angle\" of -2 to 2"));
+        assert!(err(r#"[{"kind": "rotation", "angle": 1, "paulis": ["I", "I", "Z"]}, {"kind": "measurement", "phase": "+", "paulis": ["I", "Z"]}]"#).contains("in object 2: 2 Paulis (expected 3)"));
+        assert!(err(r#"[{"kind": "rotation", "angle": 1, "paulis": ["I", "I", "Q"]}]"#).contains("invalid Pauli 'Q'"));
+        assert!(err(r#"[{"kind": "rotation", "angle": 1, "paulis": ["I", "I", "Z"]}"#).contains("the array isn't closed"));
+
+        // the separators have to be right too
+        let z = r#"{"kind": "rotation", "angle": 1, "paulis": ["I", "I", "Z"]}"#;
+        assert!(err(&format!("[{} {}]", z, z)).contains("Missing comma after object 1"));
+        assert!(err(&format!("[{}, {},]", z, z)).contains("Trailing comma after object 2"));
+        assert!(err(&format!("[, {}]", z)).contains("Expected an object or the end of the array, found ','"));
+        assert!(err(&format!("[{},, {}]", z, z)).contains("Expected an object after object 1, found ','"));
+        assert!(err(&format!("[{}, 1]", z)).contains("Expected an object after object 1, found '1'"));
+        assert!(err(&format!("[{}] {}", z, z)).contains("Unexpected '{' after the end of the array"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{io::{self, Write}, sync::atomic::{AtomicUsize, Ordering}};
+
+use anyhow::bail;
+use env_logger::fmt::Formatter;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+
+/// Warnings logged so far (see `--fail-on-warning`).
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Wraps a logger, counting the warnings that reach it.
+#[derive(Debug)]
+pub struct CountingLogger<L> {
+    inner: L,
+}
+
+
+impl<L: Log> Log for CountingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // counted even if the inner logger filters it out (e.g. `QARROT_LOG_LEVEL=error`)
+        if record.level() == Level::Warn {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+
+/// Install `logger` as the global logger, counting warnings (even if `logger` doesn't show them).
+pub fn init(logger: env_logger::Logger) -> Result<(), SetLoggerError> {
+    // warnings must get as far as the `CountingLogger` to be counted
+    let max_level = logger.filter().max(LevelFilter::Warn);
+    log::set_boxed_logger(Box::new(CountingLogger { inner: logger }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+
+/// Format a record as a single line of JSON (for `--log-format json`).
+pub fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let line = serde_json::json!({
+        "level": record.level().as_str(),
+        "timestamp": buf.timestamp_millis().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    writeln!(buf, "{}", line)
+}
+
+
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+
+/// Fail if any warnings have been logged (for `--fail-on-warning`).
+pub fn fail_on_warnings() -> anyhow::Result<()> {
+    match warning_count() {
+        0 => Ok(()),
+        1 => bail!("1 warning was logged and --fail-on-warning is set"),
+        n => bail!("{} warnings were logged and --fail-on-warning is set", n),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Stands in for stderr, so the test can read back what the logger wrote.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_json() {
+        let captured = Captured::default();
+        let logger = env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Info)
+            .format_timestamp_millis()
+            .format(format_json)
+            .target(env_logger::Target::Pipe(Box::new(captured.clone())))
+            .build();
+
+        logger.log(&Record::builder()
+            .level(Level::Warn)
+            .target("qarrot")
+            .args(format_args!("a \"quoted\"\nmessage"))
+            .build());
+
+        let out = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1, "{}", out);
+        let line: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "qarrot");
+        assert_eq!(line["message"], "a \"quoted\"\nmessage");
+        assert!(line["timestamp"].as_str().is_some_and(|t| !t.is_empty()), "{}", out);
+    }
+
+    #[test]
+    fn test_counts_filtered_warnings() {
+        let captured = Captured::default();
+        let logger = CountingLogger {
+            inner: env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Error)
+                .target(env_logger::Target::Pipe(Box::new(captured.clone())))
+                .build(),
+        };
+
+        let before = warning_count();
+        logger.log(&Record::builder().level(Level::Warn).args(format_args!("hidden")).build());
+        assert!(warning_count() > before);
+        assert!(captured.0.lock().unwrap().is_empty());
+
+        logger.log(&Record::builder().level(Level::Error).args(format_args!("shown")).build());
+        assert!(String::from_utf8(captured.0.lock().unwrap().clone()).unwrap().contains("shown"));
+    }
+
+    #[test]
+    fn test_fail_on_warnings() {
+        // not installed as the global logger, which another test may have done already; the count
+        // is shared, so this can only check that it's no longer zero
+        let logger = CountingLogger { inner: env_logger::Builder::new().filter_level(log::LevelFilter::Off).is_test(true).build() };
+        logger.log(&Record::builder().level(Level::Warn).args(format_args!("counted")).build());
+
+        assert!(warning_count() > 0);
+        let err = fail_on_warnings().unwrap_err();
+        assert!(err.to_string().contains("--fail-on-warning is set"), "{}", err);
+    }
+}
@@ -59,14 +59,17 @@ pub mod bits;
 pub mod basis;
 pub mod operation;
 pub mod input;
+pub mod json;
+pub mod logging;
 pub mod qasm;
 pub mod symplectic;
 pub mod clifford;
 pub mod optimization;
 pub mod output;
+pub mod profile;
 pub mod tester;
 
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use optimization::*;
 
 use std::{collections::HashMap, fmt::Debug, fs, io::{self, Read}, mem, path::{Path, PathBuf}};
@@ -77,23 +80,49 @@ use flate2::read::GzDecoder;
 
 use basis::{Basis, Basis8};
 use clap::Parser;
-use input::parser::complete_op;
+use input::{parser::DEFAULT_LARGE_REPEAT_THRESHOLD, DEFAULT_STDIN_CHUNK_SIZE};
 use operation::Operation;
 use output::Output;
+use profile::{IterationTimings, Profile, RoundPhase};
 
-use crate::{basis::*, input::{lexer::{Token, TokenIterator}, parser::InstructionIterator, Input}, output::{fmt_operation, StringOut, WriteOutput}, tester::Tester};
+use crate::{basis::*, qasm::parser::GateSet, input::{lexer::{PauliConvention, QubitOrder, Token, TokenIterator}, parser::InstructionIterator, Input, PadQubits}, output::{fmt_operation, FormatOptions, StringOut, Tee, Void, WriteOutput}, tester::Tester};
 
 
 // accept at most this large a preallocated buffer 
 // should help prevent DoS attacks
-// equal to 16GiB for 256-bit operation
-pub const MAX_PREALLOC_OPERATIONS: usize = (2 << 33) / mem::size_of::<Operation<Basis256>>();
+// 16GiB, or as much as the target can allocate if that's less (i.e. on 32-bit targets)
+pub const MAX_PREALLOC_BYTES: u64 = 16 << 30;
 
+/// The largest buffer [`MAX_PREALLOC_BYTES`] allows, in 256-bit operations.
+pub const MAX_PREALLOC_OPERATIONS: usize = max_prealloc_operations(mem::size_of::<Operation<Basis256>>());
 
-/// Enum of supported compression algorithms. Currently only GZip.
+const _: () = assert!(MAX_PREALLOC_OPERATIONS > 0);
+
+
+/// The number of `operation_size`-byte operations that fit in [`MAX_PREALLOC_BYTES`], capped to
+/// what a single allocation can hold on this target.
+const fn max_prealloc_operations(operation_size: usize) -> usize {
+    // `usize::try_from` isn't usable in a const, so clamp by hand
+    let max_bytes = if MAX_PREALLOC_BYTES > isize::MAX as u64 {
+        isize::MAX as usize
+    } else {
+        MAX_PREALLOC_BYTES as usize
+    };
+    max_bytes / operation_size
+}
+
+
+/// By default, stop once this many consecutive rounds report a change without changing the T count
+/// or the number of operations (see `--fixed-point-rounds`).
+pub const DEFAULT_FIXED_POINT_ROUNDS: usize = 2;
+
+
+/// Enum of supported compression algorithms: GZip, and bzip2 with the (opt-in) `bzip2` feature.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum CompressionAlgorithm {
     GZip,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
@@ -102,18 +131,23 @@ pub enum InputType {
     Other,
     Txt,
     Qasm,
+    Json,
 }
 
 
 lazy_static! {
     /// This is a hashmap from file extensions to compression algorithms used to automatically infer whether a file is compressed (overridden by `--decompression-algorithm`).
     ///
-    /// Currently, only GZip is used, recognized with the extensions "gz" and "gzip".
+    /// GZip is recognized with the extensions "gz" and "gzip", and bzip2 (with the `bzip2` feature) with "bz2" and "bzip2".
     static ref COMPRESSION_EXTENSION: HashMap<&'static str, CompressionAlgorithm> = {
         let mut map = HashMap::new();
 
         map.insert("gz", CompressionAlgorithm::GZip);
         map.insert("gzip", CompressionAlgorithm::GZip);
+        #[cfg(feature = "bzip2")]
+        map.insert("bz2", CompressionAlgorithm::Bzip2);
+        #[cfg(feature = "bzip2")]
+        map.insert("bzip2", CompressionAlgorithm::Bzip2);
 
         map
     };
@@ -131,7 +165,7 @@ struct CommandLineArgs {
     #[arg(short, long)]
     output: String,
 
-    /// File type (if not provided, automatically determined from file extension). "qasm" or "txt".
+    /// File type (if not provided, automatically determined from file extension). "qasm", "txt" or "json".
     #[arg(long, short('t'))]
     file_type: Option<String>,
 
@@ -139,18 +173,107 @@ struct CommandLineArgs {
     #[arg(short, long)]
     big_file: bool,
 
+    /// Restart with --big-file if the in-memory circuit buffer grows past this many bytes while reading the input (file input only; STDIN can't be read twice)
+    #[arg(long, conflicts_with = "big_file")]
+    memory_limit: Option<usize>,
+
+    /// Bytes of STDIN to buffer per read when no input file is given
+    #[arg(long, default_value_t = DEFAULT_STDIN_CHUNK_SIZE)]
+    stdin_chunk_size: usize,
+
     /// Hint the number of operations. If used correctly, this can reduce reallocations and is especially important if the input file is compressed.
     #[arg(short, long)]
     num_operations: Option<usize>,
 
-    /// Target buffer length
+    /// Number of operations read (and held in memory) at a time while parsing, and by --big-file between passes
+    #[arg(long, visible_alias = "target-buffer-length", default_value_t=16384)]
+    read_batch: usize,
+
+    /// Number of operations buffered before each write to the --big-file temporary files
     #[arg(long, default_value_t=16384)]
-    target_buffer_length: usize,
+    write_batch: usize,
 
     /// Shrink buffer after repeat (possibly reduce non-peak memory usage at the cost of more allocations)
     #[arg(short, long)]
     shrink_buffer_after_repeat: bool,
 
+    /// Skip (and warn about) unrecognized lines and operations with the wrong number of qubits instead of failing (text input only)
+    #[arg(long)]
+    lenient: bool,
+
+    /// Check that QASM gate arguments name the declared qreg and are within its bounds, failing with the line number otherwise (QASM input only)
+    #[arg(long)]
+    strict: bool,
+
+    /// Keep QASM `barrier`s as partition boundaries, so T gates aren't merged across them, then drop them from the output; otherwise barriers are ignored (QASM input only)
+    #[arg(long)]
+    drop_barriers_as_partition_hints: bool,
+
+    /// Directory to look for QASM `include` files in; those found there are read in place of the `include` (QASM input only)
+    #[arg(long)]
+    include_path: Option<PathBuf>,
+
+    /// Which rotations H and CX are decomposed into: "standard" (default) or "alternate", to match another compiler's decompositions (QASM input only)
+    #[arg(long)]
+    gate_set: Option<String>,
+
+    /// Pad circuits with fewer qubits than this with identity qubits, so the output is at least this wide
+    #[arg(long, default_value_t=0)]
+    min_qubits: usize,
+
+    /// Reject circuits with more than this many qubits (after --min-qubits padding), before building the optimizer
+    #[arg(long)]
+    max_qubits: Option<usize>,
+
+    /// Fail once more than this many operations (after unrolling repeats) have been read from the input
+    #[arg(long)]
+    max_operations: Option<usize>,
+
+    /// Don't append the usual final measurements if the input already ends with a measurement, e.g. to optimize the output of an earlier run further without doubling its measurements
+    #[arg(long, visible_alias = "resume-from-circuit")]
+    already_measured: bool,
+
+    /// Keep the input's measurements where they are relative to each other and to the T gates (Cliffords are still pushed through them), and don't append the usual final Z measurements. If the input doesn't end with a measurement they're still appended, as the final Clifford would otherwise be dropped
+    #[arg(long, conflicts_with_all = ["already_measured", "canonical_measure", "merge_adjacent_measurements"])]
+    retain_input_measurements_order: bool,
+
+    /// Fail (after writing the output) unless the optimized circuit has exactly this many T gates, e.g. as a regression check in CI
+    #[arg(long)]
+    assert_t_count: Option<usize>,
+
+    /// Fail (after writing the output) if the optimized circuit has more than this many T gates
+    #[arg(long)]
+    assert_max_t_count: Option<usize>,
+
+    /// Report the global phase the optimization dropped: the input is the optimized circuit, followed
+    /// by the Clifford gates absorbed while pushing T gates forward, times this phase. Rotations are
+    /// taken as exp(iθ(I - P)), so Z, S and T are exact (text and JSON input only: QASM gates are
+    /// decomposed into rotations with phases of their own, e.g. e^(iπ/4)·H for `h`, which aren't
+    /// tracked)
+    #[arg(long)]
+    phase_tracking: bool,
+
+    /// Read the circuit and run the initial reduction, report how long the parser and the reduction
+    /// each took, then stop (without optimizing or writing output)
+    #[arg(long, conflicts_with_all = ["bypass", "big_file", "iterations", "validate_equivalence", "assert_t_count", "assert_max_t_count", "phase_tracking"])]
+    dry_run_parse: bool,
+
+    /// Warn when a repeat block is repeated more than this many times
+    #[arg(long, default_value_t=DEFAULT_LARGE_REPEAT_THRESHOLD)]
+    warn_large_repeat: usize,
+
+    /// Fail if a single repeat block contains more than this many operations
+    #[arg(long)]
+    repeat_unroll_limit: Option<usize>,
+
+    /// Exit with an error after the run if any warnings were logged (e.g. for CI, to catch pathological inputs)
+    #[arg(long)]
+    fail_on_warning: bool,
+
+    /// Log format: "human" (the default), or "json" for one JSON object per line with the level, timestamp, target and message
+    #[arg(long, default_value = "human")]
+    log_format: String,
+
     /// Overwrite existing output path
     #[arg(long)]
     overwrite: bool,
@@ -159,7 +282,7 @@ struct CommandLineArgs {
     #[arg(long)]
     bypass: bool,
 
-    /// Force a particular decompression method (usually determined by extension)
+    /// Force a particular decompression method (usually determined by extension): "gzip", or "bzip2" if built with the `bzip2` feature
     #[arg(long)]
     decompression_algorithm: Option<String>,
 
@@ -167,10 +290,131 @@ struct CommandLineArgs {
     #[arg(long, short)]
     compress_output: bool,
 
+    /// Run-length encode Pauli strings in the output (e.g. `I40 X I15 Z`); useful for sparse circuits
+    #[arg(long)]
+    rle_output: bool,
+
+    /// Pretty output for reading by eye: wrap Pauli strings every this many qubits, continuing lines with a trailing `\`
+    #[arg(long, conflicts_with = "rle_output")]
+    output_columns: Option<usize>,
+
+    /// Format this many operations into one buffer before each write, instead of one at a time (the output is the same)
+    #[arg(long, visible_alias = "operations-per-line", default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    operations_per_write: usize,
+
+    /// Debugging output: write each operation as its raw symplectic form `sign x_bits z_bits` (binary strings) instead of text
+    #[arg(long, conflicts_with_all = ["rle_output", "output_columns"])]
+    dump_symplectic: bool,
+
+    /// Write single-qubit Z rotations as their named gate (`T`, `Tdg`, `S`, `Sdg`, `Z`) instead of `Rotate N`, e.g. `T: IIZI`; these are read back as usual
+    #[arg(long, conflicts_with = "dump_symplectic")]
+    output_angles_as_gates: bool,
+
+    /// Debugging output: end each operation with a `# line N` comment naming the input line it came from. This can't be read back in
+    #[cfg(feature = "source-tracking")]
+    #[arg(long, conflicts_with = "dump_symplectic")]
+    output_source_lines: bool,
+
+    /// Letters for the x-only and z-only Paulis, in text input and output: "xz" (default, x is X) or "zx" (x is Z)
+    #[arg(long)]
+    pauli_convention: Option<String>,
+
+    /// Which end of each Pauli string qubit 0 is at, in text input and output: "msb" (default, leftmost) or "lsb" (rightmost, for little-endian tools)
+    #[arg(long)]
+    qubit_order: Option<String>,
+
+    /// If a round ever increases the number of operations, stop and write the circuit from before that round (not supported with --big-file)
+    #[arg(long, conflicts_with = "big_file")]
+    abort_on_growth: bool,
+
+    /// Run exactly this many rounds (pushing T gates forward, then partitioning), even if a round changes nothing or more rounds would still help
+    #[arg(long, conflicts_with = "abort_on_growth")]
+    only_rounds: Option<usize>,
+
+    /// Stop once this many consecutive rounds report a change but leave the T count and number of operations as they were, in case the rounds oscillate instead of converging (0 never stops early)
+    #[arg(long, default_value_t=DEFAULT_FIXED_POINT_ROUNDS, conflicts_with = "only_rounds")]
+    fixed_point_rounds: usize,
+
     /// Full partitioning (much slower, but may slightly decrease final gate count)
     #[arg(long, short)]
     full_partitioning: bool,
 
+    /// Debugging output: log the partitions (T gate indexes grouped into commuting layers, e.g. `[0,1,4] [2,3] [5]`) each time --full-partitioning creates them
+    #[arg(long, requires = "full_partitioning")]
+    dump_partitions: bool,
+
+    /// Start a new partition once one has this many T gates, bounding the time --full-partitioning spends checking commutation (at the cost of maybe a higher T count)
+    #[arg(long, requires = "full_partitioning", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    partition_size_cap: Option<usize>,
+
+    /// Number of threads used to reduce partitions with --full-partitioning (1 runs sequentially)
+    #[arg(long, default_value_t=1)]
+    threads: usize,
+
+    /// Passes of the fast approximate partitioning per round, stopping early once a pass removes nothing; 0 repeats until then (not supported with --big-file)
+    #[arg(long, default_value_t=1, conflicts_with = "full_partitioning")]
+    approx_passes: usize,
+
+    /// Experimental: reorder non-overlapping rotations so rotations on the same qubits are clustered together
+    #[arg(long)]
+    reorder_independent: bool,
+
+    /// Before optimizing, merge rotations about the same Pauli that are separated only by rotations they commute with, anywhere in the circuit (with --big-file, only within each --read-batch)
+    #[arg(long)]
+    global_rotation_merge: bool,
+
+    /// Move measurements to the end of the circuit, past the rotations they commute with (not supported with --big-file)
+    #[arg(long, conflicts_with = "big_file")]
+    canonical_measure: bool,
+
+    /// Report how many distinct Paulis (ignoring sign) the pi/8 rotations of the optimized circuit have, alongside the T count; rotations with the same Pauli can share resources (not supported with --big-file)
+    #[arg(long, conflicts_with = "big_file")]
+    count_distinct_paulis: bool,
+
+    /// Experimental: write runs of identical consecutive blocks of operations in the final circuit as `Repeat`/`End` blocks (not supported with --big-file)
+    #[arg(long, conflicts_with = "dump_symplectic")]
+    split_by_repeat: bool,
+
+    /// Benchmarking: optimize the (once loaded) circuit this many times, logging the mean, median and minimum time; only the last result is written (not supported with --big-file)
+    #[arg(long, default_value_t=1)]
+    iterations: usize,
+
+    /// Paranoid mode: buffer the input, optimize it a second time with the streaming (--big-file) optimizer, and fail if the tester finds the two results diverge
+    #[arg(long, conflicts_with_all = ["bypass", "full_partitioning", "canonical_measure", "strip_trailing_clifford"])]
+    validate_equivalence: bool,
+
+    /// Debugging output: write a `# layer N` comment before each T-layer (partition of commuting rotations, as the optimizer last grouped them) of the optimized circuit (`# clifford` if it has no T gates left), and `# measurements` before the measurements. This can't be read back in (not supported with --big-file)
+    #[arg(long, conflicts_with_all = ["big_file", "split_by_repeat", "validate_equivalence"])]
+    group_output_by_layer: bool,
+
+    /// Only report the optimized circuit's T-depth (number of T-layers, see --group-output-by-layer) and T count on STDOUT; nothing is written to the output path (not supported with --big-file)
+    #[arg(long, conflicts_with_all = ["big_file", "bypass", "validate_equivalence", "iterations"])]
+    t_depth_only: bool,
+
+    /// Collapse consecutive identical measurements (same Pauli and sign) into one, in every partitioning pass
+    #[arg(long = "merge-adjacent-measurements-in-same-basis")]
+    merge_adjacent_measurements: bool,
+
+    /// Drop the pi/2 and pi/4 rotations after the last pi/8 rotation. This changes the circuit: the final measurements are then taken without those Cliffords, so only use it if that final Clifford frame doesn't matter (not supported with --big-file)
+    #[arg(long, conflicts_with = "big_file")]
+    strip_trailing_clifford: bool,
+
+    /// Write a CSV of per-round phase timings (round, phase, milliseconds, ops, t_gates) to this path
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Write a CSV with one row per round (round, ops_after_push, t_after_push, ops_after_partition, t_after_partition) to this path
+    #[arg(long)]
+    rounds_csv: Option<PathBuf>,
+
+    /// Exclude measurements from reported operation counts (e.g. the `ops` column of --profile), for comparing circuits with different numbers of qubits
+    #[arg(long)]
+    stats_rotations_only: bool,
+
+    /// Only lex the (QASM) input and print how many times each gate appears, without optimizing or writing the output
+    #[arg(long)]
+    list_gates: bool,
+
     /// Test against reference
     #[arg(long)]
     test_against: Option<PathBuf>,
@@ -178,15 +422,59 @@ struct CommandLineArgs {
 
 
 /// This struct is used to store non-circuit runtime parameters.
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct RunConfig {
-    pub target_buffer_length: usize,
+    pub read_batch: usize,
+    pub write_batch: usize,
     pub bypass: bool,
     pub shrink_buffer_after_repeat: bool,
+    pub large_repeat_threshold: usize,
+    pub repeat_unroll_limit: Option<usize>,
+    pub lenient: bool,
+    pub strict: bool,
+    pub include_path: Option<PathBuf>,
+    pub gate_set: GateSet,
+    pub drop_barriers_as_partition_hints: bool,
     pub full_partitioning: bool,
+    pub dump_partitions: bool,
+    pub partition_size_cap: Option<usize>,
+    pub threads: usize,
+    pub approx_passes: usize,
+    pub abort_on_growth: bool,
+    pub only_rounds: Option<usize>,
+    pub fixed_point_rounds: usize,
+    pub reorder_independent: bool,
+    pub global_rotation_merge: bool,
+    pub canonical_measure: bool,
+    pub count_distinct_paulis: bool,
+    pub t_depth_only: bool,
+    pub strip_trailing_clifford: bool,
+    pub merge_adjacent_measurements: bool,
+    pub split_by_repeat: bool,
+    pub group_output_by_layer: bool,
+    pub iterations: usize,
+    pub validate_equivalence: bool,
     pub big_file: bool,
+    /// Bytes the [`InMemoryOptimizer`] may buffer before giving up (see [`infer_run_file`]).
+    pub memory_limit: Option<usize>,
     pub num_operations: Option<usize>,
+    /// Size of the (uncompressed) input file, if known; used to estimate the number of operations.
+    pub input_bytes: Option<u64>,
+    pub min_qubits: usize,
+    pub max_qubits: Option<usize>,
+    pub max_operations: Option<usize>,
+    pub already_measured: bool,
+    pub retain_input_measurements_order: bool,
+    pub assert_t_count: Option<usize>,
+    pub assert_max_t_count: Option<usize>,
+    pub phase_tracking: bool,
+    pub dry_run_parse: bool,
+    pub pauli_convention: PauliConvention,
+    pub qubit_order: QubitOrder,
     pub file_type: InputType,
+    pub profile: Option<PathBuf>,
+    pub rounds_csv: Option<PathBuf>,
+    pub stats_rotations_only: bool,
 }
 
 
@@ -206,21 +494,80 @@ impl TryFrom<&CommandLineArgs> for RunConfig {
                 "txt" => {
                     file_type = InputType::Txt;
                 },
+                "json" => {
+                    file_type = InputType::Json;
+                },
                 _ => {
-                    bail!("unrecognized file_type {:?}, possible values are \"txt\" or \"qasm\".", ftype);
+                    bail!("unrecognized file_type {:?}, possible values are \"txt\", \"qasm\" or \"json\".", ftype);
                 }
             }
         }
 
-        Ok(Self {
-            target_buffer_length: args.target_buffer_length,
-            bypass: args.bypass,
-            shrink_buffer_after_repeat: args.shrink_buffer_after_repeat,
-            full_partitioning: args.full_partitioning,
-            big_file: args.big_file,
-            num_operations: args.num_operations,
-            file_type,
-        })
+        let pauli_convention = match &args.pauli_convention {
+            Some(convention) => PauliConvention::try_from(convention.as_str())?,
+            None => PauliConvention::default(),
+        };
+
+        let gate_set = match &args.gate_set {
+            Some(gate_set) => GateSet::try_from(gate_set.as_str())?,
+            None => GateSet::default(),
+        };
+
+        let qubit_order = match &args.qubit_order {
+            Some(order) => QubitOrder::try_from(order.as_str())?,
+            None => QubitOrder::default(),
+        };
+
+        Ok(RunConfig::builder()
+            .read_batch(args.read_batch)
+            .write_batch(args.write_batch)
+            .bypass(args.bypass)
+            .shrink_buffer_after_repeat(args.shrink_buffer_after_repeat)
+            .large_repeat_threshold(args.warn_large_repeat)
+            .repeat_unroll_limit(args.repeat_unroll_limit)
+            .lenient(args.lenient)
+            .strict(args.strict)
+            .include_path(args.include_path.clone())
+            .gate_set(gate_set)
+            .drop_barriers_as_partition_hints(args.drop_barriers_as_partition_hints)
+            .full_partitioning(args.full_partitioning)
+            .dump_partitions(args.dump_partitions)
+            .partition_size_cap(args.partition_size_cap)
+            .threads(args.threads)
+            .approx_passes(args.approx_passes)
+            .abort_on_growth(args.abort_on_growth)
+            .only_rounds(args.only_rounds)
+            .fixed_point_rounds(args.fixed_point_rounds)
+            .reorder_independent(args.reorder_independent)
+            .global_rotation_merge(args.global_rotation_merge)
+            .canonical_measure(args.canonical_measure)
+            .count_distinct_paulis(args.count_distinct_paulis)
+            .t_depth_only(args.t_depth_only)
+            .strip_trailing_clifford(args.strip_trailing_clifford)
+            .merge_adjacent_measurements(args.merge_adjacent_measurements)
+            .split_by_repeat(args.split_by_repeat)
+            .group_output_by_layer(args.group_output_by_layer)
+            .iterations(args.iterations)
+            .validate_equivalence(args.validate_equivalence)
+            .big_file(args.big_file)
+            .memory_limit(args.memory_limit)
+            .num_operations(args.num_operations)
+            .min_qubits(args.min_qubits)
+            .max_qubits(args.max_qubits)
+            .max_operations(args.max_operations)
+            .already_measured(args.already_measured)
+            .retain_input_measurements_order(args.retain_input_measurements_order)
+            .assert_t_count(args.assert_t_count)
+            .assert_max_t_count(args.assert_max_t_count)
+            .phase_tracking(args.phase_tracking)
+            .dry_run_parse(args.dry_run_parse)
+            .pauli_convention(pauli_convention)
+            .qubit_order(qubit_order)
+            .file_type(file_type)
+            .profile(args.profile.clone())
+            .rounds_csv(args.rounds_csv.clone())
+            .stats_rotations_only(args.stats_rotations_only)
+            .build())
     }
 }
 
@@ -228,31 +575,176 @@ impl TryFrom<&CommandLineArgs> for RunConfig {
 impl Default for RunConfig {
     fn default() -> Self {
         Self {
-            target_buffer_length: 4096,
+            read_batch: 4096,
+            write_batch: 4096,
             bypass: false,
             shrink_buffer_after_repeat: false,
+            large_repeat_threshold: DEFAULT_LARGE_REPEAT_THRESHOLD,
+            repeat_unroll_limit: None,
+            lenient: false,
+            strict: false,
+            include_path: None,
+            gate_set: GateSet::default(),
+            drop_barriers_as_partition_hints: false,
             full_partitioning: false,
+            dump_partitions: false,
+            partition_size_cap: None,
+            threads: 1,
+            approx_passes: 1,
+            abort_on_growth: false,
+            only_rounds: None,
+            fixed_point_rounds: DEFAULT_FIXED_POINT_ROUNDS,
+            reorder_independent: false,
+            global_rotation_merge: false,
+            canonical_measure: false,
+            count_distinct_paulis: false,
+            t_depth_only: false,
+            strip_trailing_clifford: false,
+            merge_adjacent_measurements: false,
+            split_by_repeat: false,
+            group_output_by_layer: false,
+            iterations: 1,
+            validate_equivalence: false,
             big_file: false,
+            memory_limit: None,
             num_operations: None,
-            file_type: InputType::default()
+            input_bytes: None,
+            min_qubits: 0,
+            max_qubits: None,
+            max_operations: None,
+            already_measured: false,
+            retain_input_measurements_order: false,
+            assert_t_count: None,
+            assert_max_t_count: None,
+            phase_tracking: false,
+            dry_run_parse: false,
+            pauli_convention: PauliConvention::default(),
+            qubit_order: QubitOrder::default(),
+            file_type: InputType::default(),
+            profile: None,
+            rounds_csv: None,
+            stats_rotations_only: false,
         }
     }
 }
 
 
+/// Builds a [`RunConfig`], starting from its [`Default`], so that code constructing one isn't
+/// broken whenever a field is added.
+///
+/// ```ignore
+/// let run_config = RunConfig::builder()
+///     .full_partitioning(true)
+///     .threads(4)
+///     .build();
+/// assert_eq!(run_config.threads, 4);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RunConfigBuilder {
+    config: RunConfig,
+}
+
+
+macro_rules! run_config_setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+
+impl RunConfigBuilder {
+    run_config_setters! {
+        read_batch: usize,
+        write_batch: usize,
+        bypass: bool,
+        shrink_buffer_after_repeat: bool,
+        large_repeat_threshold: usize,
+        repeat_unroll_limit: Option<usize>,
+        lenient: bool,
+        strict: bool,
+        include_path: Option<PathBuf>,
+        gate_set: GateSet,
+        drop_barriers_as_partition_hints: bool,
+        full_partitioning: bool,
+        dump_partitions: bool,
+        partition_size_cap: Option<usize>,
+        threads: usize,
+        approx_passes: usize,
+        abort_on_growth: bool,
+        only_rounds: Option<usize>,
+        fixed_point_rounds: usize,
+        reorder_independent: bool,
+        global_rotation_merge: bool,
+        canonical_measure: bool,
+        count_distinct_paulis: bool,
+        t_depth_only: bool,
+        strip_trailing_clifford: bool,
+        merge_adjacent_measurements: bool,
+        split_by_repeat: bool,
+        group_output_by_layer: bool,
+        iterations: usize,
+        validate_equivalence: bool,
+        big_file: bool,
+        memory_limit: Option<usize>,
+        num_operations: Option<usize>,
+        input_bytes: Option<u64>,
+        min_qubits: usize,
+        max_qubits: Option<usize>,
+        max_operations: Option<usize>,
+        already_measured: bool,
+        retain_input_measurements_order: bool,
+        assert_t_count: Option<usize>,
+        assert_max_t_count: Option<usize>,
+        phase_tracking: bool,
+        dry_run_parse: bool,
+        pauli_convention: PauliConvention,
+        qubit_order: QubitOrder,
+        file_type: InputType,
+        profile: Option<PathBuf>,
+        rounds_csv: Option<PathBuf>,
+        stats_rotations_only: bool,
+    }
+
+    pub fn build(self) -> RunConfig {
+        self.config
+    }
+}
+
+
+impl RunConfig {
+    pub fn builder() -> RunConfigBuilder {
+        RunConfigBuilder::default()
+    }
+}
+
+
 /// Entry point for running the optimizer normally.
 ///
 /// Checks command line arguments, opens files, then calls [`infer_run`].
 fn main() -> anyhow::Result<()> {
-    env_logger::Builder::new()
+    // parsed first, since --log-format configures the logger
+    let mut args = CommandLineArgs::parse();
+
+    let mut logger = env_logger::Builder::new();
+    logger
         .filter_level(log::LevelFilter::Info) // default to Info
         .format_timestamp_millis()
-        .parse_env("QARROT_LOG_LEVEL")
-        .init();
-
-    trace!("logger initialized, parsing args");
+        .parse_env("QARROT_LOG_LEVEL");
+    match args.log_format.as_str() {
+        "human" => (),
+        "json" => {
+            logger.format(logging::format_json);
+        },
+        _ => bail!("unrecognized log format {:?}, possible values are \"human\" or \"json\".", args.log_format),
+    }
+    logging::init(logger.build())?;
 
-    let mut args = CommandLineArgs::parse();
+    trace!("logger initialized");
 
     trace!("8: {}", mem::size_of::<Operation<Basis8>>());
     trace!("16: {}", mem::size_of::<Operation<Basis16>>());
@@ -265,14 +757,65 @@ fn main() -> anyhow::Result<()> {
     debug!("input path:  {:?}", args.input);
     debug!("output path: {:?}", args.output);
     debug!("big file?:   {}", args.big_file);
+    debug!("memory limit: {:?}", args.memory_limit);
+    debug!("STDIN chunk size: {}", args.stdin_chunk_size);
     debug!("full partitioning algorithm: {}", args.full_partitioning);
+    debug!("dump partitions: {}", args.dump_partitions);
+    debug!("partition size cap: {:?}", args.partition_size_cap);
+    debug!("threads: {}", args.threads);
+    debug!("approximate partitioning passes: {}", args.approx_passes);
+    debug!("reorder independent rotations: {}", args.reorder_independent);
+    debug!("global rotation merge: {}", args.global_rotation_merge);
+    debug!("abort on growth: {}", args.abort_on_growth);
+    debug!("only rounds: {:?}", args.only_rounds);
+    debug!("fixed point rounds: {}", args.fixed_point_rounds);
+    debug!("canonical measurements: {}", args.canonical_measure);
+    debug!("count distinct Paulis: {}", args.count_distinct_paulis);
+    debug!("T-depth only: {}", args.t_depth_only);
+    debug!("strip trailing Cliffords: {}", args.strip_trailing_clifford);
+    debug!("merge adjacent measurements: {}", args.merge_adjacent_measurements);
+    debug!("split by repeat: {}", args.split_by_repeat);
+    debug!("group output by layer: {}", args.group_output_by_layer);
+    debug!("iterations: {}", args.iterations);
+    debug!("validate equivalence: {}", args.validate_equivalence);
     debug!("overwrite output:        {}", args.overwrite);
     debug!("bypass optimization:     {}", args.bypass);
     debug!("decompression algorithm: {:?}", args.decompression_algorithm);
+    debug!("run-length encode output: {}", args.rle_output);
+    debug!("output columns: {:?}", args.output_columns);
+    debug!("operations per write: {}", args.operations_per_write);
+    debug!("dump symplectic: {}", args.dump_symplectic);
+    debug!("output angles as gates: {}", args.output_angles_as_gates);
+    #[cfg(feature = "source-tracking")]
+    debug!("output source lines: {}", args.output_source_lines);
+    debug!("pauli convention: {:?}", args.pauli_convention);
+    debug!("qubit order: {:?}", args.qubit_order);
     debug!("test against: {:?}", args.test_against);
-
-    debug!("target buffer length:       {:?}", args.target_buffer_length);
+    debug!("list gates: {}", args.list_gates);
+    debug!("profile output: {:?}", args.profile);
+    debug!("rounds CSV output: {:?}", args.rounds_csv);
+    debug!("minimum qubits: {}", args.min_qubits);
+    debug!("maximum qubits: {:?}", args.max_qubits);
+    debug!("maximum operations: {:?}", args.max_operations);
+    debug!("already measured: {}", args.already_measured);
+    debug!("retain input measurements order: {}", args.retain_input_measurements_order);
+    debug!("assert T count: {:?}", args.assert_t_count);
+    debug!("assert maximum T count: {:?}", args.assert_max_t_count);
+    debug!("phase tracking: {}", args.phase_tracking);
+    debug!("dry run parse: {}", args.dry_run_parse);
+
+    debug!("read batch:                 {:?}", args.read_batch);
+    debug!("write batch:                {:?}", args.write_batch);
     debug!("shrink buffer after repeat: {:?}", args.shrink_buffer_after_repeat);
+    debug!("large repeat threshold:     {:?}", args.warn_large_repeat);
+    debug!("repeat unroll limit:        {:?}", args.repeat_unroll_limit);
+    debug!("lenient parsing:            {:?}", args.lenient);
+    debug!("fail on warning:            {:?}", args.fail_on_warning);
+    debug!("log format:                 {:?}", args.log_format);
+    debug!("strict QASM parsing:        {:?}", args.strict);
+    debug!("QASM include path:          {:?}", args.include_path);
+    debug!("QASM gate set:              {:?}", args.gate_set);
+    debug!("barriers as hints:          {:?}", args.drop_barriers_as_partition_hints);
 
     let input_path = if args.input != "STDIN" {
         Some(PathBuf::from(&args.input))
@@ -295,9 +838,31 @@ fn main() -> anyhow::Result<()> {
         bail!("Output path {:?} exists and --overwrite was set, but the path is not a file.", output_path);
     } else if args.big_file && args.full_partitioning {
         bail!("Cannot use both --full-partitioning and --big-file.");
-    } else if args.target_buffer_length > MAX_PREALLOC_OPERATIONS {
-        warn!("target buffer length ({}) larger than allowed maximum ({}); setting to maximum.", args.target_buffer_length, MAX_PREALLOC_OPERATIONS);
-        args.target_buffer_length = MAX_PREALLOC_OPERATIONS;
+    } else if args.big_file && args.split_by_repeat {
+        bail!("Cannot use both --split-by-repeat and --big-file.");
+    } else if args.big_file && args.iterations > 1 {
+        bail!("Cannot use both --iterations and --big-file.");
+    } else if args.big_file && args.approx_passes != 1 {
+        bail!("Cannot use both --approx-passes and --big-file.");
+    } else if args.big_file && args.validate_equivalence {
+        bail!("Cannot use both --validate-equivalence and --big-file.");
+    } else if args.output_columns == Some(0) {
+        bail!("--output-columns must be at least 1.");
+    } else if args.read_batch == 0 || args.write_batch == 0 {
+        bail!("--read-batch and --write-batch must be at least 1.");
+    } else if args.only_rounds == Some(0) {
+        bail!("--only-rounds must be at least 1.");
+    } else if args.include_path.as_ref().is_some_and(|dir| !dir.is_dir()) {
+        bail!("Include path {:?} is not a directory.", args.include_path.as_ref().unwrap());
+    }
+
+    if args.read_batch > MAX_PREALLOC_OPERATIONS {
+        warn!("read batch ({}) larger than allowed maximum ({}); setting to maximum.", args.read_batch, MAX_PREALLOC_OPERATIONS);
+        args.read_batch = MAX_PREALLOC_OPERATIONS;
+    }
+    if args.write_batch > MAX_PREALLOC_OPERATIONS {
+        warn!("write batch ({}) larger than allowed maximum ({}); setting to maximum.", args.write_batch, MAX_PREALLOC_OPERATIONS);
+        args.write_batch = MAX_PREALLOC_OPERATIONS;
     }
 
     let inferred = if let Some(input_path) = input_path.as_ref() {
@@ -350,12 +915,23 @@ fn main() -> anyhow::Result<()> {
                     "txt" => {
                         run_config.file_type = InputType::Txt;
                     },
+                    "json" => {
+                        run_config.file_type = InputType::Json;
+                    },
                     _ => {}
                 }
             }
         }
     }
 
+    if run_config.file_type == InputType::Other && input_path.is_none() {
+        bail!("cannot determine the file type of STDIN; specify it with --file-type (\"txt\", \"qasm\" or \"json\")");
+    }
+
+    if run_config.memory_limit.is_some() && input_path.is_none() {
+        bail!("--memory-limit needs an input file: restarting with --big-file reads the input again, which STDIN can't do");
+    }
+
     trace!("args checked; inferred run configuration: {:?}", run_config);
 
     if let Some(test_against) = (&args.test_against).as_ref() {
@@ -367,52 +943,166 @@ fn main() -> anyhow::Result<()> {
         return Ok(())
     }
 
-    let output = WriteOutput::new(fs::File::create(output_path)?);
-    let input = if let Some(input_path) = &input_path {
-        if compression.is_some() {
-            Input::new_gzip(input_path)?
+    let open_input = |run_config: &mut RunConfig| -> anyhow::Result<Input> {
+        Ok(if let Some(input_path) = &input_path {
+            match compression {
+                Some(CompressionAlgorithm::GZip) => Input::new_gzip(input_path)?,
+                #[cfg(feature = "bzip2")]
+                Some(CompressionAlgorithm::Bzip2) => Input::new_bzip2(input_path)?,
+                None => {
+                    run_config.input_bytes = fs::metadata(input_path).ok().map(|metadata| metadata.len());
+                    Input::new(input_path)?
+                },
+            }
         } else {
-            Input::new(input_path)?
+            Input::stdin(args.stdin_chunk_size)?
+        })
+    };
+
+    if args.list_gates {
+        if run_config.file_type != InputType::Qasm {
+            bail!("--list-gates is only supported for QASM input");
         }
-    } else {
-        Input::stdin()?
+        let mut tokens = qasm::lexer::TokenIterator::new(open_input(&mut run_config)?);
+        tokens.set_include_path(run_config.include_path.clone());
+        let counts = tokens.gate_counts()?;
+        for (gate, count) in &counts {
+            println!("{}\t{}", gate.name(), count);
+        }
+        let clifford: usize = counts.iter().filter(|(gate, _)| gate.is_clifford()).map(|(_, count)| count).sum();
+        let t_count: usize = counts.iter().filter(|(gate, _)| !gate.is_clifford()).map(|(_, count)| count).sum();
+        println!("clifford\t{}", clifford);
+        println!("t_count\t{}", t_count);
+        if args.fail_on_warning {
+            logging::fail_on_warnings()?;
+        }
+        return Ok(())
+    }
+
+    let format = FormatOptions {
+        run_length_encode: args.rle_output,
+        pauli_convention: run_config.pauli_convention,
+        qubit_order: run_config.qubit_order,
+        columns: args.output_columns,
+        dump_symplectic: args.dump_symplectic,
+        angles_as_gates: args.output_angles_as_gates,
+        #[cfg(feature = "source-tracking")]
+        source_lines: args.output_source_lines,
     };
+    let output_file = || fs::File::create(&output_path);
 
-    match run_config.file_type {
-        InputType::Qasm => infer_run_qasm(input, output, run_config)?,
-        InputType::Txt => infer_run_txt(input, output, run_config)?,
-        InputType::Other => bail!("could not determine file type; specify with --file-type"),
+    let summary = if args.t_depth_only || args.dry_run_parse {
+        // nothing is written, so don't create (or truncate) the output
+        infer_run_file(open_input, || Ok(Void {}), run_config)?
+    } else {
+        infer_run_file(open_input, || Ok(WriteOutput::with_format(output_file()?, format).with_operations_per_write(args.operations_per_write)), run_config)?
     };
 
+    if let Some(TDepth { t_depth, t_count }) = summary.t_depth {
+        println!("t_depth\t{}", t_depth);
+        println!("t_count\t{}", t_count);
+    }
+
+    if args.fail_on_warning {
+        logging::fail_on_warnings()?;
+    }
+
     Ok(())
 }
 
+
+/// [`infer_run`], restarting with the [`FileOptimizer`] (as with `--big-file`) if the
+/// [`InMemoryOptimizer`] goes over `run_config.memory_limit` while reading the circuit.
+///
+/// Restarting reads the input again from the start, so with a memory limit `open_input` must be
+/// able to open it twice (it can't be STDIN). The output is only opened once the run that writes
+/// it has started; `open_input` may fill in `run_config.input_bytes`.
+pub fn infer_run_file<I: Read + Debug, O: Output>(open_input: impl Fn(&mut RunConfig) -> anyhow::Result<I>, open_output: impl Fn() -> anyhow::Result<O>, mut run_config: RunConfig) -> anyhow::Result<RunSummary> {
+    let input = open_input(&mut run_config)?;
+    match infer_run(input, open_output()?, run_config.clone()) {
+        Err(err) if err.downcast_ref::<MemoryLimitExceeded>().is_some() => {
+            warn!("{:#}; restarting with --big-file", err);
+            run_config.big_file = true;
+            let input = open_input(&mut run_config)?;
+            infer_run(input, open_output()?, run_config)
+        },
+        result => result,
+    }
+}
+
+
+/// Run on any input (file, STDIN, string, &c), dispatching on `run_config.file_type`.
+pub fn infer_run(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<RunSummary> {
+    match run_config.file_type {
+        InputType::Qasm => infer_run_qasm(input, output, run_config),
+        InputType::Txt => infer_run_txt(input, output, run_config),
+        InputType::Json => infer_run_json(input, output, run_config),
+        InputType::Other => bail!("could not determine file type; specify with --file-type"),
+    }
+}
+
  
 /// Trivial wrapper over [`_infer_run_txt`] to call it without a tester callback.
-pub fn infer_run_txt(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<usize> {
+pub fn infer_run_txt(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<RunSummary> {
     _infer_run_txt(input, output, run_config, |_, _, _| {})
 }
 
 
+/// Fail if the optimized circuit's `t_count` doesn't match `--assert-t-count` or is over
+/// `--assert-max-t-count`.
+fn check_t_count(t_count: usize, run_config: &RunConfig) -> anyhow::Result<()> {
+    if let Some(expected) = run_config.assert_t_count {
+        if t_count != expected {
+            bail!("optimized circuit has {} T gates, but --assert-t-count expected {}", t_count, expected);
+        }
+    }
+    if let Some(max) = run_config.assert_max_t_count {
+        if t_count > max {
+            bail!("optimized circuit has {} T gates, more than the maximum of {} (--assert-max-t-count)", t_count, max);
+        }
+    }
+    Ok(())
+}
+
+
+/// Fail if a circuit `width` qubits wide is over `--max-qubits`.
+fn check_max_qubits(width: usize, run_config: &RunConfig) -> anyhow::Result<()> {
+    if let Some(max_qubits) = run_config.max_qubits {
+        if width > max_qubits {
+            bail!("circuit has {} qubits, more than the maximum of {} (--max-qubits)", width, max_qubits);
+        }
+    }
+    Ok(())
+}
+
+
 /// Determines the number of qubits in the circuit, then calls [`run`].
-pub fn infer_run_qasm(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<usize> {
+pub fn infer_run_qasm(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<RunSummary> {
     use qasm::lexer::Token;
+    if run_config.phase_tracking {
+        bail!("--phase-tracking is not supported for QASM input: the gates' decompositions into rotations have phases of their own (e.g. e^(iπ/4)·H for `h`) which aren't tracked");
+    }
     let mut seen_openqasm = false;
     let mut n_qubits = None;
 
     let mut tokens = qasm::lexer::TokenIterator::new(input);
+    tokens.set_strict(run_config.strict);
+    tokens.set_include_path(run_config.include_path.clone());
 
-    while let Some(tok) = tokens.next() {
+    while let Some(tok) = tokens.pop().context("while reading the OpenQASM header")? {
         match tok {
             Token::Version(_) => {
                 seen_openqasm = true;
             },
-            Token::Include(_) => (),
+            Token::Include(_) | Token::Barrier => (),
             Token::QregDecl(_, qubits) => {
                 n_qubits = Some(qubits);
                 break;
             },
-            Token::FixedGate(_, _) => bail!("found OpenQASM gate before a qreg declaration"),
+            Token::FixedGate(gate, _) => bail!(
+                "found OpenQASM gate {:?} on line {} before a qreg declaration; declare the qubits first (e.g. `qreg q[N];`)",
+                gate, tokens.line_number()
+            ),
         }
     }
 
@@ -420,7 +1110,14 @@ pub fn infer_run_qasm(input: impl Read + Debug, output: impl Output, run_config:
         bail!("missing OpenQASM version declaration");
     }
 
-    let n_qubits = n_qubits.unwrap();
+    let Some(mut n_qubits) = n_qubits else {
+        bail!("missing qreg declaration");
+    };
+    if n_qubits < run_config.min_qubits {
+        info!("padding circuit from {} to {} qubits", n_qubits, run_config.min_qubits);
+        n_qubits = run_config.min_qubits;
+    }
+    check_max_qubits(n_qubits, &run_config)?;
 
     let basis_size = BasisSize::from_size(n_qubits);
 
@@ -428,12 +1125,14 @@ pub fn infer_run_qasm(input: impl Read + Debug, output: impl Output, run_config:
         ($basis:ty) => {{
             info!("circuit has {} qubits; using basis size {}", n_qubits, basis_size.bits());
 
-            let parser = qasm::parser::InstructionIterator::<_, $basis>::new(n_qubits, tokens, run_config.target_buffer_length)?;
+            let mut parser = qasm::parser::InstructionIterator::<_, $basis>::new(n_qubits, tokens, run_config.read_batch)?;
+            parser.set_barriers_as_partition_hints(run_config.drop_barriers_as_partition_hints);
+            parser.set_gate_set(run_config.gate_set);
 
             if run_config.big_file {
                 run::<_, _, FileOptimizer<_, _>>(output, parser, n_qubits, run_config)
             } else {
-                run::<_, _, InMemoryOptimizer<_>>(output, parser, n_qubits, run_config)
+                run_in_memory(output, parser, n_qubits, run_config)
             }
         }}
     }
@@ -456,13 +1155,65 @@ pub fn infer_run_qasm(input: impl Read + Debug, output: impl Output, run_config:
 }
 
 
+/// Determines the number of qubits in the circuit from the first object, then calls [`run`].
+pub fn infer_run_json(input: impl Read + Debug, output: impl Output, run_config: RunConfig) -> anyhow::Result<RunSummary> {
+    let mut objects = json::ObjectIterator::new(input);
+    let Some(first) = objects.peek()? else {
+        bail!("Empty input file, cannot infer number of qubits.");
+    };
+    let n_qubits = json::n_qubits_of(first).context("in object 1")?;
+
+    let width = n_qubits.max(run_config.min_qubits);
+    if width != n_qubits {
+        info!("padding circuit from {} to {} qubits", n_qubits, width);
+    }
+    check_max_qubits(width, &run_config)?;
+
+    let basis_size = BasisSize::from_size(width);
+
+    macro_rules! run_with_basis_size {
+        ($basis:ty) => {{
+            info!("circuit has {} qubits; using basis size {}", n_qubits, basis_size.bits());
+
+            let parser = json::InstructionIterator::<_, $basis>::new(n_qubits, objects);
+            let parser = PadQubits::new(parser, n_qubits, width);
+
+            if run_config.big_file {
+                run::<_, _, FileOptimizer<_, _>>(output, parser, width, run_config)
+            } else {
+                run_in_memory(output, parser, width, run_config)
+            }
+        }}
+    }
+
+    match basis_size {
+        BasisSize::Basis8 => run_with_basis_size!(Basis8),
+        BasisSize::Basis16 => run_with_basis_size!(Basis16),
+        BasisSize::Basis32 => run_with_basis_size!(Basis32),
+        BasisSize::Basis64 => run_with_basis_size!(Basis64),
+        BasisSize::Basis128 => run_with_basis_size!(Basis128),
+        BasisSize::Basis256 => run_with_basis_size!(Basis256),
+        BasisSize::BasisDyn => {
+            warn!("using fallback dynamically sized basis ({} qubits too large for largest static basis, {}). this will be significantly slower than using statically sized bases.", width, basis::LARGEST_STATIC_BASIS);
+            if run_config.big_file {
+                warn!("using both big file support and dynamically sized basis likely has no benefit; recommend asking the qarrot team to add a larger static basis.");
+            }
+            run_with_basis_size!(DBasis)
+        },
+    }
+}
+
+
 /// Determines the number of qubits in the circuit, then calls [`run`].
 ///
 /// Has a callback parameter for testing. This is called after pulling the one or two lines
 /// (depending on whether the first line of the circuit is a repeat).
-fn _infer_run_txt<K: Fn(usize, Option<usize>, BasisSize)>(input: impl Read + Debug, output: impl Output, run_config: RunConfig, testing_callback: K) -> anyhow::Result<usize> {
+fn _infer_run_txt<K: Fn(usize, Option<usize>, BasisSize)>(input: impl Read + Debug, output: impl Output, run_config: RunConfig, testing_callback: K) -> anyhow::Result<RunSummary> {
     trace!("running input size inference");
     let mut tokenizer = TokenIterator::new(input);
+    tokenizer.set_lenient(run_config.lenient);
+    tokenizer.set_pauli_convention(run_config.pauli_convention);
+    tokenizer.set_qubit_order(run_config.qubit_order);
 
     // need to determine appropriate basis size
     // so, we'll read one or two lines
@@ -499,23 +1250,34 @@ fn _infer_run_txt<K: Fn(usize, Option<usize>, BasisSize)>(input: impl Read + Deb
     }
 
     debug!("preread {} tokens", preread_tokens.len());
+    let preread_line = u32::try_from(tokenizer.line_number()).ok();
 
     let n_qubits = preread_tokens.len() - 1;
+    // every later line must have exactly as many Paulis
+    tokenizer.set_max_paulis(n_qubits);
+    let width = n_qubits.max(run_config.min_qubits);
+    if width != n_qubits {
+        info!("padding circuit from {} to {} qubits", n_qubits, width);
+    }
+    check_max_qubits(width, &run_config)?;
 
-    let basis_size = BasisSize::from_size(n_qubits);
+    let basis_size = BasisSize::from_size(width);
 
     // for testing
     testing_callback(n_qubits, repeat, basis_size);
 
     macro_rules! run_with_basis_size {
         ($basis:ty) => {
-            {let op = complete_op::<$basis>(n_qubits, &preread_tokens)
-                .with_context(|| format!("while building prepended operation ({} bits)", basis_size.bits()))?;
+            {let op = Operation::<$basis>::try_from_tokens(n_qubits, &preread_tokens)
+                .with_context(|| format!("while building prepended operation ({} bits)", basis_size.bits()))?
+                .with_src_line(preread_line);
             info!("circuit has {} qubits; using basis size {}", n_qubits, basis_size.bits());
             debug!("preread operation: {:?}", op);
             debug!("preread repeat: {:?}", repeat);
 
-            let mut parser = InstructionIterator::<_, $basis>::new(n_qubits, tokenizer, run_config.target_buffer_length, run_config.shrink_buffer_after_repeat);
+            let mut parser = InstructionIterator::<_, $basis>::new(n_qubits, tokenizer, run_config.read_batch, run_config.shrink_buffer_after_repeat);
+            parser.set_large_repeat_threshold(run_config.large_repeat_threshold);
+            parser.set_repeat_unroll_limit(run_config.repeat_unroll_limit);
             if let Some(repeat) = repeat {
                 debug!("prepending repeat and operation");
                 parser.prepend_repeat(repeat, op.clone())?;
@@ -523,11 +1285,12 @@ fn _infer_run_txt<K: Fn(usize, Option<usize>, BasisSize)>(input: impl Read + Deb
                 debug!("prepending operation");
                 parser.prepend_op(op.clone());
             }
+            let parser = PadQubits::new(parser, n_qubits, width);
 
             if run_config.big_file {
-                run::<_, _, FileOptimizer<_, _>>(output, parser, n_qubits, run_config)
+                run::<_, _, FileOptimizer<_, _>>(output, parser, width, run_config)
             } else {
-                run::<_, _, InMemoryOptimizer<_>>(output, parser, n_qubits, run_config)
+                run_in_memory(output, parser, width, run_config)
             }
         }
         };
@@ -562,13 +1325,80 @@ fn debug_out_circuit<B: Basis>(circuit: &[Operation<B>], n_qubits: usize) {
 }
 
 
+/// [`run`] with the [`InMemoryOptimizer`], checking the result with `--validate-equivalence`.
+///
+/// The tester compares two optimized circuits, so the input is buffered and optimized again with
+/// the [`FileOptimizer`] as the reference.
+fn run_in_memory<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug>(output: impl Output, parser: Ops, n_qubits: usize, run_config: RunConfig) -> anyhow::Result<RunSummary> {
+    if !run_config.validate_equivalence {
+        return run::<_, _, InMemoryOptimizer<_>>(output, parser, n_qubits, run_config);
+    }
+
+    info!("buffering input to validate equivalence…");
+    let input: Vec<Operation<B>> = parser.collect::<anyhow::Result<_>>().context("while reading circuit")?;
+
+    let mut reference = String::new();
+    let reference_config = RunConfig {
+        validate_equivalence: false,
+        split_by_repeat: false,
+        iterations: 1,
+        approx_passes: 1,
+        abort_on_growth: false,
+        count_distinct_paulis: false,
+        profile: None,
+        ..run_config.clone()
+    };
+    info!("optimizing reference circuit…");
+    run::<_, _, FileOptimizer<_, _>>(StringOut::new(&mut reference), input.iter().cloned().map(Ok), n_qubits, reference_config)
+        .context("while optimizing reference circuit")?;
+
+    let mut optimized = String::new();
+    let summary = run::<_, _, InMemoryOptimizer<_>>(Tee(output, StringOut::new(&mut optimized)), input.into_iter().map(Ok), n_qubits, run_config)?;
+
+    info!("validating equivalence against reference…");
+    validate_equivalence::<B>(&reference, &optimized, summary.n_qubits)?;
+    Ok(summary)
+}
+
+
+/// Check the (text) optimized circuit against the reference optimization with the [`Tester`].
+fn validate_equivalence<B: Basis>(reference: &str, optimized: &str, n_qubits: usize) -> anyhow::Result<()> {
+    Tester::<_, B>::new(reference.as_bytes(), optimized.as_bytes(), n_qubits).test_all()
+        .context("optimized circuit diverges from the reference optimization")
+}
+
+
 /// Main body of the optimization algorithm.
 ///
 /// 1. Builds a parser using the lexer, optionally prepending an operation and a repeat statement.
 /// 2. Then, loads the whole circuit (currently; this should be changed, at least for large files).
 /// 3. If run in bypass mode, write this circuit to the output then exit.
 /// 4. 
-pub fn run<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug, Opt: Optimizer<B, Ops>>(mut output: impl Output, mut parser: Ops, n_qubits: usize, run_config: RunConfig) -> anyhow::Result<usize> {
+pub fn run<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, Opt: Optimizer<B, Ops>>(output: impl Output, parser: Ops, n_qubits: usize, run_config: RunConfig) -> anyhow::Result<RunSummary> {
+    run_with_timings::<B, Ops, Opt>(output, parser, n_qubits, run_config).map(|(summary, _)| summary)
+}
+
+
+/// What a [`run`] found out about the circuit, for `main` to report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// The width of the circuit (after any padding).
+    pub n_qubits: usize,
+    /// The T-depth and T count of the optimized circuit, with `--t-depth-only`.
+    pub t_depth: Option<TDepth>,
+}
+
+
+/// The T-depth and T count of an optimized circuit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TDepth {
+    pub t_depth: usize,
+    pub t_count: usize,
+}
+
+
+/// [`run`], also returning how long each optimization took (more than one with `--iterations`).
+pub fn run_with_timings<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, Opt: Optimizer<B, Ops>>(mut output: impl Output, mut parser: Ops, n_qubits: usize, run_config: RunConfig) -> anyhow::Result<(RunSummary, IterationTimings)> {
     trace!("beginning run");
     let size_of_operation = mem::size_of::<Operation<B>>();
     debug!("size_of operation: {}", size_of_operation);
@@ -578,80 +1408,222 @@ pub fn run<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug, Opt: Optimizer<
 
     if run_config.bypass {
         info!("running in bypass mode; writing output");
+        output.begin(n_qubits)?;
         while let Some(op) = parser.next() {
-            output.write_operation(n_qubits, &op)?;
+            output.write_operation(n_qubits, &op.context("while reading circuit")?)?;
         }
+        output.end()?;
         output.flush()?;
 
-        return Ok(n_qubits);
+        return Ok((RunSummary { n_qubits, ..Default::default() }, IterationTimings::default()));
     }
 
     // let mut optimizer = InMemoryOptimizer::new(n_qubits, parser, &run_config)?;
     let mut optimizer = Opt::new(n_qubits, parser, &run_config)?;
 
+    if run_config.strip_trailing_clifford && !optimizer.strip_trailing_clifford() {
+        warn!("--strip-trailing-clifford is not supported by this optimizer (is --big-file set?); ignoring");
+    }
+
     let initial_circuit_length = optimizer.initial_circuit_length();
 
     let (alloc, used) = optimizer.current_heap_usage();
     debug!("circuit using {} ({} allocated) bytes of heap", used, alloc);
 
-    let start_time = std::time::Instant::now();
-    let duration_reduce_rotations = start_time.duration_since(pre_parse);
+    let duration_reduce_rotations = pre_parse.elapsed();
     if initial_circuit_length.is_none() {
         info!("initialized optimizer in {:?}", duration_reduce_rotations);
     } else {
         info!("initial reduction pass done in {:?}; reduced from {:?} to {:?} operations.", duration_reduce_rotations, initial_circuit_length.unwrap(), optimizer.post_reduction_length().unwrap());
     }
 
-    let mut needs_more_rounds = true;
-    let mut rounds = 0usize;
-
-    let mut duration_t_forward = std::time::Duration::from_nanos(0);
-    let mut duration_partition = std::time::Duration::from_nanos(0);
-
-    while needs_more_rounds {
-        let round = rounds + 1;
-        info!("beginning round {}; pushing T gates forward…", round);
+    if run_config.dry_run_parse {
+        match optimizer.parse_timings() {
+            Some(ParseTimings { parse, reduction }) => info!("dry run: parsing took {:?}, initial reduction took {:?}", parse, reduction),
+            None => warn!("--dry-run-parse is not supported by this optimizer (is --big-file set?); only the total is known"),
+        }
+        return Ok((RunSummary { n_qubits, ..Default::default() }, IterationTimings::default()));
+    }
 
+    let iterations = run_config.iterations.max(1);
+    let mut timings = IterationTimings::default();
+    for iteration in 1..iterations {
+        // time the optimization on a fresh copy, keeping the original for the final (written) iteration
+        let Some(mut copy) = optimizer.try_clone() else {
+            warn!("--iterations is not supported by this optimizer (is --big-file set?); ignoring");
+            break;
+        };
+        info!("benchmark iteration {}/{}", iteration, iterations);
         let t0 = std::time::Instant::now();
-        needs_more_rounds = false;
+        optimization_rounds(&mut copy, &run_config)?;
+        timings.push(t0.elapsed());
+    }
 
-        let (_changed, _t_gate_count) = optimizer.push_t_forward().context("while pushing T gates forward")?;
+    let start_time = std::time::Instant::now();
+    let RoundsSummary { rounds, duration_t_forward, duration_partition, profile } = optimization_rounds(&mut optimizer, &run_config)?;
+    timings.push(start_time.elapsed());
+    if timings.len() > 1 {
+        info!("{} iterations: mean {:?}, median {:?}, min {:?}", timings.len(), timings.mean(), timings.median(), timings.min());
+    }
 
-        let t1 = std::time::Instant::now();
-        duration_t_forward += t1.duration_since(t0);
-        let t_forward_time = t1.duration_since(t0);
-        if let Some(stats) = optimizer.latest_stats() {
-            info!("pushed T gates forward in {:?}. currently {} operations ({} t gates). partitioning ({})…", t_forward_time, stats.total_operations, stats.t_gates, if run_config.full_partitioning { "full" } else { "fast approximate" });
-        } else {
-            info!("pushed T gates forward in {:?}. partitioning ({})…", t_forward_time, if run_config.full_partitioning { "full" } else { "fast approximate" });
+    let final_stats = optimizer.latest_stats().unwrap();
+    let final_time = std::time::Instant::now();
+    let final_t_gates = final_stats.t_gates;
+    let duration_total = final_time.duration_since(start_time);
+    info!("finished optimizing circuit from {} operations (final T count: {}) after {} rounds, taking {:?} ({:?} pushing T gates forward, {:?} partitioning).", optimizer.initial_circuit_length().unwrap() , final_t_gates, rounds, duration_total, duration_t_forward, duration_partition);
+    info!("final circuit: {} total operations, {} rotations ({} Clifford)", final_stats.total_operations, final_stats.rotations_only, final_stats.clifford_gates);
+    if run_config.phase_tracking {
+        match optimizer.global_phase() {
+            Some(phase) => info!("global phase: {}", phase),
+            None => warn!("--phase-tracking is not supported by this optimizer; ignoring"),
         }
+    }
+    if run_config.count_distinct_paulis {
+        match optimizer.count_distinct_t_paulis() {
+            Some(distinct) => info!("distinct T rotation Paulis: {} (of {} T gates)", distinct, final_t_gates),
+            None => warn!("--count-distinct-paulis is not supported by this optimizer (is --big-file set?); ignoring"),
+        }
+    }
 
-        // reduce t gate layer
+    if let Some(profile_path) = &run_config.profile {
+        info!("writing profile to {:?}", profile_path);
+        let file = fs::File::create(profile_path).with_context(|| format!("while creating profile output {:?}", profile_path))?;
+        profile.write_csv(io::BufWriter::new(file), run_config.stats_rotations_only).context("while writing profile")?;
+    }
+
+    if let Some(rounds_csv_path) = &run_config.rounds_csv {
+        info!("writing per-round counts to {:?}", rounds_csv_path);
+        let file = fs::File::create(rounds_csv_path).with_context(|| format!("while creating rounds CSV output {:?}", rounds_csv_path))?;
+        profile.write_rounds_csv(io::BufWriter::new(file), run_config.stats_rotations_only).context("while writing rounds CSV")?;
+    }
+
+    if run_config.canonical_measure && !optimizer.canonical_measure() {
+        warn!("--canonical-measure is not supported by this optimizer (is --big-file set?); ignoring");
+    }
+
+    if run_config.t_depth_only {
+        let Some(t_depth) = optimizer.t_depth() else {
+            bail!("--t-depth-only is not supported by this optimizer (is --big-file set?)");
+        };
+        info!("T-depth: {}, T count: {}", t_depth, final_t_gates);
+        check_t_count(final_t_gates, &run_config)?;
+        return Ok((RunSummary { n_qubits, t_depth: Some(TDepth { t_depth, t_count: final_t_gates }) }, timings));
+    }
+
+    info!("saving optimized circuit…");
+    optimizer.write_to_output(output)?;
+    check_t_count(final_t_gates, &run_config)?;
+
+    trace!("done, exiting");
+    Ok((RunSummary { n_qubits, ..Default::default() }, timings))
+}
+
+
+/// What [`optimization_rounds`] did, for reporting.
+struct RoundsSummary {
+    rounds: usize,
+    duration_t_forward: std::time::Duration,
+    duration_partition: std::time::Duration,
+    profile: Profile,
+}
+
+
+/// Run rounds of pushing T gates forward and partitioning until nothing changes (or exactly
+/// `run_config.only_rounds` rounds, if set).
+///
+/// Also stops once `run_config.fixed_point_rounds` rounds in a row report a change but end with the
+/// same T count and number of operations, so a `changed` flag that never settles can't loop forever.
+fn optimization_rounds<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, Opt: Optimizer<B, Ops>>(optimizer: &mut Opt, run_config: &RunConfig) -> anyhow::Result<RoundsSummary> {
+    let mut needs_more_rounds = true;
+    let mut rounds = 0usize;
+
+    let mut duration_t_forward = std::time::Duration::from_nanos(0);
+    let mut duration_partition = std::time::Duration::from_nanos(0);
+    let mut profile = Profile::default();
+
+    let mut abort_on_growth = run_config.abort_on_growth;
+    let mut operations_before_round = optimizer.post_reduction_length();
+    // (t_gates, total_operations) after the last round, and how many rounds in a row left them alone
+    let mut last_metrics = None;
+    let mut unchanged_rounds = 0usize;
+
+    while needs_more_rounds {
+        let round = rounds + 1;
+        if abort_on_growth && !optimizer.checkpoint() {
+            warn!("--abort-on-growth is not supported by this optimizer (is --big-file set?); ignoring");
+            abort_on_growth = false;
+        }
+        info!("beginning round {}; pushing T gates forward…", round);
+
+        let t0 = std::time::Instant::now();
+        needs_more_rounds = false;
+
+        let (_changed, t_forward_stats) = optimizer.push_t_forward().context("while pushing T gates forward")?;
+
+        let t1 = std::time::Instant::now();
+        duration_t_forward += t1.duration_since(t0);
+        let t_forward_time = t1.duration_since(t0);
+        profile.record(round, RoundPhase::TForward, t_forward_time, t_forward_stats);
+        if let Some(stats) = optimizer.latest_stats() {
+            info!("pushed T gates forward in {:?}. currently {} operations ({} t gates). partitioning ({})…", t_forward_time, stats.total_operations, stats.t_gates, if run_config.full_partitioning { "full" } else { "fast approximate" });
+        } else {
+            info!("pushed T gates forward in {:?}. partitioning ({})…", t_forward_time, if run_config.full_partitioning { "full" } else { "fast approximate" });
+        }
+
+        if round == 1 && t_forward_stats.t_gates == 0 && run_config.only_rounds.is_none() && !run_config.merge_adjacent_measurements {
+            // no T gates to begin with: everything has been absorbed into the Clifford frame, only
+            // the measurements are left (but they're only merged while partitioning)
+            info!("all-Clifford circuit; skipping partitioning");
+            rounds += 1;
+            break;
+        }
+
+        // reduce t gate layer
         let (changed, stats) = optimizer.partition().context("while partitioning")?;
         needs_more_rounds |= changed;
 
         let t2 = std::time::Instant::now();
         info!("partitioned gates in {:?}. currently {} total operations, {} t gates", t2.duration_since(t1), stats.total_operations, stats.t_gates);
         duration_partition += t2.duration_since(t1);
+        profile.record(round, RoundPhase::Partition, t2.duration_since(t1), stats);
 
         rounds += 1;
+
+        if abort_on_growth && operations_before_round.is_some_and(|before| stats.total_operations > before) {
+            error!("round {} increased the number of operations from {} to {}; aborting and writing the circuit from before this round", round, operations_before_round.unwrap(), stats.total_operations);
+            optimizer.restore_checkpoint().context("while restoring circuit after growth")?;
+            break;
+        }
+        operations_before_round = Some(stats.total_operations);
+
+        if let Some(only_rounds) = run_config.only_rounds {
+            needs_more_rounds = rounds < only_rounds;
+        } else if needs_more_rounds && run_config.fixed_point_rounds > 0 {
+            let metrics = (stats.t_gates, stats.total_operations);
+            unchanged_rounds = if last_metrics == Some(metrics) { unchanged_rounds + 1 } else { 0 };
+            last_metrics = Some(metrics);
+            if unchanged_rounds >= run_config.fixed_point_rounds {
+                info!("T count and number of operations unchanged for {} rounds; stopping at this fixed point", unchanged_rounds);
+                needs_more_rounds = false;
+            }
+        }
     }
 
-    let final_stats = optimizer.latest_stats().unwrap();
-    let final_time = std::time::Instant::now();
-    let final_t_gates = final_stats.t_gates;
-    let duration_total = final_time.duration_since(start_time);
-    info!("finished optimizing circuit from {} operations (final T count: {}) after {} rounds, taking {:?} ({:?} pushing T gates forward, {:?} partitioning).", optimizer.initial_circuit_length().unwrap() , final_t_gates, rounds, duration_total, duration_t_forward, duration_partition);
+    Ok(RoundsSummary { rounds, duration_t_forward, duration_partition, profile })
+}
 
-    info!("saving optimized circuit…");
-    optimizer.write_to_output(output)?;
 
-    trace!("done, exiting");
-    Ok(n_qubits)
+fn test_files(in_path: &Path, cmp_path: &Path, big_file: bool) {
+    let cfg = RunConfig {
+        full_partitioning: true,
+        big_file,
+        ..Default::default()
+    };
+    test_files_with_config(in_path, cmp_path, cfg);
 }
 
 
-fn test_files(in_path: &Path, cmp_path: &Path, big_file: bool) {
+fn test_files_with_config(in_path: &Path, cmp_path: &Path, cfg: RunConfig) {
     dbg!(&in_path);
     dbg!(&cmp_path);
     
@@ -668,19 +1640,14 @@ fn test_files(in_path: &Path, cmp_path: &Path, big_file: bool) {
     let mut this_output = String::with_capacity(16384);
     let output = StringOut::new(&mut this_output);
     let n_qubits;
-    let cfg = RunConfig {
-        full_partitioning: true,
-        big_file,
-        ..Default::default()
-    };
     if let Some(_) = in_path.extension().and_then(|ostr| ostr.to_str()).and_then(|ext| COMPRESSION_EXTENSION.get(ext)) {
-        n_qubits = infer_run_txt(GzDecoder::new(in_file), output, cfg).unwrap();
+        n_qubits = infer_run_txt(GzDecoder::new(in_file), output, cfg).unwrap().n_qubits;
     } else {
-        n_qubits = infer_run_txt(in_file, output, cfg).unwrap();
+        n_qubits = infer_run_txt(in_file, output, cfg).unwrap().n_qubits;
     }
 
     let mut tester: Tester<'_, _, Basis128> = Tester::new(cmp, this_output.as_bytes(), n_qubits);
-    tester.test_all();
+    tester.assert_all();
 }
 
 
@@ -712,20 +1679,20 @@ fn test_files_with_basis<B: Basis>(n_qubits: usize, in_path: &Path, cmp_path: &P
 
     if let Some(_) = in_path.extension().and_then(|ostr| ostr.to_str()).and_then(|ext| COMPRESSION_EXTENSION.get(ext)) {
         let tokens = TokenIterator::new(GzDecoder::new(in_file));
-        let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+        let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
         final_output = run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg).unwrap();
     } else {
         let tokens = TokenIterator::new(in_file);
-        let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+        let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
         final_output = run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg).unwrap();
     }
 
-    let n_qubits = final_output;
+    let n_qubits = final_output.n_qubits;
 
     assert!(n_qubits <= 256);
 
     let mut tester: Tester<'_, _, Basis256> = Tester::new(cmp, this_output.as_bytes(), n_qubits);
-    tester.test_all();
+    tester.assert_all();
 }
 
 
@@ -733,10 +1700,32 @@ fn test_files_with_basis<B: Basis>(n_qubits: usize, in_path: &Path, cmp_path: &P
 mod tests {
     use std::path::PathBuf;
 
-    use self::output::Void;
+    use self::output::{tests::QasmHeaderOut, Void};
 
     use super::*;
 
+    // the exact caps only hold where 16GiB fits in a usize
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_max_prealloc_bytes() {
+        fn cap_bytes<B: Basis>() -> u64 {
+            (MAX_PREALLOC_OPERATIONS * mem::size_of::<Operation<B>>()) as u64
+        }
+
+        let max_bytes = MAX_PREALLOC_BYTES.min(isize::MAX as u64);
+        assert_eq!(max_prealloc_operations(1) as u64, max_bytes);
+
+        // the cap is sized for the largest (256-bit) operations: as many as fit, and no more
+        let size_256 = mem::size_of::<Operation<Basis256>>() as u64;
+        assert!(cap_bytes::<Basis256>() <= max_bytes);
+        assert!(cap_bytes::<Basis256>() + size_256 > max_bytes);
+
+        // so buffers of smaller operations stay under the limit too
+        for cap in [cap_bytes::<Basis8>(), cap_bytes::<Basis16>(), cap_bytes::<Basis32>(), cap_bytes::<Basis64>(), cap_bytes::<Basis128>()] {
+            assert!(cap <= cap_bytes::<Basis256>(), "{}", cap);
+        }
+    }
+
     #[test]
     fn test_infer_norepeat() {
         let src = "Rotate 2: IXYZ\nMeasure +: IXYZ";
@@ -765,6 +1754,621 @@ mod tests {
         }).unwrap();
     }
 
+    #[test]
+    fn test_infer_repeat_0() {
+        let src = "Repeat 0\nRotate 2: IXYZ\nEnd\nRotate 1: ZZZZ\nMeasure +: IXYZ\n";
+        let mut out = String::new();
+        _infer_run_txt(src.as_bytes(), StringOut::new(&mut out), RunConfig::default(), |n_qubits, repeat, _| {
+            assert_eq!(n_qubits, 4);
+            assert_eq!(repeat, Some(0));
+        }).unwrap();
+        assert!(out.starts_with("Rotate 1: ZZZZ\nMeasure +: IXYZ\n"), "{}", out);
+        assert!(!out.contains("Rotate 2"), "{}", out);
+    }
+
+    #[test]
+    fn test_infer_lenient() {
+        let src = "Rotate 1: ZZZZ\nRotate 1: ZZ?Z\nMeasure +: IXYZ\n";
+        let cfg = RunConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        _infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg, |n_qubits, _, _| {
+            assert_eq!(n_qubits, 4);
+        }).unwrap();
+        assert!(out.starts_with("Rotate 1: ZZZZ\nMeasure +: IXYZ\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_run_config_builder() {
+        assert_eq!(RunConfig::builder().build(), RunConfig::default());
+
+        let run_config = RunConfig::builder()
+            .full_partitioning(true)
+            .threads(4)
+            .build();
+        assert_eq!(run_config, RunConfig { full_partitioning: true, threads: 4, ..Default::default() });
+    }
+
+    #[test]
+    fn test_validate_equivalence() {
+        let src = fs::read_to_string("./test_circuits/input/p2.txt").unwrap();
+        let optimize = |validate_equivalence| {
+            let cfg = RunConfig {
+                validate_equivalence,
+                ..Default::default()
+            };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            out
+        };
+        let out = optimize(true);
+        assert_eq!(out, optimize(false));
+
+        let mut reference = String::new();
+        infer_run_txt(src.as_bytes(), StringOut::new(&mut reference), RunConfig { big_file: true, ..Default::default() }).unwrap();
+        validate_equivalence::<Basis8>(&reference, &out, 5).unwrap();
+
+        let lines: Vec<_> = out.lines().map(String::from).collect();
+        let first_t = lines.iter().position(|line| line.starts_with("Rotate 1") || line.starts_with("Rotate -1")).unwrap();
+        let flip = |line: &str, from: &str, to: &str| line.replacen(from, to, 1);
+        for corruption in ["flip the sign of the last measurement", "negate a T gate", "drop a T gate"] {
+            let mut corrupted = lines.clone();
+            match corruption {
+                "flip the sign of the last measurement" => {
+                    let last = corrupted.iter_mut().rev().find(|line| line.starts_with("Measure")).unwrap();
+                    *last = if last.contains('+') { flip(last, "+", "-") } else { flip(last, "-", "+") };
+                },
+                "negate a T gate" => {
+                    let t = &mut corrupted[first_t];
+                    *t = if t.starts_with("Rotate 1") { flip(t, "Rotate 1", "Rotate -1") } else { flip(t, "Rotate -1", "Rotate 1") };
+                },
+                _ => {
+                    corrupted.remove(first_t);
+                },
+            }
+            let err = validate_equivalence::<Basis8>(&reference, &(corrupted.join("\n") + "\n"), 5).unwrap_err();
+            assert!(format!("{:#}", err).contains("diverges from the reference"), "{}: {:#}", corruption, err);
+        }
+    }
+
+    #[test]
+    fn test_validate_equivalence_fails_run() {
+        // --strip-trailing-clifford drops the S gate before the final measurement, but the streaming
+        // reference doesn't, so the two diverge (which is why the options conflict)
+        let src = "Rotate 1: ZI\nRotate 2: IZ\nRotate 2: IX\n";
+        let cfg = RunConfig { validate_equivalence: true, strip_trailing_clifford: true, ..Default::default() };
+        let err = infer_run_txt(src.as_bytes(), Void {}, cfg).unwrap_err();
+        assert!(format!("{:#}", err).contains("optimized circuit diverges from the reference optimization"), "{:#}", err);
+
+        let cfg = RunConfig { validate_equivalence: true, ..Default::default() };
+        infer_run_txt(src.as_bytes(), Void {}, cfg).unwrap();
+    }
+
+    #[test]
+    fn test_validate_equivalence_options() {
+        let configs = [
+            RunConfig::default(),
+            RunConfig { reorder_independent: true, global_rotation_merge: true, ..Default::default() },
+            RunConfig { merge_adjacent_measurements: true, ..Default::default() },
+            RunConfig { split_by_repeat: true, approx_passes: 3, ..Default::default() },
+        ];
+        for name in ["p1", "p2", "p3", "p4", "p6", "q100", "min4", "cx", "h", "x", "y", "qasm_test_50"] {
+            let src = fs::read_to_string(format!("./test_circuits/input/{}.txt", name)).unwrap();
+            for cfg in &configs {
+                let cfg = RunConfig { validate_equivalence: true, ..cfg.clone() };
+                infer_run_txt(src.as_bytes(), Void {}, cfg.clone()).unwrap_or_else(|err| panic!("{} with {:?}: {:#}", name, cfg, err));
+            }
+        }
+    }
+
+    #[cfg(feature = "source-tracking")]
+    #[test]
+    fn test_output_source_lines() {
+        let src = fs::read_to_string("./test_circuits/input/p2.txt").unwrap();
+        let input: Vec<&str> = src.lines().collect();
+
+        for big_file in [false, true] {
+            let cfg = RunConfig { big_file, ..Default::default() };
+            let format = FormatOptions { source_lines: true, ..Default::default() };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::with_format(&mut out, format), cfg).unwrap();
+
+            let mut with_source = 0;
+            for line in out.lines() {
+                let Some((op, comment)) = line.split_once("  # line ") else {
+                    // only the measurements the optimizer appends come from nowhere
+                    assert!(line.starts_with("Measure"), "no source line: {}", line);
+                    continue;
+                };
+                with_source += 1;
+                let src_line: usize = comment.parse().unwrap();
+                assert!((1..=input.len()).contains(&src_line), "{}", line);
+
+                // measurements come from measurements, and rotations from (the first of) rotations
+                let kind = op.split_whitespace().next().unwrap();
+                assert!(input[src_line - 1].starts_with(kind), "{} <- {}", line, input[src_line - 1]);
+            }
+            assert!(with_source > 0);
+        }
+    }
+
+    #[test]
+    fn test_malformed_line_mid_stream() {
+        let srcs = [
+            "Rotate 1: ZZZZ\nRotate 1: XXXX\nRotate 1: ZZ?Z\nMeasure +: IXYZ\n",
+            // an orphan End
+            "Rotate 1: ZZZZ\nRotate 1: XXXX\nEnd\nMeasure +: IXYZ\n",
+            // the bad line is read straight after a repeat block
+            "Repeat 1\nRotate 1: ZZZZ\nEnd\nRotate 1: ZZ?Z\n",
+        ];
+        for src in srcs {
+            for (big_file, bypass) in [(false, false), (true, false), (false, true)] {
+                let cfg = RunConfig {
+                    big_file,
+                    bypass,
+                    file_type: InputType::Txt,
+                    ..Default::default()
+                };
+                let err = infer_run(src.as_bytes(), Void {}, cfg).unwrap_err();
+                assert!(format!("{:#}", err).contains("while reading circuit"), "{:?}: {:#}", src, err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_infer_run_qasm_string() {
+        // what main does for `--input STDIN --file-type qasm`, minus the actual STDIN
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\nt q[0];\nh q[1];\ncx q[0],q[1];\nt q[1];\n";
+        let cfg = RunConfig {
+            file_type: InputType::Qasm,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        let n_qubits = infer_run(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap().n_qubits;
+        assert_eq!(n_qubits, 2);
+        assert_eq!(out, "Rotate 1: ZI\nRotate 1: ZX\nMeasure +: ZI\nMeasure +: ZX\n");
+
+        assert!(infer_run(src.as_bytes(), Void {}, RunConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_gate_set() {
+        let gates = [
+            "h q[0]", "t q[0]", "cx q[0],q[1]", "h q[1]", "tdg q[1]", "cx q[2],q[1]", "t q[2]", "h q[3]", "cx q[3],q[0]",
+            "t q[0]", "s q[3]", "h q[2]", "cx q[1],q[3]", "tdg q[3]", "h q[0]", "t q[1]", "cx q[0],q[2]", "t q[2]",
+        ];
+        // the barriers keep the optimizer from folding each decomposition's Cliffords into the
+        // next T gate, which each gate set would do differently (see GateSet)
+        let src = format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[4];\n{};\n", gates.join(";\nbarrier q;\n"));
+        let run = |gate_set, bypass| {
+            let mut out = String::new();
+            let cfg = RunConfig { file_type: InputType::Qasm, gate_set, bypass, drop_barriers_as_partition_hints: true, ..Default::default() };
+            let n_qubits = infer_run(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap().n_qubits;
+            (out, n_qubits)
+        };
+
+        let (standard, n_qubits) = run(GateSet::Standard, false);
+        let (alternate, _) = run(GateSet::Alternate, false);
+        Tester::<_, Basis8>::new(standard.as_bytes(), alternate.as_bytes(), n_qubits).test_all().unwrap();
+
+        // the decompositions themselves differ
+        assert_ne!(run(GateSet::Standard, true).0, run(GateSet::Alternate, true).0);
+    }
+
+    #[test]
+    fn test_retain_input_measurements_order() {
+        // the S gate is pushed through the measurement and the rotations after it; the last three
+        // rotations commute, so the two on IZ merge into another S gate
+        let src = "Rotate 1: ZI\nRotate 2: XI\nMeasure +: ZI\nRotate 1: ZZ\nRotate 1: IZ\nRotate 1: ZI\nRotate 1: IZ\nMeasure +: IZ\n";
+        let expected = "Rotate 1: ZI\nMeasure +: YI\nRotate 1: YZ\nRotate 1: YI\nMeasure +: IZ\n";
+        // without a final measurement, the final Clifford would be lost, so they're appended
+        let unmeasured = src.strip_suffix("Measure +: IZ\n").unwrap();
+        let expected_unmeasured = "Rotate 1: ZI\nMeasure +: YI\nRotate 1: YZ\nRotate 1: YI\nMeasure +: YI\nMeasure +: IZ\n";
+
+        for big_file in [false, true] {
+            for full_partitioning in [false, true] {
+                for (src, expected, measurements) in [(src, expected, 2), (unmeasured, expected_unmeasured, 3)] {
+                    let cfg = RunConfig { retain_input_measurements_order: true, big_file, full_partitioning, ..Default::default() };
+                    let mut out = String::new();
+                    let n_qubits = infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap().n_qubits;
+                    assert_eq!(out.lines().filter(|line| line.starts_with("Measure")).count(), measurements, "{}", out);
+                    Tester::<_, Basis8>::new(expected.as_bytes(), out.as_bytes(), n_qubits).test_all()
+                        .unwrap_or_else(|err| panic!("big_file {} full_partitioning {}: {:#}", big_file, full_partitioning, err));
+                }
+            }
+        }
+
+        // by default the final Z measurements are appended as usual
+        let mut out = String::new();
+        infer_run_txt(src.as_bytes(), StringOut::new(&mut out), RunConfig::default()).unwrap();
+        assert!(out.ends_with("Measure +: IZ\nMeasure +: YI\nMeasure +: IZ\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_canonical_measure() {
+        let src = "Rotate 1: ZII\nMeasure +: IZI\nRotate 1: XII\nRotate 1: IIZ\n";
+        let cfg = RunConfig {
+            canonical_measure: true,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        let n_qubits = infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap().n_qubits;
+
+        // the mid-circuit measurement commutes with both later rotations, so it joins the others
+        let expected = "Rotate 1: ZII\nRotate 1: XII\nRotate 1: IIZ\nMeasure +: IZI\nMeasure +: ZII\nMeasure +: IZI\nMeasure +: IIZ\n";
+        let mut tester: Tester<'_, _, Basis8> = Tester::new(expected.as_bytes(), out.as_bytes(), n_qubits);
+        tester.assert_all();
+    }
+
+    #[test]
+    fn test_strip_trailing_clifford() {
+        // a T gate, then an S gate on the other qubit before it's measured
+        let src = "Rotate 1: ZI\nRotate 2: IZ\nMeasure +: IX\n";
+
+        let mut out = String::new();
+        infer_run_txt(src.as_bytes(), StringOut::new(&mut out), RunConfig::default()).unwrap();
+        assert!(out.contains(": IY\n"), "{}", out);
+
+        let cfg = RunConfig {
+            strip_trailing_clifford: true,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        let n_qubits = infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap().n_qubits;
+
+        // without the S gate, the measurement isn't conjugated
+        let expected = "Rotate 1: ZI\nMeasure +: IX\nMeasure +: ZI\nMeasure +: IZ\n";
+        let mut tester: Tester<'_, _, Basis8> = Tester::new(expected.as_bytes(), out.as_bytes(), n_qubits);
+        tester.assert_all();
+    }
+
+    #[test]
+    fn test_merge_adjacent_measurements_big_file() {
+        // with a read batch of 2, the duplicate measurements are read in separate batches
+        let src = "Rotate 1: ZI\nMeasure +: IX\nMeasure +: IX\nRotate 1: IZ\n";
+
+        let run_with = |cfg: RunConfig| {
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            out
+        };
+
+        let unmerged = run_with(RunConfig::default());
+        let in_memory = run_with(RunConfig {
+            merge_adjacent_measurements: true,
+            ..Default::default()
+        });
+        let big_file = run_with(RunConfig {
+            merge_adjacent_measurements: true,
+            big_file: true,
+            read_batch: 2,
+            write_batch: 1,
+            ..Default::default()
+        });
+
+        assert_eq!(in_memory.matches("Measure +: IX").count(), 1, "{}", in_memory);
+        assert_eq!(in_memory.lines().count(), unmerged.lines().count() - 1);
+        assert_eq!(big_file, in_memory);
+    }
+
+    #[test]
+    fn test_merge_adjacent_measurements_clifford_only() {
+        // no T gates, so the measurements are all that's left after pushing forward
+        let src = "Rotate 2: XI\nMeasure +: ZX\nMeasure +: ZX\nRotate 2: ZZ\n";
+
+        for big_file in [false, true] {
+            let cfg = RunConfig {
+                merge_adjacent_measurements: true,
+                big_file,
+                ..Default::default()
+            };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            assert_eq!(out.matches("Measure +: YX").count(), 1, "big file: {}\n{}", big_file, out);
+        }
+    }
+
+    #[test]
+    fn test_output_begin_end() {
+        let src = "Rotate 1: ZII\nRotate 2: IXI\nMeasure +: IIZ\n";
+        for (big_file, bypass) in [(false, false), (true, false), (false, true)] {
+            let cfg = RunConfig {
+                big_file,
+                bypass,
+                ..Default::default()
+            };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), QasmHeaderOut(&mut out), cfg).unwrap();
+
+            assert!(out.starts_with("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\n// "), "{}", out);
+            assert_eq!(out.matches("OPENQASM").count(), 1);
+            assert!(out.ends_with("\n// end\n"), "{}", out);
+        }
+    }
+
+    #[test]
+    fn test_infer_qasm_phase_tracking() {
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nh q[0];\n";
+        let cfg = RunConfig { file_type: InputType::Qasm, phase_tracking: true, ..Default::default() };
+        let err = infer_run(src.as_bytes(), Void {}, cfg).unwrap_err();
+        assert!(err.to_string().contains("--phase-tracking is not supported for QASM input"), "{}", err);
+    }
+
+    #[test]
+    fn test_infer_qasm_gate_before_qreg() {
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nt q[0];\nqreg q[2];\n";
+        let err = infer_run_qasm(src.as_bytes(), Void {}, RunConfig::default()).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("line 3"), "{}", msg);
+        assert!(msg.contains("T"), "{}", msg);
+        assert!(msg.contains("qreg"), "{}", msg);
+    }
+
+    #[test]
+    fn test_infer_qasm_strict_bad_header() {
+        let cfg = RunConfig { strict: true, ..Default::default() };
+        let err = |src: &str| format!("{:#}", infer_run_qasm(src.as_bytes(), Void {}, cfg.clone()).unwrap_err());
+
+        let msg = err("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nh r[0];\nqreg q[2];\n");
+        assert!(msg.contains("Undeclared quantum register 'r' on line 3"), "{}", msg);
+        let msg = err("OPENQASM 2.0;\nnot qasm\nqreg q[2];\n");
+        assert!(msg.contains("Did not recognize line 2"), "{}", msg);
+        assert!(err("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n").contains("missing qreg declaration"));
+    }
+
+    #[test]
+    fn test_barriers_as_partition_hints() {
+        // the T gates on each qubit commute, and merge into S gates unless the barrier keeps them apart
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\nt q[0];\nh q[1];\nt q[1];\nbarrier q[0], q[1];\nt q[0];\nt q[1];\nh q[1];\n";
+        let t_count = |out: &str| out.lines().filter(|line| line.starts_with("Rotate 1") || line.starts_with("Rotate -1")).count();
+
+        for (big_file, full_partitioning) in [(false, false), (false, true), (true, false)] {
+            let optimize = |hints| {
+                let cfg = RunConfig { big_file, full_partitioning, drop_barriers_as_partition_hints: hints, ..Default::default() };
+                let mut out = String::new();
+                infer_run_qasm(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+                out
+            };
+
+            assert_eq!(t_count(&optimize(false)), 0, "big_file: {}, full_partitioning: {}", big_file, full_partitioning);
+            let out = optimize(true);
+            assert_eq!(t_count(&out), 4, "big_file: {}, full_partitioning: {}\n{}", big_file, full_partitioning, out);
+            // the barrier itself isn't written
+            assert!(out.lines().all(|line| line.starts_with("Rotate") || line.starts_with("Measure")), "{}", out);
+        }
+    }
+
+    #[test]
+    fn test_dry_run_parse_conflicts() {
+        let parse = |extra: &[&str]| CommandLineArgs::try_parse_from(["qarrot-optimizer", "-i", "in.txt", "-o", "out.txt", "--dry-run-parse"].iter().chain(extra));
+        assert!(parse(&[]).is_ok());
+        // a dry run stops before any of these would be checked
+        for extra in [&["--assert-t-count", "3"][..], &["--assert-max-t-count", "3"], &["--phase-tracking"]] {
+            assert!(parse(extra).is_err(), "{:?}", extra);
+        }
+    }
+
+    #[test]
+    fn test_big_file_conflicts() {
+        let parse = |extra: &[&str]| CommandLineArgs::try_parse_from(["qarrot-optimizer", "-i", "in.txt", "-o", "out.txt"].iter().chain(extra));
+        for flag in ["--abort-on-growth", "--canonical-measure", "--strip-trailing-clifford", "--count-distinct-paulis"] {
+            assert!(parse(&[flag]).is_ok(), "{}", flag);
+            assert!(parse(&[flag, "--big-file"]).is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn test_t_depth_only() {
+        let src = fs::read_to_string("./test_circuits/input/p2.txt").unwrap();
+        for full_partitioning in [false, true] {
+            // the full run's T-layers, as marked in its output
+            let cfg = RunConfig { full_partitioning, group_output_by_layer: true, ..Default::default() };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            let layers = out.lines().filter(|line| line.starts_with("# layer")).count();
+            assert!(layers > 0);
+
+            let cfg = RunConfig { full_partitioning, t_depth_only: true, ..Default::default() };
+            let parser = InstructionIterator::<_, Basis8>::new(5, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+            let mut optimizer = InMemoryOptimizer::<Basis8>::new(5, parser, &cfg).unwrap();
+            optimization_rounds::<_, InstructionIterator<&[u8], _>, _>(&mut optimizer, &cfg).unwrap();
+            assert_eq!(Optimizer::<_, InstructionIterator<&[u8], _>>::t_depth(&optimizer), Some(layers), "full_partitioning: {}", full_partitioning);
+
+            // nothing is written; the T-depth and T count are returned instead
+            let mut out = String::new();
+            let summary = infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            assert_eq!(out, "");
+            let t_count = Optimizer::<_, InstructionIterator<&[u8], _>>::latest_stats(&optimizer).unwrap().t_gates;
+            assert_eq!(summary, RunSummary { n_qubits: 5, t_depth: Some(TDepth { t_depth: layers, t_count }) }, "full_partitioning: {}", full_partitioning);
+        }
+
+        let cfg = RunConfig { big_file: true, t_depth_only: true, ..Default::default() };
+        assert!(infer_run_txt(src.as_bytes(), Void {}, cfg).is_err());
+    }
+
+    #[test]
+    fn test_group_output_by_layer() {
+        // p3 optimizes away all its T gates, so there's nothing to separate
+        for (name, n_qubits, expected_layers) in [("p3", 4, 0), ("p6", 2, 11), ("q100", 7, 2)] {
+            let src = fs::read_to_string(format!("./test_circuits/input/{}.txt", name)).unwrap();
+            let parse = |line: &str| {
+                let mut tokens = Vec::new();
+                TokenIterator::new(line.as_bytes()).pop_line(&mut tokens).unwrap();
+                Operation::<Basis8>::try_from_tokens(n_qubits, &tokens).unwrap()
+            };
+
+            for full_partitioning in [false, true] {
+                let optimize = |group_output_by_layer| {
+                    let cfg = RunConfig { full_partitioning, group_output_by_layer, ..Default::default() };
+                    let mut out = String::new();
+                    infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+                    out
+                };
+                let plain = optimize(false);
+                let grouped = optimize(true);
+
+                // the comments are the only difference
+                let stripped: String = grouped.lines().filter(|line| !line.starts_with('#')).map(|line| format!("{}\n", line)).collect();
+                assert_eq!(stripped, plain);
+                if expected_layers == 0 {
+                    assert_eq!(grouped, plain);
+                    continue;
+                }
+
+                let mut groups: Vec<(&str, Vec<Operation<Basis8>>)> = Vec::new();
+                for line in grouped.lines() {
+                    match line.strip_prefix("# ") {
+                        Some(comment) => groups.push((comment, Vec::new())),
+                        None => groups.last_mut().expect("output starts with a comment").1.push(parse(line)),
+                    }
+                }
+
+                let mut layers = 0;
+                let mut prev_layer: Option<&[Operation<Basis8>]> = None;
+                for (comment, ops) in &groups {
+                    assert!(!ops.is_empty(), "empty group {:?}\n{}", comment, grouped);
+                    if *comment == "measurements" {
+                        assert!(ops.iter().all(|op| op.is_measurement()), "{}", grouped);
+                        prev_layer = None;
+                        continue;
+                    }
+                    if *comment == "clifford" {
+                        assert!(ops.iter().all(|op| op.as_rotation().is_some_and(|rotation| !rotation.angle.is_pi8())), "{}", grouped);
+                        prev_layer = Some(ops);
+                        continue;
+                    }
+
+                    layers += 1;
+                    assert_eq!(*comment, format!("layer {}", layers));
+                    assert!(ops.iter().all(|op| op.is_rotation()));
+                    for (i, op) in ops.iter().enumerate() {
+                        assert!(ops[..i].iter().all(|prev| op.commutes_with(prev)), "layer {} doesn't commute\n{}", layers, grouped);
+                    }
+                    // each separator is needed: the layer's first rotation can't join the one before
+                    if let Some(prev_layer) = prev_layer {
+                        assert!(prev_layer.iter().any(|prev| !ops[0].commutes_with(prev)), "layer {} could be merged\n{}", layers, grouped);
+                    }
+                    prev_layer = Some(ops);
+                }
+                assert_eq!(layers, expected_layers, "{}: {}", name, grouped);
+                assert_eq!(groups.last().unwrap().0, "measurements");
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let path = Path::new("./test_circuits/input/q500.txt");
+        let open_input = |_: &mut RunConfig| Input::new(path);
+        let dir = tempfile::tempdir().unwrap();
+        let out_path = dir.path().join("out.txt");
+        let open_output = || Ok(WriteOutput::new(fs::File::create(&out_path)?));
+
+        let mut in_memory = String::new();
+        let n_qubits = infer_run_txt(Input::new(path).unwrap(), StringOut::new(&mut in_memory), RunConfig::default()).unwrap().n_qubits;
+
+        // a 1 byte limit can't even hold the preallocated buffer
+        let cfg = RunConfig { file_type: InputType::Txt, memory_limit: Some(1), ..Default::default() };
+        let err = infer_run(Input::new(path).unwrap(), Void {}, cfg.clone()).unwrap_err();
+        assert_eq!(err.downcast_ref::<MemoryLimitExceeded>(), Some(&MemoryLimitExceeded { limit: 1 }));
+
+        assert_eq!(infer_run_file(open_input, open_output, cfg).unwrap().n_qubits, n_qubits);
+        let big_file = fs::read_to_string(&out_path).unwrap();
+        let mut reference = String::new();
+        infer_run_txt(Input::new(path).unwrap(), StringOut::new(&mut reference), RunConfig { big_file: true, ..Default::default() }).unwrap();
+        assert_eq!(big_file, reference);
+        Tester::<_, Basis16>::new(in_memory.as_bytes(), big_file.as_bytes(), n_qubits).test_all().unwrap();
+
+        // a roomy limit doesn't restart
+        let cfg = RunConfig { file_type: InputType::Txt, memory_limit: Some(1 << 30), ..Default::default() };
+        infer_run_file(open_input, open_output, cfg).unwrap();
+        assert_eq!(fs::read_to_string(&out_path).unwrap(), in_memory);
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn test_bzip2_input() {
+        use std::io::Write;
+
+        let src = fs::read("./test_circuits/input/p1.txt").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("p1.txt.bz2");
+        let mut encoder = bzip2::write::BzEncoder::new(fs::File::create(&path).unwrap(), bzip2::Compression::best());
+        encoder.write_all(&src).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(COMPRESSION_EXTENSION.get("bz2"), Some(&CompressionAlgorithm::Bzip2));
+
+        let mut decompressed = Vec::new();
+        Input::new_bzip2(&path).unwrap().read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, src);
+
+        let mut expected = String::new();
+        infer_run_txt(src.as_slice(), StringOut::new(&mut expected), RunConfig::default()).unwrap();
+        let mut out = String::new();
+        infer_run_txt(Input::new_bzip2(&path).unwrap(), StringOut::new(&mut out), RunConfig::default()).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    /// Hands out at most 3 bytes per read, like a pipe that's being written to slowly.
+    #[derive(Debug)]
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_stdin_chunk_size() {
+        let src = fs::read("./test_circuits/input/p2.txt").unwrap();
+        let mut expected = String::new();
+        let n_qubits = infer_run_txt(src.as_slice(), StringOut::new(&mut expected), RunConfig::default()).unwrap().n_qubits;
+
+        for chunk_size in [1, 2, 7, 4096, DEFAULT_STDIN_CHUNK_SIZE] {
+            let mut out = String::new();
+            let piped = io::BufReader::with_capacity(chunk_size, ShortReads(&src));
+            assert_eq!(infer_run_txt(piped, StringOut::new(&mut out), RunConfig::default()).unwrap().n_qubits, n_qubits);
+            assert_eq!(out, expected, "chunk size {}", chunk_size);
+        }
+
+        let Input::Stdin(stdin) = Input::stdin(123).unwrap() else { panic!("not STDIN") };
+        assert_eq!(stdin.capacity(), 123);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let src = fs::read_to_string("./test_circuits/input/p1.txt").unwrap();
+
+        let mut text = String::new();
+        let n_qubits = infer_run_txt(src.as_bytes(), StringOut::new(&mut text), RunConfig::default()).unwrap().n_qubits;
+
+        // e.g. `Rotate -1: ZIX` and `Measure +: IZI`, as JSON objects
+        let objects: Vec<_> = text.lines().map(|line| {
+            let (op, paulis) = line.split_once(": ").unwrap();
+            let paulis: Vec<_> = paulis.chars().map(String::from).collect();
+            match op.split_once(' ').unwrap() {
+                ("Rotate", angle) => serde_json::json!({"kind": "rotation", "angle": angle.parse::<i8>().unwrap(), "paulis": paulis}),
+                ("Measure", phase) => serde_json::json!({"kind": "measurement", "phase": phase, "paulis": paulis}),
+                _ => panic!("unexpected line {:?}", line),
+            }
+        }).collect();
+        let json = serde_json::to_string_pretty(&objects).unwrap();
+
+        // read the JSON back without optimizing again
+        let mut round_trip = String::new();
+        let cfg = RunConfig { file_type: InputType::Json, bypass: true, ..Default::default() };
+        assert_eq!(infer_run(json.as_bytes(), StringOut::new(&mut round_trip), cfg).unwrap().n_qubits, n_qubits);
+
+        assert_eq!(text, round_trip);
+        Tester::<_, Basis8>::new(text.as_bytes(), round_trip.as_bytes(), n_qubits).test_all().unwrap();
+    }
+
     fn test_files_qasm(in_path: &Path, cmp_path: &Path, big_file: bool) {
         dbg!(&in_path);
         dbg!(&cmp_path);
@@ -788,10 +2392,10 @@ mod tests {
             ..Default::default()
         };
 
-        n_qubits = infer_run_qasm(in_file, output, cfg).unwrap();
+        n_qubits = infer_run_qasm(in_file, output, cfg).unwrap().n_qubits;
 
         let mut tester: Tester<'_, _, Basis128> = Tester::new(cmp, this_output.as_bytes(), n_qubits);
-        tester.test_all();
+        tester.assert_all();
     }
 
     fn test_file_qasm(filename: &str) {
@@ -845,11 +2449,11 @@ mod tests {
     
         if let Some(_) = in_path.extension().and_then(|ostr| ostr.to_str()).and_then(|ext| COMPRESSION_EXTENSION.get(ext)) {
             let tokens = TokenIterator::new(GzDecoder::new(src.as_bytes()));
-            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
             run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg).unwrap();
         } else {
             let tokens = TokenIterator::new(src.as_bytes());
-            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
             run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg).unwrap();
         }
 
@@ -866,16 +2470,16 @@ mod tests {
 
         if let Some(_) = in_path.extension().and_then(|ostr| ostr.to_str()).and_then(|ext| COMPRESSION_EXTENSION.get(ext)) {
             let tokens = TokenIterator::new(GzDecoder::new(src.as_bytes()));
-            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
             run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg).unwrap();
         } else {
             let tokens = TokenIterator::new(src.as_bytes());
-            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.target_buffer_length, cfg.shrink_buffer_after_repeat);
+            let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, cfg.read_batch, cfg.shrink_buffer_after_repeat);
             run::<B, _, InMemoryOptimizer<_>>(output, parser, n_qubits, cfg,).unwrap();
         }
 
         let mut tester: Tester<'_, _, Basis256> = Tester::new(in_mem_output.as_bytes(), big_file_output.as_bytes(), n_qubits);
-        tester.test_all();
+        tester.assert_all();
 
     }
 
@@ -996,11 +2600,151 @@ mod tests {
         test_bigfile::<Basis256>("q100.txt", 7);
     }
 
+    #[test]
+    fn test_iterations() {
+        let src = fs::read_to_string("./test_circuits/input/q100.txt").unwrap();
+        let run_iterations = |iterations| {
+            let cfg = RunConfig {
+                iterations,
+                ..Default::default()
+            };
+            let mut out = String::new();
+            let parser = InstructionIterator::<_, Basis8>::new(7, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+            let (_, timings) = run_with_timings::<Basis8, _, InMemoryOptimizer<_>>(StringOut::new(&mut out), parser, 7, cfg).unwrap();
+            (out, timings)
+        };
+
+        let (once, timings) = run_iterations(1);
+        assert_eq!(timings.len(), 1);
+
+        let (out, timings) = run_iterations(3);
+        assert_eq!(timings.len(), 3);
+        assert!(timings.min() <= timings.median() && timings.median() <= *timings.samples().iter().max().unwrap());
+        // only the last iteration is written, and it's the same as a single run
+        assert_eq!(out, once);
+    }
+
+    #[test]
+    fn test_only_rounds() {
+        let src = fs::read_to_string("./test_circuits/input/p3.txt").unwrap();
+        let rounds_with = |only_rounds| {
+            let cfg = RunConfig {
+                only_rounds,
+                ..Default::default()
+            };
+            let parser = InstructionIterator::<_, Basis8>::new(4, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+            let mut optimizer = InMemoryOptimizer::<Basis8>::new(4, parser, &cfg).unwrap();
+            optimization_rounds::<_, InstructionIterator<&[u8], _>, _>(&mut optimizer, &cfg).unwrap()
+        };
+
+        let summary = rounds_with(Some(2));
+        assert_eq!(summary.rounds, 2);
+        assert_eq!(summary.profile.records().iter().filter(|record| record.phase == RoundPhase::Partition).count(), 2);
+
+        // more rounds than it takes to converge are still all run
+        let converged = rounds_with(None).rounds;
+        assert_eq!(rounds_with(Some(converged + 3)).rounds, converged + 3);
+    }
+
+    /// Claims every round changed the circuit, without ever changing its stats.
+    #[derive(Debug)]
+    struct NeverSettles {
+        rounds: usize,
+    }
+
+    impl NeverSettles {
+        fn stats() -> Stats {
+            Stats { total_operations: 10, rotations_only: 8, t_gates: 4, clifford_gates: 0 }
+        }
+    }
+
+    impl Optimizer<Basis8, std::iter::Empty<anyhow::Result<Operation<Basis8>>>> for NeverSettles {
+        fn new(_: usize, _: std::iter::Empty<anyhow::Result<Operation<Basis8>>>, _: &RunConfig) -> anyhow::Result<Self> {
+            Ok(Self { rounds: 0 })
+        }
+        fn initial_circuit_length(&self) -> Option<usize> { None }
+        fn post_reduction_length(&self) -> Option<usize> { None }
+        fn latest_stats(&self) -> Option<Stats> { Some(Self::stats()) }
+        fn current_heap_usage(&self) -> (usize, usize) { (0, 0) }
+        fn push_t_forward(&mut self) -> anyhow::Result<(bool, Stats)> {
+            Ok((true, Self::stats()))
+        }
+        fn partition(&mut self) -> anyhow::Result<(bool, Stats)> {
+            self.rounds += 1;
+            assert!(self.rounds < 100, "didn't stop at the fixed point");
+            Ok((true, Self::stats()))
+        }
+        fn write_to_output(self, _: impl Output) -> anyhow::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_fixed_point_rounds() {
+        for (fixed_point_rounds, expected) in [(1, 2), (DEFAULT_FIXED_POINT_ROUNDS, 3), (5, 6)] {
+            let cfg = RunConfig { fixed_point_rounds, ..Default::default() };
+            let mut optimizer = NeverSettles::new(1, std::iter::empty(), &cfg).unwrap();
+            assert_eq!(optimization_rounds(&mut optimizer, &cfg).unwrap().rounds, expected);
+        }
+
+        // a circuit that converges normally isn't affected
+        let src = fs::read_to_string("./test_circuits/input/p6.txt").unwrap();
+        let rounds_with = |fixed_point_rounds| {
+            let cfg = RunConfig { fixed_point_rounds, ..Default::default() };
+            let parser = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+            let mut optimizer = InMemoryOptimizer::<Basis8>::new(2, parser, &cfg).unwrap();
+            optimization_rounds::<_, InstructionIterator<&[u8], _>, _>(&mut optimizer, &cfg).unwrap().rounds
+        };
+        assert_eq!(rounds_with(DEFAULT_FIXED_POINT_ROUNDS), rounds_with(0));
+    }
+
+    #[test]
+    fn test_100_lines_small_batches() {
+        let src = fs::read_to_string("./test_circuits/input/q100.txt").unwrap();
+
+        let mut in_mem_output = String::new();
+        let cfg = RunConfig::default();
+        let parser = InstructionIterator::<_, Basis8>::new(7, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+        run::<Basis8, _, InMemoryOptimizer<_>>(StringOut::new(&mut in_mem_output), parser, 7, cfg).unwrap();
+
+        // batches much smaller than the circuit, and not dividing each other
+        let mut big_file_output = String::new();
+        let cfg = RunConfig {
+            big_file: true,
+            read_batch: 7,
+            write_batch: 3,
+            ..Default::default()
+        };
+        let parser = InstructionIterator::<_, Basis8>::new(7, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+        run::<Basis8, _, FileOptimizer<_, _>>(StringOut::new(&mut big_file_output), parser, 7, cfg).unwrap();
+
+        let mut tester: Tester<'_, _, Basis256> = Tester::new(in_mem_output.as_bytes(), big_file_output.as_bytes(), 7);
+        tester.assert_all();
+    }
+
     #[test]
     fn test_500_lines() {
         test_file("q500.txt");
     }
 
+    #[test]
+    fn test_5000_lines_threads() {
+        // enough partitions in the first round for the layers to be reduced in parallel (q500 only has 8)
+        let src = fs::read_to_string("./test_circuits/input/qasm_test_5000.txt").unwrap();
+        let mut circuit = InstructionIterator::<_, Basis16>::new(15, TokenIterator::new(src.as_bytes()), 1024, false)
+            .collect::<anyhow::Result<Vec<_>>>().unwrap();
+        let (_, t_gate_count) = push_t_forward_inplace(&mut circuit, 15, &mut operation::phase::GlobalPhase::default());
+        let mut partitions = optimization::partitions::Partitions::new();
+        partitions.init_one_per_t_gate(t_gate_count);
+        update_t_gate_partitions(&circuit, &mut partitions, None);
+        assert!(partitions.len() >= MIN_PARALLEL_PARTITIONS, "{}", partitions.len());
+
+        let cfg = RunConfig {
+            full_partitioning: true,
+            threads: 4,
+            ..Default::default()
+        };
+        test_files_with_config(Path::new("./test_circuits/input/qasm_test_5000.txt"), Path::new("./test_circuits/expected/qasm_test_5000.txt"), cfg);
+    }
+
     #[test]
     fn test_500_lines_bigfile() {
         test_bigfile::<Basis16>("q500.txt", 15);
@@ -1077,6 +2821,26 @@ mod tests {
         test_file_with_basis::<DBasis>("p2.txt", 5);
     }
 
+    #[test]
+    fn test_p2_reorder_independent() {
+        let cfg = RunConfig {
+            full_partitioning: true,
+            reorder_independent: true,
+            ..Default::default()
+        };
+        test_files_with_config(Path::new("./test_circuits/input/p2.txt"), Path::new("./test_circuits/expected/p2.txt"), cfg);
+    }
+
+    #[test]
+    fn test_p2_reorder_independent_bigfile() {
+        let cfg = RunConfig {
+            big_file: true,
+            reorder_independent: true,
+            ..Default::default()
+        };
+        test_files_with_config(Path::new("./test_circuits/input/p2.txt"), Path::new("./test_circuits/expected/p2.txt"), cfg);
+    }
+
     #[test]
     fn test_p2c() {
         test_file("p2c.txt");
@@ -1117,6 +2881,207 @@ mod tests {
         test_file("p3.txt");
     }
 
+    #[test]
+    fn test_p3_profile() {
+        let profile_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let cfg = RunConfig {
+            profile: Some(profile_path.to_path_buf()),
+            ..Default::default()
+        };
+        test_files_with_config(Path::new("./test_circuits/input/p3.txt"), Path::new("./test_circuits/expected/p3.txt"), cfg);
+
+        let csv = fs::read_to_string(&profile_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("round,phase,milliseconds,ops,t_gates"));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert!(!rows.is_empty());
+        assert_eq!(rows.len() % 2, 0);
+        for (i, pair) in rows.chunks(2).enumerate() {
+            let round = (i + 1).to_string();
+            assert_eq!(pair[0][..2], [round.as_str(), "t_forward"]);
+            assert_eq!(pair[1][..2], [round.as_str(), "partition"]);
+            assert!(pair.iter().all(|row| row.len() == 5));
+        }
+    }
+
+    #[test]
+    fn test_p3_rounds_csv() {
+        let src = fs::read_to_string("./test_circuits/input/p3.txt").unwrap();
+        let cfg = RunConfig::default();
+        let parser = InstructionIterator::<_, Basis8>::new(4, TokenIterator::new(src.as_bytes()), cfg.read_batch, cfg.shrink_buffer_after_repeat);
+        let mut optimizer = InMemoryOptimizer::<Basis8>::new(4, parser, &cfg).unwrap();
+        let summary = optimization_rounds::<_, InstructionIterator<&[u8], _>, _>(&mut optimizer, &cfg).unwrap();
+
+        let csv_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let cfg = RunConfig { rounds_csv: Some(csv_path.to_path_buf()), ..Default::default() };
+        infer_run_txt(src.as_bytes(), Void {}, cfg).unwrap();
+
+        let csv = fs::read_to_string(&csv_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("round,ops_after_push,t_after_push,ops_after_partition,t_after_partition"));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), summary.rounds);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(row[0], (i + 1).to_string());
+            assert!(row[1..].iter().all(|count| count.parse::<usize>().is_ok()), "{:?}", row);
+        }
+
+        let partitions: Vec<_> = summary.profile.records().iter().filter(|record| record.phase == RoundPhase::Partition).collect();
+        assert_eq!(rows.last().unwrap()[4], partitions.last().unwrap().stats.t_gates.to_string());
+    }
+
+    #[test]
+    fn test_max_qubits() {
+        let run_with = |max_qubits, min_qubits| {
+            let cfg = RunConfig { max_qubits, min_qubits, ..Default::default() };
+            let txt = infer_run_txt(fs::File::open("./test_circuits/input/p1.txt").unwrap(), Void {}, cfg.clone());
+            let qasm = infer_run_qasm("OPENQASM 2.0;\nqreg q[3];\nt q[0];\n".as_bytes(), Void {}, cfg);
+            (txt, qasm)
+        };
+
+        // both are 3 qubits wide
+        for max_qubits in [None, Some(3), Some(4)] {
+            let (txt, qasm) = run_with(max_qubits, 0);
+            assert_eq!(txt.unwrap().n_qubits, 3);
+            assert_eq!(qasm.unwrap().n_qubits, 3);
+        }
+        for (max_qubits, min_qubits) in [(Some(2), 0), (Some(4), 8)] {
+            let (txt, qasm) = run_with(max_qubits, min_qubits);
+            for err in [txt.unwrap_err(), qasm.unwrap_err()] {
+                assert!(err.to_string().contains("--max-qubits"), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_operations() {
+        // 2 + 1000 * 3 + 1 operations once the repeat is unrolled
+        let src = "Rotate 1: ZI\nRotate 1: IX\nRepeat 1000\nRotate 1: ZZ\nRotate 1: XI\nRotate 1: IZ\nEnd\nRotate 1: XX\n";
+        for big_file in [false, true] {
+            let run_with = |max_operations| {
+                let cfg = RunConfig { big_file, max_operations, read_batch: 64, ..Default::default() };
+                infer_run_txt(src.as_bytes(), Void {}, cfg)
+            };
+
+            assert_eq!(run_with(None).unwrap().n_qubits, 2);
+            assert_eq!(run_with(Some(3003)).unwrap().n_qubits, 2);
+            for max_operations in [3002, 100] {
+                let err = run_with(Some(max_operations)).unwrap_err();
+                assert!(format!("{:#}", err).contains("--max-operations"), "big_file: {}, {:#}", big_file, err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_already_measured() {
+        let src = fs::read_to_string("./test_circuits/input/p2.txt").unwrap();
+        let measurements = |out: &str| out.lines().filter(|line| line.starts_with("Measure")).count();
+
+        for big_file in [false, true] {
+            let mut first = String::new();
+            let n_qubits = infer_run_txt(src.as_bytes(), StringOut::new(&mut first), RunConfig { big_file, ..Default::default() }).unwrap().n_qubits;
+            assert_eq!(measurements(&first), n_qubits);
+
+            // optimize the output again, more thoroughly
+            let second_stage = |already_measured| {
+                let mut out = String::new();
+                let cfg = RunConfig { big_file, full_partitioning: true, already_measured, read_batch: 16, ..Default::default() };
+                infer_run_txt(first.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+                out
+            };
+            let second = second_stage(true);
+            assert_eq!(measurements(&second), n_qubits, "big_file: {}", big_file);
+            assert!(second.lines().count() <= first.lines().count(), "big_file: {}", big_file);
+            assert_eq!(measurements(&second_stage(false)), 2 * n_qubits, "big_file: {}", big_file);
+
+            // nothing to resume from, so the measurements are still appended
+            let mut out = String::new();
+            let cfg = RunConfig { big_file, already_measured: true, ..Default::default() };
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            assert_eq!(out, first);
+        }
+    }
+
+    #[test]
+    fn test_assert_t_count() {
+        let run_with = |filename: &str, assert_t_count, assert_max_t_count| {
+            let cfg = RunConfig { assert_t_count, assert_max_t_count, ..Default::default() };
+            infer_run_txt(fs::File::open(Path::new("./test_circuits/input").join(filename)).unwrap(), Void {}, cfg)
+        };
+
+        // p1 optimizes down to no T gates, p2 to 8
+        run_with("p1.txt", Some(0), Some(0)).unwrap();
+        run_with("p2.txt", Some(8), Some(8)).unwrap();
+        run_with("p2.txt", None, Some(100)).unwrap();
+
+        let err = run_with("p1.txt", Some(3), None).unwrap_err();
+        assert!(format!("{:#}", err).contains("has 0 T gates, but --assert-t-count expected 3"), "{:#}", err);
+        let err = run_with("p2.txt", None, Some(7)).unwrap_err();
+        assert!(format!("{:#}", err).contains("has 8 T gates, more than the maximum of 7"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_p1_min_qubits() {
+        let cfg = RunConfig {
+            min_qubits: 8,
+            ..Default::default()
+        };
+        let in_file = fs::File::open("./test_circuits/input/p1.txt").unwrap();
+        let mut out = String::new();
+        let n_qubits = infer_run_txt(in_file, StringOut::new(&mut out), cfg).unwrap().n_qubits;
+        assert_eq!(n_qubits, 8);
+
+        for line in out.lines() {
+            let paulis = line.rsplit(' ').next().unwrap();
+            assert_eq!(paulis.len(), 8, "{}", line);
+            assert!(paulis.ends_with("IIIII") || line.starts_with("Measure"), "{}", line);
+        }
+        let measurements: Vec<_> = out.lines().filter(|line| line.starts_with("Measure")).collect();
+        assert_eq!(measurements.len(), 8);
+    }
+
+    /// Run `filename` from the test circuits with `cfg` (writing the profile to a temporary file) and
+    /// return the profile's rows, without the header.
+    fn profile_rows(filename: &str, cfg: RunConfig) -> Vec<String> {
+        let profile_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let cfg = RunConfig {
+            profile: Some(profile_path.to_path_buf()),
+            ..cfg
+        };
+        test_files_with_config(&Path::new("./test_circuits/input").join(filename), &Path::new("./test_circuits/expected").join(filename), cfg);
+
+        let csv = fs::read_to_string(&profile_path).unwrap();
+        csv.lines().skip(1).map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_abort_on_growth_never_triggers() {
+        for filename in ["p1.txt", "p2.txt", "p3.txt", "p4.txt"] {
+            let cfg = RunConfig {
+                full_partitioning: true,
+                abort_on_growth: true,
+                ..Default::default()
+            };
+
+            // the operation count after each round never increases
+            let counts: Vec<usize> = profile_rows(filename, cfg).iter()
+                .map(|line| line.split(',').collect::<Vec<_>>())
+                .filter(|row| row[1] == "partition")
+                .map(|row| row[3].parse().unwrap())
+                .collect();
+            assert!(counts.windows(2).all(|w| w[1] <= w[0]), "{}: {:?}", filename, counts);
+        }
+    }
+
+    #[test]
+    fn test_clifford_only_single_round() {
+        for filename in ["h.txt", "x.txt"] {
+            let rows = profile_rows(filename, RunConfig::default());
+            assert_eq!(rows.len(), 1, "{}: {:?}", filename, rows);
+            assert!(rows[0].starts_with("1,t_forward,"), "{}: {:?}", filename, rows);
+        }
+    }
+
     #[test]
     fn test_p_3_16() {
         test_file_with_basis::<Basis16>("p3.txt", 4);
@@ -1157,6 +3122,44 @@ mod tests {
         test_file("p6.txt");
     }
 
+    #[test]
+    fn test_p6_global_rotation_merge() {
+        let t_count = |out: &str| out.lines().filter(|line| line.starts_with("Rotate 1") || line.starts_with("Rotate -1")).count();
+        let optimize = |big_file, global_rotation_merge| {
+            let src = fs::File::open("./test_circuits/input/p6.txt").unwrap();
+            let cfg = RunConfig { big_file, global_rotation_merge, ..Default::default() };
+            let mut out = String::new();
+            infer_run_txt(src, StringOut::new(&mut out), cfg).unwrap();
+            out
+        };
+
+        for big_file in [false, true] {
+            let (baseline, merged) = (optimize(big_file, false), optimize(big_file, true));
+            assert!(t_count(&merged) <= t_count(&baseline), "big_file: {}, {} > {}", big_file, t_count(&merged), t_count(&baseline));
+
+            let cfg = RunConfig { big_file, global_rotation_merge: true, ..Default::default() };
+            test_files_with_config(Path::new("./test_circuits/input/p6.txt"), Path::new("./test_circuits/expected/p6.txt"), cfg);
+        }
+    }
+
+    #[test]
+    fn test_global_rotation_merge_t_count() {
+        // ZI commutes with IZ and IX, which don't commute with each other, so the partitioning
+        // leaves the two ZI rotations in different layers, but the merge brings them together
+        let src = "Rotate 1: ZI\nRotate 1: IZ\nRotate 1: IX\nRotate 1: ZI\n";
+        let t_count = |out: &str| out.lines().filter(|line| line.starts_with("Rotate 1") || line.starts_with("Rotate -1")).count();
+        let optimize = |big_file, global_rotation_merge| {
+            let cfg = RunConfig { big_file, global_rotation_merge, validate_equivalence: !big_file, ..Default::default() };
+            let mut out = String::new();
+            infer_run_txt(src.as_bytes(), StringOut::new(&mut out), cfg).unwrap();
+            t_count(&out)
+        };
+
+        for big_file in [false, true] {
+            assert_eq!((optimize(big_file, false), optimize(big_file, true)), (4, 2), "big_file: {}", big_file);
+        }
+    }
+
     #[test]
     fn test_1000_lines() {
         test_file("qasm_test_1000.txt");
@@ -1,4 +1,8 @@
-use crate::{basis::Basis, output::fmt_operation};
+use std::fmt;
+
+use anyhow::bail;
+
+use crate::{basis::{pauli_string, Basis}, clifford::Clifford, input::{lexer::Token, parser::paulis_from_tokens}, output::fmt_operation, symplectic::Symplectic};
 
 pub mod angle;
 pub mod phase;
@@ -15,6 +19,9 @@ pub enum OperationKind {
     Rotation {
         angle: Angle,
     },
+    /// A partition boundary from a QASM `barrier` (see `--drop-barriers-as-partition-hints`):
+    /// nothing is merged or reordered across it, and it isn't written to the output.
+    Barrier,
 }
 
 
@@ -50,11 +57,64 @@ impl<'a, B: Basis> Rotation<'a, B> {
 }
 
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// At most this many qubits of an [`Operation`]'s Pauli string are shown by its `Debug`.
+pub const MAX_DEBUG_QUBITS: usize = 64;
+
+
+/// A Pauli rotation or measurement (or a nop, i.e. an operation which has been removed).
+///
+/// Equality treats all nops as equal, regardless of any stale `x`/`z` bits they still carry.
+#[derive(Clone, Copy, Eq)]
 pub struct Operation<B: Basis> {
     pub x: B,
     pub z: B,
     pub kind: OperationKind,
+    /// The input line this operation came from (the first contributing line, for one combined
+    /// from several). Ignored by `==`.
+    #[cfg(feature = "source-tracking")]
+    pub src_line: Option<u32>,
+}
+
+
+impl<B: Basis> PartialEq for Operation<B> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.kind, other.kind) {
+            (OperationKind::Nop, OperationKind::Nop) => true,
+            (lhs, rhs) => lhs == rhs && self.x == other.x && self.z == other.z,
+        }
+    }
+}
+
+
+/// Compact, e.g. `Rotate(+π/8): IXYZ` or `Measure(-): ZZII`.
+///
+/// An operation doesn't know how many qubits its circuit has, so the Pauli string covers the
+/// whole basis (its [`Basis::bit_length`], up to [`MAX_DEBUG_QUBITS`]), with identity on any
+/// qubits past the circuit's.
+impl<B: Basis> fmt::Debug for Operation<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            OperationKind::Nop => return f.write_str("Nop"),
+            OperationKind::Barrier => return f.write_str("Barrier"),
+            OperationKind::Measurement { phase: Phase::Positive } => f.write_str("Measure(+)")?,
+            OperationKind::Measurement { phase: Phase::Negative } => f.write_str("Measure(-)")?,
+            OperationKind::Rotation { angle } => write!(f, "Rotate({})", match angle {
+                Angle::Pi2 => "π/2",
+                Angle::PlusPi8 => "+π/8",
+                Angle::MinusPi8 => "-π/8",
+                Angle::PlusPi4 => "+π/4",
+                Angle::MinusPi4 => "-π/4",
+            })?,
+        }
+
+        let width = self.x.bit_length();
+        let shown = width.min(MAX_DEBUG_QUBITS);
+        write!(f, ": {}{}", pauli_string(&self.x, &self.z, shown), if shown < width { "…" } else { "" })?;
+        if let Some(line) = self.src_line() {
+            write!(f, " (line {})", line)?;
+        }
+        Ok(())
+    }
 }
 
 
@@ -97,20 +157,110 @@ impl<B: Basis> Operation<B> {
     pub fn measurement(x: B, z: B, phase: Phase) -> Self {
         Self {
             x, z,
-            kind: OperationKind::Measurement { phase }
+            kind: OperationKind::Measurement { phase },
+            #[cfg(feature = "source-tracking")]
+            src_line: None,
         }
     }
 
     pub fn rotation(x: B, z: B, angle: Angle) -> Self {
         Self {
             x, z, 
-            kind: OperationKind::Rotation { angle }
+            kind: OperationKind::Rotation { angle },
+            #[cfg(feature = "source-tracking")]
+            src_line: None,
+        }
+    }
+
+    /// Build an operation from the tokens of one line: a `Rotate` or `Measure` token followed by
+    /// exactly `n_qubits` Paulis.
+    pub fn try_from_tokens(n_qubits: usize, tokens: &[Token]) -> anyhow::Result<Self> {
+        let Some((first, paulis)) = tokens.split_first() else {
+            bail!("internal error: no tokens given while building operation");
+        };
+        if paulis.len() > n_qubits {
+            bail!("Too many Paulis ({}, expected {})", paulis.len(), n_qubits);
+        }
+        let (x, z) = paulis_from_tokens(n_qubits, paulis.iter().copied())?;
+
+        match *first {
+            Token::Measure(phase) => Ok(Self::measurement(x, z, phase)),
+            Token::Rotate(angle) => Ok(Self::rotation(x, z, angle.into())),
+            _ => bail!("Internal error: invalid token {:?} to start operation", first),
+        }
+    }
+
+    /// The input line this operation came from, if known. Always `None` without the
+    /// `source-tracking` feature.
+    #[inline(always)]
+    pub fn src_line(&self) -> Option<u32> {
+        #[cfg(feature = "source-tracking")]
+        let line = self.src_line;
+        #[cfg(not(feature = "source-tracking"))]
+        let line = None;
+        line
+    }
+
+    /// Set the input line this operation came from; a no-op without the `source-tracking` feature.
+    #[cfg(feature = "source-tracking")]
+    #[inline(always)]
+    pub fn set_src_line(&mut self, line: Option<u32>) {
+        self.src_line = line;
+    }
+
+    /// Set the input line this operation came from; a no-op without the `source-tracking` feature.
+    #[cfg(not(feature = "source-tracking"))]
+    #[inline(always)]
+    pub fn set_src_line(&mut self, line: Option<u32>) {
+        let _ = line;
+    }
+
+    /// [`Operation::set_src_line`], for building operations.
+    #[inline(always)]
+    pub fn with_src_line(mut self, line: Option<u32>) -> Self {
+        self.set_src_line(line);
+        self
+    }
+
+    /// A partition boundary; see [`OperationKind::Barrier`].
+    pub fn barrier(n_qubits: usize) -> Self {
+        Self {
+            x: B::zero(n_qubits),
+            z: B::zero(n_qubits),
+            kind: OperationKind::Barrier,
+            #[cfg(feature = "source-tracking")]
+            src_line: None,
         }
     }
 
+    /// The canonical identity operation: a rotation by the all-identity Pauli.
+    ///
+    /// [`Angle`] has no zero, and the angle of an identity rotation is only a global phase, so this
+    /// always uses [`Angle::Pi2`].
+    pub fn identity(n_qubits: usize) -> Self {
+        Self::rotation(B::zero(n_qubits), B::zero(n_qubits), Angle::Pi2)
+    }
+
+    /// Whether this is a rotation by the all-identity Pauli (with any angle), e.g. [`Operation::identity`].
+    pub fn is_identity_rotation(&self) -> bool {
+        self.as_rotation().is_some_and(|rotation| rotation.is_identity())
+    }
+
     #[inline(always)]
     pub fn is_identity(&self) -> bool {
-        (self.x.popcnt() == 0) && (self.z.popcnt() == 0) && !matches!(self.kind, OperationKind::Nop)
+        (self.x.popcnt() == 0) && (self.z.popcnt() == 0) && !matches!(self.kind, OperationKind::Nop | OperationKind::Barrier)
+    }
+
+    /// Whether the Pauli has no X or Y, i.e. the operation is diagonal (identity counts).
+    #[inline(always)]
+    pub fn is_z_only(&self) -> bool {
+        self.x.is_zero()
+    }
+
+    /// Whether the Pauli has no Z or Y (identity counts).
+    #[inline(always)]
+    pub fn is_x_only(&self) -> bool {
+        self.z.is_zero()
     }
 
     #[inline(always)]
@@ -126,6 +276,74 @@ impl<B: Basis> Operation<B> {
         self.kind.is_rotation()
     }
 
+    pub fn is_barrier(&self) -> bool {
+        matches!(self.kind, OperationKind::Barrier)
+    }
+
+    /// Compose this operation into `clifford` (in place) if it's a Clifford rotation.
+    ///
+    /// Returns whether it was applied; measurements, nops and pi/8 rotations are left alone.
+    pub fn apply_to_clifford(&self, clifford: &mut Clifford<B>) -> bool {
+        match self.kind {
+            OperationKind::Rotation { angle: angle @ Angle::Pi2 } => clifford.compose_pi2(angle.sign_bit(), &self.x, &self.z),
+            OperationKind::Rotation { angle: angle @ (Angle::PlusPi4 | Angle::MinusPi4) } => clifford.compose_pi4(angle.sign_bit(), &self.x, &self.z),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Widen from `n_qubits` to `width` qubits; the added (trailing) qubits are identity.
+    pub fn padded(self, n_qubits: usize, width: usize) -> Self {
+        if width == n_qubits {
+            return self;
+        }
+        debug_assert!(width > n_qubits);
+
+        let mut x = B::zero(width);
+        let mut z = B::zero(width);
+        for q in 0..n_qubits {
+            if self.x.get_bit(q) {
+                x.set_bit_true(q);
+            }
+            if self.z.get_bit(q) {
+                z.set_bit_true(q);
+            }
+        }
+
+        Self { x, z, ..self }
+    }
+
+    /// Relabel the qubits: qubit `q` moves to qubit `perm[q]`. `perm` must be a permutation of
+    /// `0..n_qubits`.
+    pub fn permute_qubits(&mut self, perm: &[usize]) {
+        debug_assert!(perm.iter().all(|q| *q < perm.len()));
+
+        let n_qubits = perm.len();
+        let mut x = B::zero(n_qubits);
+        let mut z = B::zero(n_qubits);
+        for (q, to) in perm.iter().enumerate() {
+            if self.x.get_bit(q) {
+                x.set_bit_true(*to);
+            }
+            if self.z.get_bit(q) {
+                z.set_bit_true(*to);
+            }
+        }
+        self.x = x;
+        self.z = z;
+    }
+
+    /// Copy with the basis of a nop zeroed, so that structurally comparing (or hashing) the
+    /// result agrees with `==`.
+    pub fn canonical(&self) -> Self {
+        let mut new = self.clone();
+        if new.is_nop() {
+            new.x.set_zero();
+            new.z.set_zero();
+        }
+        new
+    }
+
     #[inline(always)]
     pub fn set_nop(&mut self) {
         self.kind = OperationKind::Nop;
@@ -133,19 +351,86 @@ impl<B: Basis> Operation<B> {
 
     #[inline(always)]
     pub fn commutes_with(&self, rhs: &Self) -> bool {
-        (self.z.and(&rhs.x).popcnt() + self.x.and(&rhs.z).popcnt()) % 2 == 0
+        (self.z.and_popcnt(&rhs.x) + self.x.and_popcnt(&rhs.z)).is_multiple_of(2)
         // !(self.z.and(&rhs.x).parity() ^ self.x.and(&rhs.z).parity())
     }
 
+    /// Whether the two operations act non-trivially on any common qubit.
+    ///
+    /// Operations which don't overlap always commute (and so can be freely reordered).
+    #[inline(always)]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        // (x|z) & (x'|z') is nonzero iff one of the four ANDs is; `and_popcnt` doesn't allocate the
+        // intermediate bases for a DBasis, which matters in the partitioner's commutation checks
+        self.x.and_popcnt(&other.x) != 0 || self.z.and_popcnt(&other.z) != 0
+            || self.z.and_popcnt(&other.x) != 0 || self.x.and_popcnt(&other.z) != 0
+    }
+
+    /// The qubit this operation acts on, if it acts non-trivially on exactly one (nops act on none).
+    pub fn single_qubit(&self) -> Option<usize> {
+        let support = self.x.or(&self.z);
+        if self.is_nop() || support.popcnt() != 1 {
+            return None;
+        }
+        (0..support.bit_capacity()).find(|q| support.get_bit(*q))
+    }
+
+    /// The qubit of a single-qubit Z Pauli, if this is one.
+    ///
+    /// Unlike [`Self::single_qubit`] this doesn't allocate, and only scans for the qubit once the
+    /// Pauli is known to be a single Z, so it's cheap to try on every measurement.
+    #[inline(always)]
+    pub fn single_z_qubit(&self) -> Option<usize> {
+        if self.is_nop() || !self.is_z_only() || self.z.popcnt() != 1 {
+            return None;
+        }
+        (0..self.z.bit_capacity()).find(|q| self.z.get_bit(*q))
+    }
+
+    /// The non-identity qubits among the first `n_qubits`, in order, with their Paulis.
+    pub fn iter_paulis(&self, n_qubits: usize) -> impl Iterator<Item = (usize, Pauli)> + '_ {
+        (0..n_qubits).filter_map(move |q| match (self.x.get_bit(q), self.z.get_bit(q)) {
+            (false, false) => None,
+            (true, false) => Some((q, Pauli::X)),
+            (false, true) => Some((q, Pauli::Z)),
+            (true, true) => Some((q, Pauli::Y)),
+        })
+    }
+
+    /// Number of qubits on which this operation's Pauli is Y.
+    #[inline(always)]
+    pub fn count_y(&self) -> usize {
+        self.x.count_y(&self.z)
+    }
+
+    /// The product of the two operations' Paulis (ignoring kind, angle and phase), as `(x, z, sign)`
+    /// of a Pauli R.
+    ///
+    /// If the Paulis commute, `self · other = ±R`. If they anticommute the product isn't Hermitian,
+    /// and as with [`Symplectic::mul`], `self · other = ∓iR`: e.g. X·Z = -iY gives `+Y`.
+    pub fn mul_pauli(&self, other: &Self) -> (B, B, bool) {
+        let mut product = Symplectic { sign: false, x: self.x.clone(), z: self.z.clone() };
+        let rhs = Symplectic { sign: false, x: other.x.clone(), z: other.z.clone() };
+        if product.anticommutes_with(&rhs) {
+            product.mul_by(&rhs);
+        } else {
+            product.mul_commuting(&rhs);
+        }
+        (product.x, product.z, product.sign)
+    }
+
+    /// Number of qubits on which the two operations' Paulis differ (ignoring kind, angle and phase).
+    pub fn pauli_distance(&self, other: &Self) -> usize {
+        self.x.xor(&other.x).or(&self.z.xor(&other.z)).popcnt()
+    }
+
     #[inline(always)]
     pub fn commutes_with_likely(&self, rhs: &Self) -> bool {
-        let zx = self.z.and(&rhs.x);
-        let xz = self.x.and(&rhs.z);
-        // fast return if they don't share a basis (or are identity)
-        if xz.is_zero() && zx.is_zero() {
+        // fast return if they don't share a qubit (or either is identity)
+        if !self.overlaps(rhs) {
             return true;
         }
-        (zx.popcnt() + xz.popcnt()) % 2 == 0
+        (self.z.and_popcnt(&rhs.x) + self.x.and_popcnt(&rhs.z)).is_multiple_of(2)
     }
 
     pub fn as_measurement(&self) -> Option<Measurement<'_, B>> {
@@ -170,28 +455,22 @@ impl<B: Basis> Operation<B> {
 
     pub fn pauli_angle(pauli: Pauli, angle: Angle, n_qubits: usize, qubit: usize) -> Self {
         let (x, z) = basis::<B>(n_qubits, qubit, pauli);
-        Self {
-            x, z, kind: OperationKind::Rotation { angle }
-        }
+        Self::rotation(x, z, angle)
     }
 
-    pub fn pretty_print(&self) {
-        if let Some(measurement) = self.as_measurement() {
-            // todo: speed this up
-            // this will be slow; Rust doesn't buffer printing by default
-            print!("Measurement {{ x: ");
-            self.x.pretty_print();
-            print!(", z: ");
-            self.z.pretty_print();
-            print!(", phase: {:?} }}", measurement.phase);
-            return;
+    /// The operation as a line of the text format (see [`Operation::as_string`]), or `Nop` /
+    /// `Barrier` for those, which the text format can't express.
+    pub fn pretty_string(&self, n_qubits: usize) -> String {
+        match self.kind {
+            OperationKind::Nop => String::from("Nop"),
+            OperationKind::Barrier => String::from("Barrier"),
+            _ => self.as_string(n_qubits),
         }
-        let rotation = self.as_rotation().unwrap();
-        print!("Rotation {{ x: ");
-        self.x.pretty_print();
-        print!(", z: ");
-        self.z.pretty_print();
-        print!(", angle: {:?} }}", rotation.angle);
+    }
+
+    /// Print [`Operation::pretty_string`] (in one `println!`, so it isn't interleaved with logging).
+    pub fn pretty_print(&self, n_qubits: usize) {
+        println!("{}", self.pretty_string(n_qubits));
     }
 
     pub fn as_string(&self, n_qubits: usize) -> String {
@@ -217,3 +496,316 @@ impl<B: Basis> Operation<B> {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::{basis::*, input::lexer, symplectic::Symplectic};
+
+    use super::*;
+
+    #[test]
+    fn test_try_from_tokens() {
+        let tokens = [Token::Rotate(-1), Token::Pauli(lexer::Pauli::X), Token::Pauli(lexer::Pauli::Y), Token::Pauli(lexer::Pauli::Z)];
+        let op = Operation::<Basis8>::try_from_tokens(3, &tokens).unwrap();
+        assert_eq!(op, Operation::rotation(Basis8::with_true_bits(3, &[0, 1]), Basis8::with_true_bits(3, &[1, 2]), Angle::MinusPi8));
+
+        let tokens = [Token::Measure(Phase::Negative), Token::Pauli(lexer::Pauli::Z)];
+        let op = Operation::<DBasis>::try_from_tokens(1, &tokens).unwrap();
+        assert_eq!(op, Operation::measurement(DBasis::zero(1), DBasis::one(1), Phase::Negative));
+
+        assert!(Operation::<Basis8>::try_from_tokens(2, &tokens).is_err());
+        assert!(Operation::<Basis8>::try_from_tokens(0, &tokens).is_err());
+        assert!(Operation::<Basis8>::try_from_tokens(1, &[]).is_err());
+        assert!(Operation::<Basis8>::try_from_tokens(0, &[Token::End]).is_err());
+    }
+
+    fn check_disjoint_commute<B: Basis>(n_qubits: usize) {
+        let mut rng = SmallRng::seed_from_u64(8642);
+        let half = n_qubits / 2;
+
+        for _ in 0..64 {
+            let mut lhs = Operation::<B>::rand(n_qubits, &mut rng);
+            let mut rhs = Operation::<B>::rand(n_qubits, &mut rng);
+
+            // restrict lhs to the lower half of the qubits and rhs to the upper half
+            for q in 0..n_qubits {
+                if q < half {
+                    rhs.x.set_bit_false(q);
+                    rhs.z.set_bit_false(q);
+                } else {
+                    lhs.x.set_bit_false(q);
+                    lhs.z.set_bit_false(q);
+                }
+            }
+
+            assert!(!lhs.overlaps(&rhs));
+            assert!(lhs.commutes_with(&rhs));
+            assert!(lhs.commutes_with_likely(&rhs));
+        }
+    }
+
+    #[test]
+    fn test_disjoint_commute() {
+        check_disjoint_commute::<Basis8>(8);
+        check_disjoint_commute::<Basis64>(64);
+        check_disjoint_commute::<Basis256>(200);
+        check_disjoint_commute::<DBasis>(300);
+    }
+
+    fn check_commutes_with_likely<B: Basis>(n_qubits: usize) {
+        let mut rng = SmallRng::seed_from_u64(961);
+
+        for i in 0..512 {
+            let mut lhs = Operation::<B>::rand(n_qubits, &mut rng);
+            let mut rhs = Operation::<B>::rand(n_qubits, &mut rng);
+
+            // random operations nearly always overlap, so make most pairs sparse to exercise the
+            // disjoint short-circuit too
+            if i % 4 != 0 {
+                for op in [&mut lhs, &mut rhs] {
+                    for q in 0..n_qubits {
+                        if rng.gen_range(0..8) != 0 {
+                            op.x.set_bit_false(q);
+                            op.z.set_bit_false(q);
+                        }
+                    }
+                }
+            }
+
+            let overlaps = !lhs.x.or(&lhs.z).and(&rhs.x.or(&rhs.z)).is_zero();
+            assert_eq!(overlaps, lhs.overlaps(&rhs), "{:?} {:?}", lhs, rhs);
+
+            let commutes = lhs.commutes_with(&rhs);
+            assert_eq!(commutes, lhs.commutes_with_likely(&rhs), "{:?} {:?}", lhs, rhs);
+            assert_eq!(commutes, rhs.commutes_with_likely(&lhs), "{:?} {:?}", lhs, rhs);
+            assert_eq!(commutes, Symplectic::from(&lhs).commutes_with(&Symplectic::from(&rhs)), "{:?} {:?}", lhs, rhs);
+        }
+    }
+
+    #[test]
+    fn test_commutes_with_likely() {
+        check_commutes_with_likely::<Basis8>(8);
+        check_commutes_with_likely::<Basis16>(13);
+        check_commutes_with_likely::<Basis32>(32);
+        check_commutes_with_likely::<Basis64>(50);
+        check_commutes_with_likely::<Basis128>(128);
+        check_commutes_with_likely::<Basis256>(200);
+        check_commutes_with_likely::<DBasis>(300);
+    }
+
+    fn check_count_y<B: Basis>(n_qubits: usize) {
+        let mut rng = SmallRng::seed_from_u64(896);
+
+        for _ in 0..64 {
+            let op = Operation::<B>::rand(n_qubits, &mut rng);
+            assert_eq!(op.count_y(), op.x.and(&op.z).popcnt());
+        }
+    }
+
+    #[test]
+    fn test_count_y() {
+        check_count_y::<Basis8>(8);
+        check_count_y::<Basis128>(128);
+        check_count_y::<DBasis>(300);
+    }
+
+    #[test]
+    fn test_nop_eq() {
+        let mut a = Operation::rotation(Basis8::one(4), Basis8::zero(4), Angle::PlusPi8);
+        let mut b = Operation::measurement(Basis8::zero(4), Basis8::one_bit(4, 2), Phase::Negative);
+        assert_ne!(a, b);
+
+        a.set_nop();
+        b.set_nop();
+        assert_eq!(a, b);
+        assert_ne!(a.x, b.x);
+        assert_eq!(a.canonical().x, b.canonical().x);
+        assert_eq!(a.canonical().z, b.canonical().z);
+
+        // non-nops are untouched
+        let c = Operation::rotation(Basis8::one(4), Basis8::zero(4), Angle::PlusPi8);
+        assert_eq!(c.canonical().x, c.x);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_pretty_string() {
+        let mut rng = SmallRng::seed_from_u64(4242);
+        for _ in 0..16 {
+            let op = Operation::<DBasis>::rand(70, &mut rng);
+            assert_eq!(op.pretty_string(70), op.as_string(70));
+        }
+
+        let mut op = Operation::rotation(Basis8::with_true_bits(3, &[0, 1]), Basis8::with_true_bits(3, &[1, 2]), Angle::MinusPi8);
+        assert_eq!(op.pretty_string(3), "Rotate -1: XYZ");
+        op.set_nop();
+        assert_eq!(op.pretty_string(3), "Nop");
+        assert_eq!(Operation::<Basis8>::barrier(3).pretty_string(3), "Barrier");
+
+        assert_eq!(Basis8::with_true_bits(3, &[0]).pretty_string(), "10000000");
+        assert_eq!(DBasis::with_true_bits(3, &[0]).pretty_string(), "DBasis { (0) 1000000000000000000000000000000000000000000000000000000000000000 }");
+    }
+
+    #[test]
+    fn test_debug() {
+        let op = Operation::rotation(Basis8::with_true_bits(4, &[1, 2]), Basis8::with_true_bits(4, &[2, 3]), Angle::MinusPi8);
+        assert_eq!(format!("{:?}", op), "Rotate(-π/8): IXYZIIII");
+        let op = Operation::measurement(Basis8::zero(2), Basis8::one(2), Phase::Negative);
+        assert_eq!(format!("{:?}", op), "Measure(-): ZZIIIIII");
+        assert_eq!(format!("{:?}", Operation::<Basis8>::barrier(2)), "Barrier");
+
+        let op = Operation::rotation(DBasis::one_bit(300, 1), DBasis::zero(300), Angle::Pi2);
+        let debug = format!("{:?}", op);
+        assert!(debug.starts_with("Rotate(π/2): IXII"), "{}", debug);
+        assert!(debug.ends_with("…"), "{}", debug);
+        assert_eq!(debug.chars().filter(|ch| "IXYZ".contains(*ch)).count(), MAX_DEBUG_QUBITS);
+        // a dynamic basis knows its length, so its padding isn't shown
+        let op = Operation::rotation(DBasis::one_bit(10, 1), DBasis::zero(10), Angle::Pi2);
+        assert_eq!(format!("{:?}", op), "Rotate(π/2): IXIIIIIIII");
+
+        let mut op = op;
+        op.set_nop();
+        assert_eq!(format!("{:?}", vec![op]), "[Nop]");
+    }
+
+    #[test]
+    fn test_single_qubit() {
+        let op = Operation::rotation(Basis8::zero(3), Basis8::with_true_bits(3, &[2]), Angle::PlusPi8);
+        assert_eq!(op.single_qubit(), Some(2));
+        // Y is still a single qubit
+        let op = Operation::rotation(Basis8::with_true_bits(3, &[1]), Basis8::with_true_bits(3, &[1]), Angle::PlusPi8);
+        assert_eq!(op.single_qubit(), Some(1));
+        let op = Operation::rotation(Basis8::with_true_bits(3, &[0]), Basis8::with_true_bits(3, &[1]), Angle::PlusPi8);
+        assert_eq!(op.single_qubit(), None);
+        assert_eq!(Operation::<Basis8>::identity(3).single_qubit(), None);
+
+        let op = Operation::measurement(DBasis::with_true_bits(70, &[69]), DBasis::zero(70), Phase::Positive);
+        assert_eq!(op.single_qubit(), Some(69));
+    }
+
+    #[test]
+    fn test_single_z_qubit() {
+        let op = Operation::measurement(Basis8::zero(3), Basis8::with_true_bits(3, &[2]), Phase::Negative);
+        assert_eq!(op.single_z_qubit(), Some(2));
+        // a single X or Y isn't a Z
+        let op = Operation::measurement(Basis8::with_true_bits(3, &[1]), Basis8::with_true_bits(3, &[1]), Phase::Positive);
+        assert_eq!(op.single_z_qubit(), None);
+        let op = Operation::measurement(Basis8::zero(3), Basis8::with_true_bits(3, &[0, 1]), Phase::Positive);
+        assert_eq!(op.single_z_qubit(), None);
+        assert_eq!(Operation::<Basis8>::identity(3).single_z_qubit(), None);
+
+        let op = Operation::measurement(DBasis::zero(257), DBasis::with_true_bits(257, &[256]), Phase::Positive);
+        assert_eq!(op.single_z_qubit(), Some(256));
+    }
+
+    #[test]
+    fn test_z_only_x_only() {
+        let z = Operation::rotation(Basis8::zero(3), Basis8::with_true_bits(3, &[0, 2]), Angle::PlusPi8);
+        assert!(z.is_z_only());
+        assert!(!z.is_x_only());
+
+        let x = Operation::rotation(Basis8::with_true_bits(3, &[1]), Basis8::zero(3), Angle::MinusPi4);
+        assert!(!x.is_z_only());
+        assert!(x.is_x_only());
+
+        let y = Operation::rotation(Basis8::with_true_bits(3, &[1]), Basis8::with_true_bits(3, &[1]), Angle::PlusPi8);
+        assert!(!y.is_z_only());
+        assert!(!y.is_x_only());
+
+        let identity = Operation::<Basis8>::identity(3);
+        assert!(identity.is_z_only() && identity.is_x_only());
+    }
+
+    #[test]
+    fn test_iter_paulis() {
+        // IXYZ
+        let op = Operation::rotation(Basis8::with_true_bits(4, &[1, 2]), Basis8::with_true_bits(4, &[2, 3]), Angle::PlusPi8);
+        assert_eq!(op.iter_paulis(4).collect::<Vec<_>>(), vec![(1, Pauli::X), (2, Pauli::Y), (3, Pauli::Z)]);
+        assert_eq!(op.iter_paulis(3).collect::<Vec<_>>(), vec![(1, Pauli::X), (2, Pauli::Y)]);
+        assert_eq!(Operation::<Basis8>::identity(4).iter_paulis(4).count(), 0);
+
+        let op = Operation::measurement(DBasis::zero(300), DBasis::with_true_bits(300, &[0, 299]), Phase::Positive);
+        assert_eq!(op.iter_paulis(300).collect::<Vec<_>>(), vec![(0, Pauli::Z), (299, Pauli::Z)]);
+    }
+
+    #[test]
+    fn test_mul_pauli() {
+        let pauli = |x: &[usize], z: &[usize]| Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), Angle::PlusPi8);
+        let x = pauli(&[0], &[]);
+        let y = pauli(&[0], &[0]);
+        let z = pauli(&[], &[0]);
+
+        // X·Z = -iY, Z·X = iY
+        assert_eq!(x.mul_pauli(&z), (y.x, y.z, false));
+        assert_eq!(z.mul_pauli(&x), (y.x, y.z, true));
+        // Z·Y = -iX, Y·Z = iX
+        assert_eq!(z.mul_pauli(&y), (x.x, x.z, false));
+        assert_eq!(y.mul_pauli(&z), (x.x, x.z, true));
+
+        // commuting: X·X = I, ZZ·XX = (ZX)(ZX) = (iY)(iY) = -YY, ZI·ZZ = IZ
+        assert_eq!(x.mul_pauli(&x), (Basis8::zero(2), Basis8::zero(2), false));
+        let yy = pauli(&[0, 1], &[0, 1]);
+        assert_eq!(pauli(&[], &[0, 1]).mul_pauli(&pauli(&[0, 1], &[])), (yy.x, yy.z, true));
+        assert_eq!(pauli(&[], &[0]).mul_pauli(&pauli(&[], &[0, 1])), (Basis8::zero(2), Basis8::with_true_bits(2, &[1]), false));
+        // XY·YX = (X·Y)(Y·X) = (iZ)(-iZ) = ZZ
+        assert_eq!(pauli(&[0, 1], &[1]).mul_pauli(&pauli(&[0, 1], &[0])), (Basis8::zero(2), Basis8::one(2), false));
+    }
+
+    #[test]
+    fn test_pauli_distance() {
+        let xx = Operation::rotation(Basis8::one(2), Basis8::zero(2), Angle::PlusPi8);
+        let yz = Operation::rotation(Basis8::one_bit(2, 0), Basis8::one(2), Angle::PlusPi8);
+        let zi = Operation::measurement(Basis8::zero(2), Basis8::one_bit(2, 0), Phase::Positive);
+
+        assert_eq!(xx.pauli_distance(&xx), 0);
+        assert_eq!(xx.pauli_distance(&yz), 2);
+        assert_eq!(yz.pauli_distance(&zi), 2);
+        assert_eq!(zi.pauli_distance(&yz), 2);
+        assert_eq!(xx.pauli_distance(&zi), 2);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let x0 = Operation::rotation(Basis8::one_bit(4, 0), Basis8::zero(4), Angle::PlusPi8);
+        let z0 = Operation::rotation(Basis8::zero(4), Basis8::one_bit(4, 0), Angle::PlusPi8);
+        let z1 = Operation::rotation(Basis8::zero(4), Basis8::one_bit(4, 1), Angle::PlusPi8);
+
+        assert!(x0.overlaps(&z0));
+        assert!(!x0.commutes_with_likely(&z0));
+        assert!(!x0.overlaps(&z1));
+        assert!(!z0.overlaps(&z1));
+    }
+
+    fn check_permute_qubits<B: Basis>(n_qubits: usize) {
+        use rand::seq::SliceRandom;
+
+        let mut rng = SmallRng::seed_from_u64(922);
+        for _ in 0..64 {
+            let original = Operation::<B>::rand(n_qubits, &mut rng);
+            let mut perm: Vec<_> = (0..n_qubits).collect();
+            perm.shuffle(&mut rng);
+            let mut inverse = vec![0; n_qubits];
+            for (q, to) in perm.iter().enumerate() {
+                inverse[*to] = q;
+            }
+
+            let mut op = original.clone();
+            op.permute_qubits(&perm);
+            for (q, to) in perm.iter().enumerate() {
+                assert_eq!(op.x.get_bit(*to), original.x.get_bit(q));
+                assert_eq!(op.z.get_bit(*to), original.z.get_bit(q));
+            }
+            op.permute_qubits(&inverse);
+            assert_eq!(op, original);
+        }
+    }
+
+    #[test]
+    fn test_permute_qubits() {
+        check_permute_qubits::<Basis8>(7);
+        check_permute_qubits::<DBasis>(300);
+    }
+}
@@ -41,6 +41,29 @@ impl Angle {
         matches!(self, Self::PlusPi8 | Self::MinusPi8)
     }
 
+    /// The name of the gate this is as a single-qubit Z rotation (see `--output-angles-as-gates`).
+    pub fn gate_name(&self) -> &'static str {
+        match self {
+            Angle::Pi2 => "Z",
+            Angle::PlusPi8 => "T",
+            Angle::MinusPi8 => "Tdg",
+            Angle::PlusPi4 => "S",
+            Angle::MinusPi4 => "Sdg",
+        }
+    }
+
+    /// The inverse of [`Angle::gate_name`].
+    pub fn from_gate_name(name: &str) -> Option<Self> {
+        match name {
+            "Z" => Some(Angle::Pi2),
+            "T" => Some(Angle::PlusPi8),
+            "Tdg" => Some(Angle::MinusPi8),
+            "S" => Some(Angle::PlusPi4),
+            "Sdg" => Some(Angle::MinusPi4),
+            _ => None,
+        }
+    }
+
     pub fn rand(rng: &mut impl Rng) -> Self {
         let val = rng.gen_range(-2..=2);
         Self::from(val)
@@ -1,3 +1,7 @@
+use std::{fmt, ops::MulAssign};
+
+use super::angle::Angle;
+
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Phase {
@@ -54,3 +58,55 @@ macro_rules! impl_to_from_phase {
 
 
 impl_to_from_phase!(i8, i16, i32, i64, i128, isize);
+
+
+/// A global phase, as a power of e^(iπ/4) (see `--phase-tracking`).
+///
+/// Phases are relative to rotations taken as exp(iθ(I - P)), which is what makes a pi/2, pi/4 and
+/// pi/8 rotation about Z exactly the Z, S and T gates. In that convention rotations about the same
+/// Pauli combine without any phase, so the only phase the optimizer drops is when it writes a
+/// rotation about -P as one about P (see [`GlobalPhase::of_negated_axis`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct GlobalPhase(u8);
+
+
+impl GlobalPhase {
+    pub const ONE: Self = Self(0);
+
+    /// e^(iπk/4).
+    pub fn from_eighth_turns(k: i8) -> Self {
+        Self(k.rem_euclid(8) as u8)
+    }
+
+    /// The phase dropped by writing a rotation by `angle` about -P as a rotation by `-angle` about
+    /// P: exp(iθ(I + P)) = e^(2iθ) exp(-iθ(I - P)).
+    pub fn of_negated_axis(angle: Angle) -> Self {
+        match angle {
+            Angle::Pi2 => Self(4),
+            angle => Self::from_eighth_turns(angle as i8),
+        }
+    }
+}
+
+
+impl MulAssign for GlobalPhase {
+    fn mul_assign(&mut self, rhs: Self) {
+        self.0 = (self.0 + rhs.0) % 8;
+    }
+}
+
+
+impl fmt::Display for GlobalPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            0 => "1",
+            1 => "e^(iπ/4)",
+            2 => "i",
+            3 => "e^(3iπ/4)",
+            4 => "-1",
+            5 => "e^(-3iπ/4)",
+            6 => "-i",
+            _ => "e^(-iπ/4)",
+        })
+    }
+}
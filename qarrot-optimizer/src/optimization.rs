@@ -1,8 +1,8 @@
 
 pub mod push_t_forward;
-use anyhow::Context;
+use anyhow::{bail, Context};
 use fs2::FileExt;
-use log::{trace, warn};
+use log::{debug, info, trace, warn};
 pub use push_t_forward::*;
 
 pub mod partitions;
@@ -13,10 +13,25 @@ pub use partition::*;
 pub mod rotation_combination;
 pub use rotation_combination::*;
 
-use crate::{basis::Basis, clifford::Clifford, operation::Operation, output::Output, RunConfig};
+pub mod reorder_independent;
+pub use reorder_independent::*;
+
+pub mod global_rotation_merge;
+pub use global_rotation_merge::*;
+
+pub mod canonical_measure;
+pub use canonical_measure::*;
+
+pub mod trailing_clifford;
+pub use trailing_clifford::*;
+
+pub mod repeats;
+pub use repeats::*;
+
+use crate::{basis::Basis, clifford::Clifford, operation::{phase::GlobalPhase, Operation}, output::Output, InputType, RunConfig};
 
 use core::slice;
-use std::{fmt::Debug, fs, io::{Read, Seek, Write}, mem};
+use std::{collections::BTreeSet, fmt::{self, Debug}, fs, io::{Read, Seek, Write}, mem, sync::Arc, time::{Duration, Instant}};
 
 use self::partitions::Partitions;
 
@@ -24,7 +39,22 @@ use self::partitions::Partitions;
 #[derive(Clone, Copy, Debug)]
 pub struct Stats {
     pub total_operations: usize,
+    /// Like `total_operations`, but without measurements (so not skewed by the number of qubits).
+    pub rotations_only: usize,
     pub t_gates: usize,
+    /// pi/2 and pi/4 rotations. Pushing T gates forward absorbs all of them, but partitioning can
+    /// merge T gates into new ones.
+    pub clifford_gates: usize,
+}
+
+
+/// How long reading the circuit took, split between the parser and the initial reduction (see
+/// `--dry-run-parse`).
+#[derive(Clone, Copy, Debug)]
+pub struct ParseTimings {
+    pub parse: Duration,
+    /// Everything else while reading: combining adjacent rotations, and any other passes on the input.
+    pub reduction: Duration,
 }
 
 
@@ -32,13 +62,50 @@ impl Stats {
     fn zero() -> Self {
         Stats {
             total_operations: 0,
+            rotations_only: 0,
             t_gates: 0,
+            clifford_gates: 0,
+        }
+    }
+
+    /// Count the (non-nop) operations in `circuit`.
+    pub fn of<B: Basis>(circuit: &[Operation<B>]) -> Self {
+        let mut stats = Self::zero();
+        for op in circuit.iter().filter(|op| !op.is_nop()) {
+            stats.total_operations += 1;
+            if let Some(rotation) = op.as_rotation() {
+                stats.rotations_only += 1;
+                stats.t_gates += rotation.angle.is_pi8() as usize;
+                stats.clifford_gates += !rotation.angle.is_pi8() as usize;
+            }
+        }
+        stats
+    }
+
+    /// The operation count to report: `rotations_only` if `rotations_only` is set (see
+    /// `--stats-rotations-only`), otherwise `total_operations`.
+    pub fn operations(&self, rotations_only: bool) -> usize {
+        if rotations_only {
+            self.rotations_only
+        } else {
+            self.total_operations
         }
     }
 }
 
 
-pub trait Optimizer<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug>: Sized + Debug {
+/// The number of distinct Paulis `(x, z)` among the pi/8 rotations of `circuit`, ignoring their
+/// sign (see `--count-distinct-paulis`).
+pub fn count_distinct_t_paulis<B: Basis>(circuit: &[Operation<B>]) -> usize {
+    circuit.iter()
+        .filter(|op| op.as_rotation().is_some_and(|rotation| rotation.angle.is_pi8()))
+        .map(|op| (&op.x, &op.z))
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+
+pub trait Optimizer<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug>: Sized + Debug {
     fn new(n_qubits: usize, instructions: Ops, run_config: &RunConfig) -> anyhow::Result<Self>;
     fn initial_circuit_length(&self) -> Option<usize>; // may not be known
     fn post_reduction_length(&self) -> Option<usize>;
@@ -47,9 +114,203 @@ pub trait Optimizer<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug>: Sized
     fn push_t_forward(&mut self) -> anyhow::Result<(bool, Stats)>; // changed, t_gate_count
     fn partition(&mut self) -> anyhow::Result<(bool, Stats)>;
     fn write_to_output(self, output: impl Output) -> anyhow::Result<()>;
+
+    /// Save a copy of the current circuit, which [`Optimizer::restore_checkpoint`] goes back to.
+    ///
+    /// Returns false if checkpoints aren't supported.
+    fn checkpoint(&mut self) -> bool {
+        false
+    }
+
+    fn restore_checkpoint(&mut self) -> anyhow::Result<()> {
+        bail!("checkpoints are not supported by this optimizer")
+    }
+
+    /// Move measurements to the end of the circuit where legal (see [`canonical_measure`]).
+    ///
+    /// Returns false if this isn't supported.
+    fn canonical_measure(&mut self) -> bool {
+        false
+    }
+
+    /// Drop the Clifford rotations after the last pi/8 rotation (see [`strip_trailing_clifford`]).
+    ///
+    /// Returns false if this isn't supported.
+    fn strip_trailing_clifford(&mut self) -> bool {
+        false
+    }
+
+    /// [`count_distinct_t_paulis`] of the current circuit.
+    ///
+    /// Returns `None` if this isn't supported.
+    fn count_distinct_t_paulis(&self) -> Option<usize> {
+        None
+    }
+
+    /// The number of T-layers in the current circuit, as the partitioner grouped them. Layers with
+    /// only Clifford rotations (e.g. left over with `--only-rounds`) don't count.
+    ///
+    /// Returns `None` if this isn't supported.
+    fn t_depth(&self) -> Option<usize> {
+        None
+    }
+
+    /// The global phase dropped by pushing T gates forward so far (see [`push_accumulator`]).
+    ///
+    /// Returns `None` if this isn't tracked.
+    fn global_phase(&self) -> Option<GlobalPhase> {
+        None
+    }
+
+    /// How long reading the circuit took, if `run_config.dry_run_parse` was set when it was read.
+    ///
+    /// Returns `None` if this isn't measured.
+    fn parse_timings(&self) -> Option<ParseTimings> {
+        None
+    }
+
+    /// An independent copy of this optimizer in its current state (see `--iterations`).
+    ///
+    /// Returns `None` if this isn't supported.
+    fn try_clone(&self) -> Option<Self> {
+        None
+    }
+}
+
+
+/// Preallocation when there's no hint of the circuit size; the buffer grows (geometrically) from here.
+pub const MIN_PREALLOC_OPERATIONS: usize = 64;
+
+/// Rough length of a text operation line, excluding the Paulis (e.g. `"Rotate 2: "` and a newline).
+const TXT_LINE_OVERHEAD: u64 = 11;
+
+
+/// How many operations [`InMemoryOptimizer`] should preallocate for.
+///
+/// Uses `--num-operations` if given, otherwise estimates from the size of a text input file (every
+/// line has `n_qubits` Paulis), and otherwise starts small. Always capped at
+/// [`super::MAX_PREALLOC_OPERATIONS`].
+pub fn initial_capacity(n_qubits: usize, run_config: &RunConfig) -> usize {
+    if let Some(num_operations) = run_config.num_operations {
+        if num_operations > super::MAX_PREALLOC_OPERATIONS {
+            warn!("number of operations given ({}) is larger than allowed default {}; using max allowed", num_operations, super::MAX_PREALLOC_OPERATIONS);
+            return super::MAX_PREALLOC_OPERATIONS;
+        }
+        return num_operations;
+    }
+
+    if let (Some(input_bytes), InputType::Txt) = (run_config.input_bytes, run_config.file_type) {
+        // plus the measurements appended to the circuit
+        let estimate = (input_bytes / (n_qubits as u64 + TXT_LINE_OVERHEAD)) as usize + n_qubits;
+        return estimate.clamp(MIN_PREALLOC_OPERATIONS, super::MAX_PREALLOC_OPERATIONS);
+    }
+
+    MIN_PREALLOC_OPERATIONS
+}
+
+
+/// Returned (wrapped in an [`anyhow::Error`]) by [`InMemoryOptimizer::new`] when the circuit's
+/// buffer grows past `--memory-limit`, so the caller can restart with the [`FileOptimizer`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryLimitExceeded {
+    pub limit: usize,
+}
+
+
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit doesn't fit in the memory limit ({} bytes)", self.limit)
+    }
+}
+
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+
+/// Fail once more than `--max-operations` operations (counted after unrolling repeats, before any
+/// reduction) have been read from the input.
+fn check_max_operations(read: usize, max_operations: Option<usize>) -> anyhow::Result<()> {
+    match max_operations {
+        Some(max_operations) if read > max_operations => bail!("circuit has more than the maximum of {} operations (--max-operations)", max_operations),
+        _ => Ok(()),
+    }
+}
+
+
+/// Append a Z measurement of each qubit, as the optimizer does at the end of every circuit.
+///
+/// With `--already-measured`, a circuit which already ends with a measurement (e.g. the output of
+/// an earlier run, being optimized further) is left alone, so re-feeding output doesn't double its
+/// measurements. With `--retain-input-measurements-order` the same goes for any circuit ending with
+/// a measurement; one that doesn't still gets them, as the final Clifford (which the optimizer
+/// drops) would otherwise be lost.
+pub(crate) fn append_measurements<B: Basis>(circuit: &mut Vec<Operation<B>>, n_qubits: usize, retain_input_measurements: bool, already_measured: bool, ends_with_measurement: bool) {
+    if retain_input_measurements {
+        if ends_with_measurement {
+            debug!("keeping only the input's measurements; not appending more");
+            return;
+        }
+        warn!("--retain-input-measurements-order is set, but the circuit doesn't end with a measurement; appending measurements so the final Clifford isn't lost");
+    } else if already_measured {
+        if ends_with_measurement {
+            debug!("circuit already ends with measurements; not appending more");
+            return;
+        }
+        warn!("--already-measured is set, but the circuit doesn't end with a measurement; appending measurements");
+    }
+
+    for i in 0..n_qubits {
+        let z = B::one_bit(n_qubits, i);
+        circuit.push(Operation::measurement(B::zero(n_qubits), z, false.into()))
+    }
+}
+
+
+/// Write `circuit`, with a `# layer N` comment before each of its T-layers and `# measurements`
+/// before each run of measurements after one. A layer with only Clifford rotations isn't a
+/// T-layer, and gets a `# clifford` comment instead.
+///
+/// `layer_starts` are the indexes of each layer's first rotation among the circuit's rotations (see
+/// [`partition_t_gates`]).
+fn write_grouped_by_layer<B: Basis>(output: &mut impl Output, n_qubits: usize, circuit: &[Operation<B>], layer_starts: &[usize]) -> anyhow::Result<()> {
+    let has_t_gate = t_layers_with_t_gates(circuit, layer_starts);
+    let mut next_layer = 0;
+    let mut t_layers = 0;
+    let mut rotations = 0;
+    let mut in_layer = false;
+    let layer_comment = |layer: usize, t_layers: usize| if has_t_gate[layer] {
+        format!("layer {}", t_layers)
+    } else {
+        "clifford".to_string()
+    };
+    for op in circuit {
+        if op.is_rotation() {
+            if layer_starts.get(next_layer) == Some(&rotations) {
+                t_layers += has_t_gate[next_layer] as usize;
+                output.write_comment(&layer_comment(next_layer, t_layers))?;
+                next_layer += 1;
+                in_layer = true;
+            } else if !in_layer && next_layer > 0 {
+                // --canonical-measure moved measurements into the middle of the layer
+                output.write_comment(&format!("{} (continued)", layer_comment(next_layer - 1, t_layers)))?;
+                in_layer = true;
+            }
+            rotations += 1;
+        } else if in_layer && op.is_measurement() {
+            output.write_comment("measurements")?;
+            in_layer = false;
+        }
+        output.write_operation(n_qubits, op)?;
+    }
+    Ok(())
 }
 
 
+/// What [`InMemoryOptimizer::checkpoint`] saves: the circuit, its stats, the global phase and the
+/// T-layers.
+type Checkpoint<B> = (Vec<Operation<B>>, Stats, GlobalPhase, Option<Vec<usize>>);
+
+
 #[derive(Debug)]
 pub struct InMemoryOptimizer<B: Basis> {
     n_qubits: usize,
@@ -58,42 +319,86 @@ pub struct InMemoryOptimizer<B: Basis> {
     post_reduction_length: usize,
     partitions: Partitions,
     full_partitioning: bool,
+    dump_partitions: bool,
+    partition_size_cap: Option<usize>,
+    approx_passes: usize,
+    merge_adjacent_measurements: bool,
+    split_by_repeat: bool,
+    group_output_by_layer: bool,
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
     latest_stats: Option<Stats>,
+    global_phase: GlobalPhase,
+    /// The T-layers recorded by the last partitioning (see [`partition_t_gates`]), or `None` if
+    /// the circuit has changed since.
+    t_layers: Option<Vec<usize>>,
+    checkpoint: Option<Checkpoint<B>>,
+    parse_timings: Option<ParseTimings>,
 }
 
-impl<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug> Optimizer<B, Ops> for InMemoryOptimizer<B> {
+impl<B: Basis, Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug> Optimizer<B, Ops> for InMemoryOptimizer<B> {
     fn new(n_qubits: usize, instructions: Ops, run_config: &RunConfig) -> anyhow::Result<Self> {
-        let prealloc = if let Some(num_operations) = run_config.num_operations {
-            if num_operations > super::MAX_PREALLOC_OPERATIONS {
-                warn!("number of operations given ({}) is larger than allowed default {}; using max allowed", num_operations, super::MAX_PREALLOC_OPERATIONS);
-                super::MAX_PREALLOC_OPERATIONS
-            } else {
-                num_operations
-            }
-        } else {
-            1024
-        };
+        let start = Instant::now();
+        let prealloc = initial_capacity(n_qubits, run_config);
+        debug!("preallocating {} operations", prealloc);
         let mut circuit = Vec::with_capacity(prealloc);
 
+        // only checked when the buffer grows
+        let check_memory_limit = |circuit: &Vec<Operation<B>>| match run_config.memory_limit {
+            Some(limit) if circuit.capacity() * mem::size_of::<Operation<B>>() > limit => Err(MemoryLimitExceeded { limit }),
+            _ => Ok(()),
+        };
+        check_memory_limit(&circuit)?;
+
         let mut reducer = OptimizeRotationsAdjacent::new(instructions);
+        if run_config.dry_run_parse {
+            reducer = reducer.with_source_timing();
+        }
 
         while let Some(next) = reducer.next() {
-            if let Some(next) = next {
+            check_max_operations(reducer.pre_op_count(), run_config.max_operations)?;
+            if let Some(next) = next.context("while reading circuit")? {
+                let capacity = circuit.capacity();
                 circuit.push(next);
+                if circuit.capacity() != capacity {
+                    check_memory_limit(&circuit)?;
+                }
             }
         }
 
-        for i in 0..n_qubits {
-            let z = B::one_bit(n_qubits, i);
-            circuit.push(Operation::measurement(B::zero(n_qubits), z, false.into()))
+        if run_config.reorder_independent {
+            let moved = reorder_independent(&mut circuit, REORDER_INDEPENDENT_WINDOW);
+            debug!("reordered {} independent rotations", moved);
+        }
+
+        if run_config.global_rotation_merge {
+            let t_count_before = t_count(&circuit);
+            let merged = global_rotation_merge(&mut circuit, GLOBAL_ROTATION_MERGE_WINDOW);
+            info!("global rotation merge: merged away {} commuting rotations; T count {} -> {}", merged, t_count_before, t_count(&circuit));
         }
 
+        let ends_with_measurement = circuit.last().is_some_and(|op| op.is_measurement());
+        append_measurements(&mut circuit, n_qubits, run_config.retain_input_measurements_order, run_config.already_measured, ends_with_measurement);
+
         if run_config.shrink_buffer_after_repeat {
             circuit.shrink_to_fit();
         }
 
         let initial_circuit_length = reducer.pre_op_count();
 
+        let thread_pool = if run_config.threads > 1 {
+            Some(Arc::new(rayon::ThreadPoolBuilder::new()
+                .num_threads(run_config.threads)
+                .build()
+                .context("while creating thread pool")?))
+        } else {
+            None
+        };
+
+        let parse_timings = reducer.source_time().map(|parse| ParseTimings {
+            parse,
+            reduction: start.elapsed().saturating_sub(parse),
+        });
+
         Ok(Self {
             post_reduction_length: circuit.len(),
             latest_stats: None, // todo: can be determined
@@ -102,6 +407,17 @@ impl<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug> Optimizer<B, Ops> for
             initial_circuit_length,
             partitions: Partitions::new(),
             full_partitioning: run_config.full_partitioning,
+            dump_partitions: run_config.dump_partitions,
+            partition_size_cap: run_config.partition_size_cap,
+            approx_passes: run_config.approx_passes,
+            merge_adjacent_measurements: run_config.merge_adjacent_measurements,
+            split_by_repeat: run_config.split_by_repeat,
+            group_output_by_layer: run_config.group_output_by_layer,
+            thread_pool,
+            global_phase: GlobalPhase::ONE,
+            t_layers: None,
+            checkpoint: None,
+            parse_timings,
         })
     }
 
@@ -117,6 +433,14 @@ impl<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug> Optimizer<B, Ops> for
         self.latest_stats
     }
 
+    fn global_phase(&self) -> Option<GlobalPhase> {
+        Some(self.global_phase)
+    }
+
+    fn parse_timings(&self) -> Option<ParseTimings> {
+        self.parse_timings
+    }
+
     fn current_heap_usage(&self) -> (usize, usize) {
         let allocated = self.circuit.capacity() * mem::size_of::<Operation<B>>();
         let used = self.circuit.len() * mem::size_of::<Operation<B>>();
@@ -128,11 +452,15 @@ impl<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug> Optimizer<B, Ops> for
         // mem::swap(&mut self.buffer, &mut self.circuit);
         // self.buffer.clear();
 
-        let (changed, t_gates) = push_t_forward_inplace(&mut self.circuit, self.n_qubits);
+        let (changed, t_gates) = push_t_forward_inplace(&mut self.circuit, self.n_qubits, &mut self.global_phase);
+        self.t_layers = None;
 
         let stats = Stats {
             total_operations: self.circuit.len(),
+            rotations_only: self.circuit.iter().filter(|op| op.is_rotation()).count(),
             t_gates,
+            // all absorbed into the accumulated Clifford
+            clifford_gates: 0,
         };
 
         self.latest_stats = Some(stats);
@@ -142,24 +470,124 @@ impl<B: Basis, Ops: Iterator<Item = Operation<B>> + Debug> Optimizer<B, Ops> for
 
     fn partition(&mut self) -> anyhow::Result<(bool, Stats)> {
         let t_gate_count = self.latest_stats.unwrap().t_gates;
+        let mut t_layers = self.t_layers.take().unwrap_or_default();
 
-        let changed = if self.full_partitioning {
-            partition_t_gates(&mut self.partitions, &mut self.circuit, t_gate_count)
+        let mut changed = if self.full_partitioning {
+            partition_t_gates(&mut self.partitions, &mut self.circuit, t_gate_count, self.thread_pool.as_deref(), self.dump_partitions, self.partition_size_cap, &mut t_layers)
         } else {
-            let (changed, stats) = approximate_partition_t_gates(&mut self.circuit);
+            let (changed, stats) = approximate_partition_t_gates_passes(&mut self.circuit, self.approx_passes, &mut t_layers);
             self.latest_stats = Some(stats);
             changed
         };
+        // merging measurements below doesn't move any rotations
+        self.t_layers = Some(t_layers);
+
+        if self.merge_adjacent_measurements {
+            let merged = merge_adjacent_measurements(&mut self.circuit);
+            if merged > 0 {
+                changed = true;
+                if let Some(stats) = &mut self.latest_stats {
+                    stats.total_operations -= merged;
+                }
+            }
+        }
 
         Ok((changed, self.latest_stats.unwrap()))
     }
 
     fn write_to_output(self, mut output: impl Output) -> anyhow::Result<()> {
-        for op in &self.circuit {
-            output.write_operation(self.n_qubits, op)?;
+        output.begin(self.n_qubits)?;
+        if self.split_by_repeat {
+            let blocks = find_repeated_blocks(&self.circuit, MAX_REPEAT_BLOCK_LEN);
+            debug!("writing {} repeated blocks", blocks.len());
+            write_with_repeats(&mut output, self.n_qubits, &self.circuit, &blocks)?;
+        } else if self.group_output_by_layer {
+            let t_layers = self.t_layers.unwrap_or_else(|| t_layer_starts(&self.circuit));
+            write_grouped_by_layer(&mut output, self.n_qubits, &self.circuit, &t_layers)?;
+        } else {
+            for op in &self.circuit {
+                output.write_operation(self.n_qubits, op)?;
+            }
         }
+        output.end()?;
         output.flush()
     }
+
+    fn checkpoint(&mut self) -> bool {
+        // before the first round there are no stats yet
+        let current_stats = self.latest_stats.unwrap_or_else(|| Stats::of(&self.circuit));
+
+        match &mut self.checkpoint {
+            Some((circuit, stats, global_phase, t_layers)) => {
+                // reuse the allocation
+                circuit.clone_from(&self.circuit);
+                *stats = current_stats;
+                *global_phase = self.global_phase;
+                t_layers.clone_from(&self.t_layers);
+            },
+            None => self.checkpoint = Some((self.circuit.clone(), current_stats, self.global_phase, self.t_layers.clone())),
+        }
+        true
+    }
+
+    fn restore_checkpoint(&mut self) -> anyhow::Result<()> {
+        let Some((circuit, stats, global_phase, t_layers)) = self.checkpoint.take() else {
+            bail!("no checkpoint to restore");
+        };
+        self.circuit = circuit;
+        self.latest_stats = Some(stats);
+        self.global_phase = global_phase;
+        self.t_layers = t_layers;
+        Ok(())
+    }
+
+    fn canonical_measure(&mut self) -> bool {
+        let (moved, blocked) = canonical_measure(&mut self.circuit);
+        debug!("moved {} measurements to the end ({} blocked)", moved, blocked);
+        true
+    }
+
+    fn strip_trailing_clifford(&mut self) -> bool {
+        let removed = strip_trailing_clifford(&mut self.circuit);
+        debug!("removed {} trailing Clifford rotations", removed);
+        self.post_reduction_length = self.circuit.len();
+        true
+    }
+
+    fn count_distinct_t_paulis(&self) -> Option<usize> {
+        Some(count_distinct_t_paulis(&self.circuit))
+    }
+
+    fn t_depth(&self) -> Option<usize> {
+        // a circuit that hasn't been partitioned (e.g. restored by --abort-on-growth before the
+        // first round) only has estimated layers
+        let count = |layer_starts: &[usize]| t_layers_with_t_gates(&self.circuit, layer_starts).into_iter().filter(|has_t_gate| *has_t_gate).count();
+        Some(self.t_layers.as_deref().map_or_else(|| count(&t_layer_starts(&self.circuit)), count))
+    }
+
+    fn try_clone(&self) -> Option<Self> {
+        Some(Self {
+            n_qubits: self.n_qubits,
+            circuit: self.circuit.clone(),
+            initial_circuit_length: self.initial_circuit_length,
+            post_reduction_length: self.post_reduction_length,
+            partitions: self.partitions.clone(),
+            full_partitioning: self.full_partitioning,
+            dump_partitions: self.dump_partitions,
+            partition_size_cap: self.partition_size_cap,
+            approx_passes: self.approx_passes,
+            merge_adjacent_measurements: self.merge_adjacent_measurements,
+            split_by_repeat: self.split_by_repeat,
+            group_output_by_layer: self.group_output_by_layer,
+            // the pool has no state between uses, so it can be shared
+            thread_pool: self.thread_pool.clone(),
+            latest_stats: self.latest_stats,
+            global_phase: self.global_phase,
+            t_layers: self.t_layers.clone(),
+            checkpoint: self.checkpoint.clone(),
+            parse_timings: self.parse_timings,
+        })
+    }
 }
 
 
@@ -216,31 +644,45 @@ impl ReadWriteSwap {
 
 
 #[derive(Debug)]
-pub struct FileOptimizer<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> {
+pub struct FileOptimizer<Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, B: Basis> {
     n_qubits: usize,
-    target_buffer_length: usize,
+    /// Operations read into `circuit_buffer` at a time (bounds resident operations).
+    read_batch: usize,
+    /// Operations buffered before each write to the temporary files.
+    write_batch: usize,
     circuit_buffer: Vec<Operation<B>>,
     instructions: Option<OptimizeRotationsAdjacent<B, Ops>>,
+    reorder_independent: bool,
+    global_rotation_merge: bool,
+    max_operations: Option<usize>,
+    retain_input_measurements_order: bool,
+    already_measured: bool,
+    /// Whether the last operation read from the input was a measurement (see `--already-measured`).
+    ends_with_measurement: bool,
+    merge_adjacent_measurements: bool,
     initial_circuit_length: Option<usize>,
     post_reduction_length: Option<usize>,
     latest_stats: Option<Stats>,
+    global_phase: GlobalPhase,
     files: ReadWriteSwap,
 }
 
 
-impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B> {
+impl<Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, B: Basis> FileOptimizer<Ops, B> {
     /// Returns number of instructions read
     /// None if we're done
-    fn read_from_source(&mut self) -> Option<usize> {
+    fn read_from_source(&mut self) -> anyhow::Result<Option<usize>> {
         self.circuit_buffer.clear();
         if let Some(instructions) = &mut self.instructions {
             trace!("reading from reducer");
             loop {
                 if let Some(next) = instructions.next() {
-                    if let Some(next) = next {
+                    check_max_operations(instructions.pre_op_count(), self.max_operations)?;
+                    if let Some(next) = next.context("while reading circuit")? {
+                        self.ends_with_measurement = next.is_measurement();
                         self.circuit_buffer.push(next);
                     }
-                    if self.circuit_buffer.len() >= self.target_buffer_length {
+                    if self.circuit_buffer.len() >= self.read_batch {
                         break;
                     }
                 } else {
@@ -250,21 +692,31 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
 
                     // todo: do this better
                     // this might cause us to realloc the circuit buffer
-                    for i in 0..self.n_qubits {
-                        let z = B::one_bit(self.n_qubits, i);
-                        self.circuit_buffer.push(Operation::measurement(B::zero(self.n_qubits), z, false.into()))
-                    }
+                    append_measurements(&mut self.circuit_buffer, self.n_qubits, self.retain_input_measurements_order, self.already_measured, self.ends_with_measurement);
 
                     break;
                 }
             }
 
+            if self.reorder_independent {
+                // only reorders within this chunk, which is still correct (just less thorough)
+                let moved = reorder_independent(&mut self.circuit_buffer, REORDER_INDEPENDENT_WINDOW);
+                trace!("reordered {} independent rotations", moved);
+            }
+
+            if self.global_rotation_merge {
+                // likewise only within this chunk
+                let t_count_before = t_count(&self.circuit_buffer);
+                let merged = global_rotation_merge(&mut self.circuit_buffer, GLOBAL_ROTATION_MERGE_WINDOW);
+                debug!("global rotation merge: merged away {} commuting rotations in this batch; T count {} -> {}", merged, t_count_before, t_count(&self.circuit_buffer));
+            }
+
             if self.circuit_buffer.is_empty() {
                 trace!("read 0");
-                None
+                Ok(None)
             } else {
                 trace!("read {}", self.circuit_buffer.len());
-                Some(self.circuit_buffer.len())
+                Ok(Some(self.circuit_buffer.len()))
             }
         } else {
             let read_file = self.files.read();
@@ -279,15 +731,16 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
             // and special care must be taken to ensure correct pointer alignment and seek positioning
             // otherwise, Bad Things can happen (the BEST case scenario is a segfault)
 
-            debug_assert!(self.circuit_buffer.capacity() >= self.target_buffer_length);
+            debug_assert!(self.circuit_buffer.capacity() >= self.read_batch);
             let buf = self.circuit_buffer.as_mut_ptr();
 
             let slice = unsafe {
                 let buf_as_bytes: *mut u8 = buf as *mut _ as *mut u8;
-                slice::from_raw_parts_mut(buf_as_bytes, self.target_buffer_length * mem::size_of::<Operation<B>>())
+                slice::from_raw_parts_mut(buf_as_bytes, self.read_batch * mem::size_of::<Operation<B>>())
             };
 
-            debug_assert!(slice.len() == mem::size_of::<Operation<B>>() * self.circuit_buffer.capacity());
+            // the buffer may have grown past read_batch (e.g. when appending the final measurements)
+            debug_assert!(slice.len() <= mem::size_of::<Operation<B>>() * self.circuit_buffer.capacity());
 
             let bytes_read = read_file.read(slice).context("while filling buffer from file in FileOptimizer").unwrap();
 
@@ -297,7 +750,7 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
 
             let operations_read = bytes_read / mem::size_of::<Operation<B>>();
 
-            debug_assert!(operations_read <= self.target_buffer_length);
+            debug_assert!(operations_read <= self.read_batch);
 
             unsafe {
                 self.circuit_buffer.set_len(operations_read);
@@ -305,10 +758,10 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
 
             if operations_read == 0 {
                 trace!("read 0");
-                None
+                Ok(None)
             } else {
                 trace!("read {}", operations_read);
-                Some(operations_read)
+                Ok(Some(operations_read))
             }
         }
     }
@@ -319,7 +772,7 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
         // on its own, this is less unsafe (the main thing is to get the slice length correct)
         // HOWEVER, it needs to be precisely correct for the above read function to be correct
 
-        debug_assert!(self.circuit_buffer.capacity() >= self.target_buffer_length);
+        debug_assert!(self.circuit_buffer.capacity() >= self.read_batch);
         let buf = self.circuit_buffer.as_ptr();
 
         trace!("writing {} ops to sink", self.circuit_buffer.len());
@@ -368,21 +821,30 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> FileOptimizer<Ops, B>
 
         Self {
             n_qubits,
-            target_buffer_length: 32,
+            read_batch: 32,
+            write_batch: 32,
             circuit_buffer: Vec::with_capacity(32),
             instructions: None,
+            reorder_independent: false,
+            global_rotation_merge: false,
+            max_operations: None,
+            retain_input_measurements_order: false,
+            already_measured: false,
+            ends_with_measurement: false,
+            merge_adjacent_measurements: false,
             initial_circuit_length: None,
             post_reduction_length: None,
             latest_stats: None,
+            global_phase: GlobalPhase::ONE,
             files: ReadWriteSwap::new(file_a, file_b),
         }
     }
 }
 
 
-impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for FileOptimizer<Ops, B> {
+impl<Ops: Iterator<Item = anyhow::Result<Operation<B>>> + Debug, B: Basis> Optimizer<B, Ops> for FileOptimizer<Ops, B> {
     fn new(n_qubits: usize, instructions: Ops, run_config: &RunConfig) -> anyhow::Result<Self> {
-        let circuit_buffer = Vec::with_capacity(run_config.target_buffer_length);
+        let circuit_buffer = Vec::with_capacity(run_config.read_batch);
         let reducer = OptimizeRotationsAdjacent::new(instructions);
 
         trace!("creating and locking tempfiles");
@@ -399,12 +861,21 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
 
         Ok(Self {
             n_qubits,
-            target_buffer_length: run_config.target_buffer_length,
+            read_batch: run_config.read_batch,
+            write_batch: run_config.write_batch,
             circuit_buffer,
             initial_circuit_length: None,
             post_reduction_length: None,
             instructions: Some(reducer),
+            reorder_independent: run_config.reorder_independent,
+            global_rotation_merge: run_config.global_rotation_merge,
+            max_operations: run_config.max_operations,
+            retain_input_measurements_order: run_config.retain_input_measurements_order,
+            already_measured: run_config.already_measured,
+            ends_with_measurement: false,
+            merge_adjacent_measurements: run_config.merge_adjacent_measurements,
             latest_stats: None,
+            global_phase: GlobalPhase::ONE,
             files: ReadWriteSwap::new(file_a, file_b),
         })
     }
@@ -421,6 +892,10 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
         self.latest_stats
     }
 
+    fn global_phase(&self) -> Option<GlobalPhase> {
+        Some(self.global_phase)
+    }
+
     fn current_heap_usage(&self) -> (usize, usize) {
         let (used, alloc) = (self.circuit_buffer.len(), self.circuit_buffer.capacity());
         (used, alloc)
@@ -431,20 +906,20 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
         let mut stats = Stats::zero();
 
         let mut accumulator = Clifford::identity(self.n_qubits);
-        let mut clifford_buf = Clifford::identity(self.n_qubits);
 
         // in a loop:
         // - fill the buffer from the current read source
         // - push T forward through the buffer
         // - write the result in the buffer to the current write sink
-        while let Some(operations_read) = self.read_from_source() {
+        while let Some(operations_read) = self.read_from_source()? {
             stats.total_operations += operations_read;
 
             let mut out_index = 0;
 
             for op_index in 0..self.circuit_buffer.len() {
                 debug_assert!(out_index <= op_index);
-                let (did_change, was_t_gate, new_operation) = push_accumulator(&mut accumulator, &mut clifford_buf, &self.circuit_buffer[op_index]);
+                stats.rotations_only += self.circuit_buffer[op_index].is_rotation() as usize;
+                let (did_change, was_t_gate, new_operation) = push_accumulator(&mut accumulator, &mut self.global_phase, &self.circuit_buffer[op_index]);
                 changed |= did_change;
                 if was_t_gate {
                     stats.t_gates += 1;
@@ -469,36 +944,46 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
     fn partition(&mut self) -> anyhow::Result<(bool, Stats)> {
         debug_assert!(self.instructions.is_none());
 
-        let mut last_partition = Vec::with_capacity(self.target_buffer_length);
+        let mut last_partition = Vec::with_capacity(self.read_batch);
         let mut changed = false;
         let mut stats = Stats::zero();
         // buffer writes to avoid many tiny syscalls
-        let mut write_buf = Vec::with_capacity(self.target_buffer_length);
-        let target_buffer_length = self.target_buffer_length;
+        let mut write_buf = Vec::with_capacity(self.write_batch);
+        let write_batch = self.write_batch;
+        // the last operation written, if it was a measurement; kept separately from write_buf since
+        // that may have just been flushed
+        let mut last_measurement: Option<Operation<B>> = None;
 
-        let update = |changed: &mut bool, last_partition: &mut Vec<Operation<B>>, write_buf: &mut Vec<Operation<B>>, this: &mut Self, stats: &mut Stats| {
+        let update = |changed: &mut bool, last_partition: &mut Vec<Operation<B>>, write_buf: &mut Vec<Operation<B>>, this: &mut Self, stats: &mut Stats, last_measurement: &mut Option<Operation<B>>| {
             *changed |= reduce_rotations_no_ordering_slice(last_partition);
             for op in last_partition.iter() {
                 if !op.is_nop() {
+                    if this.merge_adjacent_measurements && op.is_measurement() && last_measurement.as_ref() == Some(op) {
+                        *changed = true;
+                        continue;
+                    }
+                    *last_measurement = op.is_measurement().then(|| op.clone());
                     write_buf.push(op.clone());
                     stats.total_operations += 1;
                     if let Some(rot) = op.as_rotation() {
+                        stats.rotations_only += 1;
                         stats.t_gates += rot.angle.is_pi8() as usize;
+                        stats.clifford_gates += !rot.angle.is_pi8() as usize;
                     }
                 }
             }
             last_partition.clear();
-            if write_buf.len() >= target_buffer_length {
+            if write_buf.len() >= write_batch {
                 this.write_buf_to_sink(&write_buf);
                 write_buf.clear();
             }
         }; 
 
-        while let Some(_) = self.read_from_source() {
+        while self.read_from_source()?.is_some() {
             'ops: for op_idx in 0..self.circuit_buffer.len() {
                 if !self.circuit_buffer[op_idx].is_rotation() {
                     last_partition.push(self.circuit_buffer[op_idx].clone());
-                    update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats);
+                    update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats, &mut last_measurement);
                     continue 'ops;
                 }
 
@@ -511,7 +996,7 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
                 }
 
                 if !commutes_with_all {
-                    update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats);
+                    update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats, &mut last_measurement);
                 }
 
                 last_partition.push(self.circuit_buffer[op_idx].clone());
@@ -519,7 +1004,7 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
         }
 
         if !last_partition.is_empty() {
-            update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats);
+            update(&mut changed, &mut last_partition, &mut write_buf, self, &mut stats, &mut last_measurement);
         }
         self.write_buf_to_sink(&write_buf);
 
@@ -531,13 +1016,14 @@ impl<Ops: Iterator<Item = Operation<B>> + Debug, B: Basis> Optimizer<B, Ops> for
     }
 
     fn write_to_output(mut self, mut output: impl Output) -> anyhow::Result<()> {
-        while let Some(_) = self.read_from_source() {
+        output.begin(self.n_qubits)?;
+        while self.read_from_source()?.is_some() {
             for op in &self.circuit_buffer {
                 output.write_operation(self.n_qubits, op)?;
             }
         }
-
-        Ok(())
+        output.end()?;
+        output.flush()
     }
 }
 
@@ -549,7 +1035,7 @@ mod tests {
 
     use super::*;
 
-    use crate::basis::*;
+    use crate::{basis::*, operation::angle::Angle, output::StringOut};
 
     struct EmptyIter<B: Basis> {
         phantom: PhantomData<B>,
@@ -565,7 +1051,7 @@ mod tests {
     }
 
     impl<B: Basis> Iterator for EmptyIter<B> {
-        type Item = Operation<B>;
+        type Item = anyhow::Result<Operation<B>>;
     
         fn next(&mut self) -> Option<Self::Item> {
             None
@@ -583,7 +1069,7 @@ mod tests {
 
         opt.write_buf_to_sink(ops);
         opt.files.swap();
-        opt.read_from_source();
+        opt.read_from_source().unwrap();
 
         assert_eq!(opt.circuit_buffer.len(), ops.len());
 
@@ -595,7 +1081,7 @@ mod tests {
 
         opt.write_buf_to_sink(&ops[0..(ops.len()/2)]);
         opt.files.swap();
-        opt.read_from_source();
+        opt.read_from_source().unwrap();
         assert_eq!(opt.circuit_buffer.len(), ops.len() / 2);
     }
 
@@ -617,4 +1103,238 @@ mod tests {
 
         test_rw(n_qubits, &ops);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_initial_capacity() {
+        assert_eq!(initial_capacity(15, &RunConfig::default()), MIN_PREALLOC_OPERATIONS);
+
+        let cfg = RunConfig {
+            num_operations: Some(5000),
+            input_bytes: Some(1 << 30),
+            ..Default::default()
+        };
+        assert_eq!(initial_capacity(15, &cfg), 5000);
+
+        // q500.txt: 1054 lines of 15 qubits
+        let cfg = RunConfig {
+            file_type: InputType::Txt,
+            input_bytes: Some(27931),
+            ..Default::default()
+        };
+        let estimate = initial_capacity(15, &cfg);
+        assert!((1054..1200).contains(&estimate), "{}", estimate);
+
+        // the size of a QASM file says little about the number of operations
+        let cfg = RunConfig {
+            file_type: InputType::Qasm,
+            input_bytes: Some(27931),
+            ..Default::default()
+        };
+        assert_eq!(initial_capacity(15, &cfg), MIN_PREALLOC_OPERATIONS);
+
+        let cfg = RunConfig {
+            num_operations: Some(usize::MAX),
+            ..Default::default()
+        };
+        assert_eq!(initial_capacity(15, &cfg), crate::MAX_PREALLOC_OPERATIONS);
+    }
+
+    #[test]
+    fn test_stats_rotations_only() {
+        let n_qubits = 8;
+        let mut rng = SmallRng::seed_from_u64(904);
+        let ops: Vec<_> = (0..64)
+            .map(|_| Ok(Operation::<Basis8>::rotation(Basis8::rand(n_qubits, &mut rng), Basis8::rand(n_qubits, &mut rng), Angle::rand(&mut rng))))
+            .collect();
+
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        let mut opt = <InMemoryOptimizer<Basis8> as Optimizer<_, Ops>>::new(n_qubits, ops.into_iter(), &RunConfig::default()).unwrap();
+
+        // the optimizer appends one measurement per qubit
+        let stats = Stats::of(&opt.circuit);
+        assert_eq!(stats.rotations_only, stats.total_operations - n_qubits);
+
+        let (_, stats) = Optimizer::<_, Ops>::push_t_forward(&mut opt).unwrap();
+        assert_eq!(stats.rotations_only, stats.total_operations - n_qubits);
+        assert_eq!(stats.operations(true), stats.rotations_only);
+        assert_eq!(stats.operations(false), stats.total_operations);
+
+        let (_, stats) = Optimizer::<_, Ops>::partition(&mut opt).unwrap();
+        assert_eq!(stats.rotations_only, stats.total_operations - n_qubits);
+    }
+
+    #[test]
+    fn test_stats_clifford_gates() {
+        fn check<Opt: Optimizer<Basis8, Ops>>() {
+            let t = |q| Ok(Operation::rotation(Basis8::zero(2), Basis8::one_bit(2, q), Angle::PlusPi8));
+            // not adjacent, so only partitioning merges the T gates on qubit 0 (into an S gate)
+            let ops = vec![t(0), t(1), t(0)];
+            let mut opt = Opt::new(2, ops.into_iter(), &RunConfig::default()).unwrap();
+
+            let (_, stats) = opt.push_t_forward().unwrap();
+            assert_eq!((stats.t_gates, stats.clifford_gates), (3, 0));
+
+            let (_, stats) = opt.partition().unwrap();
+            assert_eq!((stats.t_gates, stats.clifford_gates), (1, 1));
+            assert_eq!(stats.rotations_only, 2);
+        }
+
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        check::<InMemoryOptimizer<Basis8>>();
+        check::<FileOptimizer<Ops, Basis8>>();
+    }
+
+    #[test]
+    fn test_count_distinct_t_paulis() {
+        let rotation = |x, z, angle| Ok(Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), angle));
+        // the X rotation keeps the Z ones on qubit 0 from merging, whatever their sign
+        let ops = vec![
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+            rotation(&[], &[0], Angle::MinusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+            rotation(&[], &[0, 1], Angle::PlusPi8),
+        ];
+
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        let mut opt = <InMemoryOptimizer<Basis8> as Optimizer<_, Ops>>::new(2, ops.into_iter(), &RunConfig::default()).unwrap();
+        Optimizer::<_, Ops>::push_t_forward(&mut opt).unwrap();
+        let (_, stats) = Optimizer::<_, Ops>::partition(&mut opt).unwrap();
+        assert_eq!(stats.t_gates, 5);
+        assert_eq!(Optimizer::<_, Ops>::count_distinct_t_paulis(&opt), Some(3));
+        assert_eq!(count_distinct_t_paulis(&opt.circuit), 3);
+    }
+
+    #[test]
+    fn test_global_phase() {
+        fn check<Opt: Optimizer<Basis8, Ops>>(ops: Vec<Operation<Basis8>>) -> GlobalPhase {
+            let mut opt = Opt::new(1, ops.into_iter().map(Ok), &RunConfig::default()).unwrap();
+            assert_eq!(opt.global_phase(), Some(GlobalPhase::ONE));
+            opt.push_t_forward().unwrap();
+            opt.global_phase().unwrap()
+        }
+
+        let x = Operation::rotation(Basis8::one(1), Basis8::zero(1), Angle::Pi2);
+        let z = |angle| Operation::rotation(Basis8::zero(1), Basis8::one(1), angle);
+
+        type Ops = std::iter::Map<std::vec::IntoIter<Operation<Basis8>>, fn(Operation<Basis8>) -> anyhow::Result<Operation<Basis8>>>;
+        for check in [check::<InMemoryOptimizer<Basis8>>, check::<FileOptimizer<Ops, Basis8>>] {
+            // S·S·S·S = I exactly
+            assert_eq!(check(vec![z(Angle::PlusPi4); 4]), GlobalPhase::ONE);
+            // T after X: T·X = e^(iπ/4)·X·Tdg
+            assert_eq!(check(vec![x, z(Angle::PlusPi8)]), GlobalPhase::from_eighth_turns(1));
+            assert_eq!(check(vec![x, z(Angle::MinusPi8)]), GlobalPhase::from_eighth_turns(-1));
+            // S isn't pushed through anything, so no phase: it's left in the Clifford frame
+            assert_eq!(check(vec![x, z(Angle::PlusPi4)]), GlobalPhase::ONE);
+            // only the first T gate is conjugated by X, and X, T, X, T is e^(iπ/4) times the identity
+            assert_eq!(check(vec![x, z(Angle::PlusPi8), x, z(Angle::PlusPi8)]), GlobalPhase::from_eighth_turns(1));
+        }
+
+        assert_eq!(GlobalPhase::from_eighth_turns(2).to_string(), "i");
+        assert_eq!(GlobalPhase::from_eighth_turns(-3).to_string(), "e^(-3iπ/4)");
+    }
+
+    #[test]
+    fn test_parse_timings() {
+        // a slow parser: every operation takes a millisecond to read
+        type Ops = std::iter::Map<std::ops::Range<usize>, fn(usize) -> anyhow::Result<Operation<Basis8>>>;
+        fn slow_parser() -> Ops {
+            (0..5).map(|_| {
+                std::thread::sleep(Duration::from_millis(1));
+                Ok(Operation::rotation(Basis8::zero(1), Basis8::one(1), Angle::PlusPi8))
+            })
+        }
+        fn parse_timings(run_config: &RunConfig) -> Option<ParseTimings> {
+            let opt = InMemoryOptimizer::<Basis8>::new(1, slow_parser(), run_config).unwrap();
+            Optimizer::<Basis8, Ops>::parse_timings(&opt)
+        }
+
+        assert!(parse_timings(&RunConfig::default()).is_none());
+
+        let start = Instant::now();
+        let ParseTimings { parse, reduction } = parse_timings(&RunConfig { dry_run_parse: true, ..Default::default() }).unwrap();
+        let total = start.elapsed();
+        assert!(parse >= Duration::from_millis(5), "{:?}", parse);
+        assert!(parse + reduction <= total, "{:?} + {:?} > {:?}", parse, reduction, total);
+    }
+
+    #[test]
+    fn test_group_output_by_layer_partitions() {
+        let rotation = |x: &[usize], z: &[usize], angle| Ok(Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), angle));
+        // the X rotations cancel once their partition is reduced, which leaves three layers of T
+        // gates that all commute: the partitioner's layers, not the fewest possible
+        let ops = vec![
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+            rotation(&[], &[1], Angle::PlusPi8),
+            rotation(&[0], &[], Angle::MinusPi8),
+            rotation(&[], &[0], Angle::PlusPi8),
+        ];
+
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        let cfg = RunConfig { group_output_by_layer: true, approx_passes: 1, ..Default::default() };
+        let mut opt = <InMemoryOptimizer<Basis8> as Optimizer<_, Ops>>::new(2, ops.into_iter(), &cfg).unwrap();
+        Optimizer::<_, Ops>::push_t_forward(&mut opt).unwrap();
+        Optimizer::<_, Ops>::partition(&mut opt).unwrap();
+        assert_eq!(opt.t_layers, Some(vec![0, 1, 2]));
+        assert_eq!(t_layer_starts(&opt.circuit), vec![0]);
+        assert_eq!(Optimizer::<_, Ops>::t_depth(&opt), Some(3));
+
+        let mut out = String::new();
+        Optimizer::<_, Ops>::write_to_output(opt, StringOut::new(&mut out)).unwrap();
+        let comments: Vec<_> = out.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(comments, vec!["# layer 1", "# layer 2", "# layer 3", "# measurements"], "{}", out);
+    }
+
+    #[test]
+    fn test_t_depth_leftover_clifford() {
+        let rotation = |x: &[usize], z: &[usize], angle| Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), angle);
+        // after one round, the first partition is reduced to an S gate, which a second round
+        // would push forward
+        let ops: Vec<_> = [
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[1], &[], Angle::PlusPi8),
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[1], &[], Angle::MinusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+        ].into_iter().map(Ok).collect();
+
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        let cfg = RunConfig { group_output_by_layer: true, approx_passes: 1, ..Default::default() };
+        let mut opt = <InMemoryOptimizer<Basis8> as Optimizer<_, Ops>>::new(2, ops.into_iter(), &cfg).unwrap();
+        Optimizer::<_, Ops>::push_t_forward(&mut opt).unwrap();
+        Optimizer::<_, Ops>::partition(&mut opt).unwrap();
+        assert_eq!(opt.circuit[..2], [rotation(&[], &[0], Angle::PlusPi4), rotation(&[0], &[], Angle::PlusPi8)]);
+        assert_eq!(opt.t_layers, Some(vec![0, 1]));
+        assert_eq!(Optimizer::<_, Ops>::t_depth(&opt), Some(1));
+
+        // the estimate for an unpartitioned circuit doesn't count the S gate either
+        assert_eq!(t_layer_starts(&opt.circuit), vec![1]);
+        assert_eq!(t_layer_starts(&[rotation(&[], &[0], Angle::PlusPi8), rotation(&[0], &[], Angle::PlusPi4), rotation(&[], &[1], Angle::PlusPi8)]), vec![0]);
+
+        let mut out = String::new();
+        Optimizer::<_, Ops>::write_to_output(opt, StringOut::new(&mut out)).unwrap();
+        let comments: Vec<_> = out.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(comments, vec!["# clifford", "# layer 1", "# measurements"], "{}", out);
+    }
+
+    #[test]
+    fn test_checkpoint_in_memory() {
+        let n_qubits = 8;
+        let mut rng = SmallRng::seed_from_u64(892);
+        let ops: Vec<_> = (0..64).map(|_| Ok(Operation::<Basis8>::rand(n_qubits, &mut rng))).collect();
+
+        // the optimizer is generic over the instruction iterator, so it needs to be named
+        type Ops = std::vec::IntoIter<anyhow::Result<Operation<Basis8>>>;
+        let mut opt = <InMemoryOptimizer<Basis8> as Optimizer<_, Ops>>::new(n_qubits, ops.into_iter(), &RunConfig::default()).unwrap();
+        assert!(Optimizer::<_, Ops>::checkpoint(&mut opt));
+        let before = opt.circuit.clone();
+
+        Optimizer::<_, Ops>::push_t_forward(&mut opt).unwrap();
+        assert_ne!(opt.circuit, before);
+
+        Optimizer::<_, Ops>::restore_checkpoint(&mut opt).unwrap();
+        assert_eq!(opt.circuit, before);
+        assert!(Optimizer::<_, Ops>::restore_checkpoint(&mut opt).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use log::{trace, warn};
+
+use crate::{basis::Basis, operation::Operation};
+
+
+/// Move measurements towards the end of the circuit, past the rotations they commute with.
+///
+/// A measurement only moves past rotations which commute with it (so the circuit is unchanged),
+/// and never past another measurement (so measurements stay in the same order). A measurement
+/// which is blocked by a non-commuting rotation is left there, with a warning; moving it would
+/// require applying the rotation to it.
+///
+/// Returns `(moved, blocked)`: the number of measurements which were moved, and the number which
+/// are still followed by a rotation.
+pub fn canonical_measure<B: Basis>(circuit: &mut [Operation<B>]) -> (usize, usize) {
+    trace!("moving measurements to the end of {} operations", circuit.len());
+    let mut moved = 0;
+    let mut blocked = 0;
+
+    // go backwards, so measurements later in the circuit are already as far along as they'll go
+    for index in (0..circuit.len()).rev() {
+        if !circuit[index].is_measurement() {
+            continue;
+        }
+
+        let mut new_index = index;
+        while new_index + 1 < circuit.len() {
+            let next = &circuit[new_index + 1];
+            if !next.is_rotation() || !next.commutes_with(&circuit[index]) {
+                break;
+            }
+            new_index += 1;
+        }
+
+        if new_index != index {
+            circuit[index..=new_index].rotate_left(1);
+            moved += 1;
+        }
+
+        if circuit.get(new_index + 1).is_some_and(|next| next.is_rotation()) {
+            blocked += 1;
+        }
+    }
+
+    if blocked > 0 {
+        warn!("{} measurement(s) don't commute with a later rotation and were left in place", blocked);
+    }
+    trace!("moved {} measurements", moved);
+    (moved, blocked)
+}
+
+
+/// Collapse runs of identical (same basis and phase) consecutive measurements into one, since
+/// measuring the same Pauli again immediately is redundant (see
+/// `--merge-adjacent-measurements-in-same-basis`).
+///
+/// Returns the number of measurements removed.
+pub fn merge_adjacent_measurements<B: Basis>(circuit: &mut Vec<Operation<B>>) -> usize {
+    let before = circuit.len();
+    circuit.dedup_by(|next, previous| next.is_measurement() && next == previous);
+    let removed = before - circuit.len();
+    trace!("merged {} adjacent measurements", removed);
+    removed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::*, operation::{angle::Angle, phase::Phase}};
+
+    use super::*;
+
+    fn rotation(x: &[usize], z: &[usize]) -> Operation<Basis8> {
+        Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), Angle::PlusPi8)
+    }
+
+    fn measurement(x: &[usize], z: &[usize]) -> Operation<Basis8> {
+        Operation::measurement(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), Phase::Positive)
+    }
+
+    #[test]
+    fn test_commuting() {
+        let mut circuit = vec![
+            measurement(&[], &[1]),
+            rotation(&[], &[0]),
+            measurement(&[], &[0]),
+            rotation(&[], &[0, 1]),
+            measurement(&[], &[0]),
+        ];
+
+        assert_eq!(canonical_measure(&mut circuit), (2, 0));
+        assert_eq!(circuit, vec![
+            rotation(&[], &[0]),
+            rotation(&[], &[0, 1]),
+            measurement(&[], &[1]),
+            measurement(&[], &[0]),
+            measurement(&[], &[0]),
+        ]);
+    }
+
+    #[test]
+    fn test_blocked() {
+        let mut circuit = vec![
+            measurement(&[], &[0]),
+            rotation(&[], &[1]),
+            rotation(&[0], &[]),
+            measurement(&[], &[1]),
+        ];
+
+        assert_eq!(canonical_measure(&mut circuit), (1, 1));
+        assert_eq!(circuit, vec![
+            rotation(&[], &[1]),
+            measurement(&[], &[0]),
+            rotation(&[0], &[]),
+            measurement(&[], &[1]),
+        ]);
+    }
+
+    #[test]
+    fn test_merge_adjacent_measurements() {
+        let negative = Operation::measurement(Basis8::zero(2), Basis8::with_true_bits(2, &[0]), Phase::Negative);
+        let mut circuit = vec![
+            measurement(&[], &[0]),
+            measurement(&[], &[0]),
+            measurement(&[], &[0]),
+            negative,
+            rotation(&[], &[0]),
+            rotation(&[], &[0]),
+            measurement(&[], &[0]),
+            measurement(&[], &[1]),
+        ];
+
+        // identical rotations and measurements with a different phase or basis are kept
+        assert_eq!(merge_adjacent_measurements(&mut circuit), 2);
+        assert_eq!(circuit, vec![
+            measurement(&[], &[0]),
+            negative,
+            rotation(&[], &[0]),
+            rotation(&[], &[0]),
+            measurement(&[], &[0]),
+            measurement(&[], &[1]),
+        ]);
+    }
+}
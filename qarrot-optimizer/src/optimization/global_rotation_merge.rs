@@ -0,0 +1,139 @@
+use log::trace;
+
+use crate::{basis::Basis, operation::Operation};
+
+use super::{AngleAlgebra, CliffordT, RotationCombineResult};
+
+
+/// How far forward (in operations) [`global_rotation_merge`] will look for a rotation to merge
+/// with.
+///
+/// Long runs of commuting rotations (e.g. all diagonal) would otherwise make the pass quadratic.
+pub const GLOBAL_ROTATION_MERGE_WINDOW: usize = 4096;
+
+
+/// Merge rotations about the same Pauli which are separated only by rotations they commute with.
+///
+/// For each rotation, scans forward past the rotations it commutes with, merging it with each one
+/// about the same Pauli (as [`CliffordT`] allows). Since it commutes with everything in between,
+/// it can be moved next to the other rotation, so this never changes the circuit. The merged
+/// rotation takes the place of the first. This is a commutation-aware version of
+/// [`super::reduce_rotations_no_ordering`], across the whole circuit:
+///
+/// - the scan stops at the first rotation that doesn't commute, and at any measurement or barrier
+/// - it looks at most `window` operations ahead
+///
+/// Returns the number of rotations removed.
+pub fn global_rotation_merge<B: Basis>(circuit: &mut Vec<Operation<B>>, window: usize) -> usize {
+    trace!("merging commuting rotations in {} operations", circuit.len());
+    let original_len = circuit.len();
+
+    for index in 0..circuit.len() {
+        if !circuit[index].is_rotation() || circuit[index].is_identity() {
+            continue;
+        }
+
+        let end = circuit.len().min(index.saturating_add(window).saturating_add(1));
+        for later in index + 1..end {
+            let (head, tail) = circuit.split_at_mut(later);
+            let (op, other) = (&mut head[index], &mut tail[0]);
+            if other.is_nop() {
+                continue;
+            }
+            if !other.is_rotation() || !op.commutes_with(other) {
+                break;
+            }
+            if op.x != other.x || op.z != other.z {
+                continue;
+            }
+
+            match CliffordT::combine(op, other) {
+                RotationCombineResult::CombineTo(merged) => {
+                    *op = merged;
+                    other.set_nop();
+                },
+                RotationCombineResult::KeepNeither => {
+                    op.set_nop();
+                    other.set_nop();
+                    break;
+                },
+                // e.g. pi/2 and pi/8; the other rotation still commutes, so keep looking
+                _ => (),
+            }
+        }
+    }
+
+    circuit.retain(|op| !op.is_nop());
+
+    let removed = original_len - circuit.len();
+    trace!("merged away {} rotations", removed);
+    removed
+}
+
+
+/// The number of T gates (pi/8 rotations) in the circuit, e.g. to report what
+/// [`global_rotation_merge`] saved.
+pub fn t_count<B: Basis>(circuit: &[Operation<B>]) -> usize {
+    circuit.iter().filter(|op| op.as_rotation().is_some_and(|rotation| rotation.angle.is_pi8())).count()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::*, operation::angle::Angle};
+
+    use super::*;
+
+    fn rotation(x: &[usize], z: &[usize], angle: Angle) -> Operation<Basis8> {
+        Operation::rotation(Basis8::with_true_bits(2, x), Basis8::with_true_bits(2, z), angle)
+    }
+
+    #[test]
+    fn test_merge_past_commuting() {
+        // ZI commutes with IZ and ZZ, but not with XI
+        let mut circuit = vec![
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[], &[1], Angle::PlusPi8),
+            rotation(&[], &[0, 1], Angle::MinusPi8),
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+            rotation(&[], &[0], Angle::PlusPi8),
+        ];
+
+        assert_eq!(t_count(&circuit), 6);
+        assert_eq!(global_rotation_merge(&mut circuit, GLOBAL_ROTATION_MERGE_WINDOW), 1);
+        assert_eq!(t_count(&circuit), 4);
+        assert_eq!(circuit, vec![
+            rotation(&[], &[0], Angle::PlusPi4),
+            rotation(&[], &[1], Angle::PlusPi8),
+            rotation(&[], &[0, 1], Angle::MinusPi8),
+            rotation(&[0], &[], Angle::PlusPi8),
+            rotation(&[], &[0], Angle::PlusPi8),
+        ]);
+    }
+
+    #[test]
+    fn test_cancel_and_window() {
+        let mut circuit = vec![
+            rotation(&[], &[0], Angle::PlusPi8),
+            rotation(&[], &[1], Angle::PlusPi8),
+            rotation(&[], &[0], Angle::MinusPi8),
+        ];
+        let mut limited = circuit.clone();
+
+        assert_eq!(global_rotation_merge(&mut limited, 1), 0);
+        assert_eq!(global_rotation_merge(&mut circuit, 2), 2);
+        assert_eq!(circuit, vec![rotation(&[], &[1], Angle::PlusPi8)]);
+    }
+
+    #[test]
+    fn test_stops_at_measurement() {
+        let mut circuit = vec![
+            rotation(&[], &[0], Angle::PlusPi8),
+            Operation::measurement(Basis8::zero(2), Basis8::with_true_bits(2, &[1]), false.into()),
+            rotation(&[], &[0], Angle::PlusPi8),
+        ];
+        assert_eq!(global_rotation_merge(&mut circuit, GLOBAL_ROTATION_MERGE_WINDOW), 0);
+        assert_eq!(circuit.len(), 3);
+    }
+}
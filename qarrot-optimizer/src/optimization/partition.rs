@@ -1,15 +1,26 @@
-use log::trace;
+use log::{info, trace};
+use rayon::{prelude::*, ThreadPool};
 
 use crate::{basis::Basis, operation::Operation, optimization::{partitions::Partitions, reduce_rotations_no_ordering}, reduce_rotations_no_ordering_slice, Stats};
 
 
-pub fn update_t_gate_partitions<B: Basis>(circuit: &[Operation<B>], partitions: &mut Partitions) -> bool {
+/// Move each T gate down into the previous partition while it commutes with all of that
+/// partition's members.
+///
+/// With a `size_cap`, a partition with that many members takes no more, which bounds the (otherwise
+/// quadratic) commutation checks at the cost of maybe missing some merges (see
+/// `--partition-size-cap`).
+pub fn update_t_gate_partitions<B: Basis>(circuit: &[Operation<B>], partitions: &mut Partitions, size_cap: Option<usize>) -> bool {
     let mut partitions_changed = true;
     while partitions_changed {
         partitions_changed = false;
         // for each partition (other than the last)
         
         partitions_changed |= partitions.swap_down(|prev_partition, this_rotation| {
+            if size_cap.is_some_and(|cap| prev_partition.len() >= cap) {
+                return false;
+            }
+
             let mut commutes_with_all = true;
 
             for prev_index in prev_partition {
@@ -26,7 +37,71 @@ pub fn update_t_gate_partitions<B: Basis>(circuit: &[Operation<B>], partitions:
 }
 
 
-fn merge_partitions<B: Basis>(circuit: &mut Vec<Operation<B>>, partitions: &Partitions, t_gate_count: usize, original_len: usize) -> bool {
+/// Where each T-layer of a circuit the partitioner hasn't grouped starts: a layer is a run of pi/8
+/// rotations that all commute with each other. Clifford rotations are passed over (they're in
+/// whichever layer is open, but don't start or end one); anything other than a rotation (e.g. a
+/// measurement) ends the layer. Like the recorded layers, each start is the index of the layer's
+/// first rotation among the circuit's rotations.
+///
+/// Only an estimate; once the circuit has been partitioned, the layers recorded by
+/// [`partition_t_gates`] or [`approximate_partition_t_gates`] are the real ones.
+pub fn t_layer_starts<B: Basis>(circuit: &[Operation<B>]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut layer_start = None;
+    let mut rotations = 0;
+    for (index, op) in circuit.iter().enumerate() {
+        if !op.is_rotation() {
+            layer_start = None;
+            continue;
+        }
+        if !is_t_gate(op) {
+            rotations += 1;
+            continue;
+        }
+        let in_layer = layer_start.is_some_and(|start| circuit[start..index].iter().filter(|prev| is_t_gate(prev)).all(|prev| op.commutes_with(prev)));
+        if !in_layer {
+            starts.push(rotations);
+            layer_start = Some(index);
+        }
+        rotations += 1;
+    }
+    starts
+}
+
+
+/// Which of the layers starting at `layer_starts` (see [`t_layer_starts`]) have a pi/8 rotation;
+/// the others are left with only Clifford rotations, and don't add to the T-depth.
+pub fn t_layers_with_t_gates<B: Basis>(circuit: &[Operation<B>], layer_starts: &[usize]) -> Vec<bool> {
+    let mut has_t_gate = vec![false; layer_starts.len()];
+    let mut layer = 0;
+    for (rotation, op) in circuit.iter().filter(|op| op.is_rotation()).enumerate() {
+        while layer_starts.get(layer + 1).is_some_and(|start| *start <= rotation) {
+            layer += 1;
+        }
+        if layer_starts.first().is_some_and(|start| *start <= rotation) && is_t_gate(op) {
+            has_t_gate[layer] = true;
+        }
+    }
+    has_t_gate
+}
+
+
+fn is_t_gate<B: Basis>(op: &Operation<B>) -> bool {
+    op.as_rotation().is_some_and(|rotation| rotation.angle.is_pi8())
+}
+
+
+/// Below this many partitions, [`merge_partitions_parallel`] isn't worth the overhead of
+/// spreading the work across threads.
+pub const MIN_PARALLEL_PARTITIONS: usize = 64;
+
+
+/// Reduce each partition, and replace the circuit's `t_gate_count` leading T gates with the
+/// reduced partitions, in order.
+///
+/// The start of each reduced partition (as an index into the circuit) is pushed onto `layers`;
+/// partitions that reduce to nothing are left out.
+fn merge_partitions<B: Basis>(circuit: &mut Vec<Operation<B>>, partitions: &Partitions, t_gate_count: usize, original_len: usize, layers: &mut Vec<usize>) -> bool {
     trace!("merging {} partitions", partitions.len());
     // reduce within each partition
     // this buffer should be unnecessary, and there's a bunch of unneeded copies here
@@ -44,6 +119,9 @@ fn merge_partitions<B: Basis>(circuit: &mut Vec<Operation<B>>, partitions: &Part
         }
         changed |= reduce_rotations_no_ordering(&mut layer_buf, &mut index_buf);
 
+        if !layer_buf.is_empty() {
+            layers.push(combined_t_gates.len());
+        }
         combined_t_gates.append(&mut layer_buf);
     }
 
@@ -68,7 +146,104 @@ fn merge_partitions<B: Basis>(circuit: &mut Vec<Operation<B>>, partitions: &Part
 }
 
 
-pub fn partition_t_gates<B: Basis>(partitions: &mut Partitions, circuit: &mut Vec<Operation<B>>, t_gate_count: usize) -> bool {
+/// Like [`merge_partitions`], but reduces the partitions in parallel on `pool`.
+///
+/// Each partition is reduced into its own buffer, and the buffers are concatenated in partition
+/// order, so the result is identical to the sequential version.
+fn merge_partitions_parallel<B: Basis>(circuit: &mut Vec<Operation<B>>, partitions: &Partitions, t_gate_count: usize, original_len: usize, layers: &mut Vec<usize>, pool: &ThreadPool) -> bool {
+    trace!("merging {} partitions on {} threads", partitions.len(), pool.current_num_threads());
+    let (indexes, boundaries) = partitions.as_slices();
+    let source: &[Operation<B>] = circuit;
+
+    let reduced: Vec<(Vec<Operation<B>>, bool)> = pool.install(|| {
+        (0..boundaries.len())
+            .into_par_iter()
+            .map_init(Vec::new, |index_buf, partition| {
+                let end = boundaries.get(partition + 1).copied().unwrap_or(indexes.len());
+                let mut layer_buf: Vec<_> = indexes[boundaries[partition]..end]
+                    .iter()
+                    .map(|element| source[*element].clone())
+                    .collect();
+                let changed = reduce_rotations_no_ordering(&mut layer_buf, index_buf);
+                (layer_buf, changed)
+            })
+            .collect()
+    });
+
+    let changed = reduced.iter().any(|(_, changed)| *changed);
+    let combined_len = reduced.iter().map(|(layer, _)| layer.len()).sum::<usize>();
+
+    // everything past the original t gates is kept as is, after the reduced partitions
+    let rest = circuit.split_off(t_gate_count);
+    circuit.clear();
+    circuit.reserve(combined_len + rest.len());
+    for (mut layer, _) in reduced {
+        if !layer.is_empty() {
+            layers.push(circuit.len());
+        }
+        circuit.append(&mut layer);
+    }
+    circuit.extend(rest);
+    debug_assert!(circuit.len() <= original_len);
+    trace!("final operation count: {} (changed: {})", circuit.len(), changed);
+
+    changed
+}
+
+
+/// Partition the `t_gate_count` pi/8 rotations into commuting layers, and reduce each layer.
+///
+/// Any non-rotation (i.e. a measurement in the middle of the circuit) is a barrier: the rotations
+/// between two barriers are partitioned on their own, like in [`approximate_partition_t_gates`].
+///
+/// If `pool` is given and there are at least [`MIN_PARALLEL_PARTITIONS`] partitions, the layers
+/// are reduced in parallel.
+///
+/// With `dump_partitions`, each run's partitions (as indexes into the run) are logged before
+/// they're reduced (see `--dump-partitions`). `size_cap` limits each partition's size (see
+/// [`update_t_gate_partitions`]).
+///
+/// `layers` is set to where each reduced partition (T-layer) starts, as the index of its first
+/// rotation among the circuit's rotations (see `--group-output-by-layer`).
+pub fn partition_t_gates<B: Basis>(partitions: &mut Partitions, circuit: &mut Vec<Operation<B>>, t_gate_count: usize, pool: Option<&ThreadPool>, dump_partitions: bool, size_cap: Option<usize>, layers: &mut Vec<usize>) -> bool {
+    layers.clear();
+    if circuit[..t_gate_count.min(circuit.len())].iter().all(|op| op.is_rotation()) {
+        // the usual case: all the measurements are at the end
+        return partition_leading_t_gates(partitions, circuit, t_gate_count, pool, dump_partitions, size_cap, layers);
+    }
+
+    trace!("measurements among the t gates; partitioning each run of rotations separately");
+    let mut changed = false;
+    let mut partitioned = Vec::with_capacity(circuit.len());
+    let mut rotations = 0;
+    let mut start = 0;
+    while start < circuit.len() {
+        if !circuit[start].is_rotation() {
+            partitioned.push(circuit[start].clone());
+            start += 1;
+            continue;
+        }
+
+        let run_len = circuit[start..].iter().take_while(|op| op.is_rotation()).count();
+        let mut run = circuit[start..start + run_len].to_vec();
+        let run_layers = layers.len();
+        changed |= partition_leading_t_gates(partitions, &mut run, run_len, pool, dump_partitions, size_cap, layers);
+        // the run's layers start at indexes into the run
+        for layer in &mut layers[run_layers..] {
+            *layer += rotations;
+        }
+        rotations += run.len();
+        partitioned.append(&mut run);
+        start += run_len;
+    }
+
+    *circuit = partitioned;
+    changed
+}
+
+
+/// [`partition_t_gates`] for a circuit whose `t_gate_count` leading operations are all pi/8 rotations.
+fn partition_leading_t_gates<B: Basis>(partitions: &mut Partitions, circuit: &mut Vec<Operation<B>>, t_gate_count: usize, pool: Option<&ThreadPool>, dump_partitions: bool, size_cap: Option<usize>, layers: &mut Vec<usize>) -> bool {
     let original_len = circuit.len();
     trace!("starting t gate partition with {} operations", original_len);
 
@@ -86,12 +261,18 @@ pub fn partition_t_gates<B: Basis>(partitions: &mut Partitions, circuit: &mut Ve
 
     while partitions_changed {
         trace!("partitions changed (currently {}); running partition round {}", partitions.len(), rounds + 1);
-        partitions_changed = update_t_gate_partitions(circuit, partitions);
+        partitions_changed = update_t_gate_partitions(circuit, partitions, size_cap);
         rounds += 1;
     }
 
     trace!("done creating {} partitions", partitions.len());
-    merge_partitions(circuit, &partitions, t_gate_count, original_len)
+    if dump_partitions {
+        info!("partitions: {}", partitions);
+    }
+    match pool {
+        Some(pool) if partitions.len() >= MIN_PARALLEL_PARTITIONS => merge_partitions_parallel(circuit, partitions, t_gate_count, original_len, layers, pool),
+        _ => merge_partitions(circuit, partitions, t_gate_count, original_len, layers),
+    }
 }
 
 
@@ -125,12 +306,18 @@ pub fn partition_t_gates<B: Basis>(partitions: &mut Partitions, circuit: &mut Ve
 
 
 
-pub fn approximate_partition_t_gates<B: Basis>(circuit: &mut Vec<Operation<B>>) -> (bool, Stats) {
+/// Greedily partition the circuit into runs of commuting rotations in a single scan, and reduce
+/// each run.
+///
+/// `layers` is set to where each reduced partition (T-layer) starts, as in [`partition_t_gates`].
+pub fn approximate_partition_t_gates<B: Basis>(circuit: &mut Vec<Operation<B>>, layers: &mut Vec<usize>) -> (bool, Stats) {
     let original_len = circuit.len();
     trace!("starting whole circuit t gate partition (approximate) with {} operations", original_len);
 
     let mut partition_start = None;
     let mut partitions = 0usize;
+    // the index of each partition's first rotation
+    let mut partition_starts = Vec::new();
     let mut last_rotation_index = 0usize;
 
     let mut changed = false;
@@ -146,6 +333,7 @@ pub fn approximate_partition_t_gates<B: Basis>(circuit: &mut Vec<Operation<B>>)
         last_rotation_index = new_index;
         if partition_start.is_none() {
             partition_start = Some(new_index);
+            partition_starts.push(new_index);
             continue;
         }
 
@@ -162,31 +350,188 @@ pub fn approximate_partition_t_gates<B: Basis>(circuit: &mut Vec<Operation<B>>)
             partitions += 1;
             changed |= reduce_rotations_no_ordering_slice(&mut circuit[partition_start.unwrap()..new_index]);
             partition_start = Some(new_index);
+            partition_starts.push(new_index);
         }
     }
 
     if let Some(partition_start) = partition_start {
-        changed |= reduce_rotations_no_ordering_slice(&mut circuit[partition_start..last_rotation_index]);
+        // only reached when the circuit ends with rotations, e.g. with --retain-input-measurements-order
+        changed |= reduce_rotations_no_ordering_slice(&mut circuit[partition_start..=last_rotation_index]);
     }
 
     trace!("done partitioning ({} partitions) and reducing. cleaning removed rotations…", partitions);
 
+    // a partition's layer starts at its first rotation left after reducing, if any
+    layers.clear();
+    let mut rotations = 0;
+    let mut partition = 0;
+    let mut last_layer = None;
+    for (index, op) in circuit.iter().enumerate() {
+        while partition_starts.get(partition).is_some_and(|start| *start <= index) {
+            partition += 1;
+        }
+        if op.is_rotation() {
+            if last_layer != Some(partition) {
+                layers.push(rotations);
+                last_layer = Some(partition);
+            }
+            rotations += 1;
+        }
+    }
+
     circuit.retain(|op| {
         !op.is_nop()
     });
 
-    let mut stats = Stats {
-        total_operations: circuit.len(),
-        t_gates: 0,
-    };
+    (changed, Stats::of(circuit))
+}
 
-    for op in circuit.iter() {
-        if let Some(r) = op.as_rotation() {
-            if r.angle.is_pi8() {
-                stats.t_gates += 1;
-            }
+
+/// Repeat [`approximate_partition_t_gates`] up to `max_passes` times (without limit if
+/// `max_passes` is 0), stopping early once a pass doesn't remove any operations.
+///
+/// Reducing one partition can let its neighbours merge across the old boundary, which the single
+/// greedy scan misses. `layers` is set to the last pass's T-layers.
+pub fn approximate_partition_t_gates_passes<B: Basis>(circuit: &mut Vec<Operation<B>>, max_passes: usize, layers: &mut Vec<usize>) -> (bool, Stats) {
+    let (mut changed, mut stats) = approximate_partition_t_gates(circuit, layers);
+    let mut passes = 1;
+
+    while max_passes == 0 || passes < max_passes {
+        let before = stats.total_operations;
+        let (pass_changed, pass_stats) = approximate_partition_t_gates(circuit, layers);
+        changed |= pass_changed;
+        stats = pass_stats;
+        passes += 1;
+
+        if !pass_changed || stats.total_operations >= before {
+            break;
         }
     }
 
+    trace!("ran {} approximate partitioning passes", passes);
     (changed, stats)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::{basis::{Basis, Basis8}, operation::angle::Angle};
+
+    use super::*;
+
+    #[test]
+    fn test_merge_partitions_parallel() {
+        let mut rng = SmallRng::seed_from_u64(899);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+        let t_gate_count = 400;
+        let mut circuit: Vec<_> = (0..t_gate_count)
+            .map(|_| Operation::rotation(Basis8::rand(3, &mut rng), Basis8::rand(3, &mut rng), Angle::PlusPi8))
+            .collect();
+        for q in 0..3 {
+            circuit.push(Operation::measurement(Basis8::zero(3), Basis8::one_bit(3, q), false.into()));
+        }
+
+        let mut partitions = Partitions::new();
+        partitions.init_one_per_t_gate(t_gate_count);
+        update_t_gate_partitions(&circuit, &mut partitions, None);
+        assert!(partitions.len() >= MIN_PARALLEL_PARTITIONS);
+
+        let mut sequential = circuit.clone();
+        let mut parallel = circuit.clone();
+        let (mut sequential_layers, mut parallel_layers) = (Vec::new(), Vec::new());
+        let sequential_changed = merge_partitions(&mut sequential, &partitions, t_gate_count, circuit.len(), &mut sequential_layers);
+        let parallel_changed = merge_partitions_parallel(&mut parallel, &partitions, t_gate_count, circuit.len(), &mut parallel_layers, &pool);
+
+        assert_eq!(sequential_changed, parallel_changed);
+        assert_eq!(sequential, parallel);
+        assert_eq!(sequential_layers, parallel_layers);
+    }
+
+    #[test]
+    fn test_partition_mid_circuit_measurement() {
+        let z = |angle| Operation::rotation(Basis8::zero(1), Basis8::one(1), angle);
+        let x = |angle| Operation::rotation(Basis8::one(1), Basis8::zero(1), angle);
+        let measure_x = Operation::measurement(Basis8::one(1), Basis8::zero(1), false.into());
+        let measure_z = Operation::measurement(Basis8::zero(1), Basis8::one(1), false.into());
+
+        // the first two T gates commute with the Z measurement, but may not merge across it
+        let circuit = vec![
+            z(Angle::PlusPi8), measure_z, z(Angle::PlusPi8), x(Angle::MinusPi8), measure_x, x(Angle::MinusPi8), x(Angle::MinusPi8), measure_z,
+        ];
+        let t_gate_count = circuit.iter().filter(|op| op.is_rotation()).count();
+
+        let mut full = circuit.clone();
+        let mut full_layers = Vec::new();
+        assert!(partition_t_gates(&mut Partitions::new(), &mut full, t_gate_count, None, false, None, &mut full_layers));
+        assert_eq!(full, vec![
+            z(Angle::PlusPi8), measure_z, z(Angle::PlusPi8), x(Angle::MinusPi8), measure_x, x(Angle::MinusPi4), measure_z,
+        ]);
+        // the layers are counted in rotations, across the measurements
+        assert_eq!(full_layers, vec![0, 1, 2, 3]);
+
+        let mut approximate = circuit.clone();
+        let mut approximate_layers = Vec::new();
+        approximate_partition_t_gates(&mut approximate, &mut approximate_layers);
+        assert_eq!(full, approximate);
+        assert_eq!(full_layers, approximate_layers);
+    }
+
+    #[test]
+    fn test_approximate_partition_trailing_rotations() {
+        let z = |angle| Operation::rotation(Basis8::zero(1), Basis8::one(1), angle);
+
+        // without a measurement to close it, the last partition runs to the end of the circuit,
+        // and its last rotation has to be reduced along with the rest
+        let mut circuit = vec![z(Angle::PlusPi8), z(Angle::PlusPi8)];
+        let mut layers = Vec::new();
+        let (changed, stats) = approximate_partition_t_gates(&mut circuit, &mut layers);
+        assert!(changed);
+        assert_eq!(stats.t_gates, 0);
+        assert_eq!(circuit, vec![z(Angle::PlusPi4)]);
+    }
+
+    #[test]
+    fn test_partition_size_cap() {
+        let mut rng = SmallRng::seed_from_u64(969);
+        // diagonal, so they all commute
+        let circuit: Vec<_> = (0..10)
+            .map(|_| Operation::rotation(Basis8::zero(4), Basis8::rand(4, &mut rng), Angle::PlusPi8))
+            .collect();
+
+        let mut partitions = Partitions::new();
+        partitions.init_one_per_t_gate(circuit.len());
+        update_t_gate_partitions(&circuit, &mut partitions, None);
+        assert_eq!(partitions.len(), 1);
+
+        partitions.init_one_per_t_gate(circuit.len());
+        update_t_gate_partitions(&circuit, &mut partitions, Some(3));
+        assert_eq!(partitions.iter().map(|partition| partition.len()).collect::<Vec<_>>(), vec![3, 3, 3, 1]);
+    }
+
+    #[test]
+    fn test_approximate_passes() {
+        let z = |angle| Operation::rotation(Basis8::zero(1), Basis8::one(1), angle);
+        let x = |angle| Operation::rotation(Basis8::one(1), Basis8::zero(1), angle);
+        let measure = Operation::measurement(Basis8::zero(1), Basis8::one(1), false.into());
+        // the X rotations cancel, but only after the first pass has split the Zs into different partitions
+        let circuit = vec![z(Angle::PlusPi8), x(Angle::PlusPi8), x(Angle::MinusPi8), z(Angle::PlusPi8), measure];
+
+        let mut single = circuit.clone();
+        let mut layers = Vec::new();
+        let (_, stats) = approximate_partition_t_gates_passes(&mut single, 1, &mut layers);
+        assert_eq!(stats.t_gates, 2);
+        assert_eq!(single, vec![z(Angle::PlusPi8), z(Angle::PlusPi8), measure]);
+        // the Zs are still in the partitions they were put in, on either side of the cancelled Xs
+        assert_eq!(layers, vec![0, 1]);
+
+        let mut fixpoint = circuit.clone();
+        let (changed, stats) = approximate_partition_t_gates_passes(&mut fixpoint, 0, &mut layers);
+        assert!(changed);
+        assert_eq!(stats.t_gates, 0);
+        assert_eq!(fixpoint, vec![z(Angle::PlusPi4), measure]);
+        assert_eq!(layers, vec![0]);
+    }
+}
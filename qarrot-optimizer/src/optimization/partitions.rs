@@ -1,4 +1,4 @@
-use std::ops::Index;
+use std::{fmt, ops::Index};
 
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -129,6 +129,15 @@ impl Partitions {
         changed
     }
 
+    /// The underlying `(indexes, boundaries)`, for consumers which need the whole structure at
+    /// once (e.g. to split the partitions across threads) rather than a serial iterator.
+    ///
+    /// Partition `i` is `indexes[boundaries[i]..boundaries[i + 1]]`, and the last partition runs to
+    /// the end of `indexes`.
+    pub fn as_slices(&self) -> (&[usize], &[usize]) {
+        (&self.indexes, &self.boundaries)
+    }
+
     pub fn iter<'a>(&'a self) -> PartitionIter<'a> {
         PartitionIter {
             partition: &self,
@@ -138,6 +147,27 @@ impl Partitions {
 }
 
 
+/// Each partition's indexes, e.g. `[0,1,4] [2,3] [5]`.
+impl fmt::Display for Partitions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, partition) in self.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str("[")?;
+            for (j, index) in partition.iter().enumerate() {
+                if j != 0 {
+                    f.write_str(",")?;
+                }
+                write!(f, "{}", index)?;
+            }
+            f.write_str("]")?;
+        }
+        Ok(())
+    }
+}
+
+
 // get the i'th partition
 impl Index<usize> for Partitions {
     type Output = [usize];
@@ -182,6 +212,17 @@ impl<'a> ExactSizeIterator for PartitionIter<'a> {}
 mod tests {
     use super::Partitions;
 
+    #[test]
+    fn test_display() {
+        let partitions = Partitions { indexes: vec![0, 1, 4, 2, 3, 5], boundaries: vec![0, 3, 5] };
+        assert_eq!(partitions.to_string(), "[0,1,4] [2,3] [5]");
+        assert_eq!(Partitions::new().to_string(), "");
+
+        let mut partitions = Partitions::new();
+        partitions.init_one_per_t_gate(2);
+        assert_eq!(partitions.to_string(), "[0] [1]");
+    }
+
     #[test]
     fn test_basic() {
         let mut new = Partitions::new();
@@ -218,6 +259,23 @@ mod tests {
         assert_eq!(&new[1], &[2]);
     }
 
+    #[test]
+    fn test_as_slices() {
+        let mut new = Partitions::new();
+        new.init(7, |_last_partition: &[usize], this_index| { this_index % 3 == 0 });
+        new.swap_down(|_, i| i == 4);
+
+        let (indexes, boundaries) = new.as_slices();
+        let mut reconstructed = Vec::new();
+        for (i, &start) in boundaries.iter().enumerate() {
+            let end = boundaries.get(i + 1).copied().unwrap_or(indexes.len());
+            reconstructed.push(&indexes[start..end]);
+        }
+
+        assert_eq!(reconstructed, new.iter().collect::<Vec<_>>());
+        assert_eq!(reconstructed.len(), 3);
+    }
+
     #[test]
     fn test_swap_down_single() {
         let mut new = Partitions::new();
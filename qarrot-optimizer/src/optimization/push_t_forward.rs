@@ -1,6 +1,6 @@
 use log::trace;
 
-use crate::{basis::Basis, clifford::Clifford, operation::{angle::Angle, Operation, OperationKind}};
+use crate::{basis::Basis, clifford::Clifford, operation::{angle::Angle, phase::GlobalPhase, Operation, OperationKind}};
 
 // returns result (did_change, number_of_t_gates)
 // pub fn push_t_forward<B: Basis>(output: &mut Vec<Operation<B>>, circuit: &[Operation<B>], n_qubits: usize) -> anyhow::Result<(bool, usize)> {
@@ -27,12 +27,14 @@ use crate::{basis::Basis, clifford::Clifford, operation::{angle::Angle, Operatio
 // }
 
 
-pub fn push_t_forward_inplace<B: Basis>(circuit: &mut Vec<Operation<B>>, n_qubits: usize) -> (bool, usize) {
+/// Push the T gates forward through the Clifford rotations, absorbing the latter.
+///
+/// `global_phase` is multiplied by the global phase this drops (see [`push_accumulator`]).
+pub fn push_t_forward_inplace<B: Basis>(circuit: &mut Vec<Operation<B>>, n_qubits: usize, global_phase: &mut GlobalPhase) -> (bool, usize) {
     trace!("pushing T gates forwards. current circuit length {}.", circuit.len());
 
     let mut changed_last_iteration = false;
     let mut accumulator = Clifford::identity(n_qubits);
-    let mut clifford_buf = Clifford::identity(n_qubits);
 
     let mut t_gate_count = 0;
 
@@ -40,7 +42,7 @@ pub fn push_t_forward_inplace<B: Basis>(circuit: &mut Vec<Operation<B>>, n_qubit
 
     for op_index in 0..circuit.len() {
         debug_assert!(out_index <= op_index);
-        let (did_change, was_t_gate, new_operation) = push_accumulator(&mut accumulator, &mut clifford_buf, &circuit[op_index]);
+        let (did_change, was_t_gate, new_operation) = push_accumulator(&mut accumulator, global_phase, &circuit[op_index]);
         changed_last_iteration |= did_change;
         if was_t_gate {
             t_gate_count += 1;
@@ -60,34 +62,43 @@ pub fn push_t_forward_inplace<B: Basis>(circuit: &mut Vec<Operation<B>>, n_qubit
 
 
 // returns (did_change, was_t_gate, new_operation)
+//
+// the input is the output followed by the Cliffords absorbed into `accumulator` (in input order),
+// times the global phase multiplied into `global_phase`
 #[inline(always)]
-pub fn push_accumulator<B: Basis>(accumulator: &mut Clifford<B>, clifford_buf: &mut Clifford<B>, op: &Operation<B>) -> (bool, bool, Option<Operation<B>>) {
+pub fn push_accumulator<B: Basis>(accumulator: &mut Clifford<B>, global_phase: &mut GlobalPhase, op: &Operation<B>) -> (bool, bool, Option<Operation<B>>) {
     match op.kind {
         OperationKind::Nop => {
             panic!("nop found while pushing T gates forward")
         },
+        OperationKind::Barrier => (false, false, Some(op.clone())),
         OperationKind::Measurement { phase } => {
-            let new_symplectic = accumulator.conjugate(phase.sign_bit(), &op.x, &op.z);
+            let new_symplectic = match op.single_z_qubit() {
+                // e.g. the Z measurements appended to every circuit
+                Some(qubit) => {
+                    let mut new_symplectic = accumulator.conjugate_basis_z(qubit);
+                    new_symplectic.sign ^= phase.sign_bit();
+                    new_symplectic
+                },
+                _ => accumulator.conjugate(phase.sign_bit(), &op.x, &op.z),
+            };
             let changed_last_iteration = (new_symplectic.x != op.x) || (new_symplectic.z != op.z) || (new_symplectic.sign != phase.sign_bit());
-            let new_measurement = Operation::measurement(new_symplectic.x, new_symplectic.z, new_symplectic.sign.into());
+            let new_measurement = Operation::measurement(new_symplectic.x, new_symplectic.z, new_symplectic.sign.into()).with_src_line(op.src_line());
             (changed_last_iteration, false, Some(new_measurement))
         },
         OperationKind::Rotation { angle } => match angle {
             Angle::PlusPi8 | Angle::MinusPi8 => {
                 let new_symplectic = accumulator.conjugate(angle.sign_bit(), &op.x, &op.z);
                 let changed_last_iteration = (new_symplectic.x != op.x) || (new_symplectic.z != op.z);
-                (changed_last_iteration, true, Some(Operation::rotation(new_symplectic.x, new_symplectic.z, angle.use_sign_bit(new_symplectic.sign))))
+                if new_symplectic.sign != angle.sign_bit() {
+                    // conjugated to a rotation about -P
+                    *global_phase *= GlobalPhase::of_negated_axis(angle);
+                }
+                let new_rotation = Operation::rotation(new_symplectic.x, new_symplectic.z, angle.use_sign_bit(new_symplectic.sign)).with_src_line(op.src_line());
+                (changed_last_iteration, true, Some(new_rotation))
             },
-            Angle::Pi2 => {
-                clifford_buf.from_pi2(angle.sign_bit(), &op.x, &op.z);
-                *clifford_buf *= &accumulator;
-                accumulator.set_to(&clifford_buf);
-                (true, false, None)
-            },
-            Angle::PlusPi4 | Angle::MinusPi4 => {
-                clifford_buf.from_pi4(angle.sign_bit(), &op.x, &op.z);
-                *clifford_buf *= &accumulator; // todo: fix this operation order
-                accumulator.set_to(&clifford_buf);
+            Angle::Pi2 | Angle::PlusPi4 | Angle::MinusPi4 => {
+                op.apply_to_clifford(accumulator);
                 (true, false, None)
             },
         },
@@ -0,0 +1,113 @@
+use log::trace;
+
+use crate::{basis::Basis, operation::Operation};
+
+
+/// How far back (in operations) [`reorder_independent`] will move a single rotation.
+///
+/// Bounds the pass to linear time; anything further is left where it is.
+pub const REORDER_INDEPENDENT_WINDOW: usize = 256;
+
+
+/// Stable reordering which clusters rotations acting on overlapping qubits.
+///
+/// Each rotation is moved backwards past the directly preceding rotations it shares no qubits
+/// with, so that it lands right after the last rotation it overlaps. Since operations with
+/// disjoint support always commute, this never changes the circuit. This is deliberately
+/// conservative:
+///
+/// - measurements are never moved, and nothing is moved past a measurement
+/// - a rotation is only moved if it would end up adjacent to one it overlaps (otherwise there's no
+///   locality to gain)
+/// - rotations are moved at most `window` places
+///
+/// Returns the number of rotations that were moved.
+pub fn reorder_independent<B: Basis>(circuit: &mut [Operation<B>], window: usize) -> usize {
+    trace!("reordering independent rotations in {} operations", circuit.len());
+    let mut moved = 0;
+
+    for index in 1..circuit.len() {
+        if !circuit[index].is_rotation() {
+            continue;
+        }
+
+        let mut new_index = index;
+        while new_index > 0 && index - new_index < window {
+            let prev = &circuit[new_index - 1];
+            if !prev.is_rotation() || prev.overlaps(&circuit[index]) {
+                break;
+            }
+            new_index -= 1;
+        }
+
+        // only move if we stopped next to an overlapping rotation
+        if new_index != index && new_index > 0 && circuit[new_index - 1].is_rotation() && circuit[new_index - 1].overlaps(&circuit[index]) {
+            circuit[new_index..=index].rotate_right(1);
+            moved += 1;
+        }
+    }
+
+    trace!("moved {} rotations", moved);
+    moved
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::*, operation::{angle::Angle, phase::Phase}};
+
+    use super::*;
+
+    fn z_rotation(n_qubits: usize, qubits: &[usize]) -> Operation<Basis8> {
+        Operation::rotation(Basis8::zero(n_qubits), Basis8::with_true_bits(n_qubits, qubits), Angle::PlusPi8)
+    }
+
+    #[test]
+    fn test_cluster() {
+        let mut circuit = vec![
+            z_rotation(4, &[0]),
+            z_rotation(4, &[1]),
+            z_rotation(4, &[2]),
+            z_rotation(4, &[0, 3]),
+            z_rotation(4, &[1]),
+        ];
+
+        let moved = reorder_independent(&mut circuit, REORDER_INDEPENDENT_WINDOW);
+        assert_eq!(moved, 2);
+        assert_eq!(circuit, vec![
+            z_rotation(4, &[0]),
+            z_rotation(4, &[0, 3]),
+            z_rotation(4, &[1]),
+            z_rotation(4, &[1]),
+            z_rotation(4, &[2]),
+        ]);
+    }
+
+    #[test]
+    fn test_measurement_barrier() {
+        let measurement = Operation::measurement(Basis8::zero(4), Basis8::one_bit(4, 3), Phase::Positive);
+        let mut circuit = vec![
+            z_rotation(4, &[0]),
+            measurement,
+            z_rotation(4, &[0]),
+        ];
+        let expected = circuit.clone();
+
+        assert_eq!(reorder_independent(&mut circuit, REORDER_INDEPENDENT_WINDOW), 0);
+        assert_eq!(circuit, expected);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut circuit = vec![
+            z_rotation(4, &[0]),
+            z_rotation(4, &[1]),
+            z_rotation(4, &[2]),
+            z_rotation(4, &[0]),
+        ];
+        let expected = circuit.clone();
+
+        assert_eq!(reorder_independent(&mut circuit, 1), 0);
+        assert_eq!(circuit, expected);
+    }
+}
@@ -0,0 +1,120 @@
+use log::trace;
+
+use crate::{basis::Basis, operation::Operation, output::Output};
+
+
+/// Longest block [`find_repeated_blocks`] looks for with `--split-by-repeat`.
+pub const MAX_REPEAT_BLOCK_LEN: usize = 256;
+
+
+/// `repeats` consecutive copies of `circuit[start..start + len]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RepeatedBlock {
+    pub start: usize,
+    pub len: usize,
+    pub repeats: usize,
+}
+
+
+/// Find runs of identical consecutive blocks (of at most `max_block_len` operations), so they can
+/// be written as `Repeat`/`End` blocks again.
+///
+/// This is greedy: from each position it takes the block covering the most operations (preferring
+/// shorter blocks on ties), then continues after it. The blocks are returned in order and don't
+/// overlap.
+pub fn find_repeated_blocks<B: Basis>(circuit: &[Operation<B>], max_block_len: usize) -> Vec<RepeatedBlock> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+
+    while start < circuit.len() {
+        let mut best: Option<RepeatedBlock> = None;
+        for len in 1..=max_block_len.min((circuit.len() - start) / 2) {
+            let block = &circuit[start..start + len];
+            let mut repeats = 1;
+            while start + (repeats + 1) * len <= circuit.len() && circuit[start + repeats * len..start + (repeats + 1) * len] == *block {
+                repeats += 1;
+            }
+
+            if repeats > 1 && best.is_none_or(|best| len * repeats > best.len * best.repeats) {
+                best = Some(RepeatedBlock { start, len, repeats });
+            }
+        }
+
+        if let Some(best) = best {
+            blocks.push(best);
+            start += best.len * best.repeats;
+        } else {
+            start += 1;
+        }
+    }
+
+    trace!("found {} repeated blocks", blocks.len());
+    blocks
+}
+
+
+/// Write `circuit`, with each of `blocks` (from [`find_repeated_blocks`]) written once inside a
+/// repeat block.
+pub fn write_with_repeats<B: Basis>(output: &mut impl Output, n_qubits: usize, circuit: &[Operation<B>], blocks: &[RepeatedBlock]) -> anyhow::Result<()> {
+    let mut index = 0;
+    for block in blocks {
+        for op in &circuit[index..block.start] {
+            output.write_operation(n_qubits, op)?;
+        }
+        output.begin_repeat(block.repeats)?;
+        for op in &circuit[block.start..block.start + block.len] {
+            output.write_operation(n_qubits, op)?;
+        }
+        output.end_repeat()?;
+        index = block.start + block.len * block.repeats;
+    }
+
+    for op in &circuit[index..] {
+        output.write_operation(n_qubits, op)?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::*, input::{lexer::TokenIterator, parser::InstructionIterator}, operation::angle::Angle, output::StringOut};
+
+    use super::*;
+
+    fn rotation(q: usize) -> Operation<Basis8> {
+        Operation::rotation(Basis8::zero(4), Basis8::one_bit(4, q), Angle::PlusPi8)
+    }
+
+    #[test]
+    fn test_find_repeated_blocks() {
+        let (a, b, c) = (rotation(0), rotation(1), rotation(2));
+        let circuit = [c, a, b, a, b, a, b, c, c, c, a];
+        assert_eq!(find_repeated_blocks(&circuit, 4), vec![
+            RepeatedBlock { start: 1, len: 2, repeats: 3 },
+            RepeatedBlock { start: 7, len: 1, repeats: 3 },
+        ]);
+
+        // too long to find
+        assert!(find_repeated_blocks(&circuit[1..7], 1).is_empty());
+        assert!(find_repeated_blocks::<Basis8>(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_repeat_round_trip() {
+        let (a, b, c, d) = (rotation(0), rotation(1), rotation(2), rotation(3));
+        let circuit = [d, a, b, c, a, b, c, a, b, c, a, b, c, d, d, a];
+        let blocks = find_repeated_blocks(&circuit, MAX_REPEAT_BLOCK_LEN);
+        assert_eq!(blocks.len(), 2);
+
+        let mut out = String::new();
+        write_with_repeats(&mut StringOut::new(&mut out), 4, &circuit, &blocks).unwrap();
+        assert_eq!(out, "Rotate 1: IIIZ\nRepeat 4\nRotate 1: ZIII\nRotate 1: IZII\nRotate 1: IIZI\nEnd\nRepeat 2\nRotate 1: IIIZ\nEnd\nRotate 1: ZIII\n");
+
+        let parsed = InstructionIterator::<_, Basis8>::new(4, TokenIterator::new(out.as_bytes()), 16, false)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(parsed, circuit);
+    }
+}
@@ -1,8 +1,11 @@
+use std::{marker::PhantomData, time::{Duration, Instant}};
+
 use crate::{basis::Basis, operation::{angle::Angle, Operation}};
 
 
-#[derive(Clone, Copy, Debug)]
-enum RotationCombineResult<B: Basis> {
+/// What to do with two adjacent operations, as decided by an [`AngleAlgebra`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RotationCombineResult<B: Basis> {
     KeepNeither,
     KeepFirst,
     KeepLast,
@@ -62,12 +65,52 @@ fn try_combine_rotations<'a, B: Basis>(op_1: &'a Operation<B>, op_2: &'a Operati
         new_angle = 0;
     }
 
-    let new_op = Operation::rotation(op_1.x.clone(), op_1.z.clone(), new_angle.into());
+    let new_op = Operation::rotation(op_1.x.clone(), op_1.z.clone(), new_angle.into())
+        .with_src_line(op_1.src_line().or(op_2.src_line()));
 
     RotationCombineResult::CombineTo(new_op)
 }
 
 
+/// The rules for combining two adjacent operations, so the reducers can target gate sets other
+/// than Clifford+T.
+///
+/// Implementations decide everything, including how identities and non-rotations are handled.
+pub trait AngleAlgebra {
+    fn combine<B: Basis>(op_1: &Operation<B>, op_2: &Operation<B>) -> RotationCombineResult<B>;
+}
+
+
+/// The Clifford+T rules: rotations about the same Pauli add their angles, and pi/2 only combines
+/// with pi/2 (to the identity) or -pi/4 (to pi/4).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CliffordT;
+
+
+impl AngleAlgebra for CliffordT {
+    #[inline(always)]
+    fn combine<B: Basis>(op_1: &Operation<B>, op_2: &Operation<B>) -> RotationCombineResult<B> {
+        try_combine_rotations(op_1, op_2)
+    }
+}
+
+
+/// Combine two adjacent operations into a single one, if possible.
+///
+/// Returns `None` if both have to be kept. Rotations which cancel out combine to
+/// [`Operation::identity`], and an identity rotation combined with another operation yields that
+/// operation.
+pub fn combine_rotations<B: Basis>(op_1: &Operation<B>, op_2: &Operation<B>, n_qubits: usize) -> Option<Operation<B>> {
+    match CliffordT::combine(op_1, op_2) {
+        RotationCombineResult::KeepNeither => Some(Operation::identity(n_qubits)),
+        RotationCombineResult::KeepFirst => Some(op_1.clone()),
+        RotationCombineResult::KeepLast => Some(op_2.clone()),
+        RotationCombineResult::KeepBoth => None,
+        RotationCombineResult::CombineTo(new_op) => Some(new_op),
+    }
+}
+
+
 #[inline(always)]
 fn compute_next_index(slots: &[bool], mut index: usize) -> usize {
     loop {
@@ -84,93 +127,138 @@ fn compute_next_index(slots: &[bool], mut index: usize) -> usize {
 
 
 #[derive(Clone, Debug)]
-pub struct OptimizeRotationsAdjacent<B: Basis, I: Iterator<Item = Operation<B>>> {
+pub struct OptimizeRotationsAdjacent<B: Basis, I: Iterator<Item = anyhow::Result<Operation<B>>>, A: AngleAlgebra = CliffordT> {
     source: I,
     source_is_done: bool,
     current: Option<Operation<B>>,
     pre_op_count: usize,
     // post_op_count: usize,
+    /// Time spent in `source` (the parser), if timed (see [`OptimizeRotationsAdjacent::with_source_timing`]).
+    source_time: Option<Duration>,
+    algebra: PhantomData<A>,
 }
 
 
-impl<B: Basis, I: Iterator<Item = Operation<B>>> OptimizeRotationsAdjacent<B, I> {
+impl<B: Basis, I: Iterator<Item = anyhow::Result<Operation<B>>>> OptimizeRotationsAdjacent<B, I> {
     pub fn new(iterator: I) -> Self {
+        Self::with_algebra(iterator)
+    }
+}
+
+
+impl<B: Basis, I: Iterator<Item = anyhow::Result<Operation<B>>>, A: AngleAlgebra> OptimizeRotationsAdjacent<B, I, A> {
+    pub fn with_algebra(iterator: I) -> Self {
         Self {
             source: iterator,
             source_is_done: false,
             current: None,
             pre_op_count: 0,
+            source_time: None,
+            algebra: PhantomData,
         }
     }
 
+    /// Time every read from the source, so the parser can be told apart from the reduction.
+    pub fn with_source_timing(mut self) -> Self {
+        self.source_time = Some(Duration::ZERO);
+        self
+    }
+
     pub fn pre_op_count(&self) -> usize {
         self.pre_op_count
     }
+
+    /// Time spent reading from the source so far, if [`OptimizeRotationsAdjacent::with_source_timing`] was used.
+    pub fn source_time(&self) -> Option<Duration> {
+        self.source_time
+    }
+
+    fn next_source(&mut self) -> Option<anyhow::Result<Operation<B>>> {
+        match &mut self.source_time {
+            Some(source_time) => {
+                let start = Instant::now();
+                let next = self.source.next();
+                *source_time += start.elapsed();
+                next
+            },
+            None => self.source.next(),
+        }
+    }
 }
 
 
-impl<B: Basis, I: Iterator<Item = Operation<B>>> Iterator for OptimizeRotationsAdjacent<B, I> {
-    type Item = Option<Operation<B>>;
+impl<B: Basis, I: Iterator<Item = anyhow::Result<Operation<B>>>, A: AngleAlgebra> Iterator for OptimizeRotationsAdjacent<B, I, A> {
+    type Item = anyhow::Result<Option<Operation<B>>>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // possible return values:
-        // - Some(Some(value)): the next value
-        // - Some(None): we need more calls to next() before there are results
+        // - Some(Ok(Some(value))): the next value
+        // - Some(Ok(None)): we need more calls to next() before there are results
+        // - Some(Err(e)): the source failed; iteration stops here
         // - None: done iterating
         if self.source_is_done {
             if let Some(current) = &self.current {
                 let ret = current.clone();
                 self.current = None;
-                return Some(Some(ret));
+                return Some(Ok(Some(ret)));
             } else {
                 return None;
             }
         }
 
-        if let Some(next) = self.source.next() {
-            self.pre_op_count += 1;
-            if let Some(current) = &self.current {
-                match try_combine_rotations(&current, &next) {
-                    RotationCombineResult::KeepNeither => {
-                        self.current = None;
-                        Some(None)
-                    },
-                    RotationCombineResult::KeepFirst => {
-                        Some(None)
-                    },
-                    RotationCombineResult::KeepLast => {
-                        self.current = Some(next);
-                        Some(None)
-                    },
-                    RotationCombineResult::KeepBoth => {
-                        let current = current.clone();
-                        self.current = Some(next);
-                        Some(Some(current))
-                    },
-                    RotationCombineResult::CombineTo(new_op) => {
-                        self.current = Some(new_op);
-                        Some(None)
-                    },
+        match self.next_source() {
+            Some(Ok(next)) => {
+                self.pre_op_count += 1;
+                if let Some(current) = &self.current {
+                    match A::combine(current, &next) {
+                        RotationCombineResult::KeepNeither => {
+                            self.current = None;
+                            Some(Ok(None))
+                        },
+                        RotationCombineResult::KeepFirst => {
+                            Some(Ok(None))
+                        },
+                        RotationCombineResult::KeepLast => {
+                            self.current = Some(next);
+                            Some(Ok(None))
+                        },
+                        RotationCombineResult::KeepBoth => {
+                            let current = current.clone();
+                            self.current = Some(next);
+                            Some(Ok(Some(current)))
+                        },
+                        RotationCombineResult::CombineTo(new_op) => {
+                            self.current = Some(new_op);
+                            Some(Ok(None))
+                        },
+                    }
+                } else {
+                    self.current = Some(next);
+                    Some(Ok(None))
                 }
-            } else {
-                self.current = Some(next);
-                Some(None)
-            }
-        } else {
-            self.source_is_done = true;
-            if let Some(current) = &self.current {
-                let current = current.clone();
+            },
+            Some(Err(e)) => {
+                // don't emit anything after an error
+                self.source_is_done = true;
                 self.current = None;
-                Some(Some(current))
-            } else {
-                None
-            }
+                Some(Err(e))
+            },
+            None => {
+                self.source_is_done = true;
+                if let Some(current) = &self.current {
+                    let current = current.clone();
+                    self.current = None;
+                    Some(Ok(Some(current)))
+                } else {
+                    None
+                }
+            },
         }
     }
 }
 
 
-fn inner_reduce_rotations_no_ordering<B: Basis>(operations: &mut Vec<Operation<B>>, keep_indexes: &mut Vec<bool>) -> bool {
+fn inner_reduce_rotations_no_ordering<A: AngleAlgebra, B: Basis>(operations: &mut Vec<Operation<B>>, keep_indexes: &mut Vec<bool>) -> bool {
     keep_indexes.clear();
     
     if operations.is_empty() {
@@ -197,7 +285,7 @@ fn inner_reduce_rotations_no_ordering<B: Basis>(operations: &mut Vec<Operation<B
         // dbg!(&operations[index1]);
         // dbg!(&operations[index2]);
         // try to combine
-        let res = try_combine_rotations(&operations[index1], &operations[index2]);
+        let res = A::combine(&operations[index1], &operations[index2]);
         // dbg!(&res);
         match res {
             RotationCombineResult::KeepNeither => {
@@ -250,11 +338,17 @@ fn inner_reduce_rotations_no_ordering<B: Basis>(operations: &mut Vec<Operation<B
 
 
 pub fn reduce_rotations_no_ordering<B: Basis>(operations: &mut Vec<Operation<B>>, keep_indexes: &mut Vec<bool>) -> bool {
-    let mut changed = inner_reduce_rotations_no_ordering(operations, keep_indexes);
+    reduce_rotations_no_ordering_with::<CliffordT, B>(operations, keep_indexes)
+}
+
+
+/// [`reduce_rotations_no_ordering`] with the combination rules of `A`.
+pub fn reduce_rotations_no_ordering_with<A: AngleAlgebra, B: Basis>(operations: &mut Vec<Operation<B>>, keep_indexes: &mut Vec<bool>) -> bool {
+    let mut changed = inner_reduce_rotations_no_ordering::<A, B>(operations, keep_indexes);
     let overall_changed = changed;
 
     while changed {
-        changed = inner_reduce_rotations_no_ordering(operations, keep_indexes);
+        changed = inner_reduce_rotations_no_ordering::<A, B>(operations, keep_indexes);
     }
 
     overall_changed
@@ -276,7 +370,7 @@ fn compute_next_index_slice<B: Basis>(operations: &[Operation<B>], mut index: us
 }
 
 
-fn inner_reduce_rotations_no_ordering_slice<B: Basis>(operations: &mut [Operation<B>]) -> bool {
+fn inner_reduce_rotations_no_ordering_slice<A: AngleAlgebra, B: Basis>(operations: &mut [Operation<B>]) -> bool {
     if operations.is_empty() {
         return false;
     } else if operations.len() == 1 {
@@ -304,7 +398,7 @@ fn inner_reduce_rotations_no_ordering_slice<B: Basis>(operations: &mut [Operatio
 
     'outer: while index1 < operations.len() {
         // try to combine
-        let res = try_combine_rotations(&operations[index1], &operations[index2]);
+        let res = A::combine(&operations[index1], &operations[index2]);
         // dbg!(&res);
         match res {
             RotationCombineResult::KeepNeither => {
@@ -353,11 +447,17 @@ fn inner_reduce_rotations_no_ordering_slice<B: Basis>(operations: &mut [Operatio
 }
 
 pub fn reduce_rotations_no_ordering_slice<B: Basis>(operations: &mut [Operation<B>]) -> bool {
-    let mut changed = inner_reduce_rotations_no_ordering_slice(operations);
+    reduce_rotations_no_ordering_slice_with::<CliffordT, B>(operations)
+}
+
+
+/// [`reduce_rotations_no_ordering_slice`] with the combination rules of `A`.
+pub fn reduce_rotations_no_ordering_slice_with<A: AngleAlgebra, B: Basis>(operations: &mut [Operation<B>]) -> bool {
+    let mut changed = inner_reduce_rotations_no_ordering_slice::<A, B>(operations);
     let overall_changed = changed;
 
     while changed {
-        changed = inner_reduce_rotations_no_ordering_slice(operations);
+        changed = inner_reduce_rotations_no_ordering_slice::<A, B>(operations);
     }
 
     overall_changed
@@ -377,6 +477,37 @@ mod tests {
         assert!(!changed);
     }
 
+    #[test]
+    fn test_combine_inverse() {
+        let mut basis = Basis8::zero(5);
+        basis.set_bit(2, true);
+
+        for angle in [Angle::PlusPi8, Angle::MinusPi8, Angle::PlusPi4, Angle::MinusPi4, Angle::Pi2] {
+            let op = Operation::rotation(basis, basis, angle);
+            let inverse = Operation::rotation(basis, basis, -angle);
+
+            let combined = combine_rotations(&op, &inverse, 5).unwrap();
+            assert_eq!(combined, Operation::identity(5));
+            assert!(combined.is_identity_rotation());
+
+            // the identity is absorbed by anything it's combined with
+            assert_eq!(combine_rotations(&combined, &op, 5), Some(op));
+            assert_eq!(combine_rotations(&op, &combined, 5), Some(op));
+
+            let mut operations = vec![op, inverse];
+            assert!(reduce_rotations_no_ordering::<Basis8>(&mut operations, &mut Vec::new()));
+            assert!(operations.is_empty());
+        }
+
+        let mut operations = vec![Operation::<Basis8>::identity(5)];
+        assert!(reduce_rotations_no_ordering::<Basis8>(&mut operations, &mut Vec::new()));
+        assert!(operations.is_empty());
+
+        let measurement = Operation::measurement(basis, Basis8::zero(5), false.into());
+        assert!(!measurement.is_identity_rotation());
+        assert_eq!(combine_rotations(&measurement, &Operation::identity(5), 5), None);
+    }
+
     #[test]
     fn test_reduce_one() {
         let mut basis = Basis8::zero(5);
@@ -532,4 +663,75 @@ mod tests {
         assert_eq!(operations.len(), 5);
         
     }
+
+    #[test]
+    fn test_clifford_t_table() {
+        use Angle::*;
+        let angles = [Pi2, PlusPi8, MinusPi8, PlusPi4, MinusPi4];
+        let combine_to = [
+            (PlusPi8, PlusPi8, PlusPi4), (MinusPi8, MinusPi8, MinusPi4),
+            (PlusPi8, MinusPi4, MinusPi8), (MinusPi4, PlusPi8, MinusPi8),
+            (MinusPi8, PlusPi4, PlusPi8), (PlusPi4, MinusPi8, PlusPi8),
+            (PlusPi4, PlusPi4, Pi2), (MinusPi4, MinusPi4, Pi2),
+            (Pi2, MinusPi4, PlusPi4), (MinusPi4, Pi2, PlusPi4),
+        ];
+
+        let x = Basis8::one_bit(2, 0);
+        let z = Basis8::one_bit(2, 1);
+        let rotation = |angle| Operation::rotation(x, z, angle);
+
+        for a in angles {
+            for b in angles {
+                let expected = if let Some((_, _, c)) = combine_to.iter().find(|(a_, b_, _)| (*a_, *b_) == (a, b)) {
+                    RotationCombineResult::CombineTo(rotation(*c))
+                } else if a as i8 + b as i8 == 0 {
+                    RotationCombineResult::KeepNeither
+                } else {
+                    RotationCombineResult::KeepBoth
+                };
+                assert_eq!(CliffordT::combine(&rotation(a), &rotation(b)), expected, "{:?} + {:?}", a, b);
+            }
+        }
+
+        // different axes, identities and measurements are structural, not part of the table
+        let other = Operation::rotation(z, x, PlusPi8);
+        assert_eq!(CliffordT::combine(&rotation(PlusPi8), &other), RotationCombineResult::KeepBoth);
+        assert_eq!(CliffordT::combine(&Operation::identity(2), &other), RotationCombineResult::KeepLast);
+        let measurement = Operation::measurement(x, z, false.into());
+        assert_eq!(CliffordT::combine(&measurement, &measurement), RotationCombineResult::KeepBoth);
+    }
+
+    #[test]
+    fn test_custom_algebra() {
+        struct NeverCombine;
+
+        impl AngleAlgebra for NeverCombine {
+            fn combine<B: Basis>(_: &Operation<B>, _: &Operation<B>) -> RotationCombineResult<B> {
+                RotationCombineResult::KeepBoth
+            }
+        }
+
+        let x = Basis8::one_bit(2, 0);
+        let operations = vec![
+            Operation::rotation(x, x, Angle::PlusPi8),
+            Operation::rotation(x, x, Angle::MinusPi8),
+        ];
+
+        let mut never = operations.clone();
+        assert!(!reduce_rotations_no_ordering_with::<NeverCombine, _>(&mut never, &mut Vec::new()));
+        assert_eq!(never, operations);
+        assert!(!reduce_rotations_no_ordering_slice_with::<NeverCombine, _>(&mut never));
+        assert_eq!(never, operations);
+
+        let adjacent: Vec<_> = OptimizeRotationsAdjacent::<_, _, NeverCombine>::with_algebra(operations.iter().cloned().map(Ok))
+            .filter_map(|op| op.unwrap())
+            .collect();
+        assert_eq!(adjacent, operations);
+
+        // the default rules cancel them
+        let adjacent: Vec<_> = OptimizeRotationsAdjacent::new(operations.iter().cloned().map(Ok))
+            .filter_map(|op| op.unwrap())
+            .collect();
+        assert!(adjacent.is_empty());
+    }
 }
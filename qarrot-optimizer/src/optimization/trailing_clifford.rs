@@ -0,0 +1,58 @@
+use log::trace;
+
+use crate::{basis::Basis, operation::Operation};
+
+
+/// Remove the Clifford (pi/2 and pi/4) rotations after the last pi/8 rotation (see
+/// `--strip-trailing-clifford`).
+///
+/// This does **not** preserve the circuit: the measurements after the removed rotations are left
+/// as they are, so they end up measuring in the frame from before those Cliffords rather than after.
+/// It's only correct if that final Clifford frame doesn't matter (e.g. it's applied classically, or
+/// only the non-Clifford part of the circuit is of interest).
+///
+/// Returns the number of rotations removed.
+pub fn strip_trailing_clifford<B: Basis>(circuit: &mut Vec<Operation<B>>) -> usize {
+    let start = circuit.iter()
+        .rposition(|op| op.as_rotation().is_some_and(|rot| rot.angle.is_pi8()))
+        .map_or(0, |last_t| last_t + 1);
+
+    let before = circuit.len();
+    let mut index = 0;
+    circuit.retain(|op| {
+        index += 1;
+        index <= start || !op.is_rotation()
+    });
+
+    let removed = before - circuit.len();
+    trace!("removed {} trailing Clifford rotations", removed);
+    removed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{basis::*, operation::{angle::Angle, phase::Phase}};
+
+    use super::*;
+
+    #[test]
+    fn test_strip_trailing_clifford() {
+        let x = Basis8::with_true_bits(2, &[0]);
+        let z = Basis8::with_true_bits(2, &[1]);
+        let zero = Basis8::zero(2);
+
+        let t = Operation::rotation(zero, z, Angle::PlusPi8);
+        let s = Operation::rotation(x, zero, Angle::PlusPi4);
+        let measure = Operation::measurement(zero, z, Phase::Positive);
+
+        let mut circuit = vec![s, t, s, measure, Operation::rotation(x, z, Angle::Pi2), measure];
+        assert_eq!(strip_trailing_clifford(&mut circuit), 2);
+        assert_eq!(circuit, vec![s, t, measure, measure]);
+
+        // without any pi/8 rotations, every Clifford rotation is trailing
+        let mut circuit = vec![s, measure, s];
+        assert_eq!(strip_trailing_clifford(&mut circuit), 2);
+        assert_eq!(circuit, vec![measure]);
+    }
+}
@@ -1,20 +1,98 @@
 use std::io;
 
-use crate::{basis::Basis, operation::{phase::Phase, Operation}};
+use anyhow::bail;
+
+use crate::{basis::Basis, input::lexer::{PauliConvention, QubitOrder}, operation::{phase::Phase, Operation}, symplectic::Symplectic};
 
 
 pub trait Output {
     fn flush(&mut self) -> anyhow::Result<()>;
     fn write_operation<B: Basis>(&mut self, n_qubits: usize, operation: &Operation<B>) -> anyhow::Result<()>;
+
+    /// Called once before the first operation is written, e.g. for a format's header. The text
+    /// format doesn't have one.
+    fn begin(&mut self, _n_qubits: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after the last operation is written (and before the final flush).
+    fn end(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Start a block of operations repeated `repeats` times, closed by [`Output::end_repeat`]
+    /// (see `--split-by-repeat`).
+    fn begin_repeat(&mut self, _repeats: usize) -> anyhow::Result<()> {
+        bail!("this output doesn't support repeat blocks")
+    }
+
+    fn end_repeat(&mut self) -> anyhow::Result<()> {
+        bail!("this output doesn't support repeat blocks")
+    }
+
+    /// Write a `# comment` line, e.g. between T-layers (see `--group-output-by-layer`). These
+    /// can't be read back in.
+    fn write_comment(&mut self, _comment: &str) -> anyhow::Result<()> {
+        bail!("this output doesn't support comments")
+    }
+}
+
+
+/// Options controlling how operations are formatted in the text output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FormatOptions {
+    /// Run-length encode the Pauli string, e.g. `I40 X I15 Z` instead of forty `I`s, an `X`, &c.
+    pub run_length_encode: bool,
+    /// Letters used for each Pauli.
+    pub pauli_convention: PauliConvention,
+    /// Which end of the Pauli string qubit 0 is written at.
+    pub qubit_order: QubitOrder,
+    /// Wrap the (dense) Pauli string every this many qubits, for reading wide circuits by eye.
+    ///
+    /// Each wrapped line ends with ` \` and the next is indented to line up with the first Pauli;
+    /// the lexer joins such continuation lines back together.
+    pub columns: Option<usize>,
+    /// Write the raw symplectic form `sign x_bits z_bits` instead of the text format, for diffing
+    /// against other simulators. This can't be read back in (the kind and angle are dropped).
+    pub dump_symplectic: bool,
+    /// Write single-qubit Z rotations as their named gate (`T`, `Tdg`, `S`, `Sdg` or `Z`) in
+    /// place of `Rotate N`, e.g. `T: IIZI`. Other rotations are written as usual.
+    pub angles_as_gates: bool,
+    /// Debugging output: end each operation with a `# line N` comment naming the input line it
+    /// came from (when known). This can't be read back in.
+    #[cfg(feature = "source-tracking")]
+    pub source_lines: bool,
 }
 
 
 pub fn fmt_operation<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Operation<B>) -> anyhow::Result<()> {
-    use std::fmt::Write;
+    fmt_operation_with(buf, n_qubits, operation, &FormatOptions::default())
+}
+
 
+pub fn fmt_operation_with<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Operation<B>, options: &FormatOptions) -> anyhow::Result<()> {
     buf.clear();
+    push_operation_with(buf, n_qubits, operation, options)
+}
+
+
+/// Like [`fmt_operation_with`], but appends to `buf` rather than replacing its contents.
+pub fn push_operation_with<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Operation<B>, options: &FormatOptions) -> anyhow::Result<()> {
+    use std::fmt::Write;
+
+    let line_start = buf.len();
+    if operation.is_barrier() {
+        // only a hint for the partitioner
+        return Ok(());
+    }
+    if options.dump_symplectic {
+        Symplectic::from(operation).dump(buf, n_qubits);
+        buf.write_char('\n')?;
+        return Ok(());
+    }
+
     match operation.kind {
-        crate::operation::OperationKind::Nop => unreachable!(),
+        crate::operation::OperationKind::Nop | crate::operation::OperationKind::Barrier => unreachable!(),
         crate::operation::OperationKind::Measurement { phase } => {
             buf.write_str("Measure ")?;
             match phase {
@@ -23,23 +101,38 @@ pub fn fmt_operation<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Op
             }?;
         },
         crate::operation::OperationKind::Rotation { angle } => {
-            let angle_code = angle as i8;
-            buf.write_fmt(format_args!("Rotate {}", angle_code))?;
+            if options.angles_as_gates && operation.is_z_only() && operation.z.popcnt() == 1 {
+                buf.write_str(angle.gate_name())?;
+            } else {
+                let angle_code = angle as i8;
+                buf.write_fmt(format_args!("Rotate {}", angle_code))?;
+            }
         },
     };
 
     buf.write_str(": ")?;
 
-    // TODO: make this more efficient
-    for q in 0..n_qubits {
-        let x = operation.x.get_bit(q);
-        let z = operation.z.get_bit(q);
-        match (x, z) {
-            (false, false) => buf.write_char('I')?,
-            (true, false) => buf.write_char('X')?,
-            (false, true) => buf.write_char('Z')?,
-            (true, true) => buf.write_char('Y')?,
-        };
+    if options.run_length_encode {
+        fmt_paulis_rle(buf, n_qubits, operation, options.pauli_convention, options.qubit_order)?;
+    } else if let Some(columns) = options.columns {
+        let indent = buf.len() - line_start;
+        for position in 0..n_qubits {
+            if position != 0 && position % columns == 0 {
+                buf.write_str(" \\\n")?;
+                for _ in 0..indent {
+                    buf.write_char(' ')?;
+                }
+            }
+            let q = options.qubit_order.qubit(position, n_qubits);
+            buf.write_char(pauli_char(&operation.x, &operation.z, q, options.pauli_convention))?;
+        }
+    } else {
+        write_paulis(buf, &operation.x, &operation.z, n_qubits, options.pauli_convention, options.qubit_order);
+    }
+
+    #[cfg(feature = "source-tracking")]
+    if let (true, Some(line)) = (options.source_lines, operation.src_line) {
+        buf.write_fmt(format_args!("  # line {}", line))?;
     }
 
     buf.write_char('\n')?;
@@ -48,6 +141,72 @@ pub fn fmt_operation<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Op
 }
 
 
+pub fn fmt_repeat(buf: &mut String, repeats: usize) -> anyhow::Result<()> {
+    use std::fmt::Write;
+
+    buf.clear();
+    buf.write_fmt(format_args!("Repeat {}\n", repeats))?;
+    Ok(())
+}
+
+
+pub fn fmt_end_repeat(buf: &mut String) {
+    buf.clear();
+    buf.push_str("End\n");
+}
+
+
+pub fn fmt_comment(buf: &mut String, comment: &str) {
+    buf.clear();
+    buf.push_str("# ");
+    buf.push_str(comment);
+    buf.push('\n');
+}
+
+
+#[inline(always)]
+fn pauli_char<B: Basis>(x: &B, z: &B, qubit: usize, convention: PauliConvention) -> char {
+    convention.to_char(x.get_bit(qubit), z.get_bit(qubit))
+}
+
+
+fn write_paulis<B: Basis>(buf: &mut String, x: &B, z: &B, n_qubits: usize, convention: PauliConvention, order: QubitOrder) {
+    // TODO: make this more efficient
+    buf.reserve(n_qubits);
+    for position in 0..n_qubits {
+        buf.push(pauli_char(x, z, order.qubit(position, n_qubits), convention));
+    }
+}
+
+
+// writes runs as the Pauli followed by the run length (omitted for runs of one), separated by spaces
+fn fmt_paulis_rle<B: Basis>(buf: &mut String, n_qubits: usize, operation: &Operation<B>, convention: PauliConvention, order: QubitOrder) -> anyhow::Result<()> {
+    use std::fmt::Write;
+
+    let char_at = |position| pauli_char(&operation.x, &operation.z, order.qubit(position, n_qubits), convention);
+    let mut position = 0;
+    while position < n_qubits {
+        let ch = char_at(position);
+        let mut run = 1;
+        while position + run < n_qubits && char_at(position + run) == ch {
+            run += 1;
+        }
+
+        if position != 0 {
+            buf.write_char(' ')?;
+        }
+        buf.write_char(ch)?;
+        if run > 1 {
+            buf.write_fmt(format_args!("{}", run))?;
+        }
+
+        position += run;
+    }
+
+    Ok(())
+}
+
+
 // throw away writes, useful for testing
 #[derive(Debug)]
 pub struct Void { }
@@ -72,19 +231,80 @@ impl Output for Void {
     fn write_operation<B: Basis>(&mut self, _n_qubits: usize, _operation: &Operation<B>) -> anyhow::Result<()> {
         Ok(())
     }
+
+    fn begin_repeat(&mut self, _repeats: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn end_repeat(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_comment(&mut self, _comment: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Writes everything to both outputs.
+#[derive(Debug)]
+pub struct Tee<A: Output, B: Output>(pub A, pub B);
+
+
+impl<A: Output, B: Output> Output for Tee<A, B> {
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+
+    fn write_operation<Bs: Basis>(&mut self, n_qubits: usize, operation: &Operation<Bs>) -> anyhow::Result<()> {
+        self.0.write_operation(n_qubits, operation)?;
+        self.1.write_operation(n_qubits, operation)
+    }
+
+    fn begin(&mut self, n_qubits: usize) -> anyhow::Result<()> {
+        self.0.begin(n_qubits)?;
+        self.1.begin(n_qubits)
+    }
+
+    fn end(&mut self) -> anyhow::Result<()> {
+        self.0.end()?;
+        self.1.end()
+    }
+
+    fn begin_repeat(&mut self, repeats: usize) -> anyhow::Result<()> {
+        self.0.begin_repeat(repeats)?;
+        self.1.begin_repeat(repeats)
+    }
+
+    fn end_repeat(&mut self) -> anyhow::Result<()> {
+        self.0.end_repeat()?;
+        self.1.end_repeat()
+    }
+
+    fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
+        self.0.write_comment(comment)?;
+        self.1.write_comment(comment)
+    }
 }
 
 
 #[derive(Debug)]
 pub struct StringOut<'a> {
     single: String,
+    format: FormatOptions,
     pub output: &'a mut String,
 }
 
 impl<'a> StringOut<'a> {
     pub fn new(output: &'a mut String) -> Self {
+        Self::with_format(output, FormatOptions::default())
+    }
+
+    pub fn with_format(output: &'a mut String, format: FormatOptions) -> Self {
         Self {
             single: String::with_capacity(128),
+            format,
             output,
         }
     }
@@ -96,45 +316,366 @@ impl<'a> Output for StringOut<'a> {
     }
 
     fn write_operation<B: Basis>(&mut self, n_qubits: usize, operation: &Operation<B>) -> anyhow::Result<()> {
-        fmt_operation(&mut self.single, n_qubits, operation)?;
+        fmt_operation_with(&mut self.single, n_qubits, operation, &self.format)?;
         for char in self.single.chars() {
             self.output.push(char);
         }
         Ok(())
     }
+
+    fn begin_repeat(&mut self, repeats: usize) -> anyhow::Result<()> {
+        fmt_repeat(&mut self.single, repeats)?;
+        self.output.push_str(&self.single);
+        Ok(())
+    }
+
+    fn end_repeat(&mut self) -> anyhow::Result<()> {
+        fmt_end_repeat(&mut self.single);
+        self.output.push_str(&self.single);
+        Ok(())
+    }
+
+    fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
+        fmt_comment(&mut self.single, comment);
+        self.output.push_str(&self.single);
+        Ok(())
+    }
 }
 
 
 #[derive(Debug)]
 pub struct WriteOutput<W: io::Write> {
     writer: io::BufWriter<W>, // Write tries to flush on drop, we don't need to impl that manually
+    /// The formatted operations not yet handed to `writer`.
     line_buf: String,
+    format: FormatOptions,
+    operations_per_write: usize,
+    /// How many operations are in `line_buf`.
+    pending: usize,
 }
 
 
 impl<W: io::Write> WriteOutput<W> {
     pub fn new(writer: W) -> Self {
+        Self::with_format(writer, FormatOptions::default())
+    }
+
+    pub fn with_format(writer: W, format: FormatOptions) -> Self {
         Self {
             writer: io::BufWriter::new(writer),
             line_buf: String::with_capacity(256),
+            format,
+            operations_per_write: 1,
+            pending: 0,
         }
     }
+
+    /// Format this many operations into one buffer before each write to the `BufWriter` (see
+    /// `--operations-per-write`). The output is the same.
+    pub fn with_operations_per_write(mut self, operations_per_write: usize) -> Self {
+        self.operations_per_write = operations_per_write.max(1);
+        self
+    }
+
+    fn write_pending(&mut self) -> anyhow::Result<()> {
+        use io::Write;
+
+        if self.pending > 0 {
+            self.writer.write_all(self.line_buf.as_bytes())?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
 }
 
 impl<W: io::Write> Output for WriteOutput<W> {
     fn flush(&mut self) -> anyhow::Result<()> {
         use io::Write;
+        self.write_pending()?;
         self.writer.flush()?;
         Ok(())
     }
 
     fn write_operation<B: Basis>(&mut self, n_qubits: usize, operation: &Operation<B>) -> anyhow::Result<()> {
+        if self.pending == 0 {
+            self.line_buf.clear();
+        }
+        push_operation_with(&mut self.line_buf, n_qubits, operation, &self.format)?;
+        self.pending += 1;
+
+        if self.pending >= self.operations_per_write {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn begin_repeat(&mut self, repeats: usize) -> anyhow::Result<()> {
+        use io::Write;
+
+        self.write_pending()?;
+        fmt_repeat(&mut self.line_buf, repeats)?;
+        self.writer.write_all(self.line_buf.as_bytes())?;
+        Ok(())
+    }
+
+    fn end_repeat(&mut self) -> anyhow::Result<()> {
+        use io::Write;
+
+        self.write_pending()?;
+        fmt_end_repeat(&mut self.line_buf);
+        self.writer.write_all(self.line_buf.as_bytes())?;
+        Ok(())
+    }
+
+    fn write_comment(&mut self, comment: &str) -> anyhow::Result<()> {
         use io::Write;
-        
-        fmt_operation(&mut self.line_buf, n_qubits, operation)?;
 
-        let amount = self.writer.write(self.line_buf.as_bytes())?;
-        debug_assert!(amount == self.line_buf.as_bytes().len());
+        self.write_pending()?;
+        fmt_comment(&mut self.line_buf, comment);
+        self.writer.write_all(self.line_buf.as_bytes())?;
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+pub mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::{basis::*, input::{lexer::TokenIterator, parser::InstructionIterator}, operation::angle::Angle};
+
+    use super::*;
+
+    /// Writes a QASM header in `begin`, and the operations as comments.
+    pub struct QasmHeaderOut<'a>(pub &'a mut String);
+
+    impl<'a> Output for QasmHeaderOut<'a> {
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn write_operation<B: Basis>(&mut self, n_qubits: usize, operation: &Operation<B>) -> anyhow::Result<()> {
+            self.0.push_str(&format!("// {}\n", operation.as_string(n_qubits)));
+            Ok(())
+        }
+
+        fn begin(&mut self, n_qubits: usize) -> anyhow::Result<()> {
+            self.0.push_str(&format!("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[{}];\n", n_qubits));
+            Ok(())
+        }
+
+        fn end(&mut self) -> anyhow::Result<()> {
+            self.0.push_str("// end\n");
+            Ok(())
+        }
+    }
+
+    fn round_trip_with<B: Basis>(n_qubits: usize, ops: &[Operation<B>], format: FormatOptions) {
+        let mut out = String::new();
+        let mut output = StringOut::with_format(&mut out, format);
+        for op in ops {
+            output.write_operation(n_qubits, op).unwrap();
+        }
+
+        let mut tokens = TokenIterator::new(out.as_bytes());
+        tokens.set_pauli_convention(format.pauli_convention);
+        tokens.set_qubit_order(format.qubit_order);
+        let parser = InstructionIterator::<_, B>::new(n_qubits, tokens, 16, false);
+        let parsed = parser.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(&parsed, ops);
+    }
+
+    fn round_trip<B: Basis>(n_qubits: usize, ops: &[Operation<B>]) {
+        round_trip_with(n_qubits, ops, FormatOptions { run_length_encode: true, ..Default::default() });
+    }
+
+    #[test]
+    fn test_qasm_header() {
+        let op = Operation::rotation(Basis8::zero(3), Basis8::one_bit(3, 0), Angle::PlusPi8);
+        let (mut qasm, mut text) = (String::new(), String::new());
+        let mut output = Tee(QasmHeaderOut(&mut qasm), StringOut::new(&mut text));
+        output.begin(3).unwrap();
+        output.write_operation(3, &op).unwrap();
+        output.end().unwrap();
+
+        assert_eq!(qasm, "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\n// Rotate 1: ZII\n// end\n");
+        // the text format has neither a header nor a footer
+        assert_eq!(text, "Rotate 1: ZII\n");
+    }
+
+    #[test]
+    fn test_fmt_rle() {
+        let mut buf = String::new();
+        let op = Operation::rotation(Basis64::one_bit(57, 40), Basis64::one_bit(57, 56), Angle::PlusPi8);
+        fmt_operation_with(&mut buf, 57, &op, &FormatOptions { run_length_encode: true, ..Default::default() }).unwrap();
+        assert_eq!(buf, "Rotate 1: I40 X I15 Z\n");
+
+        let op = Operation::measurement(Basis8::with_true_bits(4, &[0, 1]), Basis8::with_true_bits(4, &[1]), Phase::Negative);
+        fmt_operation_with(&mut buf, 4, &op, &FormatOptions { run_length_encode: true, ..Default::default() }).unwrap();
+        assert_eq!(buf, "Measure -: X Y I2\n");
+    }
+
+    #[test]
+    fn test_rle_round_trip() {
+        round_trip(200, &[
+            Operation::rotation(Basis256::one_bit(200, 150), Basis256::one_bit(200, 150), Angle::MinusPi8),
+            Operation::rotation(Basis256::zero(200), Basis256::with_true_bits(200, &[0, 199]), Angle::PlusPi4),
+            Operation::measurement(Basis256::one_bit(200, 3), Basis256::zero(200), Phase::Positive),
+        ]);
+
+        round_trip(300, &[
+            Operation::rotation(DBasis::one_bit(300, 299), DBasis::zero(300), Angle::Pi2),
+            Operation::measurement(DBasis::zero(300), DBasis::with_true_bits(300, &[1, 2, 3, 130]), Phase::Negative),
+        ]);
+    }
+
+    #[test]
+    fn test_fmt_columns() {
+        let mut buf = String::new();
+        let op = Operation::rotation(Basis16::with_true_bits(10, &[0, 9]), Basis16::one_bit(10, 4), Angle::MinusPi8);
+        let format = FormatOptions { columns: Some(4), ..Default::default() };
+        fmt_operation_with(&mut buf, 10, &op, &format).unwrap();
+        assert_eq!(buf, "Rotate -1: XIII \\\n           ZIII \\\n           IX\n");
+
+        // no trailing continuation when the qubits divide evenly
+        fmt_operation_with(&mut buf, 8, &Operation::measurement(Basis8::zero(8), Basis8::one(8), Phase::Positive), &format).unwrap();
+        assert_eq!(buf, "Measure +: ZZZZ \\\n           ZZZZ\n");
+    }
+
+    #[test]
+    fn test_columns_round_trip() {
+        let format = FormatOptions { columns: Some(7), ..Default::default() };
+        round_trip_with(200, &[
+            Operation::rotation(Basis256::one_bit(200, 150), Basis256::one_bit(200, 150), Angle::MinusPi8),
+            Operation::rotation(Basis256::zero(200), Basis256::with_true_bits(200, &[0, 199]), Angle::PlusPi4),
+            Operation::measurement(Basis256::one_bit(200, 3), Basis256::zero(200), Phase::Positive),
+        ], format);
+
+        round_trip_with(300, &[
+            Operation::rotation(DBasis::one_bit(300, 299), DBasis::zero(300), Angle::Pi2),
+            Operation::measurement(DBasis::zero(300), DBasis::with_true_bits(300, &[1, 2, 3, 130]), Phase::Negative),
+        ], FormatOptions { columns: Some(64), pauli_convention: PauliConvention::ZX, ..Default::default() });
+    }
+
+    #[test]
+    fn test_angles_as_gates() {
+        let format = FormatOptions { angles_as_gates: true, ..Default::default() };
+        let ops = [
+            Operation::rotation(Basis8::zero(4), Basis8::one_bit(4, 2), Angle::PlusPi8),
+            Operation::rotation(Basis8::zero(4), Basis8::one_bit(4, 0), Angle::MinusPi4),
+            Operation::rotation(Basis8::zero(4), Basis8::with_true_bits(4, &[0, 1]), Angle::PlusPi8),
+            Operation::rotation(Basis8::one_bit(4, 3), Basis8::zero(4), Angle::MinusPi8),
+            Operation::measurement(Basis8::zero(4), Basis8::one_bit(4, 1), Phase::Positive),
+        ];
+
+        let mut out = String::new();
+        let mut output = StringOut::with_format(&mut out, format);
+        for op in &ops {
+            output.write_operation(4, op).unwrap();
+        }
+        assert_eq!(out, "T: IIZI\nSdg: ZIII\nRotate 1: ZZII\nRotate -1: IIIX\nMeasure +: IZII\n");
+
+        round_trip_with(4, &ops, format);
+        round_trip_with(200, &[
+            Operation::rotation(Basis256::zero(200), Basis256::one_bit(200, 150), Angle::MinusPi8),
+            Operation::rotation(Basis256::zero(200), Basis256::one_bit(200, 0), Angle::Pi2),
+            Operation::rotation(Basis256::zero(200), Basis256::one_bit(200, 199), Angle::PlusPi4),
+        ], FormatOptions { run_length_encode: true, ..format });
+    }
+
+    #[test]
+    fn test_dump_symplectic() {
+        let format = FormatOptions { dump_symplectic: true, ..Default::default() };
+        let mut buf = String::new();
+
+        let op = Operation::rotation(Basis8::with_true_bits(5, &[0, 1]), Basis8::with_true_bits(5, &[1, 4]), Angle::MinusPi8);
+        fmt_operation_with(&mut buf, 5, &op, &format).unwrap();
+        assert_eq!(buf, "1 11000 01001\n");
+
+        let op = Operation::measurement(DBasis::one_bit(70, 69), DBasis::zero(70), Phase::Positive);
+        fmt_operation_with(&mut buf, 70, &op, &format).unwrap();
+        let fields: Vec<_> = buf.trim_end().split(' ').collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], "0");
+        for q in 0..70 {
+            assert_eq!(fields[1].as_bytes()[q] == b'1', op.x.get_bit(q));
+            assert_eq!(fields[2].as_bytes()[q] == b'1', op.z.get_bit(q));
+        }
+    }
+
+    #[test]
+    fn test_pauli_convention() {
+        let op = Operation::rotation(Basis8::with_true_bits(4, &[0, 1]), Basis8::with_true_bits(4, &[1, 2]), Angle::PlusPi8);
+        let mut buf = String::new();
+        fmt_operation_with(&mut buf, 4, &op, &FormatOptions::default()).unwrap();
+        assert_eq!(buf, "Rotate 1: XYZI\n");
+        fmt_operation_with(&mut buf, 4, &op, &FormatOptions { pauli_convention: PauliConvention::ZX, ..Default::default() }).unwrap();
+        assert_eq!(buf, "Rotate 1: ZYXI\n");
+    }
+
+    #[test]
+    fn test_pauli_convention_round_trip() {
+        let ops = [
+            Operation::rotation(Basis8::with_true_bits(4, &[0, 1]), Basis8::with_true_bits(4, &[1, 2]), Angle::PlusPi8),
+            Operation::measurement(Basis8::one_bit(4, 3), Basis8::zero(4), Phase::Negative),
+        ];
+        for pauli_convention in [PauliConvention::XZ, PauliConvention::ZX] {
+            for run_length_encode in [false, true] {
+                round_trip_with(4, &ops, FormatOptions { run_length_encode, pauli_convention, ..Default::default() });
+            }
+        }
+    }
+
+    #[test]
+    fn test_qubit_order_round_trip() {
+        let op = Operation::rotation(Basis8::with_true_bits(4, &[0, 1]), Basis8::with_true_bits(4, &[1, 2]), Angle::PlusPi8);
+        let mut buf = String::new();
+        fmt_operation_with(&mut buf, 4, &op, &FormatOptions { qubit_order: QubitOrder::Lsb, ..Default::default() }).unwrap();
+        assert_eq!(buf, "Rotate 1: IZYX\n");
+        fmt_operation_with(&mut buf, 4, &op, &FormatOptions { qubit_order: QubitOrder::Lsb, run_length_encode: true, ..Default::default() }).unwrap();
+        assert_eq!(buf, "Rotate 1: I Z Y X\n");
+
+        let ops = [
+            op,
+            Operation::measurement(Basis8::one_bit(4, 3), Basis8::zero(4), Phase::Negative),
+        ];
+        let wide = [
+            Operation::rotation(Basis256::one_bit(200, 150), Basis256::one_bit(200, 150), Angle::MinusPi8),
+            Operation::measurement(Basis256::zero(200), Basis256::with_true_bits(200, &[0, 1, 199]), Phase::Positive),
+        ];
+        for qubit_order in [QubitOrder::Msb, QubitOrder::Lsb] {
+            for run_length_encode in [false, true] {
+                round_trip_with(4, &ops, FormatOptions { run_length_encode, qubit_order, ..Default::default() });
+                round_trip_with(200, &wide, FormatOptions { run_length_encode, qubit_order, ..Default::default() });
+            }
+            round_trip_with(200, &wide, FormatOptions { columns: Some(64), qubit_order, ..Default::default() });
+        }
+    }
+
+    #[test]
+    fn test_operations_per_write() {
+        let mut rng = SmallRng::seed_from_u64(974);
+        let ops: Vec<_> = (0..50).map(|_| Operation::<Basis128>::rand(100, &mut rng)).collect();
+        let write = |operations_per_write, format| {
+            let mut output = WriteOutput::with_format(Vec::new(), format).with_operations_per_write(operations_per_write);
+            output.write_comment("start").unwrap();
+            for (i, op) in ops.iter().enumerate() {
+                if i == 20 {
+                    output.begin_repeat(3).unwrap();
+                } else if i == 30 {
+                    output.end_repeat().unwrap();
+                }
+                output.write_operation(100, op).unwrap();
+            }
+            output.flush().unwrap();
+            output.writer.get_ref().clone()
+        };
+
+        for format in [FormatOptions::default(), FormatOptions { columns: Some(32), ..Default::default() }] {
+            let expected = write(1, format);
+            for operations_per_write in [2, 7, 64] {
+                assert_eq!(write(operations_per_write, format), expected, "{}", operations_per_write);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{io::Write, time::Duration};
+
+use crate::optimization::Stats;
+
+
+/// The phases of an optimization round, as recorded in a [`Profile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundPhase {
+    TForward,
+    Partition,
+}
+
+
+impl RoundPhase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoundPhase::TForward => "t_forward",
+            RoundPhase::Partition => "partition",
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseRecord {
+    pub round: usize,
+    pub phase: RoundPhase,
+    pub duration: Duration,
+    pub stats: Stats,
+}
+
+
+/// Total optimization time of each iteration with `--iterations`.
+#[derive(Clone, Debug, Default)]
+pub struct IterationTimings {
+    samples: Vec<Duration>,
+}
+
+
+impl IterationTimings {
+    pub fn push(&mut self, duration: Duration) {
+        self.samples.push(duration);
+    }
+
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    /// The middle sample (the mean of the two middle samples for an even count).
+    pub fn median(&self) -> Duration {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        match sorted.len() {
+            0 => Duration::ZERO,
+            len if len % 2 == 1 => sorted[len / 2],
+            len => (sorted[len / 2 - 1] + sorted[len / 2]) / 2,
+        }
+    }
+
+    pub fn min(&self) -> Duration {
+        self.samples.iter().min().copied().unwrap_or_default()
+    }
+}
+
+
+/// Per-round, per-phase timings collected by [`crate::run`] when `--profile` is given.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    records: Vec<PhaseRecord>,
+}
+
+
+impl Profile {
+    pub fn record(&mut self, round: usize, phase: RoundPhase, duration: Duration, stats: Stats) {
+        self.records.push(PhaseRecord { round, phase, duration, stats });
+    }
+
+    pub fn records(&self) -> &[PhaseRecord] {
+        &self.records
+    }
+
+    /// Write the records as CSV, with columns `round,phase,milliseconds,ops,t_gates`.
+    ///
+    /// `ops` excludes measurements if `rotations_only` is set (see [`Stats::operations`]).
+    pub fn write_csv(&self, mut out: impl Write, rotations_only: bool) -> anyhow::Result<()> {
+        writeln!(out, "round,phase,milliseconds,ops,t_gates")?;
+        for record in &self.records {
+            writeln!(
+                out,
+                "{},{},{:.3},{},{}",
+                record.round,
+                record.phase.name(),
+                record.duration.as_secs_f64() * 1000.0,
+                record.stats.operations(rotations_only),
+                record.stats.t_gates,
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Write one row per round, with columns
+    /// `round,ops_after_push,t_after_push,ops_after_partition,t_after_partition` (see `--rounds-csv`).
+    ///
+    /// The partition columns are empty for a round which didn't partition (an all-Clifford circuit).
+    pub fn write_rounds_csv(&self, mut out: impl Write, rotations_only: bool) -> anyhow::Result<()> {
+        writeln!(out, "round,ops_after_push,t_after_push,ops_after_partition,t_after_partition")?;
+        let mut records = self.records.iter().peekable();
+        while let Some(push) = records.next() {
+            write!(out, "{},{},{}", push.round, push.stats.operations(rotations_only), push.stats.t_gates)?;
+            match records.next_if(|record| record.round == push.round && record.phase == RoundPhase::Partition) {
+                Some(partition) => writeln!(out, ",{},{}", partition.stats.operations(rotations_only), partition.stats.t_gates)?,
+                None => writeln!(out, ",,")?,
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
-use std::{io::{Read, BufRead, BufReader}, collections::VecDeque};
+use std::{io::{Read, BufRead, BufReader}, collections::{HashMap, VecDeque}, fs, path::PathBuf};
 use lazy_static::lazy_static;
+use log::warn;
 use regex::Regex;
 use anyhow::{bail, Context};
 
@@ -15,6 +16,7 @@ pub enum FixedGate {
     Y,
     Z,
     Cx,
+    Id,
 }
 
 
@@ -32,18 +34,76 @@ impl TryFrom<&str> for FixedGate {
             "y" => Ok(Self::Y),
             "z" => Ok(Self::Z),
             "cx" => Ok(Self::Cx),
+            "id" => Ok(Self::Id),
             _ => Err(String::from(value)),
         }
     }
 }
 
 
+impl FixedGate {
+    /// Every gate, in declaration order.
+    pub const ALL: [FixedGate; 10] = [Self::H, Self::T, Self::Tdg, Self::S, Self::Sdg, Self::X, Self::Y, Self::Z, Self::Cx, Self::Id];
+
+    /// The OpenQASM name of the gate (the inverse of `FixedGate::try_from`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::H => "h",
+            Self::T => "t",
+            Self::Tdg => "tdg",
+            Self::S => "s",
+            Self::Sdg => "sdg",
+            Self::X => "x",
+            Self::Y => "y",
+            Self::Z => "z",
+            Self::Cx => "cx",
+            Self::Id => "id",
+        }
+    }
+
+    /// Number of qubits the gate acts on.
+    pub fn n_qubits(&self) -> usize {
+        match self {
+            Self::Cx => 2,
+            _ => 1,
+        }
+    }
+
+    /// Whether the gate is a Clifford, i.e. only becomes pi/2 and pi/4 rotations (everything but
+    /// `t` and `tdg`, which are pi/8 rotations).
+    pub fn is_clifford(&self) -> bool {
+        !matches!(self, Self::T | Self::Tdg)
+    }
+}
+
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     Version(i8),
     Include(String),
     QregDecl(String, usize),
     FixedGate(FixedGate, Vec<usize>),
+    /// A `barrier`, on any qubits.
+    Barrier,
+}
+
+
+/// A user-defined gate, `gate name a, b { ... }`, flattened into builtin gates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GateDefinition {
+    n_args: usize,
+    /// The builtin gates the body applies, with the indices of the arguments they act on.
+    body: Vec<(FixedGate, Vec<usize>)>,
+}
+
+
+/// A file being read in place of the `include` statement naming it.
+#[derive(Debug)]
+struct IncludedFile {
+    path: PathBuf,
+    source: BufReader<fs::File>,
+    /// Line count of the including file, restored once this one is done.
+    outer_line_count: usize,
 }
 
 
@@ -53,6 +113,14 @@ pub struct TokenIterator<R: Read> {
     token_buf: VecDeque<Token>,
     line_buf: String,
     line_count: usize,
+    strict: bool,
+    /// The first qreg declaration seen, `(name, size)`.
+    qreg: Option<(String, usize)>,
+    include_path: Option<PathBuf>,
+    /// The files currently being included, innermost last.
+    includes: Vec<IncludedFile>,
+    /// User-defined gates, by name.
+    gates: HashMap<String, GateDefinition>,
 }
 
 
@@ -63,8 +131,41 @@ impl<R: Read> TokenIterator<R> {
             token_buf: VecDeque::with_capacity(128),
             line_buf: String::with_capacity(128),
             line_count: 0,
+            strict: false,
+            qreg: None,
+            include_path: None,
+            includes: Vec::new(),
+            gates: HashMap::new(),
         }
     }
+
+    /// The (1-indexed) line number of the most recently read line, within the file it was read
+    /// from (which may be an included one).
+    pub fn line_number(&self) -> usize {
+        self.line_count
+    }
+
+    /// In strict mode, gate arguments must name the declared qreg (and, checked by the parser,
+    /// be in bounds), failing with the line number otherwise.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Look for included files in `dir`; those found there are lexed in place of their `include`
+    /// statement (recursively). Without an include path, or for files not found in it, the
+    /// `include` is only passed on as a [`Token::Include`].
+    pub fn set_include_path(&mut self, dir: Option<PathBuf>) {
+        self.include_path = dir;
+    }
+
+    /// The size of the first qreg declared so far, if any.
+    pub fn qreg_size(&self) -> Option<usize> {
+        self.qreg.as_ref().map(|(_, size)| *size)
+    }
 }
 
 lazy_static! {
@@ -84,6 +185,22 @@ lazy_static! {
         Regex::new(r"^\s*creg\s+(?<name>\w+)\[(?<size>\d+)\]\s*;\s*$").unwrap()
     };
 
+    static ref BARRIER: Regex = {
+        Regex::new(r"^\s*barrier\s+[^;]*;\s*$").unwrap()
+    };
+
+    static ref GATEDEF_START: Regex = {
+        Regex::new(r"^\s*gate\s").unwrap()
+    };
+
+    static ref GATEDEF: Regex = {
+        Regex::new(r"^\s*gate\s+(?<name>\w+)\s+(?<args>\w+(\s*,\s*\w+)*)\s*\{(?<body>[^}]*)\}\s*$").unwrap()
+    };
+
+    static ref GATE_STATEMENT: Regex = {
+        Regex::new(r"^(?<gate>\w+)\s+(?<args>\w+(\s*,\s*\w+)*)$").unwrap()
+    };
+
     static ref FIXEDGATE: Regex = {
         Regex::new(r"^\s*(?<gate>\w+)\s+(?<qreg>(\w+(\[\d+\])?)(,\s*\w+(\[\d+\])?)*)\s*;\s*$").unwrap()
     };
@@ -99,23 +216,29 @@ lazy_static! {
 
 
 fn qregs(value: &str) -> anyhow::Result<Vec<usize>> {
+    Ok(qreg_args(value)?.into_iter().map(|(_, idx)| idx).collect())
+}
+
+
+/// Like [`qregs`], but with the register name of each argument.
+fn qreg_args(value: &str) -> anyhow::Result<Vec<(&str, usize)>> {
     let qregs_iter = SEPARATOR.split(value).into_iter();
-    let mut idxs = Vec::with_capacity(2);
+    let mut args = Vec::with_capacity(2);
     for reg in qregs_iter {
         if let Some(m) = QREG_CAPTURE.captures(reg) {
             let idx: usize = m["index"].parse().with_context(|| format!("Invalid quantum registers {}')", value))?;
-            idxs.push(idx);
+            args.push((m.name("qreg_name").unwrap().as_str(), idx));
         } else {
             bail!("could not interpret {} as quantum registers", value)
         }
     }
 
-    Ok(idxs)
+    Ok(args)
 }
 
 
 impl<R: Read> TokenIterator<R> {
-    fn pop(&mut self) -> anyhow::Result<Option<Token>> {
+    pub(crate) fn pop(&mut self) -> anyhow::Result<Option<Token>> {
         if let Some(tok) = self.token_buf.pop_front() {
             return Ok(Some(tok));
         }
@@ -129,42 +252,197 @@ impl<R: Read> TokenIterator<R> {
     }
 
 
-    fn fill_token_buf(&mut self) -> anyhow::Result<()>{
-        // read lines until one is nonempty
-        loop {
+    /// Read the next line into `line_buf` from the innermost included file, or the source once
+    /// those are done. Returns the number of bytes read (0 at the end of the source).
+    fn read_line(&mut self) -> anyhow::Result<usize> {
+        while let Some(included) = self.includes.last_mut() {
+            let len = included.source.read_line(&mut self.line_buf)
+                .with_context(|| format!("while reading included file {}", included.path.display()))?;
+            if len != 0 {
+                return Ok(len);
+            }
+
+            // back to the line after the include
+            self.line_count = included.outer_line_count + 1;
+            self.includes.pop();
+        }
+
+        Ok(self.source.read_line(&mut self.line_buf)?)
+    }
+
+    /// Lex `filename` from the include path in place of its `include` statement. Returns whether
+    /// it was found there.
+    fn include(&mut self, filename: &str) -> anyhow::Result<bool> {
+        let Some(dir) = &self.include_path else {
+            return Ok(false);
+        };
+        let path = dir.join(filename);
+        if !path.is_file() {
+            // the standard library's gates are builtin
+            if filename != "qelib1.inc" {
+                warn!("included file {} not found in {}; ignoring it", filename, dir.display());
+            }
+            return Ok(false);
+        }
+
+        let path = path.canonicalize().with_context(|| format!("Could not resolve included file {}", path.display()))?;
+        if self.includes.iter().any(|included| included.path == path) {
+            bail!("Include cycle: {} is already being included (on line {})", filename, self.line_count);
+        }
+        let file = fs::File::open(&path).with_context(|| format!("Could not open included file {}", path.display()))?;
+
+        self.includes.push(IncludedFile {
+            path,
+            source: BufReader::new(file),
+            outer_line_count: self.line_count,
+        });
+        self.line_count = 0;
+
+        Ok(true)
+    }
+
+    /// Read the rest of a gate definition starting on the current line, and add it to the gate
+    /// table. Invocations of other user-defined gates in the body are inlined, so a gate can't
+    /// (directly or indirectly) invoke itself.
+    fn define_gate(&mut self) -> anyhow::Result<()> {
+        let start_line = self.line_count;
+        let mut definition = self.line_buf.clone();
+        while !definition.contains('}') {
             self.line_count += 1;
             self.line_buf.clear();
-            let len = self.source.read_line(&mut self.line_buf)?;
+            if self.read_line()? == 0 {
+                bail!("Unexpected end of file in the gate definition starting on line {}", start_line);
+            }
+            definition.push_str(&self.line_buf);
+        }
 
-            if len == 0 {
-                // EOF
-                return Ok(());
+        let Some(m) = GATEDEF.captures(&definition) else {
+            bail!("Did not recognize the gate definition on line {} (parameterized gates aren't supported)", start_line);
+        };
+        let name = &m["name"];
+        if FixedGate::try_from(name).is_ok() {
+            bail!("Can't redefine the builtin gate '{}' on line {}", name, start_line);
+        }
+        let args: Vec<&str> = SEPARATOR.split(&m["args"]).collect();
+
+        let mut body = Vec::new();
+        for statement in m["body"].split(';').map(str::trim).filter(|statement| !statement.is_empty()) {
+            let Some(sm) = GATE_STATEMENT.captures(statement) else {
+                bail!("Did not recognize '{}' in the definition of gate '{}' on line {}", statement, name, start_line);
+            };
+            let gate = &sm["gate"];
+            let mut indices = Vec::with_capacity(2);
+            for arg in SEPARATOR.split(&sm["args"]) {
+                let Some(idx) = args.iter().position(|a| *a == arg) else {
+                    bail!("Unknown argument '{}' in the definition of gate '{}' on line {}", arg, name, start_line);
+                };
+                indices.push(idx);
             }
 
-            if self.line_buf.chars().any(|ch| !ch.is_ascii_whitespace()) {
-                break;
+            if gate == name {
+                bail!("Gate '{}' invokes itself in its definition on line {}", name, start_line);
+            } else if let Ok(fixed) = FixedGate::try_from(gate) {
+                if indices.len() != fixed.n_qubits() {
+                    bail!("Gate '{}' takes {} arguments but was given {} in the definition of gate '{}' on line {}", gate, fixed.n_qubits(), indices.len(), name, start_line);
+                }
+                body.push((fixed, indices));
+            } else if let Some(inner) = self.gates.get(gate) {
+                if indices.len() != inner.n_args {
+                    bail!("Gate '{}' takes {} arguments but was given {} in the definition of gate '{}' on line {}", gate, inner.n_args, indices.len(), name, start_line);
+                }
+                body.extend(inner.body.iter().map(|(fixed, inner_args)| (*fixed, inner_args.iter().map(|a| indices[*a]).collect())));
+            } else {
+                bail!("Unsupported gate '{}' in the definition of gate '{}' on line {}", gate, name, start_line);
             }
         }
 
-        // otherwise, fill the token buffer
+        let definition = GateDefinition { n_args: args.len(), body };
+        self.gates.insert(String::from(name), definition);
 
+        Ok(())
+    }
+
+    fn fill_token_buf(&mut self) -> anyhow::Result<()>{
+        // resolved includes, gate definitions and creg declarations don't produce any tokens
+        while self.token_buf.is_empty() {
+            // read lines until one is nonempty
+            loop {
+                self.line_count += 1;
+                self.line_buf.clear();
+                let len = self.read_line()?;
+
+                if len == 0 {
+                    // EOF
+                    return Ok(());
+                }
+
+                if self.line_buf.chars().any(|ch| !ch.is_ascii_whitespace()) {
+                    break;
+                }
+            }
+
+            let included_from = self.includes.last().map(|included| included.path.clone());
+            let tokenized = self.tokenize_line();
+            if let Some(path) = included_from {
+                tokenized.with_context(|| format!("in included file {}", path.display()))?;
+            } else {
+                tokenized?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tokenize_line(&mut self) -> anyhow::Result<()> {
         if let Some(m) = VERSION.captures(&self.line_buf) {
             let version: i8 = m["version_number_major"].parse().with_context(|| format!("Invalid version number on line {} ('{}')", self.line_count, self.line_buf))?;
             self.token_buf.push_back(Token::Version(version));
         } else if let Some(m) = INCLUDE.captures(&self.line_buf) {
             let filename: String = String::from(&m["filename"]);
-            self.token_buf.push_back(Token::Include(filename))
+            if !self.include(&filename)? {
+                self.token_buf.push_back(Token::Include(filename))
+            }
         } else if let Some(m) = QREGDECL.captures(&self.line_buf) {
             let size: usize = m["size"].parse().with_context(|| format!("Invalid qreg size on line {} ('{}')", self.line_count, self.line_buf))?;
             let name: String = String::from(&m["name"]);
+            if self.qreg.is_none() {
+                self.qreg = Some((name.clone(), size));
+            }
             self.token_buf.push_back(Token::QregDecl(name, size));
         } else if let Some(_) = CREGDECL.captures(&self.line_buf) {
-            self.fill_token_buf()?;
+            // ignored
+        } else if BARRIER.is_match(&self.line_buf) {
+            self.token_buf.push_back(Token::Barrier);
+        } else if GATEDEF_START.is_match(&self.line_buf) {
+            self.define_gate()?;
         } else if let Some(m) = FIXEDGATE.captures(&self.line_buf) {
-            let gate_type = FixedGate::try_from(&m["gate"]).unwrap();
+            let gate_type = FixedGate::try_from(&m["gate"]);
+            if let Err(name) = &gate_type {
+                if !self.gates.contains_key(name) {
+                    bail!("Unsupported gate '{}' on line {}", name, self.line_count);
+                }
+            }
+            if self.strict {
+                for (name, _) in qreg_args(&m["qreg"])? {
+                    if self.qreg.as_ref().is_none_or(|(declared, _)| declared != name) {
+                        bail!("Undeclared quantum register '{}' on line {}", name, self.line_count);
+                    }
+                }
+            }
             let qregs = qregs(&m["qreg"]).unwrap();
-            let token = Token::FixedGate(gate_type, qregs);
-            self.token_buf.push_back(token);
+            match gate_type {
+                Ok(gate_type) => self.token_buf.push_back(Token::FixedGate(gate_type, qregs)),
+                Err(name) => {
+                    // inline the user-defined gate
+                    let definition = &self.gates[&name];
+                    if qregs.len() != definition.n_args {
+                        bail!("Gate '{}' takes {} arguments but was given {} on line {}", name, definition.n_args, qregs.len(), self.line_count);
+                    }
+                    for (gate, args) in &definition.body {
+                        self.token_buf.push_back(Token::FixedGate(*gate, args.iter().map(|a| qregs[*a]).collect()));
+                    }
+                },
+            }
         } else {
             bail!("Did not recognize line {} ('{}')", self.line_count, self.line_buf);
         }
@@ -189,6 +467,20 @@ impl<R: Read> TokenIterator<R> {
 
         Ok(self.token_buf.front())
     }
+
+    /// Lex the rest of the input, counting each gate without building any operations.
+    ///
+    /// Returns `(gate, count)` for every gate that appears, in [`FixedGate::ALL`] order.
+    pub fn gate_counts(&mut self) -> anyhow::Result<Vec<(FixedGate, usize)>> {
+        let mut counts = [0usize; FixedGate::ALL.len()];
+        while let Some(tok) = self.pop()? {
+            if let Token::FixedGate(gate, _) = tok {
+                counts[gate as usize] += 1;
+            }
+        }
+
+        Ok(FixedGate::ALL.into_iter().zip(counts).filter(|(_, count)| *count > 0).collect())
+    }
 }
 
 
@@ -274,4 +566,120 @@ mod tests {
         dbg!(&tok);
         assert_eq!(tok.len(), 9);
     }
+
+    #[test]
+    fn test_gate_counts() {
+        let src = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    qreg q[14];
+    creg c[14];
+    h q[1];
+    t q[14];
+    t q[12];
+    t q[1];
+    cx q[12],q[14];
+    cx q[1],q[12];
+    "#;
+
+        let counts = TokenIterator::new(src.as_bytes()).gate_counts().unwrap();
+        assert_eq!(counts, vec![(FixedGate::H, 1), (FixedGate::T, 3), (FixedGate::Cx, 2)]);
+
+        for gate in FixedGate::ALL {
+            assert_eq!(FixedGate::try_from(gate.name()), Ok(gate));
+        }
+    }
+
+    #[test]
+    fn test_is_clifford() {
+        use crate::{basis::Basis8, qasm::parser::InstructionIterator};
+
+        for gate in FixedGate::ALL {
+            let qubits = if gate == FixedGate::Cx { "q[0], q[1]" } else { "q[0]" };
+            let src = format!("{} {};\n", gate.name(), qubits);
+            let ops = InstructionIterator::<_, Basis8>::new(2, TokenIterator::new(src.as_bytes()), 16).unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap();
+
+            // matches the angles the parser gives the gate
+            let any_pi8 = ops.iter().any(|op| op.as_rotation().unwrap().angle.is_pi8());
+            assert_eq!(gate.is_clifford(), !any_pi8, "{:?}", gate);
+        }
+        assert_eq!(FixedGate::ALL.iter().filter(|gate| !gate.is_clifford()).count(), 2);
+    }
+
+    #[test]
+    fn test_unsupported_gate() {
+        let src = "OPENQASM 2.0;\nqreg q[2];\nccx q[0], q[1];\n";
+        let err = TokenIterator::new(src.as_bytes()).gate_counts().unwrap_err();
+        assert!(format!("{:#}", err).contains("Unsupported gate 'ccx' on line 3"));
+    }
+
+    #[test]
+    fn test_strict_undeclared_register() {
+        let src = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], r[1];\n";
+        assert_eq!(TokenIterator::new(src.as_bytes()).gate_counts().unwrap().len(), 2);
+
+        let mut lexer = TokenIterator::new(src.as_bytes());
+        lexer.set_strict(true);
+        let err = lexer.gate_counts().unwrap_err();
+        assert!(format!("{:#}", err).contains("Undeclared quantum register 'r' on line 4"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_gate_definition_errors() {
+        let err = |src: &str| format!("{:#}", TokenIterator::new(src.as_bytes()).gate_counts().unwrap_err());
+
+        assert!(err("gate loop a { h a; loop a; }\n").contains("Gate 'loop' invokes itself in its definition on line 1"));
+        assert!(err("gate g a, b { cx a; }\n").contains("Gate 'cx' takes 2 arguments but was given 1"));
+        assert!(err("gate g a { ccx a; }\n").contains("Unsupported gate 'ccx' in the definition of gate 'g'"));
+        assert!(err("gate g a {\nh b;\n}\n").contains("Unknown argument 'b' in the definition of gate 'g' on line 1"));
+        assert!(err("gate h a { x a; }\n").contains("Can't redefine the builtin gate 'h'"));
+        assert!(err("gate rz(theta) a { z a; }\n").contains("parameterized gates aren't supported"));
+        assert!(err("qreg q[2];\ngate g a {\nh a;\n").contains("Unexpected end of file in the gate definition starting on line 2"));
+        assert!(err("qreg q[2];\ngate g a, b { cx a, b; }\ng q[0];\n").contains("Gate 'g' takes 2 arguments but was given 1 on line 3"));
+        // undefined gates are still unsupported
+        assert!(err("g q[0];\n").contains("Unsupported gate 'g' on line 1"));
+    }
+
+    #[test]
+    fn test_include_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("prep.inc"), "h q[0];\n\ninclude \"entangle.inc\";\n").unwrap();
+        fs::write(dir.path().join("entangle.inc"), "cx q[0], q[1];\n").unwrap();
+
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\ninclude \"prep.inc\";\nt q[1];\nh q[2;\n";
+        let mut lexer = TokenIterator::new(src.as_bytes());
+        lexer.set_include_path(Some(dir.path().to_path_buf()));
+        let tok: Vec<_> = std::iter::from_fn(|| lexer.pop().transpose()).take(6).collect::<anyhow::Result<_>>().unwrap();
+        assert_eq!(tok, vec![
+            Token::Version(2),
+            // not in the include path, so passed on as before
+            Token::Include(String::from("qelib1.inc")),
+            Token::QregDecl(String::from("q"), 2),
+            Token::FixedGate(FixedGate::H, vec![0]),
+            Token::FixedGate(FixedGate::Cx, vec![0, 1]),
+            Token::FixedGate(FixedGate::T, vec![1]),
+        ]);
+        // line numbers pick up after the include
+        let err = lexer.pop().unwrap_err();
+        assert!(format!("{:#}", err).contains("Did not recognize line 6"), "{:#}", err);
+
+        // without an include path, includes are only tokens
+        assert_eq!(tokens("include \"prep.inc\";\n"), vec![Token::Include(String::from("prep.inc"))]);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.inc"), "h q[0];\ninclude \"b.inc\";\n").unwrap();
+        fs::write(dir.path().join("b.inc"), "include \"a.inc\";\n").unwrap();
+
+        let mut lexer = TokenIterator::new("include \"a.inc\";\n".as_bytes());
+        lexer.set_include_path(Some(dir.path().to_path_buf()));
+        assert_eq!(lexer.pop().unwrap(), Some(Token::FixedGate(FixedGate::H, vec![0])));
+        let err = lexer.pop().unwrap_err();
+        assert!(format!("{:#}", err).contains("Include cycle: a.inc is already being included (on line 1)"), "{:#}", err);
+        assert!(format!("{:#}", err).contains("b.inc"), "{:#}", err);
+    }
 }
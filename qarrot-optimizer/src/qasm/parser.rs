@@ -14,14 +14,52 @@ pub struct InstructionIterator<R: Read, B: Basis> {
     buf_size: usize,
 
     n_qubits: usize,
+    barriers_as_partition_hints: bool,
+    gate_set: GateSet,
 
     // buffering, repeats
     operation_buf: VecDeque<Operation<B>>,
 }
 
-fn qasm_to_rotations<B: Basis>(n_qubits: usize, gate: &FixedGate, qregs: &Vec<usize>, ops: &mut VecDeque<Operation<B>>) -> anyhow::Result<()> {
+/// Which (equivalent) rotations the Clifford gates H and CX are decomposed into.
+///
+/// The decompositions implement the same gates, but the optimizer may fold their Clifford
+/// rotations into neighbouring T gates differently, so the T gates in the output can differ by a
+/// Pauli (e.g. one gate set's T is the other's T†, with the difference moved into the Cliffords).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GateSet {
+    /// H as Z, X, Z pi/4 rotations; CX as a ZX pi/4 rotation then -pi/4 rotations on each qubit.
+    #[default]
+    Standard,
+    /// H as X, Z, X pi/4 rotations; CX as pi/4 rotations on each qubit then a -pi/4 ZX rotation
+    /// (the decomposition of CX's inverse, which is CX again).
+    Alternate,
+}
+
+
+impl TryFrom<&str> for GateSet {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "standard" => Ok(Self::Standard),
+            "alternate" => Ok(Self::Alternate),
+            _ => bail!("unrecognized gate set {:?}, possible values are \"standard\" or \"alternate\".", value),
+        }
+    }
+}
+
+
+fn qasm_to_rotations<B: Basis>(n_qubits: usize, gate: FixedGate, qregs: &[usize], gate_set: GateSet, ops: &mut VecDeque<Operation<B>>) -> anyhow::Result<()> {
 
     match gate {
+        FixedGate::H if gate_set == GateSet::Alternate => {
+            let x_rotation = || Operation::rotation(B::with_true_bits(n_qubits, &qregs[..1]), B::zero(n_qubits), Angle::PlusPi4);
+            ops.push_back(x_rotation());
+            ops.push_back(Operation::rotation(B::zero(n_qubits), B::with_true_bits(n_qubits, &qregs[..1]), Angle::PlusPi4));
+            ops.push_back(x_rotation());
+        },
+
         FixedGate::H => {
             let x = B::zero(n_qubits);
             let mut z = B::zero(n_qubits);
@@ -99,6 +137,14 @@ fn qasm_to_rotations<B: Basis>(n_qubits: usize, gate: &FixedGate, qregs: &Vec<us
             ops.push_back(Operation::rotation(x, z, angle));
         }
 
+        FixedGate::Cx if gate_set == GateSet::Alternate => {
+            let control = B::with_true_bits(n_qubits, &qregs[..1]);
+            let target = B::with_true_bits(n_qubits, &qregs[1..2]);
+            ops.push_back(Operation::rotation(B::zero(n_qubits), control.clone(), Angle::PlusPi4));
+            ops.push_back(Operation::rotation(target.clone(), B::zero(n_qubits), Angle::PlusPi4));
+            ops.push_back(Operation::rotation(target, control, Angle::MinusPi4));
+        }
+
         FixedGate::Cx => {
             let mut x = B::zero(n_qubits);
             let mut z = B::zero(n_qubits);
@@ -119,6 +165,9 @@ fn qasm_to_rotations<B: Basis>(n_qubits: usize, gate: &FixedGate, qregs: &Vec<us
             x.set_bit_true(qregs[1]);
             ops.push_back(Operation::rotation(x, z, angle));
         }
+
+        // no effect on the circuit
+        FixedGate::Id => {}
     }
     return Ok(());
 
@@ -131,16 +180,29 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
             source,
             buf_size,
             n_qubits,
+            barriers_as_partition_hints: false,
+            gate_set: GateSet::default(),
             operation_buf: VecDeque::with_capacity(buf_size),
         });
     }
 
+    /// Emit a [`Operation::barrier`] for each `barrier`, so that the partitioner doesn't merge
+    /// across it. Otherwise barriers are skipped.
+    pub fn set_barriers_as_partition_hints(&mut self, hints: bool) {
+        self.barriers_as_partition_hints = hints;
+    }
+
+    /// Decompose H and CX as `gate_set` says (see [`GateSet`]).
+    pub fn set_gate_set(&mut self, gate_set: GateSet) {
+        self.gate_set = gate_set;
+    }
+
     
     fn fill_buff(&mut self) -> anyhow::Result<()> {
         self.operation_buf.clear();
 
         while self.operation_buf.len() < self.buf_size {
-            if let Some(first) = self.source.next() {
+            if let Some(first) = self.source.pop()? {
                 match first {
                     Token::Version(_) => {
                         bail!("unexpected version statement");
@@ -155,8 +217,27 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
                         bail!("multiple qreg declarations found; not supported");
                     }
 
+                    Token::Barrier => {
+                        if self.barriers_as_partition_hints {
+                            self.operation_buf.push_back(Operation::barrier(self.n_qubits));
+                        }
+                    }
+
                     Token::FixedGate(gate, qregs) => {
-                        qasm_to_rotations(self.n_qubits, &gate, &qregs, &mut self.operation_buf).unwrap();
+                        if self.source.is_strict() {
+                            let size = self.source.qreg_size().unwrap_or(0);
+                            if let Some(idx) = qregs.iter().find(|idx| **idx >= size) {
+                                bail!("Qubit index {} out of bounds for qreg of size {} on line {}", idx, size, self.source.line_number());
+                            }
+                        }
+                        let start = self.operation_buf.len();
+                        qasm_to_rotations(self.n_qubits, gate, &qregs, self.gate_set, &mut self.operation_buf)?;
+                        if cfg!(feature = "source-tracking") {
+                            let line = u32::try_from(self.source.line_number()).ok();
+                            for op in self.operation_buf.range_mut(start..) {
+                                op.set_src_line(line);
+                            }
+                        }
                     }
                 }
             } else {
@@ -168,6 +249,12 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
     }
 
 
+    /// A lower bound on the number of operations left: those already buffered (the source isn't
+    /// read ahead).
+    pub fn remaining_hint(&self) -> usize {
+        self.operation_buf.len()
+    }
+
     pub fn next(&mut self) -> anyhow::Result<Option<Operation<B>>> {
         if let Some(front) = self.operation_buf.pop_front() {
             return Ok(Some(front));
@@ -185,10 +272,14 @@ impl<R: Read, B: Basis> InstructionIterator<R, B> {
 
 
 impl<R: Read, B: Basis> Iterator for InstructionIterator<R, B> {
-    type Item = Operation<B>;
+    type Item = anyhow::Result<Operation<B>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next().unwrap()
+        self.next().transpose()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_hint(), None)
     }
 }
 
@@ -225,7 +316,7 @@ mod tests {
                 Token::Version(_) => {
                     seen_openqasm = true;
                 },
-                Token::Include(_) => (),
+                Token::Include(_) | Token::Barrier => (),
                 Token::QregDecl(_, qubits) => {
                     n_qubits = Some(qubits);
                     break;
@@ -244,11 +335,77 @@ mod tests {
         let parser = InstructionIterator::<_, Basis16>::new(4, lexer, 32).unwrap();
         dbg!(&parser);
 
-        let parsed: Vec<_> = parser.collect();
+        let parsed = parser.collect::<anyhow::Result<Vec<_>>>().unwrap();
         dbg!(&parsed);
 
         assert_eq!(parsed.len(), 12);
 
 
     }
+
+    #[test]
+    fn test_remaining_hint() {
+        let src = "OPENQASM 2.0;\nqreg q[3];\nh q[0];\nt q[1];\ncx q[0],q[2];\nbarrier q[0],q[1];\ns q[2];\nccx q[0],q[1],q[2];\n";
+        let parse = || {
+            let mut tokens = TokenIterator::new(src.as_bytes());
+            // skip the header, as infer_run_qasm does
+            while !matches!(tokens.next(), Some(Token::QregDecl(..))) {}
+            tokens
+        };
+        let expected = InstructionIterator::<_, Basis16>::new(3, parse(), 2).unwrap().count();
+
+        let mut parser = InstructionIterator::<_, Basis16>::new(3, parse(), 2).unwrap();
+        let mut remaining = expected;
+        loop {
+            let (lower, upper) = Iterator::size_hint(&parser);
+            assert!(lower <= remaining, "hint {} with {} left", lower, remaining);
+            assert_eq!(upper, None);
+            if Iterator::next(&mut parser).is_none() {
+                break;
+            }
+            remaining -= 1;
+        }
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn test_id_gate() {
+        let parse = |src: &str| {
+            InstructionIterator::<_, Basis16>::new(3, TokenIterator::new(src.as_bytes()), 4).unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+
+        let without = "h q[0];\nt q[1];\ncx q[0],q[2];\ntdg q[2];\ns q[1];\n";
+        let with = "id q[0];\nh q[0];\nid q[1];\nid q[2];\nt q[1];\ncx q[0],q[2];\nid q[0];\nid q[0];\nid q[0];\nid q[0];\ntdg q[2];\ns q[1];\nid q[1];\n";
+        assert_eq!(parse(with), parse(without));
+        assert!(parse("id q[0];\nid q[1];\n").is_empty());
+    }
+
+    #[test]
+    fn test_gate_definition() {
+        let parse = |src: &str| {
+            InstructionIterator::<_, Basis16>::new(3, TokenIterator::new(src.as_bytes()), 4).unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+
+        let defined = "gate mycz a, b {\n  h b;\n  cx a, b;\n  h b;\n}\ngate swapcz a,b { mycz a, b; mycz b,a; }\nmycz q[1], q[0];\nswapcz q[0], q[2];\n";
+        let inlined = "h q[0];\ncx q[1],q[0];\nh q[0];\nh q[2];\ncx q[0],q[2];\nh q[2];\nh q[0];\ncx q[2],q[0];\nh q[0];\n";
+        let ops = parse(defined);
+        assert_eq!(ops, parse(inlined));
+        assert_eq!(ops.len(), 9 * 3);
+    }
+
+    #[test]
+    fn test_strict_out_of_bounds() {
+        let src = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\nt q[2];\ncx q[0],q[3];\n";
+        let mut lexer = TokenIterator::<_>::new(src.as_bytes());
+        lexer.set_strict(true);
+        while !matches!(lexer.next(), Some(Token::QregDecl(_, _))) {}
+
+        let parser = InstructionIterator::<_, Basis16>::new(3, lexer, 32).unwrap();
+        let err = parser.collect::<anyhow::Result<Vec<_>>>().unwrap_err();
+        assert!(format!("{:#}", err).contains("Qubit index 3 out of bounds for qreg of size 3 on line 5"), "{:#}", err);
+    }
 }
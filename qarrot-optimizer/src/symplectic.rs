@@ -1,5 +1,7 @@
-use crate::basis::Basis;
-use std::{fmt::Debug, ops::BitXorAssign};
+use anyhow::bail;
+
+use crate::{basis::Basis, input::{lexer::{Pauli, Token}, parser::paulis_from_tokens}, operation::{Operation, OperationKind}};
+use std::{fmt::{Debug, Write}, ops::BitXorAssign};
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Symplectic<B: Basis> {
@@ -14,28 +16,44 @@ impl<B: Basis> Debug for Symplectic<B> {
     }
 }
 
+impl<B: Basis> From<&Operation<B>> for Symplectic<B> {
+    fn from(op: &Operation<B>) -> Self {
+        let sign = match op.kind {
+            OperationKind::Nop | OperationKind::Barrier => false,
+            OperationKind::Measurement { phase } => phase.sign_bit(),
+            OperationKind::Rotation { angle } => angle.sign_bit(),
+        };
+        Self { sign, x: op.x.clone(), z: op.z.clone() }
+    }
+}
+
+fn write_bits<B: Basis>(buf: &mut String, bits: &B, n_qubits: usize) {
+    for bit in 0..n_qubits {
+        buf.write_char(if bits.get_bit(bit) {
+            '1'
+        } else {
+            '0'
+        }).unwrap();
+    }
+}
+
 impl<B: Basis> Symplectic<B> {
     pub fn dbg(&self, buf: &mut String, n_qubits: usize) {
-        use std::fmt::Write;
         buf.write_fmt(format_args!("Symplectic{} {{ {} | 0b", self.x.bit_capacity(), self.sign as u8)).unwrap();
-        for bit in 0..n_qubits {
-            buf.write_char(if self.x.get_bit(bit) {
-                '1'
-            } else {
-                '0'
-            }).unwrap();
-        }
+        write_bits(buf, &self.x, n_qubits);
         buf.write_str(" | 0b").unwrap();
-        for bit in 0..n_qubits {
-            buf.write_char(if self.z.get_bit(bit) {
-                '1'
-            } else {
-                '0'
-            }).unwrap();
-        }
+        write_bits(buf, &self.z, n_qubits);
         buf.write_str(" }").unwrap();
     }
 
+    /// Write the same bits as [`Symplectic::dbg`], undecorated: `sign x_bits z_bits` (see `--dump-symplectic`).
+    pub fn dump(&self, buf: &mut String, n_qubits: usize) {
+        buf.write_fmt(format_args!("{} ", self.sign as u8)).unwrap();
+        write_bits(buf, &self.x, n_qubits);
+        buf.write_char(' ').unwrap();
+        write_bits(buf, &self.z, n_qubits);
+    }
+
     pub fn dbg_out(&self, n_qubits: usize) -> String {
         let mut buf = String::with_capacity(128);
         self.dbg(&mut buf, n_qubits);
@@ -43,9 +61,7 @@ impl<B: Basis> Symplectic<B> {
     }
 
     pub fn count_i(&self) -> usize {
-        self.x
-            .and(&self.z)
-            .popcnt()
+        self.x.count_y(&self.z)
     }
 
     pub fn zero(n_qubits: usize) -> Self {
@@ -100,29 +116,62 @@ impl<B: Basis> Symplectic<B> {
         new
     }
 
+    /// Build a symplectic from a Pauli string like `"IXYZ"` (one case-insensitive letter per qubit,
+    /// qubit 0 first).
+    pub fn from_pauli_str(n_qubits: usize, sign: bool, paulis: &str) -> anyhow::Result<Self> {
+        let tokens = paulis.chars()
+            .map(|ch| Pauli::try_from(ch).map(Token::Pauli).map_err(|ch| anyhow::anyhow!("Invalid Pauli '{}' in {:?}", ch, paulis)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if tokens.len() != n_qubits {
+            bail!("Expected {} Paulis but found {} in {:?}", n_qubits, tokens.len(), paulis);
+        }
+        let (x, z) = paulis_from_tokens(n_qubits, tokens)?;
+
+        Ok(Self { sign, x, z })
+    }
+
     pub fn commutes_with(&self, rhs: &Self) -> bool {
-        (self.z.and(&rhs.x).popcnt() + self.x.and(&rhs.z).popcnt()) % 2 == 0
+        (self.z.and_popcnt(&rhs.x) + self.x.and_popcnt(&rhs.z)).is_multiple_of(2)
+    }
+
+    pub fn anticommutes_with(&self, rhs: &Self) -> bool {
+        !self.commutes_with(rhs)
     }
 
     // only valid when they don't commute
     pub fn mul_by(&mut self, rhs: &Self) {
-        let p_i = self.count_i();
-        let q_i = rhs.count_i();
+        // for anticommuting Paulis the product is -i·R, up to the sign
+        self.mul_by_i_power(rhs, 1);
+    }
+
+    /// Multiply by a Pauli this one commutes with, so that `self · rhs = ±R` exactly.
+    pub fn mul_commuting(&mut self, rhs: &Self) {
+        debug_assert!(self.commutes_with(rhs));
+        self.mul_by_i_power(rhs, 0);
+    }
 
-        let theta_c = ((
-            self.z.and(&rhs.x).popcnt() + // | x_z & z_x | +
-            rhs.x.and(&self.z).and(&rhs.z).and(&self.x).popcnt() // | x_x & z_z & x_z & z_x |
-        ) % 2) != 0; // % 2
+    /// `self · rhs` as `i^-extra_i_power` times the (signed) result, with `extra_i_power` 1 for
+    /// anticommuting Paulis and 0 for commuting ones.
+    fn mul_by_i_power(&mut self, rhs: &Self, extra_i_power: usize) {
+        // single scratch basis for all the intermediate results (saves allocations for DBasis)
+        let mut scratch = self.x.clone();
+
+        self.x.and_into(&self.z, &mut scratch);
+        let p_i = scratch.popcnt();
+        rhs.x.and_into(&rhs.z, &mut scratch);
+        let q_i = scratch.popcnt();
+
+        // with Y = iXZ, P·Q = i^(|P_y| + |Q_y| - |R_y|) (-1)^|z_P & x_Q| R
+        self.z.and_into(&rhs.x, &mut scratch);
+        let theta_c = !scratch.popcnt().is_multiple_of(2); // | z_P & x_Q |
 
         *self ^= rhs;
 
-        let mut phase_sum = (p_i + q_i + 1) as isize;
-        phase_sum -= self.count_i() as isize;
-        phase_sum = phase_sum.abs();
+        self.x.and_into(&self.z, &mut scratch);
+        let phase_sum = (p_i + q_i + extra_i_power) as isize - scratch.popcnt() as isize;
         debug_assert!(phase_sum % 2 == 0);
-        phase_sum /= 2;
 
-        self.sign ^= phase_sum != 0;
+        self.sign ^= (phase_sum / 2).rem_euclid(2) != 0;
         self.sign ^= theta_c;
     }
 
@@ -143,6 +192,8 @@ impl<'a, B: Basis> BitXorAssign<&'a Symplectic<B>> for Symplectic<B> {
 
 #[cfg(test)]
 mod tests {
+    use rand::{Rng, SeedableRng};
+
     use super::*;
     use crate::basis::{*};
 
@@ -159,6 +210,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_from_pauli_str() {
+        let s = Symplectic::<Basis8>::from_pauli_str(4, false, "IXYZ").unwrap();
+        assert_eq!(s, Symplectic::from_indexes(4, false, &[1, 2], &[2, 3]));
+
+        let s = Symplectic::<DBasis>::from_pauli_str(3, true, "zyx").unwrap();
+        assert_eq!(s, Symplectic::from_indexes(3, true, &[1, 2], &[0, 1]));
+
+        assert!(Symplectic::<Basis8>::from_pauli_str(4, false, "IXY").is_err());
+        assert!(Symplectic::<Basis8>::from_pauli_str(4, false, "IXYQ").is_err());
+    }
+
+    #[test]
+    fn test_anticommutes_with() {
+        let x0 = Symplectic::<Basis8>::from_indexes(2, false, &[0], &[]);
+        let z0 = Symplectic::<Basis8>::from_indexes(2, false, &[], &[0]);
+        let z1 = Symplectic::<Basis8>::from_indexes(2, false, &[], &[1]);
+
+        assert!(x0.anticommutes_with(&z0));
+        assert!(z0.anticommutes_with(&x0));
+        assert!(!x0.anticommutes_with(&z1));
+        assert!(x0.commutes_with(&z1));
+        assert!(!x0.anticommutes_with(&x0));
+    }
+
+    /// The power of i in `p·q`, one qubit at a time.
+    fn mul_power(n_qubits: usize, p: &Symplectic<Basis8>, q: &Symplectic<Basis8>) -> usize {
+        let mut power = 2 * (p.sign as usize + q.sign as usize);
+        for i in 0..n_qubits {
+            let pauli = |s: &Symplectic<Basis8>| (s.x.get_bit(i), s.z.get_bit(i));
+            power += match (pauli(p), pauli(q)) {
+                // X·Y = iZ, Y·Z = iX, Z·X = iY, and the reverse is -i
+                ((true, false), (true, true)) | ((true, true), (false, true)) | ((false, true), (true, false)) => 1,
+                ((true, true), (true, false)) | ((false, true), (true, true)) | ((true, false), (false, true)) => 3,
+                _ => 0,
+            };
+        }
+        power % 4
+    }
+
+    #[test]
+    fn test_mul_sign() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(891);
+        let n_qubits = 6;
+        let mut checked = 0;
+        for _ in 0..4096 {
+            let p = Symplectic { sign: rng.gen(), x: Basis8::rand(n_qubits, &mut rng), z: Basis8::rand(n_qubits, &mut rng) };
+            let q = Symplectic { sign: rng.gen(), x: Basis8::rand(n_qubits, &mut rng), z: Basis8::rand(n_qubits, &mut rng) };
+            if p.commutes_with(&q) {
+                continue;
+            }
+            // P·Q = -i·R: i^3 for a positive R, and i for a negative one
+            let mut expected = p.clone();
+            expected ^= &q;
+            expected.sign = mul_power(n_qubits, &p, &q) == 1;
+            assert_eq!(p.mul(&q), expected, "{:?} · {:?}", p, q);
+            checked += 1;
+        }
+        assert!(checked > 1000);
+    }
+
     #[test]
     fn test_multiplication_8() {
         test_multiplication::<Basis8>(8, 1);
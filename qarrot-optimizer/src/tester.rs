@@ -1,5 +1,7 @@
 use std::{collections::BTreeSet, io};
 
+use anyhow::{bail, Context};
+
 use crate::{basis::Basis, input::{lexer::TokenIterator, parser::InstructionIterator}, symplectic::Symplectic};
 
 
@@ -45,33 +47,34 @@ fn dbg_out<B: Basis>(set: &BTreeSet<Symplectic<B>>, n_qubits: usize) -> String {
 
 
 impl<'a, R: io::Read, B: Basis> Tester<'a, R, B> {
-    fn finalize_sets(&mut self) {
+    fn finalize_sets(&mut self) -> anyhow::Result<()> {
         if self.this_set != self.reference_set {
-            panic!("Divergence in pi/8 rotations found before line {}. The difference is between {} (remaining reference) and {} (remaining program output)", self.counter, dbg_out(&self.reference_set, self.n_qubits), dbg_out(&self.this_set, self.n_qubits));
+            bail!("Divergence in pi/8 rotations found before line {}. The difference is between {} (remaining reference) and {} (remaining program output)", self.counter, dbg_out(&self.reference_set, self.n_qubits), dbg_out(&self.this_set, self.n_qubits));
         }
+        Ok(())
     }
 
     // returns is_done
-    pub fn test_next(&mut self) -> bool {
-        let reference = self.cmp.next().unwrap();
-        let this = self.this.next().unwrap();
+    pub fn test_next(&mut self) -> anyhow::Result<bool> {
+        let reference = self.cmp.next().context("while reading the reference")?;
+        let this = self.this.next().context("while reading the program output")?;
         self.counter += 1;
 
         if reference.is_none() && this.is_none() {
-            self.finalize_sets();
-            return true;
+            self.finalize_sets()?;
+            return Ok(true);
         }
         let Some(reference) = reference else {
-            panic!("The reference source contains fewer lines than the program output (divergence at line {})", self.counter);
+            bail!("The reference source contains fewer lines than the program output (divergence at line {})", self.counter);
         };
         let Some(this) = this else {
-            panic!("The reference source contains more lines than the program output (divergence at line {})", self.counter);
+            bail!("The reference source contains more lines than the program output (divergence at line {})", self.counter);
         };
 
         // if one is a rotation, both should be
         if let Some(reference_r) = reference.as_rotation() {
             let Some(this_r) = this.as_rotation() else {
-                panic!("At line {}: should be {} but was {}.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
+                bail!("At line {}: should be {} but was {}.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
             };
 
             if reference_r.angle.is_pi8() && this_r.angle.is_pi8() {
@@ -89,11 +92,11 @@ impl<'a, R: io::Read, B: Basis> Tester<'a, R, B> {
 
                 if self.this_set.is_empty() && self.reference_set.is_empty() {
                     if this_s == reference_s {
-                        return false;
+                        return Ok(false);
                     }
                     self.this_set.insert(this_s);
                     self.reference_set.insert(reference_s);
-                    return false;
+                    return Ok(false);
                 }
 
                 let mut reference_commutes_with_all = true;
@@ -127,13 +130,13 @@ impl<'a, R: io::Read, B: Basis> Tester<'a, R, B> {
                 } else if reference_commutes_with_all || this_commutes_with_all {
                     let ref_fmt = reference_s.dbg_out(self.n_qubits);
                     let this_fmt = this_s.dbg_out(self.n_qubits);
-                    panic!(
+                    bail!(
                         "Divergence in pi/8 rotations (commutes {}|{} r|t) found before line {}. The difference is between {} (remaining reference) and {} (remaining program output) with {} (reference) and {} (actual) not added.",
                         reference_commutes_with_all as u8, this_commutes_with_all as u8,
                         self.counter, dbg_out(&self.reference_set, self.n_qubits), dbg_out(&self.this_set, self.n_qubits), ref_fmt, this_fmt);
                 } else {
                     if self.this_set != self.reference_set {
-                        panic!("Divergence in pi/8 rotations (neither commutes) found before line {}. The difference is between {} (remaining reference) and {} (remaining program output)", self.counter, dbg_out(&self.reference_set, self.n_qubits), dbg_out(&self.this_set, self.n_qubits));
+                        bail!("Divergence in pi/8 rotations (neither commutes) found before line {}. The difference is between {} (remaining reference) and {} (remaining program output)", self.counter, dbg_out(&self.reference_set, self.n_qubits), dbg_out(&self.this_set, self.n_qubits));
                     }
                     self.this_set.clear();
                     self.reference_set.clear();
@@ -141,38 +144,47 @@ impl<'a, R: io::Read, B: Basis> Tester<'a, R, B> {
                     self.reference_set.insert(reference_s);
                 }
             } else if reference_r.angle.is_pi8() || this_r.angle.is_pi8() {
-                panic!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
+                bail!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
             } else {
                 // horrible hack, oops
                 // but the whole state is in self so we can't hold these references past the call to finalize_sets
                 let fmt = format!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
-                let do_panic = reference_r != this_r;
-                self.finalize_sets();
+                let diverged = reference_r != this_r;
+                self.finalize_sets()?;
 
-                if do_panic {
-                    panic!("{}", fmt);
+                if diverged {
+                    bail!("{}", fmt);
                 }
             }
         } else {
             let reference_m = reference.as_measurement().unwrap();
             let Some(this_m) = this.as_measurement() else {
-                panic!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
+                bail!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
             };
 
             let fmt = format!("At line {}: should be `{}` but was `{}`.", self.counter, reference.as_string(self.n_qubits), this.as_string(self.n_qubits));
-            let do_panic = reference_m != this_m;
+            let diverged = reference_m != this_m;
 
-            self.finalize_sets();
+            self.finalize_sets()?;
 
-            if do_panic {
-                panic!("{}", fmt);
+            if diverged {
+                bail!("{}", fmt);
             }
         }
 
-        false
+        Ok(false)
+    }
+
+    /// Compare the whole circuits, returning the first divergence as an error.
+    pub fn test_all(&mut self) -> anyhow::Result<()> {
+        while !self.test_next()? {}
+        Ok(())
     }
 
-    pub fn test_all(&mut self) {
-        while !self.test_next() {}
+    /// [`Tester::test_all`], panicking on the first divergence.
+    pub fn assert_all(&mut self) {
+        if let Err(err) = self.test_all() {
+            panic!("{:#}", err);
+        }
     }
 }